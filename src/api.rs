@@ -8,31 +8,42 @@
 //!
 //! ## Endpoints
 //!
-//! | Method | Path        | Description                              |
-//! |--------|-------------|------------------------------------------|
-//! | GET    | /health     | Liveness probe (`{"status":"ok"}`)       |
-//! | GET    | /status     | Interfaces + containers + version        |
-//! | GET    | /interfaces | Current interface→IP mapping             |
-//! | GET    | /containers | Docker container→IP mapping              |
-//! | POST   | /reload     | Trigger config reload                    |
-//! | GET    | /metrics    | Prometheus text format (basic counters)  |
+//! | Method | Path           | Description                                   |
+//! |--------|----------------|-----------------------------------------------|
+//! | GET    | /health        | Liveness probe (`{"status":"ok"}`/`degraded`) |
+//! | GET    | /status        | Interfaces, containers, counters, last apply  |
+//! | GET    | /interfaces    | Current interface→IP mapping                  |
+//! | GET    | /addresses     | Addresses with scope, flags and lifetimes     |
+//! | GET    | /containers    | Docker container→IP mapping                   |
+//! | GET    | /routes        | Main routing table and default gateways       |
+//! | GET    | /peers         | LAN peers from the neighbor (ARP/NDP) table   |
+//! | POST   | /reload        | Trigger config reload                         |
+//! | GET    | /metrics       | Prometheus text format (incl. zone traffic)   |
+//! | GET    | /nftables/plan | Dry run: nftables changes a reload would make |
+//! | GET    | /blocklist     | Blocked addresses and networks                |
+//! | POST   | /blocklist     | Block `{"ip": ..., "ttl": ..., "reason": ...}`|
+//! | DELETE | /blocklist/:ip | Unblock an address or network                 |
 
 use axum::{
     Router,
-    extract::State,
+    extract::{Path, State},
     http::StatusCode,
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, post},
     Json,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
-use crate::types::{EventSender, NetworkState, SystemEvent, ControlCommand};
+use crate::blocklist::parse_target;
+use crate::config::load_config;
+use crate::nftables::{ApplyResult, NftablesManager, ZoneCounter};
+use crate::types::{AppError, EventSender, InterfaceAddress, NetworkState, Peer, Route, SystemEvent, ControlCommand};
 
 // ---------------------------------------------------------------------------
 // Shared state passed into Axum handlers
@@ -46,6 +57,9 @@ pub struct ApiState {
     pub container_ips: Arc<Mutex<HashMap<String, IpAddr>>>,
     pub event_tx: EventSender,
     pub version: &'static str,
+    pub nftables: Arc<NftablesManager>,
+    /// `--config` path, re-read by `reload` and the reload plan.
+    pub config_path: Option<PathBuf>,
 }
 
 // ---------------------------------------------------------------------------
@@ -55,21 +69,65 @@ pub struct ApiState {
 #[derive(Serialize)]
 struct HealthResponse {
     status: &'static str,
+    /// Error of the last failed apply while degraded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
 }
 
 #[derive(Serialize)]
 struct StatusResponse {
     version: &'static str,
     interfaces: HashMap<String, Vec<String>>,
+    addresses: HashMap<String, Vec<InterfaceAddress>>,
     containers: HashMap<String, String>,
+    default_gateways: DefaultGateways,
+    zone_counters: Vec<ZoneCounter>,
+    last_apply: Option<ApplyResult>,
+}
+
+/// Lowest-metric default route per address family.
+#[derive(Serialize)]
+struct DefaultGateways {
+    ipv4: Option<Route>,
+    ipv6: Option<Route>,
+}
+
+impl DefaultGateways {
+    fn of(ns: &NetworkState) -> Self {
+        DefaultGateways {
+            ipv4: ns.default_route(false).cloned(),
+            ipv6: ns.default_route(true).cloned(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RoutesResponse {
+    default_gateways: DefaultGateways,
+    routes: Vec<Route>,
+}
+
+#[derive(Deserialize)]
+struct BlockRequest {
+    /// Address or CIDR network.
+    #[serde(alias = "cidr")]
+    ip: String,
+    /// Seconds until the block expires; permanent if omitted.
+    ttl: Option<u64>,
+    reason: Option<String>,
 }
 
 // ---------------------------------------------------------------------------
 // Handlers
 // ---------------------------------------------------------------------------
 
-async fn health() -> Json<HealthResponse> {
-    Json(HealthResponse { status: "ok" })
+/// `degraded` once `nftables.degraded_after` applies in a row have failed; the
+/// daemon keeps running and retrying, so the status code stays 200.
+async fn health(State(state): State<ApiState>) -> Json<HealthResponse> {
+    match state.nftables.degraded().await {
+        Some(reason) => Json(HealthResponse { status: "degraded", reason: Some(reason) }),
+        None => Json(HealthResponse { status: "ok", reason: None }),
+    }
 }
 
 async fn get_status(State(state): State<ApiState>) -> Json<StatusResponse> {
@@ -79,6 +137,9 @@ async fn get_status(State(state): State<ApiState>) -> Json<StatusResponse> {
         .iter()
         .map(|(k, v)| (k.clone(), v.iter().map(|ip| ip.to_string()).collect()))
         .collect();
+    let addresses = ns.interface_addresses.clone();
+    let default_gateways = DefaultGateways::of(&ns);
+    drop(ns);
 
     let containers: HashMap<String, String> = state
         .container_ips
@@ -91,7 +152,11 @@ async fn get_status(State(state): State<ApiState>) -> Json<StatusResponse> {
     Json(StatusResponse {
         version: state.version,
         interfaces,
+        addresses,
         containers,
+        default_gateways,
+        zone_counters: state.nftables.zone_counters().await,
+        last_apply: state.nftables.last_apply().await,
     })
 }
 
@@ -105,6 +170,10 @@ async fn get_interfaces(State(state): State<ApiState>) -> Json<Value> {
     Json(json!(map))
 }
 
+async fn get_addresses(State(state): State<ApiState>) -> Json<HashMap<String, Vec<InterfaceAddress>>> {
+    Json(state.network_state.lock().await.interface_addresses.clone())
+}
+
 async fn get_containers(State(state): State<ApiState>) -> Json<Value> {
     let map: HashMap<String, String> = state
         .container_ips
@@ -116,6 +185,23 @@ async fn get_containers(State(state): State<ApiState>) -> Json<Value> {
     Json(json!(map))
 }
 
+async fn get_routes(State(state): State<ApiState>) -> Json<RoutesResponse> {
+    let ns = state.network_state.lock().await;
+    Json(RoutesResponse {
+        default_gateways: DefaultGateways::of(&ns),
+        routes: ns.routes.iter().cloned().collect(),
+    })
+}
+
+/// Every neighbor table entry, sorted by interface and address; `state`
+/// tells live peers from failed or unresolved ones.
+async fn get_peers(State(state): State<ApiState>) -> Json<Vec<Peer>> {
+    let ns = state.network_state.lock().await;
+    let mut peers: Vec<Peer> = ns.peers.values().flat_map(|peers| peers.values().cloned()).collect();
+    peers.sort_by(|a, b| (&a.interface, a.ip).cmp(&(&b.interface, b.ip)));
+    Json(peers)
+}
+
 async fn post_reload(State(state): State<ApiState>) -> impl IntoResponse {
     match state
         .event_tx
@@ -136,15 +222,92 @@ async fn get_metrics(State(state): State<ApiState>) -> String {
     let ns = state.network_state.lock().await;
     let container_count = state.container_ips.lock().await.len();
     let interface_count = ns.interface_ips.len();
-    format!(
+    let zone_counters = state.nftables.zone_counters().await;
+    let mut metrics = format!(
         "# HELP network_mgr_interfaces_total Number of monitored interfaces\n\
          # TYPE network_mgr_interfaces_total gauge\n\
          network_mgr_interfaces_total {}\n\
          # HELP network_mgr_containers_total Number of tracked Docker containers\n\
          # TYPE network_mgr_containers_total gauge\n\
-         network_mgr_containers_total {}\n",
-        interface_count, container_count,
-    )
+         network_mgr_containers_total {}\n\
+         # HELP network_mgr_ruleset_repairs_total Repairs of the managed nftables ruleset after external changes\n\
+         # TYPE network_mgr_ruleset_repairs_total counter\n\
+         network_mgr_ruleset_repairs_total {}\n",
+        interface_count, container_count, state.nftables.repair_count(),
+    );
+    if !zone_counters.is_empty() {
+        for (name, unit, bytes) in [
+            ("network_mgr_zone_packets_total", "Packets", false),
+            ("network_mgr_zone_bytes_total", "Bytes", true),
+        ] {
+            metrics.push_str(&format!(
+                "# HELP {} {} per zone and direction (in: from the zone, out: to the zone)\n# TYPE {} counter\n",
+                name, unit, name
+            ));
+            for counter in &zone_counters {
+                let value = if bytes { counter.bytes } else { counter.packets };
+                metrics.push_str(&format!(
+                    "{}{{zone=\"{}\",direction=\"{}\"}} {}\n",
+                    name, counter.zone, counter.direction, value
+                ));
+            }
+        }
+    }
+    metrics
+}
+
+/// Dry run of a reload against the current state: readable changes plus the
+/// exact nftables JSON transaction, for the configuration file a reload would
+/// load. Nothing is applied.
+async fn get_nftables_plan(State(state): State<ApiState>) -> impl IntoResponse {
+    let error = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e})));
+    let config = match load_config(state.config_path.as_deref().and_then(|path| path.to_str())) {
+        Ok(config) => config,
+        Err(e) => return error(format!("Failed to load configuration: {}", e)),
+    };
+    let network_state = state.network_state.lock().await.clone();
+    let container_ips = state.container_ips.lock().await.clone();
+    // Listing the table runs `nft`, which blocks
+    let nftables = state.nftables.clone();
+    let live = match tokio::task::spawn_blocking(move || nftables.live_ruleset()).await {
+        Ok(Ok(live)) => live,
+        Ok(Err(e)) => return error(e.to_string()),
+        Err(e) => return error(e.to_string()),
+    };
+    let planner = state.nftables.with_config(config).await;
+    match planner.plan(Some((&network_state, &container_ips)), &live).await {
+        Ok(plan) => (StatusCode::OK, Json(json!(plan))),
+        Err(e) => error(e.to_string()),
+    }
+}
+
+async fn get_blocklist(State(state): State<ApiState>) -> Json<Value> {
+    Json(json!(state.nftables.blocklist().await))
+}
+
+async fn post_blocklist(State(state): State<ApiState>, Json(request): Json<BlockRequest>) -> impl IntoResponse {
+    let net = match parse_target(&request.ip) {
+        Ok(net) => net,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({"error": e}))),
+    };
+    match state.nftables.block(net, request.ttl, request.reason).await {
+        Ok(entry) => (StatusCode::OK, Json(json!(entry))),
+        Err(e @ AppError::ConfigValidation(_)) => (StatusCode::BAD_REQUEST, Json(json!({"error": e.to_string()}))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e.to_string()}))),
+    }
+}
+
+/// The path may be a network, e.g. `DELETE /blocklist/198.51.100.0/24`.
+async fn delete_blocklist(State(state): State<ApiState>, Path(target): Path<String>) -> impl IntoResponse {
+    let net = match parse_target(&target) {
+        Ok(net) => net,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({"error": e}))),
+    };
+    match state.nftables.unblock(net).await {
+        Ok(Some(entry)) => (StatusCode::OK, Json(json!(entry))),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({"error": format!("{} is not blocked", net)}))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e.to_string()}))),
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -158,9 +321,15 @@ pub fn build_router(state: ApiState) -> Router {
         .route("/health", get(health))
         .route("/status", get(get_status))
         .route("/interfaces", get(get_interfaces))
+        .route("/addresses", get(get_addresses))
         .route("/containers", get(get_containers))
+        .route("/routes", get(get_routes))
+        .route("/peers", get(get_peers))
         .route("/reload", post(post_reload))
         .route("/metrics", get(get_metrics))
+        .route("/nftables/plan", get(get_nftables_plan))
+        .route("/blocklist", get(get_blocklist).post(post_blocklist))
+        .route("/blocklist/*target", delete(delete_blocklist))
         .with_state(state)
 }
 
@@ -0,0 +1,1042 @@
+//! Firewall backends: where the `NftablesManager` sends its transactions.
+//!
+//! `NftBackend` talks to the kernel through the `nft` executable (JSON API) and
+//! is the default. `IpsetBackend` maintains the same sets as ipsets for hosts
+//! still on iptables. `MemoryBackend` keeps tables, sets, elements, chains and
+//! rules in memory and records every transaction, so the manager can be tested
+//! without root or `nft`. Library users can plug in their own implementation.
+
+use crate::ruleset::{empty_ruleset, render_expr};
+use crate::types::{AppError, FirewallBackendKind};
+use log::{debug, info, warn};
+use nftables::{
+    expr::{Expression, NamedExpression, Prefix},
+    helper::{self, NftablesError},
+    schema::{
+        Chain, Counter, Element, FlowTable, FlushObject, NfCmd, NfListObject, NfObject, Nftables, Rule, Set, SetFlag, SetType,
+        SetTypeValue, Table,
+    },
+    stmt::{Counter as CounterStatement, Statement},
+    types::NfFamily,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// Applies nftables transactions and lists tables.
+pub trait FirewallBackend: Send + Sync {
+    /// Applies a transaction; if `is_atomic`, either every command takes effect or none does.
+    fn apply(&self, ruleset: &Nftables) -> Result<(), AppError>;
+
+    /// Whether a failed `apply` leaves everything as it was. For backends that
+    /// may carry out part of a transaction, the manager snapshots the table
+    /// first and rolls back from it.
+    fn is_atomic(&self) -> bool {
+        true
+    }
+
+    /// Lists one table (`nft -j list table <family> <name>`).
+    ///
+    /// A table that does not exist yields an empty ruleset.
+    fn list_table(&self, family: NfFamily, table: &str) -> Result<Nftables<'static>, AppError>;
+
+    /// Reads the named counters of one table as `name -> (packets, bytes)`.
+    ///
+    /// Backends without counter support report none.
+    fn list_counters(&self, _family: NfFamily, _table: &str) -> Result<BTreeMap<String, (u64, u64)>, AppError> {
+        Ok(BTreeMap::new())
+    }
+
+    /// Whether chains, rules and counters are supported. Without them the
+    /// manager only maintains sets (no zone policy, NAT or zone counters).
+    fn supports_chains(&self) -> bool {
+        true
+    }
+
+    /// Validates a transaction without applying it (`nft --check`).
+    fn check(&self, _ruleset: &Nftables) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    /// Applies a script in nft syntax as one transaction (`nft -f`).
+    fn apply_script(&self, _script: &str) -> Result<(), AppError> {
+        Err(AppError::ConfigValidation("this firewall backend cannot apply nft scripts".to_string()))
+    }
+
+    /// Validates a script in nft syntax without applying it (`nft --check -f`).
+    fn check_script(&self, _script: &str) -> Result<(), AppError> {
+        Err(AppError::ConfigValidation("this firewall backend cannot apply nft scripts".to_string()))
+    }
+}
+
+/// Backend for the configured `firewall_backend`.
+///
+/// `auto` picks nftables when `nft` can list the kernel's tables, ipset when
+/// only `ipset` works, and nftables otherwise (so its errors get reported).
+pub fn from_config(kind: FirewallBackendKind) -> Arc<dyn FirewallBackend> {
+    match kind {
+        FirewallBackendKind::Nftables => Arc::new(NftBackend::default()),
+        FirewallBackendKind::Ipset => Arc::new(IpsetBackend::with_state_file(IPSET_STATE_PATH)),
+        FirewallBackendKind::Auto => {
+            if !command_succeeds("nft", &["list", "tables"]) && command_succeeds("ipset", &["list", "-n"]) {
+                info!("nftables is not available, maintaining the zone sets with ipset.");
+                Arc::new(IpsetBackend::with_state_file(IPSET_STATE_PATH))
+            } else {
+                Arc::new(NftBackend::default())
+            }
+        }
+    }
+}
+
+fn command_succeeds(program: &str, args: &[&str]) -> bool {
+    Command::new(program)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// The kernel, through the `nft` executable.
+#[derive(Debug, Default)]
+pub struct NftBackend {
+    /// `nft` executable to run; `None` uses the one on `PATH`.
+    pub program: Option<String>,
+}
+
+impl FirewallBackend for NftBackend {
+    fn apply(&self, ruleset: &Nftables) -> Result<(), AppError> {
+        helper::apply_ruleset_with_args(ruleset, self.program.as_deref(), helper::DEFAULT_ARGS)
+            .map_err(AppError::NftablesError)
+    }
+
+    fn list_table(&self, family: NfFamily, table: &str) -> Result<Nftables<'static>, AppError> {
+        let family = family_name(family);
+        let Some(mut listing) = self.list(&["list", "table", family.as_str(), table])? else {
+            return Ok(empty_ruleset());
+        };
+        // nftables-rs keeps counter values in 32 bits; they are not needed here
+        strip_counter_values(&mut listing);
+        Ok(serde_json::from_value(listing)?)
+    }
+
+    fn list_counters(&self, family: NfFamily, table: &str) -> Result<BTreeMap<String, (u64, u64)>, AppError> {
+        let family = family_name(family);
+        let Some(listing) = self.list(&["list", "counters", "table", family.as_str(), table])? else {
+            return Ok(BTreeMap::new());
+        };
+        let objects = listing["nftables"].as_array().cloned().unwrap_or_default();
+        Ok(objects.iter()
+            .filter_map(|object| {
+                let counter = object.get("counter")?;
+                Some((
+                    counter["name"].as_str()?.to_string(),
+                    (counter["packets"].as_u64().unwrap_or(0), counter["bytes"].as_u64().unwrap_or(0)),
+                ))
+            })
+            .collect())
+    }
+
+    fn check(&self, ruleset: &Nftables) -> Result<(), AppError> {
+        helper::apply_ruleset_with_args(ruleset, self.program.as_deref(), &["--check"])
+            .map_err(AppError::NftablesError)
+    }
+
+    fn apply_script(&self, script: &str) -> Result<(), AppError> {
+        self.run_script(&["-f", "-"], script)
+    }
+
+    fn check_script(&self, script: &str) -> Result<(), AppError> {
+        self.run_script(&["--check", "-f", "-"], script)
+    }
+}
+
+impl NftBackend {
+    /// Runs `nft <args>` with `script` on stdin.
+    fn run_script(&self, args: &[&str], script: &str) -> Result<(), AppError> {
+        let program = self.program.as_deref().unwrap_or("nft");
+        let execution = |inner| AppError::NftablesError(NftablesError::NftExecution { program: program.into(), inner });
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(execution)?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(script.as_bytes()).map_err(execution)?;
+        }
+        let output = child.wait_with_output().map_err(execution)?;
+        if output.status.success() {
+            return Ok(());
+        }
+        Err(AppError::NftablesError(NftablesError::NftFailed {
+            program: program.into(),
+            hint: "applying rule script".to_string(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }))
+    }
+
+    /// Runs `nft -j <args>` and parses the output; `None` if the table does not exist.
+    fn list(&self, args: &[&str]) -> Result<Option<serde_json::Value>, AppError> {
+        match helper::get_current_ruleset_raw(self.program.as_deref(), args) {
+            Ok(output) => Ok(Some(serde_json::from_str(&output)?)),
+            Err(NftablesError::NftFailed { ref stderr, .. }) if stderr.contains("No such file or directory") => Ok(None),
+            Err(e) => Err(AppError::NftablesError(e)),
+        }
+    }
+}
+
+/// Removes the `packets`/`bytes` values of named and anonymous counters from a listing.
+fn strip_counter_values(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if let Some(serde_json::Value::Object(counter)) = map.get_mut("counter") {
+                counter.remove("packets");
+                counter.remove("bytes");
+            }
+            map.values_mut().for_each(strip_counter_values);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(strip_counter_values),
+        _ => {}
+    }
+}
+
+/// File recording the ipsets created by the daemon. Like the ipsets themselves,
+/// `/run` does not survive a reboot.
+pub const IPSET_STATE_PATH: &str = "/run/rust-network-mgr/ipsets.json";
+
+/// ipset sets for hosts running iptables (`hash:ip` for address sets, `hash:net`
+/// for subnet sets), changed with `ipset restore`.
+///
+/// Only sets are maintained: tables need no equivalent, and chains, rules and
+/// counters are skipped, so iptables rules matching the sets
+/// (`-m set --match-set lan_ips src`) stay hand-written. `ipset restore` stops at
+/// the first failing line without undoing the earlier ones; the manager then
+/// flushes and refills the sets on its next reconcile.
+///
+/// ipsets cannot carry a comment, so the sets created through this backend are
+/// recorded with theirs, and `list_table` reports only those.
+#[derive(Debug, Default)]
+pub struct IpsetBackend {
+    /// `ipset` executable to run; `None` uses the one on `PATH`.
+    pub program: Option<String>,
+    /// File the created sets are recorded in, so they are recognised after a
+    /// restart; `None` keeps the record in memory only.
+    pub state_path: Option<PathBuf>,
+    /// Created sets and their comments, loaded from `state_path` on first use.
+    owned: Mutex<Option<BTreeMap<String, Option<String>>>>,
+}
+
+impl IpsetBackend {
+    pub fn with_state_file(path: impl Into<PathBuf>) -> Self {
+        Self { state_path: Some(path.into()), ..Self::default() }
+    }
+
+    /// Runs `f` on the record of created sets, saving it if `f` changed it.
+    fn with_owned<T>(&self, f: impl FnOnce(&mut BTreeMap<String, Option<String>>) -> T) -> T {
+        let mut guard = self.owned.lock().unwrap();
+        let owned = guard.get_or_insert_with(|| self.load_owned());
+        let before = owned.clone();
+        let result = f(owned);
+        if *owned != before {
+            self.save_owned(owned);
+        }
+        result
+    }
+
+    fn load_owned(&self) -> BTreeMap<String, Option<String>> {
+        let Some(path) = &self.state_path else {
+            return BTreeMap::new();
+        };
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable ipset state file {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                warn!("Could not read ipset state file {}: {}", path.display(), e);
+                BTreeMap::new()
+            }
+        }
+    }
+
+    fn save_owned(&self, owned: &BTreeMap<String, Option<String>>) {
+        let Some(path) = &self.state_path else {
+            return;
+        };
+        let written = path.parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(path, serde_json::to_string(owned).unwrap_or_default()));
+        if let Err(e) = written {
+            warn!("Could not write ipset state file {}: {}", path.display(), e);
+        }
+    }
+
+    fn run(&self, args: &[&str], input: Option<&str>) -> Result<String, AppError> {
+        let program = self.program.as_deref().unwrap_or("ipset");
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| AppError::Ipset(format!("failed to run {}: {}", program, e)))?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            stdin.write_all(input.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(AppError::Ipset(format!(
+                "{} {} failed: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+impl FirewallBackend for IpsetBackend {
+    fn apply(&self, ruleset: &Nftables) -> Result<(), AppError> {
+        let script = ipset_restore_script(ruleset);
+        if script.is_empty() {
+            return Ok(());
+        }
+        debug!("ipset restore script:\n{}", script);
+        let result = self.run(&["restore"], Some(&script)).map(|_| ());
+        // Sets are recorded even if the restore stopped early, as any of them
+        // may have been created; a recorded set that does not exist is not listed
+        self.with_owned(|owned| {
+            for object in ruleset.objects.iter() {
+                match object {
+                    NfObject::ListObject(NfListObject::Set(set))
+                    | NfObject::CmdObject(NfCmd::Add(NfListObject::Set(set)) | NfCmd::Create(NfListObject::Set(set))) => {
+                        owned.insert(set.name.to_string(), set.comment.as_ref().map(|c| c.to_string()));
+                    }
+                    NfObject::CmdObject(NfCmd::Delete(NfListObject::Set(set))) if result.is_ok() => {
+                        owned.remove(set.name.as_ref());
+                    }
+                    _ => {}
+                }
+            }
+        });
+        result
+    }
+
+    fn list_table(&self, family: NfFamily, table: &str) -> Result<Nftables<'static>, AppError> {
+        let output = self.run(&["save"], None)?;
+        Ok(self.with_owned(|owned| parse_ipset_save(&output, family, table, owned)))
+    }
+
+    /// `ipset restore` stops at the first failing line, keeping the earlier ones.
+    fn is_atomic(&self) -> bool {
+        false
+    }
+
+    fn supports_chains(&self) -> bool {
+        false
+    }
+}
+
+/// Translates the set commands of a transaction into an `ipset restore` script.
+fn ipset_restore_script(ruleset: &Nftables) -> String {
+    let mut lines = Vec::new();
+    for object in ruleset.objects.iter() {
+        match object {
+            NfObject::ListObject(obj) => ipset_add_lines(obj, &mut lines),
+            NfObject::CmdObject(NfCmd::Add(obj) | NfCmd::Create(obj) | NfCmd::Insert(obj)) => {
+                ipset_add_lines(obj, &mut lines)
+            }
+            NfObject::CmdObject(NfCmd::Delete(NfListObject::Set(set))) => lines.push(format!("destroy {}", set.name)),
+            NfObject::CmdObject(NfCmd::Delete(NfListObject::Element(element))) => {
+                for elem in element.elem.iter() {
+                    lines.push(format!("del {} {} -exist", element.name, render_expr(elem)));
+                }
+            }
+            NfObject::CmdObject(NfCmd::Flush(FlushObject::Set(set))) => lines.push(format!("flush {}", set.name)),
+            // Tables, chains, rules and counters have no ipset equivalent
+            _ => {}
+        }
+    }
+    lines.into_iter().map(|line| line + "\n").collect()
+}
+
+fn ipset_add_lines(obj: &NfListObject, lines: &mut Vec<String>) {
+    match obj {
+        NfListObject::Set(set) => {
+            let kind = match &set.flags {
+                Some(flags) if flags.contains(&SetFlag::Interval) => "hash:net",
+                _ => "hash:ip",
+            };
+            let family = match set.set_type {
+                SetTypeValue::Single(SetType::Ipv6Addr) => "inet6",
+                _ => "inet",
+            };
+            let timeout = set.timeout.map(|t| format!(" timeout {}", t)).unwrap_or_default();
+            lines.push(format!("create {} {} family {}{} -exist", set.name, kind, family, timeout));
+            for elem in set.elem.iter().flat_map(|elem| elem.iter()) {
+                lines.push(format!("add {} {} -exist", set.name, render_expr(elem)));
+            }
+        }
+        NfListObject::Element(element) => {
+            for elem in element.elem.iter() {
+                lines.push(format!("add {} {} -exist", element.name, render_expr(elem)));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Builds a listing of the `owned` `hash:ip` / `hash:net` sets in `ipset save`
+/// output, with their recorded comments, as if they were sets of the given table.
+/// The table itself is always reported.
+fn parse_ipset_save(
+    output: &str,
+    family: NfFamily,
+    table: &str,
+    owned: &BTreeMap<String, Option<String>>,
+) -> Nftables<'static> {
+    let mut sets: BTreeMap<String, Set<'static>> = BTreeMap::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["create", name, kind @ ("hash:ip" | "hash:net"), options @ ..] => {
+                let Some(comment) = owned.get(*name) else {
+                    continue;
+                };
+                let option = |key: &str| options.iter().position(|o| *o == key).and_then(|i| options.get(i + 1));
+                let set_type = if option("family") == Some(&"inet6") { SetType::Ipv6Addr } else { SetType::Ipv4Addr };
+                sets.insert(name.to_string(), Set {
+                    family,
+                    table: Cow::Owned(table.to_string()),
+                    name: Cow::Owned(name.to_string()),
+                    handle: None,
+                    set_type: SetTypeValue::Single(set_type),
+                    policy: None,
+                    flags: (*kind == "hash:net").then(|| HashSet::from([SetFlag::Interval])),
+                    comment: comment.clone().map(Cow::Owned),
+                    elem: None,
+                    gc_interval: None,
+                    size: None,
+                    timeout: option("timeout").and_then(|t| t.parse().ok()),
+                });
+            }
+            ["add", name, elem, ..] => {
+                if let Some(set) = sets.get_mut(*name) {
+                    let elem = match elem.split_once('/') {
+                        Some((addr, len)) => Expression::Named(NamedExpression::Prefix(Prefix {
+                            addr: Box::new(Expression::String(Cow::Owned(addr.to_string()))),
+                            len: len.parse().unwrap_or(0),
+                        })),
+                        None => Expression::String(Cow::Owned(elem.to_string())),
+                    };
+                    set.elem.get_or_insert_with(|| Cow::Owned(Vec::new())).to_mut().push(elem);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut objects = vec![NfObject::ListObject(NfListObject::Table(Table {
+        family,
+        name: Cow::Owned(table.to_string()),
+        handle: None,
+    }))];
+    objects.extend(sets.into_values().map(|set| NfObject::ListObject(NfListObject::Set(Box::new(set)))));
+    Nftables { objects: Cow::Owned(objects) }
+}
+
+/// Tables, sets and chains keyed by `"<family> <table>"` / `"<family> <table> <name>"`.
+#[derive(Clone, Default)]
+struct MemoryRuleset {
+    tables: BTreeMap<String, Table<'static>>,
+    /// Set definitions and their elements, keyed by rendered element.
+    sets: BTreeMap<String, (Set<'static>, BTreeMap<String, Expression<'static>>)>,
+    chains: BTreeMap<String, (Chain<'static>, Vec<Rule<'static>>)>,
+    /// Named counters and their `(packets, bytes)`.
+    counters: BTreeMap<String, (Counter<'static>, (u64, u64))>,
+    flowtables: BTreeMap<String, FlowTable<'static>>,
+}
+
+/// In-memory backend recording every transaction, for tests and dry runs.
+///
+/// Commands follow the kernel's rules where the manager relies on them: objects
+/// must be added to an existing table or set, deleting or flushing something
+/// that does not exist fails, and a failing command aborts the whole transaction.
+#[derive(Default)]
+pub struct MemoryBackend {
+    ruleset: Mutex<MemoryRuleset>,
+    transactions: Mutex<Vec<Nftables<'static>>>,
+    scripts: Mutex<Vec<String>>,
+    /// Objects to carry out before failing the next apply, and the error.
+    fail_next: Mutex<Option<(usize, String)>>,
+    /// Error of the next `apply_script`.
+    fail_next_script: Mutex<Option<String>>,
+    /// Reported by `is_atomic`, see `MemoryBackend::non_atomic`.
+    non_atomic: bool,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A backend that reports its applies as not atomic, so the manager
+    /// snapshots and rolls back around them; see `fail_next_apply_after`.
+    pub fn non_atomic() -> Self {
+        MemoryBackend { non_atomic: true, ..Self::default() }
+    }
+
+    /// Transactions applied successfully so far, oldest first.
+    pub fn transactions(&self) -> Vec<Nftables<'static>> {
+        self.transactions.lock().unwrap().clone()
+    }
+
+    /// nft syntax scripts applied so far, oldest first. Scripts are recorded,
+    /// not interpreted.
+    pub fn scripts(&self) -> Vec<String> {
+        self.scripts.lock().unwrap().clone()
+    }
+
+    /// Elements of a set rendered as strings (e.g. `192.168.1.0/24`), sorted;
+    /// `None` if the set does not exist.
+    pub fn set_elements(&self, family: NfFamily, table: &str, set: &str) -> Option<Vec<String>> {
+        let ruleset = self.ruleset.lock().unwrap();
+        ruleset.sets.get(&object_key(family, table, set)).map(|(_, elements)| elements.keys().cloned().collect())
+    }
+
+    /// Devices of a flowtable, sorted; `None` if the flowtable does not exist.
+    pub fn flowtable_devices(&self, family: NfFamily, table: &str, flowtable: &str) -> Option<Vec<String>> {
+        let ruleset = self.ruleset.lock().unwrap();
+        ruleset.flowtables.get(&object_key(family, table, flowtable)).map(|flowtable| {
+            let mut devices: Vec<String> = flowtable.dev.iter().flat_map(|dev| dev.iter()).map(|d| d.to_string()).collect();
+            devices.sort();
+            devices
+        })
+    }
+
+    /// Rules of a chain in order; `None` if the chain does not exist.
+    pub fn chain_rules(&self, family: NfFamily, table: &str, chain: &str) -> Option<Vec<Rule<'static>>> {
+        let ruleset = self.ruleset.lock().unwrap();
+        ruleset.chains.get(&object_key(family, table, chain)).map(|(_, rules)| rules.clone())
+    }
+
+    /// Sets the values of a named counter, as if traffic had matched it. Returns
+    /// `false` if the counter does not exist.
+    pub fn set_counter(&self, family: NfFamily, table: &str, name: &str, packets: u64, bytes: u64) -> bool {
+        let mut ruleset = self.ruleset.lock().unwrap();
+        match ruleset.counters.get_mut(&object_key(family, table, name)) {
+            Some((_, values)) => {
+                *values = (packets, bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes everything, like `nft flush ruleset` run by another tool.
+    pub fn flush_ruleset(&self) {
+        *self.ruleset.lock().unwrap() = MemoryRuleset::default();
+    }
+
+    /// Makes the next `apply` fail with `reason`, leaving the ruleset untouched.
+    pub fn fail_next_apply(&self, reason: &str) {
+        *self.fail_next.lock().unwrap() = Some((0, reason.to_string()));
+    }
+
+    /// Makes the next `apply` carry out its first `objects` commands and then
+    /// fail with `reason`, like a backend without atomic transactions.
+    pub fn fail_next_apply_after(&self, objects: usize, reason: &str) {
+        *self.fail_next.lock().unwrap() = Some((objects, reason.to_string()));
+    }
+
+    /// Makes the next `apply_script` fail with `reason`, recording nothing.
+    pub fn fail_next_script(&self, reason: &str) {
+        *self.fail_next_script.lock().unwrap() = Some(reason.to_string());
+    }
+}
+
+impl FirewallBackend for MemoryBackend {
+    fn apply(&self, ruleset: &Nftables) -> Result<(), AppError> {
+        let fail = self.fail_next.lock().unwrap().take();
+        let mut current = self.ruleset.lock().unwrap();
+        if let Some((objects, reason)) = fail {
+            for object in ruleset.objects.iter().take(objects) {
+                let _ = match object {
+                    NfObject::CmdObject(cmd) => current.apply_cmd(cmd),
+                    NfObject::ListObject(obj) => current.add(obj, false),
+                };
+            }
+            return Err(memory_error(reason));
+        }
+        *current = current.applied(ruleset)?;
+        self.transactions.lock().unwrap().push(to_static(ruleset)?);
+        Ok(())
+    }
+
+    fn is_atomic(&self) -> bool {
+        !self.non_atomic
+    }
+
+    fn check(&self, ruleset: &Nftables) -> Result<(), AppError> {
+        self.ruleset.lock().unwrap().applied(ruleset).map(|_| ())
+    }
+
+    fn apply_script(&self, script: &str) -> Result<(), AppError> {
+        if let Some(reason) = self.fail_next_script.lock().unwrap().take() {
+            return Err(memory_error(reason));
+        }
+        self.scripts.lock().unwrap().push(script.to_string());
+        Ok(())
+    }
+
+    fn check_script(&self, _script: &str) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn list_table(&self, family: NfFamily, table: &str) -> Result<Nftables<'static>, AppError> {
+        let ruleset = self.ruleset.lock().unwrap();
+        let key = table_key(family, table);
+        let Some(table_def) = ruleset.tables.get(&key) else {
+            return Ok(empty_ruleset());
+        };
+        let prefix = format!("{} ", key);
+        let mut objects = vec![NfObject::ListObject(NfListObject::Table(table_def.clone()))];
+        for (set, elements) in ruleset.sets.iter().filter(|(k, _)| k.starts_with(&prefix)).map(|(_, v)| v) {
+            let mut set = set.clone();
+            set.elem = (!elements.is_empty()).then(|| Cow::Owned(elements.values().cloned().collect()));
+            objects.push(NfObject::ListObject(NfListObject::Set(Box::new(set))));
+        }
+        for (chain, rules) in ruleset.chains.iter().filter(|(k, _)| k.starts_with(&prefix)).map(|(_, v)| v) {
+            objects.push(NfObject::ListObject(NfListObject::Chain(chain.clone())));
+            objects.extend(rules.iter().cloned().map(|rule| NfObject::ListObject(NfListObject::Rule(rule))));
+        }
+        for (counter, _) in ruleset.counters.iter().filter(|(k, _)| k.starts_with(&prefix)).map(|(_, v)| v) {
+            objects.push(NfObject::ListObject(NfListObject::Counter(counter.clone())));
+        }
+        for flowtable in ruleset.flowtables.iter().filter(|(k, _)| k.starts_with(&prefix)).map(|(_, v)| v) {
+            objects.push(NfObject::ListObject(NfListObject::FlowTable(flowtable.clone())));
+        }
+        Ok(Nftables { objects: Cow::Owned(objects) })
+    }
+
+    fn list_counters(&self, family: NfFamily, table: &str) -> Result<BTreeMap<String, (u64, u64)>, AppError> {
+        let ruleset = self.ruleset.lock().unwrap();
+        let prefix = format!("{} ", table_key(family, table));
+        Ok(ruleset.counters.iter()
+            .filter(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, (counter, values))| (counter.name.to_string(), *values))
+            .collect())
+    }
+}
+
+impl MemoryRuleset {
+    /// The ruleset after `ruleset`, built on a copy so a failing command
+    /// leaves no partial changes behind.
+    fn applied(&self, ruleset: &Nftables) -> Result<MemoryRuleset, AppError> {
+        let mut next = self.clone();
+        for object in ruleset.objects.iter() {
+            match object {
+                NfObject::CmdObject(cmd) => next.apply_cmd(cmd).map_err(memory_error)?,
+                NfObject::ListObject(obj) => next.add(obj, false).map_err(memory_error)?,
+            }
+        }
+        Ok(next)
+    }
+
+    fn apply_cmd(&mut self, cmd: &NfCmd) -> Result<(), String> {
+        match cmd {
+            NfCmd::Add(obj) => self.add(obj, false),
+            NfCmd::Insert(obj) => self.add(obj, true),
+            NfCmd::Create(obj) => {
+                if self.exists(obj) {
+                    return Err("object already exists".to_string());
+                }
+                self.add(obj, false)
+            }
+            NfCmd::Delete(obj) => self.delete(obj),
+            NfCmd::Flush(FlushObject::Set(set)) => {
+                let key = object_key(set.family, &set.table, &set.name);
+                let (_, elements) = self.sets.get_mut(&key).ok_or_else(|| format!("set {} does not exist", key))?;
+                elements.clear();
+                Ok(())
+            }
+            NfCmd::Flush(FlushObject::Chain(chain)) => {
+                let key = object_key(chain.family, &chain.table, &chain.name);
+                let (_, rules) = self.chains.get_mut(&key).ok_or_else(|| format!("chain {} does not exist", key))?;
+                rules.clear();
+                Ok(())
+            }
+            NfCmd::Flush(FlushObject::Table(table)) => {
+                let prefix = format!("{} ", table_key(table.family, &table.name));
+                for (_, (_, rules)) in self.chains.iter_mut().filter(|(k, _)| k.starts_with(&prefix)) {
+                    rules.clear();
+                }
+                Ok(())
+            }
+            other => Err(format!("unsupported command: {:?}", other)),
+        }
+    }
+
+    fn exists(&self, obj: &NfListObject) -> bool {
+        match obj {
+            NfListObject::Table(t) => self.tables.contains_key(&table_key(t.family, &t.name)),
+            NfListObject::Set(s) => self.sets.contains_key(&object_key(s.family, &s.table, &s.name)),
+            NfListObject::Chain(c) => self.chains.contains_key(&object_key(c.family, &c.table, &c.name)),
+            NfListObject::Counter(c) => self.counters.contains_key(&object_key(c.family, &c.table, &c.name)),
+            NfListObject::FlowTable(f) => self.flowtables.contains_key(&object_key(f.family, &f.table, &f.name)),
+            _ => false,
+        }
+    }
+
+    fn require_table(&self, family: NfFamily, table: &str) -> Result<(), String> {
+        let key = table_key(family, table);
+        if self.tables.contains_key(&key) {
+            Ok(())
+        } else {
+            Err(format!("table {} does not exist", key))
+        }
+    }
+
+    fn add(&mut self, obj: &NfListObject, insert: bool) -> Result<(), String> {
+        match obj {
+            NfListObject::Table(table) => {
+                let table: Table<'static> = to_static(table).map_err(|e| e.to_string())?;
+                self.tables.entry(table_key(table.family, &table.name)).or_insert(table);
+            }
+            NfListObject::Set(set) => {
+                self.require_table(set.family, &set.table)?;
+                let mut set: Set<'static> = to_static(set.as_ref()).map_err(|e| e.to_string())?;
+                let initial = set.elem.take().map(|elem| elem.into_owned()).unwrap_or_default();
+                let key = object_key(set.family, &set.table, &set.name);
+                // Like the kernel, refuse to re-add a set with other parameters
+                if let Some((existing, _)) = self.sets.get(&key) {
+                    let flags = |set: &Set| set.flags.clone().unwrap_or_default();
+                    if existing.set_type != set.set_type || flags(existing) != flags(&set)
+                        || existing.timeout != set.timeout || existing.gc_interval != set.gc_interval
+                    {
+                        return Err(format!("set {} exists with other parameters", key));
+                    }
+                }
+                let (_, elements) = self.sets.entry(key).or_insert_with(|| (set, BTreeMap::new()));
+                elements.extend(initial.into_iter().map(|e| (render_expr(&e), e)));
+            }
+            NfListObject::Element(element) => {
+                let element: Element<'static> = to_static(element).map_err(|e| e.to_string())?;
+                let key = object_key(element.family, &element.table, &element.name);
+                let (_, elements) = self.sets.get_mut(&key).ok_or_else(|| format!("set {} does not exist", key))?;
+                elements.extend(element.elem.iter().map(|e| (render_expr(e), e.clone())));
+            }
+            NfListObject::Chain(chain) => {
+                self.require_table(chain.family, &chain.table)?;
+                let chain: Chain<'static> = to_static(chain).map_err(|e| e.to_string())?;
+                let key = object_key(chain.family, &chain.table, &chain.name);
+                match self.chains.get_mut(&key) {
+                    // Re-adding a base chain may change its policy
+                    Some((existing, _)) => {
+                        if chain.policy.is_some() {
+                            existing.policy = chain.policy;
+                        }
+                    }
+                    None => {
+                        self.chains.insert(key, (chain, Vec::new()));
+                    }
+                }
+            }
+            NfListObject::Counter(counter) => {
+                self.require_table(counter.family, &counter.table)?;
+                let counter: Counter<'static> = to_static(counter).map_err(|e| e.to_string())?;
+                // Re-adding an existing counter keeps its values
+                self.counters
+                    .entry(object_key(counter.family, &counter.table, &counter.name))
+                    .or_insert((counter, (0, 0)));
+            }
+            NfListObject::FlowTable(flowtable) => {
+                self.require_table(flowtable.family, &flowtable.table)?;
+                let flowtable: FlowTable<'static> = to_static(flowtable).map_err(|e| e.to_string())?;
+                let key = object_key(flowtable.family, &flowtable.table, &flowtable.name);
+                match self.flowtables.get_mut(&key) {
+                    // Re-adding a flowtable adds its devices
+                    Some(existing) => {
+                        let mut devices = existing.dev.take().map(|dev| dev.into_owned()).unwrap_or_default();
+                        for device in flowtable.dev.iter().flat_map(|dev| dev.iter()) {
+                            if !devices.contains(device) {
+                                devices.push(device.clone());
+                            }
+                        }
+                        existing.dev = Some(Cow::Owned(devices));
+                    }
+                    None if flowtable.hook.is_none() => return Err(format!("flowtable {} needs a hook", key)),
+                    None => {
+                        self.flowtables.insert(key, flowtable);
+                    }
+                }
+            }
+            NfListObject::Rule(rule) => {
+                let rule: Rule<'static> = to_static(rule).map_err(|e| e.to_string())?;
+                let key = object_key(rule.family, &rule.table, &rule.chain);
+                let (_, rules) = self.chains.get_mut(&key).ok_or_else(|| format!("chain {} does not exist", key))?;
+                if insert {
+                    rules.insert(0, rule);
+                } else {
+                    rules.push(rule);
+                }
+            }
+            other => return Err(format!("unsupported object: {:?}", other)),
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, obj: &NfListObject) -> Result<(), String> {
+        match obj {
+            NfListObject::Table(table) => {
+                let key = table_key(table.family, &table.name);
+                self.tables.remove(&key).ok_or_else(|| format!("table {} does not exist", key))?;
+                let prefix = format!("{} ", key);
+                self.sets.retain(|k, _| !k.starts_with(&prefix));
+                self.chains.retain(|k, _| !k.starts_with(&prefix));
+                self.counters.retain(|k, _| !k.starts_with(&prefix));
+                self.flowtables.retain(|k, _| !k.starts_with(&prefix));
+            }
+            NfListObject::Set(set) => {
+                let key = object_key(set.family, &set.table, &set.name);
+                let reference = format!("@{}", set.name);
+                let referenced = self.chains.values()
+                    .flat_map(|(_, rules)| rules.iter())
+                    .any(|rule| rule.expr.iter().any(|stmt| match stmt {
+                        Statement::Match(m) => [&m.left, &m.right].into_iter().any(|expr| names_set(&render_expr(expr), &reference)),
+                        Statement::Set(set_stmt) => set_stmt.set == reference,
+                        _ => false,
+                    }));
+                if referenced {
+                    return Err(format!("set {} is still in use", key));
+                }
+                self.sets.remove(&key).ok_or_else(|| format!("set {} does not exist", key))?;
+            }
+            NfListObject::Chain(chain) => {
+                let key = object_key(chain.family, &chain.table, &chain.name);
+                self.chains.remove(&key).ok_or_else(|| format!("chain {} does not exist", key))?;
+            }
+            NfListObject::Counter(counter) => {
+                let key = object_key(counter.family, &counter.table, &counter.name);
+                let referenced = self.chains.values()
+                    .flat_map(|(_, rules)| rules.iter())
+                    .any(|rule| rule.expr.iter().any(|stmt| matches!(
+                        stmt,
+                        Statement::Counter(CounterStatement::Named(name)) if *name == counter.name
+                    )));
+                if referenced {
+                    return Err(format!("counter {} is still in use", key));
+                }
+                self.counters.remove(&key).ok_or_else(|| format!("counter {} does not exist", key))?;
+            }
+            NfListObject::FlowTable(flowtable) => {
+                let key = object_key(flowtable.family, &flowtable.table, &flowtable.name);
+                let reference = format!("@{}", flowtable.name);
+                let referenced = self.chains.values()
+                    .flat_map(|(_, rules)| rules.iter())
+                    .any(|rule| rule.expr.iter().any(|stmt| matches!(
+                        stmt,
+                        Statement::Flow(flow) if flow.flowtable == reference
+                    )));
+                if referenced {
+                    return Err(format!("flowtable {} is still in use", key));
+                }
+                self.flowtables.remove(&key).ok_or_else(|| format!("flowtable {} does not exist", key))?;
+            }
+            NfListObject::Element(element) => {
+                let key = object_key(element.family, &element.table, &element.name);
+                let (_, elements) = self.sets.get_mut(&key).ok_or_else(|| format!("set {} does not exist", key))?;
+                for elem in element.elem.iter() {
+                    let rendered = render_expr(elem);
+                    elements.remove(&rendered)
+                        .ok_or_else(|| format!("element {} is not in set {}", rendered, key))?;
+                }
+            }
+            other => return Err(format!("unsupported object: {:?}", other)),
+        }
+        Ok(())
+    }
+}
+
+/// Whether a rendered expression contains the `@<set>` reference, and not
+/// just a longer set name starting with it.
+fn names_set(rendered: &str, reference: &str) -> bool {
+    rendered.match_indices(reference).any(|(start, _)| {
+        !rendered[start + reference.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+    })
+}
+
+fn memory_error(reason: String) -> AppError {
+    AppError::NftablesError(NftablesError::NftFailed {
+        program: "memory".into(),
+        hint: format!("applying ruleset ({})", reason),
+        stdout: String::new(),
+        stderr: reason,
+    })
+}
+
+/// Owned (`'static`) copy of a borrowed nftables object.
+fn to_static<T: Serialize, U: DeserializeOwned>(value: &T) -> Result<U, AppError> {
+    Ok(serde_json::from_value(serde_json::to_value(value)?)?)
+}
+
+fn family_name(family: NfFamily) -> String {
+    match serde_json::to_value(family) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => "inet".to_string(),
+    }
+}
+
+fn table_key(family: NfFamily, table: &str) -> String {
+    format!("{} {}", family_name(family), table)
+}
+
+fn object_key(family: NfFamily, table: &str, name: &str) -> String {
+    format!("{} {} {}", family_name(family), table, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nftables::batch::Batch;
+
+    #[test]
+    fn test_listing_with_large_counters_deserializes() {
+        let mut listing = serde_json::json!({"nftables": [
+            {"table": {"family": "inet", "name": "filter", "handle": 1}},
+            {"counter": {"family": "inet", "table": "filter", "name": "zone_wan_in", "handle": 2,
+                         "packets": 5_000_000_000u64, "bytes": 6_000_000_000u64}},
+            {"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 3,
+                      "expr": [{"counter": {"packets": 5_000_000_000u64, "bytes": 6_000_000_000u64}}]}},
+        ]});
+        assert!(serde_json::from_value::<Nftables>(listing.clone()).is_err());
+        strip_counter_values(&mut listing);
+        let ruleset: Nftables = serde_json::from_value(listing).unwrap();
+        assert_eq!(ruleset.objects.len(), 3);
+    }
+
+    #[test]
+    fn test_ipset_restore_script_and_save_listing() {
+        let set = |name: &str, set_type, flags: Option<HashSet<SetFlag>>, timeout| Set {
+            family: NfFamily::INet,
+            table: "filter".into(),
+            name: name.to_string().into(),
+            handle: None,
+            set_type: SetTypeValue::Single(set_type),
+            policy: None,
+            flags,
+            comment: None,
+            elem: None,
+            gc_interval: None,
+            size: None,
+            timeout,
+        };
+        let element = |name: &str, elem: Vec<Expression<'static>>| Element {
+            family: NfFamily::INet,
+            table: "filter".into(),
+            name: name.to_string().into(),
+            elem: elem.into(),
+        };
+        let mut batch = Batch::new();
+        batch.add(NfListObject::Table(Table { family: NfFamily::INet, name: "filter".into(), handle: None }));
+        batch.add(NfListObject::Set(Box::new(set("lan_ips", SetType::Ipv4Addr, None, Some(3600)))));
+        batch.add(NfListObject::Set(Box::new(set("lan_nets6", SetType::Ipv6Addr, Some(HashSet::from([SetFlag::Interval])), None))));
+        batch.add_cmd(NfCmd::Flush(FlushObject::Set(Box::new(set("lan_ips", SetType::Ipv4Addr, None, None)))));
+        batch.add(NfListObject::Element(element("lan_ips", vec![Expression::String("192.168.1.1".into())])));
+        batch.delete(NfListObject::Element(element("lan_ips", vec![Expression::String("192.168.1.2".into())])));
+        batch.add(NfListObject::Element(element("lan_nets6", vec![Expression::Named(NamedExpression::Prefix(Prefix {
+            addr: Box::new(Expression::String("fd00::".into())),
+            len: 64,
+        }))])));
+        batch.add(NfListObject::Chain(Chain { name: "input".into(), ..Default::default() }));
+        assert_eq!(ipset_restore_script(&batch.to_nftables()),
+            "create lan_ips hash:ip family inet timeout 3600 -exist\n\
+             create lan_nets6 hash:net family inet6 -exist\n\
+             flush lan_ips\n\
+             add lan_ips 192.168.1.1 -exist\n\
+             del lan_ips 192.168.1.2 -exist\n\
+             add lan_nets6 fd00::/64 -exist\n");
+
+        let save = "create lan_ips hash:ip family inet hashsize 1024 maxelem 65536 timeout 3600 bucketsize 12\n\
+                    add lan_ips 192.168.1.1 timeout 3542\n\
+                    create lan_nets6 hash:net family inet6 hashsize 1024 maxelem 65536\n\
+                    add lan_nets6 fd00::/64\n\
+                    create other hash:ip,port family inet hashsize 1024 maxelem 65536\n\
+                    create foreign hash:ip family inet hashsize 1024 maxelem 65536\n\
+                    add foreign 10.0.0.1\n";
+        let owned = BTreeMap::from([
+            ("lan_ips".to_string(), Some("owner".to_string())),
+            ("lan_nets6".to_string(), Some("owner".to_string())),
+        ]);
+        let listing = parse_ipset_save(save, NfFamily::INet, "filter", &owned);
+        let model = crate::ruleset::RulesetModel::from_ruleset(&listing);
+        assert!(model.tables.contains("inet filter"));
+        assert_eq!(model.sets.len(), 2);
+        assert_eq!(model.sets["inet filter lan_ips"].elements.iter().collect::<Vec<_>>(), vec!["192.168.1.1"]);
+        assert_eq!(model.sets["inet filter lan_nets6"].elements.iter().collect::<Vec<_>>(), vec!["fd00::/64"]);
+        assert_eq!(model.owned_sets("owner").len(), 2);
+    }
+
+    #[test]
+    fn test_ipset_backend_leaves_foreign_sets_alone() {
+        use crate::nftables::NftablesManager;
+        use crate::types::{AppConfig, InterfaceConfig, ShutdownAction};
+        use std::os::unix::fs::PermissionsExt;
+
+        // A stand-in `ipset` printing a fixed `save` listing and logging restores
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("restore.log");
+        let program = dir.path().join("ipset");
+        std::fs::write(&program, format!(
+            "#!/bin/sh\n\
+             case \"$1\" in\n\
+             save) printf 'create lan_ips hash:ip family inet\\ncreate foreign hash:ip family inet\\nadd foreign 10.0.0.1\\n' ;;\n\
+             restore) cat >> {} ;;\n\
+             esac\n",
+            log.display()
+        )).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let state = dir.path().join("ipsets.json");
+        let backend = Arc::new(IpsetBackend {
+            program: Some(program.display().to_string()),
+            ..IpsetBackend::with_state_file(&state)
+        });
+
+        let config = AppConfig {
+            interfaces: vec![InterfaceConfig {
+                name: "eth1".to_string(),
+                dhcp: None,
+                address: None,
+                nftables_zone: Some("lan".to_string()),
+                matcher: None,
+            }],
+            ..Default::default()
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let manager = NftablesManager::with_backend(Arc::new(tokio::sync::Mutex::new(config)), backend.clone()).await.unwrap();
+            manager.load_rules().await.unwrap();
+
+            // A restarted daemon recognises its sets from the state file
+            let restarted = IpsetBackend {
+                program: Some(program.display().to_string()),
+                ..IpsetBackend::with_state_file(&state)
+            };
+            let listing = crate::ruleset::RulesetModel::from_ruleset(&restarted.list_table(NfFamily::INet, "filter").unwrap());
+            assert!(listing.sets.contains_key("inet filter lan_ips"));
+            assert!(!listing.sets.contains_key("inet filter foreign"));
+
+            manager.cleanup(ShutdownAction::Delete).await.unwrap();
+        });
+        let restores = std::fs::read_to_string(&log).unwrap();
+        assert!(restores.contains("destroy lan_ips"));
+        assert!(!restores.contains("foreign"));
+        assert!(!std::fs::read_to_string(&state).unwrap().contains("lan_ips"));
+    }
+}
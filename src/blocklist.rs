@@ -0,0 +1,211 @@
+//! Addresses blocked at runtime through `POST /blocklist` or the `block` socket command.
+//!
+//! Entries are kept in a JSON file (`blocklist_path`) so they survive restarts;
+//! the `NftablesManager` mirrors the unexpired ones into the blocklist sets
+//! (`blocklist` / `blocklist6` by default), with the remaining lifetime as
+//! element timeout.
+
+use crate::types::AppError;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the blocklist is saved unless `blocklist_path` says otherwise.
+pub const DEFAULT_BLOCKLIST_PATH: &str = "/var/lib/rust-network-mgr/blocklist.json";
+
+/// One blocked address or network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlocklistEntry {
+    /// Blocked network; a single address is a `/32` or `/128`.
+    pub net: IpNet,
+    /// Unix time the entry was added.
+    pub added: u64,
+    /// Unix time the entry expires; `None` blocks until it is removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl BlocklistEntry {
+    /// Seconds left before the entry expires, `None` if it never does.
+    pub fn remaining(&self, now: u64) -> Option<u64> {
+        self.expires.map(|expires| expires.saturating_sub(now))
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.remaining(now) == Some(0)
+    }
+}
+
+/// The entries of the blocklist file, keyed by network.
+#[derive(Debug, Clone)]
+pub struct Blocklist {
+    path: PathBuf,
+    entries: BTreeMap<IpNet, BlocklistEntry>,
+    /// Set when an unreadable file could not be moved aside; it is never overwritten.
+    read_only: bool,
+}
+
+impl Blocklist {
+    /// A blocklist without entries, saved at `path`.
+    pub fn empty(path: &Path) -> Self {
+        Blocklist { path: path.to_path_buf(), entries: BTreeMap::new(), read_only: false }
+    }
+
+    /// Reads the blocklist saved at `path`; a missing file is an empty blocklist.
+    ///
+    /// A file that cannot be parsed is renamed to `<path>.corrupt-<unix time>` so
+    /// the next save does not replace it. A file that cannot be read (e.g. for
+    /// lack of permission) is left in place, as is a corrupt one that cannot be
+    /// moved; then the error says so and [`Blocklist::unsaved`] should be used
+    /// instead of an empty blocklist.
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Blocklist::empty(path)),
+            Err(e) => {
+                return Err(AppError::ConfigIo(format!(
+                    "Failed to read blocklist '{}': {}; leaving it in place, so it will not be overwritten",
+                    path.display(), e
+                )));
+            }
+        };
+        let entries: Vec<BlocklistEntry> = match serde_json::from_str(&content) {
+            Ok(entries) => entries,
+            Err(e) => {
+                let e = format!("Failed to parse blocklist '{}': {}", path.display(), e);
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                let mut aside = path.as_os_str().to_owned();
+                aside.push(format!(".corrupt-{}", now));
+                let aside = PathBuf::from(aside);
+                return Err(match std::fs::rename(path, &aside) {
+                    Ok(()) => AppError::ConfigIo(format!("{}; moved it to '{}'", e, aside.display())),
+                    Err(rename_error) => AppError::ConfigIo(format!(
+                        "{}; could not move it aside ({}), so it will not be overwritten", e, rename_error
+                    )),
+                });
+            }
+        };
+        let mut blocklist = Blocklist::empty(path);
+        blocklist.entries = entries.into_iter().map(|entry| (entry.net, entry)).collect();
+        Ok(blocklist)
+    }
+
+    /// An empty blocklist that refuses to save over the file at `path`.
+    pub fn unsaved(path: &Path) -> Self {
+        Blocklist { read_only: true, ..Blocklist::empty(path) }
+    }
+
+    /// Writes the unexpired entries back to the file, replacing it atomically.
+    pub fn save(&mut self, now: u64) -> Result<(), AppError> {
+        self.entries.retain(|_, entry| !entry.is_expired(now));
+        if self.read_only {
+            return Err(AppError::ConfigIo(format!(
+                "Not saving the blocklist over the unreadable file '{}'", self.path.display()
+            )));
+        }
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let entries: Vec<&BlocklistEntry> = self.entries.values().collect();
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&entries)?)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Adds an entry, replacing any entry for the same network.
+    pub fn insert(&mut self, entry: BlocklistEntry) {
+        self.entries.insert(entry.net, entry);
+    }
+
+    pub fn remove(&mut self, net: &IpNet) -> Option<BlocklistEntry> {
+        self.entries.remove(net)
+    }
+
+    /// Entries that have not expired at `now`, sorted by network.
+    pub fn active(&self, now: u64) -> Vec<BlocklistEntry> {
+        self.entries.values().filter(|entry| !entry.is_expired(now)).cloned().collect()
+    }
+}
+
+/// Parses an address (`203.0.113.7`) or network (`198.51.100.0/24`) to block;
+/// host bits of a network are cleared.
+pub fn parse_target(target: &str) -> Result<IpNet, String> {
+    if let Ok(addr) = target.parse::<IpAddr>() {
+        return Ok(IpNet::from(addr));
+    }
+    target.parse::<IpNet>()
+        .map(|net| net.trunc())
+        .map_err(|_| format!("'{}' is not an IP address or CIDR network", target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(parse_target("203.0.113.7").unwrap().to_string(), "203.0.113.7/32");
+        assert_eq!(parse_target("198.51.100.9/24").unwrap().to_string(), "198.51.100.0/24");
+        assert_eq!(parse_target("2001:db8::1").unwrap().to_string(), "2001:db8::1/128");
+        assert!(parse_target("example.com").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load_drop_expired_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("blocklist.json");
+        let mut blocklist = Blocklist::load(&path).unwrap();
+        assert!(blocklist.active(0).is_empty());
+
+        let entry = |target: &str, expires: Option<u64>| BlocklistEntry {
+            net: parse_target(target).unwrap(),
+            added: 100,
+            expires,
+            reason: Some("scanner".to_string()),
+        };
+        blocklist.insert(entry("203.0.113.7", None));
+        blocklist.insert(entry("198.51.100.0/24", Some(200)));
+        blocklist.insert(entry("192.0.2.1", Some(150)));
+        blocklist.save(150).unwrap();
+
+        let loaded = Blocklist::load(&path).unwrap();
+        assert_eq!(loaded.active(150), vec![entry("198.51.100.0/24", Some(200)), entry("203.0.113.7", None)]);
+        assert_eq!(loaded.active(200), vec![entry("203.0.113.7", None)]);
+        assert_eq!(loaded.active(150)[0].remaining(150), Some(50));
+    }
+
+    #[test]
+    fn test_corrupt_file_is_moved_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.json");
+        std::fs::write(&path, "[{\"net\": ").unwrap();
+        let error = Blocklist::load(&path).unwrap_err().to_string();
+        assert!(error.contains("moved it to"), "{}", error);
+        assert!(!path.exists());
+        let aside: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(aside.len(), 1);
+        assert!(aside[0].to_string_lossy().starts_with("blocklist.json.corrupt-"));
+
+        let mut unsaved = Blocklist::unsaved(&path);
+        assert!(unsaved.save(0).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_unreadable_file_is_left_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        // Reading a directory fails with an I/O error rather than a parse error
+        let path = dir.path().join("blocklist.json");
+        std::fs::create_dir(&path).unwrap();
+        let error = Blocklist::load(&path).unwrap_err().to_string();
+        assert!(error.contains("leaving it in place"), "{}", error);
+        assert!(path.is_dir());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -19,12 +19,13 @@ pub struct Cli {
     pub socket: Option<PathBuf>,
 
     #[command(subcommand)]
-    pub command: Commands,
+    pub command: Option<Commands>,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Default)]
 pub enum Commands {
     /// Start the daemon (default when no subcommand is given).
+    #[default]
     Daemon,
     /// Send a reload command to the running daemon.
     Reload,
@@ -34,12 +35,41 @@ pub enum Commands {
     Ping,
     /// Ask the running daemon to shut down gracefully.
     Shutdown,
-}
-
-impl Default for Commands {
-    fn default() -> Self {
-        Commands::Daemon
-    }
+    /// Block an address or network on the running daemon.
+    Block {
+        /// Address (`203.0.113.7`) or network (`198.51.100.0/24`).
+        target: String,
+        /// Seconds until the block expires; permanent if omitted.
+        #[arg(long)]
+        ttl: Option<u64>,
+        /// Note kept with the entry.
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Remove an address or network from the blocklist.
+    Unblock {
+        target: String,
+    },
+    /// List the blocked addresses and networks.
+    Blocklist,
+    /// Print the nftables changes the configuration would make, without applying them.
+    Plan {
+        /// State snapshot (JSON, same shape as `GET /status`) used to fill the zone sets.
+        #[arg(long)]
+        state: Option<PathBuf>,
+        /// Diff against an empty ruleset instead of reading the live table (no root needed).
+        #[arg(long)]
+        offline: bool,
+        /// Print the full nftables JSON transaction instead of the readable diff.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Delete the sets and chains owned by the daemon (and the table, if left empty).
+    Cleanup {
+        /// Only empty the owned sets, keeping sets and chains in place.
+        #[arg(long)]
+        flush: bool,
+    },
 }
 
 /// Connect to the Unix socket and send a one-line command, returning the response.
@@ -52,24 +82,24 @@ pub async fn send_socket_command(
 
     let mut stream = UnixStream::connect(socket_path)
         .await
-        .map_err(|e| crate::types::AppError::Io(e))?;
+        .map_err(crate::types::AppError::Io)?;
 
     stream
         .write_all(format!("{}\n", command).as_bytes())
         .await
-        .map_err(|e| crate::types::AppError::Io(e))?;
+        .map_err(crate::types::AppError::Io)?;
 
     // Signal end-of-write so the server knows we're done sending.
     stream
         .shutdown()
         .await
-        .map_err(|e| crate::types::AppError::Io(e))?;
+        .map_err(crate::types::AppError::Io)?;
 
     let mut response = String::new();
     stream
         .read_to_string(&mut response)
         .await
-        .map_err(|e| crate::types::AppError::Io(e))?;
+        .map_err(crate::types::AppError::Io)?;
 
     Ok(response)
 }
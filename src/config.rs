@@ -1,4 +1,8 @@
-use crate::types::{AppConfig, AppError, Result};
+use crate::types::{
+    AppConfig, AppError, DockerSetsConfig, NftablesConfig, PolicyConfig, PolicyProtocol, Result,
+    ANY_ZONE, LOCAL_ZONE, ZONE_PLACEHOLDER,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use directories::ProjectDirs;
 use log::{info, warn};
@@ -101,14 +105,297 @@ pub(crate) fn validate_config(config: &AppConfig) -> Result<()> {
         ));
     }
     for interface in &config.interfaces {
-        if interface.name.is_empty() {
-            // Use ConfigValidation
+        match &interface.matcher {
+            None if interface.name.is_empty() => {
+                // Use ConfigValidation
+                return Err(AppError::ConfigValidation(
+                    "Interface name cannot be empty".to_string(),
+                ));
+            }
+            Some(_) if !interface.name.is_empty() => {
+                return Err(AppError::ConfigValidation(format!(
+                    "Interface '{}' has both a name and a match block; use one of them", interface.name
+                )));
+            }
+            Some(matcher) if matcher.is_empty() => {
+                return Err(AppError::ConfigValidation(
+                    "Interface match blocks need at least one of glob, regex, mac, kind or altname".to_string(),
+                ));
+            }
+            Some(_) if interface.address.is_some() => {
+                return Err(AppError::ConfigValidation(
+                    "Interfaces selected by a match block cannot have a static address".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        if let Some(address) = &interface.address {
+            if address.parse::<ipnet::IpNet>().is_err() {
+                return Err(AppError::ConfigValidation(format!(
+                    "Interface '{}' has an invalid address '{}' (expected e.g. 192.168.1.1/24)",
+                    interface.name, address
+                )));
+            }
+        }
+        // Add more specific validation rules as needed
+        // e.g., ensure zone name isn't empty if present
+    }
+    validate_nftables(&config.nftables)?;
+    validate_zones(config)?;
+    validate_docker_sets(config)?;
+    validate_set_names(config)?;
+    validate_port_forwards(config)?;
+    validate_flow_offload(config)?;
+    if let Some(policy) = &config.policy {
+        validate_policy(config, policy)?;
+    }
+    Ok(())
+}
+
+/// Checks the managed table name and set-name templates.
+fn validate_nftables(nftables: &NftablesConfig) -> Result<()> {
+    if nftables.table.is_empty() {
+        return Err(AppError::ConfigValidation(
+            "nftables.table cannot be empty".to_string(),
+        ));
+    }
+    if nftables.blocklist_set_name.is_empty() {
+        return Err(AppError::ConfigValidation(
+            "nftables.blocklist_set_name cannot be empty".to_string(),
+        ));
+    }
+    for (key, template) in nftables.set_templates() {
+        if !template.contains(ZONE_PLACEHOLDER) {
+            return Err(AppError::ConfigValidation(format!(
+                "nftables.{} must contain the {} placeholder", key, ZONE_PLACEHOLDER
+            )));
+        }
+    }
+    // Templates of the sets a table carries must not collide
+    let used = nftables.carried_set_templates();
+    for (index, (key, template)) in used.iter().enumerate() {
+        if let Some((other, _)) = used[index + 1..].iter().find(|(_, t)| t == template) {
+            return Err(AppError::ConfigValidation(format!(
+                "nftables.{} and {} must differ", key, other
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that zone settings refer to configured zones and use usable timeouts.
+fn validate_zones(config: &AppConfig) -> Result<()> {
+    for (zone, settings) in &config.zones {
+        let configured = zone == "docker"
+            || config.interfaces.iter().any(|iface| iface.nftables_zone.as_deref() == Some(zone.as_str()));
+        if !configured {
+            return Err(AppError::ConfigValidation(format!(
+                "zones.{} does not match any interface zone or 'docker'", zone
+            )));
+        }
+        if matches!(settings.timeout, Some(timeout) if timeout < 2) {
+            return Err(AppError::ConfigValidation(format!(
+                "zones.{}.timeout must be at least 2 seconds", zone
+            )));
+        }
+        if settings.gc_interval.is_some() && settings.timeout.is_none() {
+            return Err(AppError::ConfigValidation(format!(
+                "zones.{}.gc_interval requires a timeout", zone
+            )));
+        }
+        if settings.masquerade && zone == "docker" {
             return Err(AppError::ConfigValidation(
-                "Interface name cannot be empty".to_string(),
+                "zones.docker.masquerade is not supported: masquerading needs interfaces".to_string(),
             ));
         }
-        // Add more specific validation rules as needed
-        // e.g., check format of static address, ensure zone name isn't empty if present
+        if settings.has_limits() && zone == "docker" {
+            return Err(AppError::ConfigValidation(
+                "zones.docker connection limits are not supported: limits match on interfaces".to_string(),
+            ));
+        }
+        if settings.max_new_conns_per_source.is_some_and(|rate| rate.count == 0)
+            || settings.max_conns_per_source == Some(0)
+        {
+            return Err(AppError::ConfigValidation(format!(
+                "zones.{} connection limits must be greater than 0", zone
+            )));
+        }
+        if settings.has_macs() && zone == "docker" {
+            return Err(AppError::ConfigValidation(
+                "zones.docker MAC sets are not supported: MAC addresses are learned and matched on interfaces".to_string(),
+            ));
+        }
+        if settings.filters_addresses() && zone == "docker" {
+            return Err(AppError::ConfigValidation(
+                "zones.docker address filters are not supported: they apply to interface addresses".to_string(),
+            ));
+        }
+        if settings.routed_nets && zone == "docker" {
+            return Err(AppError::ConfigValidation(
+                "zones.docker.routed_nets is not supported: routes are matched on interfaces".to_string(),
+            ));
+        }
+        if let Some(ip) = settings.mac_pins.keys().find(|ip| {
+            if ip.is_ipv4() { !config.nftables.family.has_ipv4() } else { !config.nftables.family.has_ipv6() }
+        }) {
+            return Err(AppError::ConfigValidation(format!(
+                "zones.{}.mac_pins has {}, but the managed table carries no sets of its family", zone, ip
+            )));
+        }
+        if let Some(ban_timeout) = settings.ban_timeout {
+            if !settings.has_limits() {
+                return Err(AppError::ConfigValidation(format!(
+                    "zones.{}.ban_timeout requires max_new_conns_per_source or max_conns_per_source", zone
+                )));
+            }
+            if ban_timeout == 0 {
+                return Err(AppError::ConfigValidation(format!(
+                    "zones.{}.ban_timeout must be at least 1 second", zone
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every `docker_sets:` network and label selector gets a zone name of its own.
+fn validate_docker_sets(config: &AppConfig) -> Result<()> {
+    let mut seen: HashSet<String> = config.interfaces.iter()
+        .filter_map(|iface| iface.nftables_zone.clone())
+        .collect();
+    let names = config.docker_sets.networks.iter().chain(config.docker_sets.labels.keys());
+    for name in names {
+        if name.is_empty() {
+            return Err(AppError::ConfigValidation("docker_sets names cannot be empty".to_string()));
+        }
+        let zone = DockerSetsConfig::zone_name(name);
+        if !seen.insert(zone.clone()) {
+            return Err(AppError::ConfigValidation(format!(
+                "docker_sets '{}' maps to zone '{}', which is already in use", name, zone
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that no two zones get the same set name, e.g. the IPv4 set of a zone
+/// `web_conns` and the connection meter of a zone `web` under a `{zone}` template,
+/// and that no zone set is named like a blocklist set.
+fn validate_set_names(config: &AppConfig) -> Result<()> {
+    let mut zones: BTreeSet<String> = config.interfaces.iter()
+        .filter_map(|iface| iface.nftables_zone.clone())
+        .collect();
+    zones.insert("docker".to_string());
+    zones.extend(config.docker_sets.zones());
+    let nftables = &config.nftables;
+    let mut seen: HashMap<String, String> = HashMap::new();
+    if nftables.family.has_ipv4() {
+        seen.insert(nftables.ipv4_blocklist_set_name(), "IPv4 blocklist set (blocklist_set_name)".to_string());
+    }
+    if nftables.family.has_ipv6() {
+        seen.insert(nftables.ipv6_blocklist_set_name(), "IPv6 blocklist set (blocklist_set_name)".to_string());
+    }
+    for zone in &zones {
+        for (key, name) in nftables.zone_set_names(zone) {
+            let owner = format!("{} of zone '{}'", key, zone);
+            if let Some(other) = seen.insert(name.clone(), owner.clone()) {
+                return Err(AppError::ConfigValidation(format!(
+                    "nftables set '{}' would be both the {} and the {}", name, other, owner
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that port forwards come from interface zones, fit the table family and do not shadow each other.
+fn validate_port_forwards(config: &AppConfig) -> Result<()> {
+    if let Some(zone) = &config.docker_forward_zone {
+        if !config.interfaces.iter().any(|iface| iface.nftables_zone.as_deref() == Some(zone.as_str())) {
+            return Err(AppError::ConfigValidation(format!(
+                "docker_forward_zone '{}' is not an interface zone", zone
+            )));
+        }
+    }
+    let mut listeners = HashMap::new();
+    for (index, forward) in config.port_forwards.iter().enumerate() {
+        if let Some(other) = listeners.insert(forward.listener(), index) {
+            return Err(AppError::ConfigValidation(format!(
+                "Port forwards #{} and #{} both forward {} port {} from '{}'",
+                other + 1, index + 1, forward.protocol.as_str(), forward.external_port, forward.from
+            )));
+        }
+        if !config.interfaces.iter().any(|iface| iface.nftables_zone.as_deref() == Some(forward.from.as_str())) {
+            return Err(AppError::ConfigValidation(format!(
+                "Port forward #{} comes from '{}', which is not an interface zone", index + 1, forward.from
+            )));
+        }
+        if forward.external_port == 0 || forward.target.port() == 0 {
+            return Err(AppError::ConfigValidation(format!(
+                "Port forward #{} needs non-zero ports", index + 1
+            )));
+        }
+        let supported = if forward.target.is_ipv4() {
+            config.nftables.family.has_ipv4()
+        } else {
+            config.nftables.family.has_ipv6()
+        };
+        if !supported {
+            return Err(AppError::ConfigValidation(format!(
+                "Port forward #{} targets {}, which the nftables table family cannot handle",
+                index + 1, forward.target
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_flow_offload(config: &AppConfig) -> Result<()> {
+    let Some(offload) = &config.flow_offload else {
+        return Ok(());
+    };
+    if offload.zones.is_empty() {
+        return Err(AppError::ConfigValidation("flow_offload needs at least one zone".to_string()));
+    }
+    for zone in &offload.zones {
+        if !config.interfaces.iter().any(|iface| iface.nftables_zone.as_deref() == Some(zone.as_str())) {
+            return Err(AppError::ConfigValidation(format!(
+                "flow_offload zone '{}' is not an interface zone", zone
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that policy rules only reference known zones and sensible protocol/port combinations.
+fn validate_policy(config: &AppConfig, policy: &PolicyConfig) -> Result<()> {
+    let mut known_zones: HashSet<&str> = config.interfaces.iter()
+        .filter_map(|iface| iface.nftables_zone.as_deref())
+        .collect();
+    known_zones.extend(["docker", LOCAL_ZONE, ANY_ZONE]);
+    let docker_zones = config.docker_sets.zones();
+    known_zones.extend(docker_zones.iter().map(String::as_str));
+
+    for (index, rule) in policy.rules.iter().enumerate() {
+        for zone in [&rule.from, &rule.to] {
+            if !known_zones.contains(zone.as_str()) {
+                return Err(AppError::ConfigValidation(format!(
+                    "Policy rule #{} references unknown zone '{}'", index + 1, zone
+                )));
+            }
+        }
+        if rule.from == LOCAL_ZONE && rule.to == LOCAL_ZONE {
+            return Err(AppError::ConfigValidation(format!(
+                "Policy rule #{} cannot have '{}' as both source and destination", index + 1, LOCAL_ZONE
+            )));
+        }
+        if !rule.ports.is_empty()
+            && !matches!(rule.protocol, Some(PolicyProtocol::Tcp) | Some(PolicyProtocol::Udp))
+        {
+            return Err(AppError::ConfigValidation(format!(
+                "Policy rule #{} lists ports but its protocol is not tcp or udp", index + 1
+            )));
+        }
     }
     Ok(())
 }
@@ -116,6 +403,10 @@ pub(crate) fn validate_config(config: &AppConfig) -> Result<()> {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::types::{
+        AddressFlag, AddressScope, ConnRate, FirewallBackendKind, ForwardProtocol, InterfaceMatch, Ipv6Privacy, LinkDetails, MacAddr,
+        NamePattern, NftablesFamily, RateUnit, ShutdownAction, ZoneConfig,
+    };
     use std::io::Write;
     use tempfile::NamedTempFile;
 
@@ -150,12 +441,14 @@ socket_path: /tmp/test.sock
     fn test_load_fallback_config() {
         // Ensure the test doesn't find a config at the non-existent path
         let non_existent_path = Path::new("/tmp/non_existent_config_for_test.yaml");
-        let _ = std::fs::remove_file(&non_existent_path); // Clean up if it exists
+        let _ = std::fs::remove_file(non_existent_path); // Clean up if it exists
 
         // Create the fallback file temporarily (relative to manifest dir)
         let fallback_path = get_pkg_default_config_path();
         let fallback_dir = fallback_path.parent().unwrap();
         std::fs::create_dir_all(fallback_dir).unwrap();
+        // The packaged default is tracked in the repo; keep it to restore afterwards
+        let original = std::fs::read_to_string(&fallback_path).ok();
         let fallback_yaml = r#"
 interfaces:
   - name: "fallback0"
@@ -172,9 +465,14 @@ socket_path: "/tmp/fallback.sock"
         assert_eq!(config.interfaces[0].name, "fallback0");
         assert_eq!(config.socket_path, Some("/tmp/fallback.sock".to_string()));
 
-        // Clean up the temporary fallback file
-        let _ = std::fs::remove_file(&fallback_path);
-        let _ = std::fs::remove_dir(fallback_dir); // Remove dir only if empty
+        // Restore the packaged default, or clean up the temporary fallback file
+        match original {
+            Some(content) => std::fs::write(&fallback_path, content).unwrap(),
+            None => {
+                let _ = std::fs::remove_file(&fallback_path);
+                let _ = std::fs::remove_dir(fallback_dir); // Remove dir only if empty
+            }
+        }
     }
 
     #[test]
@@ -197,8 +495,7 @@ socket_path: "/tmp/fallback.sock"
     fn test_validate_empty_interfaces() {
         let config = AppConfig {
             interfaces: vec![],
-            socket_path: None,
-            nftables_rules_path: None,
+            ..Default::default()
         };
         let result = validate_config(&config);
         assert!(result.is_err());
@@ -210,4 +507,491 @@ socket_path: "/tmp/fallback.sock"
             _ => panic!("Expected ConfigValidation error, got {:?}", result),
         }
     }
+
+    #[test]
+    fn test_load_policy_section() {
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: wan
+  - name: eth1
+    nftables_zone: lan
+policy:
+  input: drop
+  forward: drop
+  rules:
+    - from: lan
+      to: local
+      action: allow
+      protocol: tcp
+      ports: [22, 53]
+    - from: lan
+      to: wan
+      action: allow
+    - from: wan
+      to: local
+      action: reject
+      protocol: icmp
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "{}", yaml).unwrap();
+
+        let config = load_config(Some(file.path().to_str().unwrap())).unwrap();
+        let policy = config.policy.expect("policy section should be parsed");
+        assert_eq!(policy.input, crate::types::ChainPolicy::Drop);
+        assert_eq!(policy.output, crate::types::ChainPolicy::Accept);
+        assert_eq!(policy.rules.len(), 3);
+        assert_eq!(policy.rules[0].ports, vec![22, 53]);
+        assert_eq!(policy.rules[2].action, crate::types::PolicyAction::Reject);
+    }
+
+    #[test]
+    fn test_validate_policy_rejects_unknown_zone_and_bad_ports() {
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: wan
+policy:
+  rules:
+    - from: dmz
+      to: local
+      action: allow
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        match validate_config(&config) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("unknown zone 'dmz'")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: wan
+policy:
+  rules:
+    - from: wan
+      to: local
+      action: deny
+      protocol: icmp
+      ports: [80]
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        match validate_config(&config) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("not tcp or udp")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_zone_settings() {
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: wan
+zones:
+  wan:
+    grace_period: 30
+  docker:
+    timeout: 3600
+    gc_interval: 60
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        assert_eq!(config.zones["wan"].grace_period, Some(30));
+        assert_eq!(config.zones["docker"].timeout, Some(3600));
+
+        let mut bad = config.clone();
+        bad.zones.insert("dmz".to_string(), Default::default());
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("zones.dmz")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+
+        let mut bad = config.clone();
+        bad.zones.get_mut("wan").unwrap().gc_interval = Some(10);
+        assert!(validate_config(&bad).is_err());
+    }
+
+    #[test]
+    fn test_nat_settings() {
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: wan
+  - name: eth1
+    nftables_zone: lan
+zones:
+  wan:
+    masquerade: true
+port_forwards:
+  - from: wan
+    protocol: tcp
+    external_port: 8080
+    target: 192.168.1.10:80
+  - from: wan
+    protocol: udp
+    external_port: 51820
+    target: "[fd00::10]:51820"
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        assert!(config.zones["wan"].masquerade);
+        assert_eq!(config.port_forwards[0].protocol, ForwardProtocol::Tcp);
+        assert_eq!(config.port_forwards[1].target.port(), 51820);
+
+        // The same port on another family is a separate listener
+        let mut other_family = config.clone();
+        other_family.port_forwards[1].protocol = ForwardProtocol::Tcp;
+        other_family.port_forwards[1].external_port = 8080;
+        assert!(validate_config(&other_family).is_ok());
+        let mut bad = other_family.clone();
+        bad.port_forwards[1].target = "192.168.1.11:80".parse().unwrap();
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("#1 and #2"), "{}", msg),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+
+        let mut bad = config.clone();
+        bad.port_forwards[0].from = "docker".to_string();
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("not an interface zone")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+
+        let mut bad = config.clone();
+        bad.nftables.family = NftablesFamily::Ip;
+        assert!(validate_config(&bad).is_err());
+
+        let mut bad = config.clone();
+        bad.zones.insert("docker".to_string(), ZoneConfig { masquerade: true, ..Default::default() });
+        assert!(validate_config(&bad).is_err());
+    }
+
+    #[test]
+    fn test_zone_connection_limits() {
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: wan
+zones:
+  wan:
+    max_new_conns_per_source: 50/second
+    max_conns_per_source: 200
+    ban_timeout: 600
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        let wan = &config.zones["wan"];
+        assert_eq!(wan.max_new_conns_per_source, Some(ConnRate { count: 50, per: RateUnit::Second }));
+        assert_eq!(wan.max_conns_per_source, Some(200));
+        assert_eq!(wan.ban_timeout, Some(600));
+
+        let err = serde_yaml::from_str::<AppConfig>(&yaml.replace("50/second", "50/fortnight")).unwrap_err();
+        assert!(err.to_string().contains("invalid rate '50/fortnight'"));
+
+        let mut bad = config.clone();
+        bad.zones.insert("docker".to_string(), ZoneConfig { max_conns_per_source: Some(10), ..Default::default() });
+        assert!(validate_config(&bad).is_err());
+
+        let mut bad = config.clone();
+        let wan = bad.zones.get_mut("wan").unwrap();
+        wan.max_new_conns_per_source = None;
+        wan.max_conns_per_source = None;
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("ban_timeout requires")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_zone_mac_sets() {
+        let yaml = r#"
+interfaces:
+  - name: eth1
+    nftables_zone: lan
+zones:
+  lan:
+    macs: ["02:00:00:00:00:01"]
+    learn_macs: true
+    mac_pins:
+      192.168.1.10: "02-00-00-00-00-0A"
+      fd00::10: "02:00:00:00:00:0b"
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        let lan = &config.zones["lan"];
+        assert_eq!(lan.macs, vec![MacAddr([2, 0, 0, 0, 0, 1])]);
+        assert_eq!(lan.mac_pins[&"192.168.1.10".parse::<std::net::IpAddr>().unwrap()].to_string(), "02:00:00:00:00:0a");
+        assert!(lan.has_macs() && lan.pins_macs());
+
+        let err = serde_yaml::from_str::<AppConfig>(&yaml.replace("02:00:00:00:00:01", "02:00:00:00:01")).unwrap_err();
+        assert!(err.to_string().contains("invalid MAC address '02:00:00:00:01'"));
+
+        let mut bad = config.clone();
+        bad.nftables.family = NftablesFamily::Ip;
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("mac_pins has fd00::10")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+
+        let mut bad = config.clone();
+        bad.zones.insert("docker".to_string(), ZoneConfig { learn_macs: true, ..Default::default() });
+        assert!(validate_config(&bad).is_err());
+    }
+
+    #[test]
+    fn test_zone_routed_nets() {
+        let yaml = r#"
+interfaces:
+  - name: wg0
+    nftables_zone: vpn
+zones:
+  vpn:
+    routed_nets: true
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        assert!(config.zones["vpn"].routed_nets);
+
+        let mut bad = config.clone();
+        bad.zones.insert("docker".to_string(), ZoneConfig { routed_nets: true, ..Default::default() });
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("zones.docker.routed_nets")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_zone_address_filters() {
+        let yaml = r#"
+interfaces:
+  - name: eth1
+    nftables_zone: lan
+zones:
+  lan:
+    exclude_scopes: [link]
+    exclude_flags: [tentative, deprecated, dadfailed]
+    ipv6_privacy: exclude
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        let lan = &config.zones["lan"];
+        assert_eq!(lan.exclude_scopes, vec![AddressScope::Link]);
+        assert_eq!(lan.exclude_flags, vec![AddressFlag::Tentative, AddressFlag::Deprecated, AddressFlag::Dadfailed]);
+        assert_eq!(lan.ipv6_privacy, Ipv6Privacy::Exclude);
+        assert!(serde_yaml::from_str::<AppConfig>(&yaml.replace("[link]", "[galaxy]")).is_err());
+
+        let mut bad = config.clone();
+        bad.zones.insert("docker".to_string(), ZoneConfig { exclude_scopes: vec![AddressScope::Link], ..Default::default() });
+        assert!(validate_config(&bad).is_err());
+    }
+
+    #[test]
+    fn test_interface_match() {
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: wan
+  - match:
+      glob: "veth*"
+    nftables_zone: docker_veth
+  - match:
+      regex: "wg[0-9]+"
+      kind: wireguard
+    nftables_zone: vpn
+  - match:
+      mac: "02:00:00:00:00:01"
+    nftables_zone: lan
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        let matcher = |index: usize| config.interfaces[index].matcher.as_ref().unwrap();
+        let wireguard = LinkDetails { kind: Some("wireguard".to_string()), ..Default::default() };
+        let usb = LinkDetails { mac: Some(MacAddr([2, 0, 0, 0, 0, 1])), altnames: vec!["enx020000000001".to_string()], ..Default::default() };
+        assert!(matcher(1).matches("veth1a2b", &LinkDetails::default()));
+        assert!(!matcher(1).matches("eth0", &LinkDetails::default()));
+        assert!(matcher(2).matches("wg0", &wireguard));
+        assert!(!matcher(2).matches("wg0", &LinkDetails::default()));
+        assert!(!matcher(2).matches("xwg0", &wireguard));
+        assert!(matcher(3).matches("usb0", &usb));
+        // Globs and regexes look at altnames too
+        let alt = InterfaceMatch { glob: Some(NamePattern::glob("enx*").unwrap()), ..Default::default() };
+        assert!(alt.matches("usb0", &usb));
+        assert!(NamePattern::glob("eth?.1").unwrap().is_match("eth0.1"));
+        assert!(!NamePattern::glob("eth?.1").unwrap().is_match("eth0x1"));
+        assert!(serde_yaml::from_str::<AppConfig>(&yaml.replace("wg[0-9]+", "wg[0-9")).is_err());
+
+        for bad_entry in [
+            "  - name: eth1\n    match:\n      glob: \"eth*\"\n",
+            "  - match: {}\n",
+            "  - match:\n      glob: \"br*\"\n    address: 192.168.1.1/24\n",
+        ] {
+            let bad: AppConfig = serde_yaml::from_str(&format!("interfaces:\n{}", bad_entry)).unwrap();
+            assert!(matches!(validate_config(&bad), Err(AppError::ConfigValidation(_))), "{}", bad_entry);
+        }
+    }
+
+    #[test]
+    fn test_docker_sets() {
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: wan
+docker_sets:
+  networks: [backend, my-net]
+  labels:
+    db: role=db
+    traefik: traefik.enable
+policy:
+  rules:
+    - from: docker_backend
+      to: docker_db
+      action: allow
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        assert_eq!(config.docker_sets.zones(), vec!["docker_backend", "docker_my_net", "docker_db", "docker_traefik"]);
+        let db = &config.docker_sets.labels["db"];
+        assert_eq!((db.key.as_str(), db.value.as_deref()), ("role", Some("db")));
+        assert_eq!(config.docker_sets.labels["traefik"].value, None);
+
+        let err = serde_yaml::from_str::<AppConfig>(&yaml.replace("role=db", "=db")).unwrap_err();
+        assert!(err.to_string().contains("invalid label selector '=db'"));
+
+        let mut bad = config.clone();
+        bad.docker_sets.networks.push("my.net".to_string());
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("maps to zone 'docker_my_net'")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_flow_offload_zones() {
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: wan
+  - name: eth1
+    nftables_zone: lan
+flow_offload:
+  zones: [lan, wan]
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        assert_eq!(config.flow_offload.as_ref().unwrap().zones, vec!["lan", "wan"]);
+
+        let mut bad = config.clone();
+        bad.flow_offload.as_mut().unwrap().zones.push("docker".to_string());
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("'docker' is not an interface zone")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+        bad.flow_offload.as_mut().unwrap().zones.clear();
+        assert!(validate_config(&bad).is_err());
+    }
+
+    #[test]
+    fn test_nftables_section_defaults_and_overrides() {
+        let config: AppConfig = serde_yaml::from_str("interfaces:\n  - name: eth0\n").unwrap();
+        assert_eq!(config.nftables.table, "filter");
+        assert_eq!(config.nftables.family, NftablesFamily::Inet);
+        assert_eq!(config.nftables.ipv4_set_name("wan"), "wan_ips");
+        assert_eq!(config.nftables.drift_check_interval, 60);
+        assert_eq!(config.nftables.counter_interval, 15);
+        assert_eq!(config.nftables.on_shutdown, ShutdownAction::Keep);
+        assert_eq!(config.nftables.degraded_after, 3);
+        assert_eq!(config.firewall_backend, FirewallBackendKind::Auto);
+
+        let yaml = r#"
+interfaces:
+  - name: eth0
+firewall_backend: ipset
+nftables:
+  table: rust_network_mgr
+  family: ip
+  ipv4_set_template: "rnm_{zone}_v4"
+  on_shutdown: delete
+"#;
+        let config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(validate_config(&config).is_ok());
+        assert_eq!(config.nftables.table, "rust_network_mgr");
+        assert_eq!(config.nftables.family, NftablesFamily::Ip);
+        assert_eq!(config.nftables.ipv4_set_name("wan"), "rnm_wan_v4");
+        assert_eq!(config.nftables.on_shutdown, ShutdownAction::Delete);
+        assert_eq!(config.nftables.ipv6_set_name("wan"), "wan_ipv6");
+        assert_eq!(config.firewall_backend, FirewallBackendKind::Ipset);
+
+        let mut bad = config.clone();
+        bad.nftables.ipv6_set_template = "static_name".to_string();
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("ipv6_set_template")),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_zone_set_names_must_not_collide() {
+        let yaml = r#"
+interfaces:
+  - name: eth0
+    nftables_zone: web
+  - name: eth1
+    nftables_zone: web_conns
+nftables:
+  ipv4_set_template: "{zone}"
+"#;
+        let mut config: AppConfig = serde_yaml::from_str(yaml).unwrap();
+        match validate_config(&config) {
+            Err(AppError::ConfigValidation(msg)) => {
+                assert!(msg.contains("'web_conns'") && msg.contains("ipv4_conn_meter_template"), "{}", msg)
+            }
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+
+        config.nftables.ipv4_conn_meter_template = "{zone}_ct".to_string();
+        assert!(validate_config(&config).is_ok());
+        assert_eq!(config.nftables.limit_meter_names("web"), vec!["web_newconn", "web_newconn6", "web_ct", "web_conns6"]);
+
+        let mut bad = config.clone();
+        bad.nftables.ipv6_ban_set_template = "{zone}_ipv6".to_string();
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("ipv6_ban_set_template"), "{}", msg),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+
+        // A zone `web_macs` would collide with the MAC set of zone `web`
+        let mut bad = config.clone();
+        bad.interfaces[1].nftables_zone = Some("web_macs".to_string());
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("mac_set_template"), "{}", msg),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+        config.nftables.mac_set_template = "mac_{zone}".to_string();
+        assert_eq!(config.nftables.mac_set_name("web"), "mac_web");
+
+        // So would a peer set template matching the subnet sets
+        let mut bad = config.clone();
+        bad.nftables.ipv6_peer_set_template = "{zone}_nets6".to_string();
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => assert!(msg.contains("ipv6_peer_set_template"), "{}", msg),
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+        config.nftables.ipv4_peer_set_template = "{zone}_hosts".to_string();
+        assert!(validate_config(&config).is_ok());
+        assert_eq!(config.nftables.ipv4_peer_set_name("web"), "web_hosts");
+
+        // A zone named like the blocklist would share its set
+        let mut bad = config.clone();
+        bad.interfaces[1].nftables_zone = Some("blocklist".to_string());
+        match validate_config(&bad) {
+            Err(AppError::ConfigValidation(msg)) => {
+                assert!(msg.contains("'blocklist'") && msg.contains("blocklist_set_name"), "{}", msg)
+            }
+            other => panic!("Expected ConfigValidation error, got {:?}", other),
+        }
+        bad.nftables.blocklist_set_name = "denylist".to_string();
+        assert!(validate_config(&bad).is_ok());
+        assert_eq!(bad.nftables.ipv6_blocklist_set_name(), "denylist6");
+    }
 }
@@ -6,11 +6,16 @@ use bollard::service::EventMessage; // Use this type for events
 use bollard::system::EventsOptions;
 use futures_util::stream::StreamExt;
 use tokio::sync::mpsc;
-use crate::types::{Result, AppError, SystemEvent};
+use crate::types::{Result, AppError, ContainerDetails, ContainerForward, ForwardProtocol, SystemEvent};
 use log::{info, error, warn};
-use bollard::container::InspectContainerOptions;
+use bollard::container::{InspectContainerOptions, ListContainersOptions};
+use bollard::models::ContainerInspectResponse;
 use std::collections::HashMap;
 use std::net::IpAddr; // Import IpAddr for parsing
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Container label requesting port forwards, e.g. `rust-network-mgr.forward=tcp:8080->80,udp:5353->53`.
+pub const FORWARD_LABEL: &str = "rust-network-mgr.forward";
 
 /// Monitors Docker events via the Docker daemon socket.
 pub struct DockerMonitor {
@@ -30,17 +35,27 @@ impl DockerMonitor {
     }
 
     /// Starts the Docker event monitoring loop.
-    /// Listens for container start/stop events and sends DockerEvents.
+    /// Reports the containers already running, then listens for container
+    /// start/stop and network connect/disconnect events and sends DockerEvents.
     pub async fn start(self) -> Result<()> {
         info!("Starting Docker event listener...");
 
-        // Filter for specific container events
+        // Replay events from before the scan so a container started meanwhile is not missed
+        let since = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs().to_string()).ok();
+        if let Err(e) = self.scan_running().await {
+            warn!("Failed to list running Docker containers: {}", e);
+        }
+
+        // Filter for specific container and network events
         let mut filters = HashMap::new();
-        filters.insert("type".to_string(), vec!["container".to_string()]);
-        filters.insert("event".to_string(), vec!["start".to_string(), "stop".to_string(), "die".to_string()]);
+        filters.insert("type".to_string(), vec!["container".to_string(), "network".to_string()]);
+        filters.insert("event".to_string(), vec![
+            "start".to_string(), "stop".to_string(), "die".to_string(),
+            "connect".to_string(), "disconnect".to_string(),
+        ]);
 
         let options = EventsOptions::<String> {
-            since: None,
+            since,
             until: None,
             filters,
         };
@@ -65,6 +80,19 @@ impl DockerMonitor {
         Ok(())
     }
 
+    /// Reports every running container as started, so containers that were up
+    /// before the daemon are known too.
+    async fn scan_running(&self) -> Result<()> {
+        let options = ListContainersOptions::<String> { all: false, ..Default::default() };
+        let containers = self.docker.list_containers(Some(options)).await
+            .map_err(|e| AppError::DockerError(format!("Bollard list containers error: {}", e)))?;
+        info!("Found {} running Docker containers", containers.len());
+        for container_id in containers.into_iter().filter_map(|container| container.id) {
+            self.report_container(container_id, None, true).await?;
+        }
+        Ok(())
+    }
+
     /// Processes a single Docker event.
     async fn handle_event(&self, event: EventMessage) -> Result<()> {
         match (event.typ, event.action.as_deref()) {
@@ -73,25 +101,11 @@ impl DockerMonitor {
                     let container_id = actor.id.unwrap_or_else(|| "Unknown".to_string());
                     info!("Docker container started: {}", container_id);
 
-                    let ip_address_str = match self.get_container_ip(&container_id).await {
-                        Ok(ip) => ip,
-                        Err(e) => {
-                            warn!("Failed to inspect container {} for IP: {}", container_id, e);
-                            None
-                        }
-                    };
-
-                    // Parse the string IP into Option<IpAddr>
-                    let ip_address: Option<IpAddr> = ip_address_str
-                        .clone()
-                        .and_then(|ip_str| ip_str.parse().ok());
-
-                    if ip_address.is_none() && ip_address_str.is_some() {
-                        warn!("Failed to parse IP address string: {}", ip_address_str.unwrap());
-                    }
-
-                    self.event_tx.send(SystemEvent::Docker(crate::types::DockerEvent::ContainerStarted(container_id, ip_address))).await
-                        .map_err(|e| AppError::MpscSendError(format!("Failed to send DockerEvent::ContainerStarted: {}", e)))?;
+                    // Container events carry the container's labels as actor attributes
+                    let label = actor.attributes.as_ref()
+                        .and_then(|attributes| attributes.get(FORWARD_LABEL))
+                        .cloned();
+                    self.report_container(container_id, label, false).await?;
                 }
             }
             (Some(EventMessageTypeEnum::CONTAINER), Some("stop")) | (Some(EventMessageTypeEnum::CONTAINER), Some("die")) => {
@@ -102,7 +116,17 @@ impl DockerMonitor {
                          .map_err(|e| AppError::MpscSendError(format!("Failed to send DockerEvent::ContainerStopped: {}", e)))?;
                  }
             }
-             // TODO: Handle other events like network connect/disconnect if needed for IP discovery
+            (Some(EventMessageTypeEnum::NETWORK), Some(action @ ("connect" | "disconnect"))) => {
+                // Network events name the network as actor and the container as an attribute
+                let container_id = event.actor
+                    .and_then(|actor| actor.attributes)
+                    .and_then(|mut attributes| attributes.remove("container"));
+                if let Some(container_id) = container_id {
+                    info!("Docker network {} for container {}", action, container_id);
+                    // A stopping container disconnects after it died; only running ones are re-reported
+                    self.report_container(container_id, None, true).await?;
+                }
+            }
             _ => {
                  // Ignore other event types/actions for now
             }
@@ -110,37 +134,66 @@ impl DockerMonitor {
         Ok(())
     }
 
-    /// Inspects a container and retrieves its primary IP address.
-    /// Returns the IP address as a String if found, otherwise None.
-    async fn get_container_ip(&self, container_id: &str) -> Result<Option<String>> {
+    /// Inspects a container and sends its current IP address, label port forwards,
+    /// networks and labels as `ContainerStarted`. The forward label comes from the
+    /// event attributes when given, otherwise from the inspected labels. With
+    /// `only_running`, containers that are not running are skipped.
+    async fn report_container(&self, container_id: String, label: Option<String>, only_running: bool) -> Result<()> {
+        let (ip_address_str, details, running) = match self.inspect_container(&container_id).await {
+            Ok(inspected) => inspected,
+            Err(e) if only_running => return Err(e),
+            Err(e) => {
+                warn!("Failed to inspect container {} for IP: {}", container_id, e);
+                (None, ContainerDetails::default(), true)
+            }
+        };
+        if only_running && !running {
+            info!("Docker container {} is not running, not reporting it", container_id);
+            return Ok(());
+        }
+
+        let forwards = label.as_ref()
+            .or_else(|| details.labels.get(FORWARD_LABEL))
+            .map(|value| parse_forward_label(&container_id, value))
+            .unwrap_or_default();
+
+        // Parse the string IP into Option<IpAddr>
+        let ip_address: Option<IpAddr> = ip_address_str
+            .clone()
+            .and_then(|ip_str| ip_str.parse().ok());
+
+        if let (None, Some(ip_str)) = (ip_address, &ip_address_str) {
+            warn!("Failed to parse IP address string: {}", ip_str);
+        }
+
+        self.event_tx.send(SystemEvent::Docker(crate::types::DockerEvent::ContainerStarted(container_id, ip_address, forwards, details))).await
+            .map_err(|e| AppError::MpscSendError(format!("Failed to send DockerEvent::ContainerStarted: {}", e)))
+    }
+
+    /// Inspects a container and retrieves its primary IP address, plus its
+    /// networks, labels and whether it is running. The IP address is a String
+    /// if found, otherwise None.
+    async fn inspect_container(&self, container_id: &str) -> Result<(Option<String>, ContainerDetails, bool)> {
         info!("Inspecting container {} for IP address...", container_id);
         let options = InspectContainerOptions { size: false };
         match self.docker.inspect_container(container_id, Some(options)).await {
             Ok(inspect_info) => {
-                // Try to find an IP address in the network settings
-                if let Some(network_settings) = inspect_info.network_settings {
-                    // Check the default network IP first
-                    if let Some(ip) = network_settings.ip_address {
-                        if !ip.is_empty() {
-                            info!("Found default IP {} for container {}", ip, container_id);
-                            return Ok(Some(ip));
-                        }
+                let details = container_details(&inspect_info);
+                let running = inspect_info.state.as_ref().and_then(|state| state.running).unwrap_or(false);
+                match primary_address(&inspect_info) {
+                    Some((ip, Some(network_name))) => {
+                        info!("Found IP {} for container {} in network \"{}\"", ip, container_id, network_name);
+                        Ok((Some(ip.to_string()), details, running))
                     }
-                    // If not found, check connected networks
-                    if let Some(networks) = network_settings.networks {
-                        // Iterate through connected networks (e.g., bridge, host, custom)
-                        for (network_name, network_data) in networks {
-                            if let Some(ip) = network_data.ip_address {
-                                if !ip.is_empty() {
-                                    info!("Found IP {} for container {} in network \"{}\"", ip, container_id, network_name);
-                                    return Ok(Some(ip));
-                                }
-                            }
-                        }
+                    Some((ip, None)) => {
+                        info!("Found default IP {} for container {}", ip, container_id);
+                        Ok((Some(ip.to_string()), details, running))
+                    }
+                    None => {
+                        warn!("No IP address found for container {} in inspect details.", container_id);
+                        Ok((None, details, running)) // No IP found in the expected places
                     }
                 }
-                warn!("No IP address found for container {} in inspect details.", container_id);
-                Ok(None) // No IP found in the expected places
             }
             Err(e) => {
                 error!("Failed to inspect container {}: {}", container_id, e);
@@ -148,4 +201,139 @@ impl DockerMonitor {
             }
         }
     }
-} 
\ No newline at end of file
+}
+
+/// Networks (with the container's IPv4 and IPv6 addresses on each) and labels
+/// of an inspected container.
+fn container_details(inspect_info: &ContainerInspectResponse) -> ContainerDetails {
+    let mut details = ContainerDetails::default();
+    if let Some(labels) = inspect_info.config.as_ref().and_then(|config| config.labels.as_ref()) {
+        details.labels = labels.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+    }
+    let networks = inspect_info.network_settings.as_ref().and_then(|settings| settings.networks.as_ref());
+    for (network_name, endpoint) in networks.into_iter().flatten() {
+        let addresses = [&endpoint.ip_address, &endpoint.global_ipv6_address].into_iter()
+            .filter_map(|address| address.as_deref())
+            .filter_map(|address| address.parse::<IpAddr>().ok())
+            .collect();
+        details.networks.insert(network_name.clone(), addresses);
+    }
+    details
+}
+
+/// The container's primary IPv4 address: the default network address when set,
+/// otherwise the address on the first connected network by name, so the choice
+/// does not depend on the map order of the inspect response. Returns the network
+/// name alongside unless the default address was used.
+fn primary_address(inspect_info: &ContainerInspectResponse) -> Option<(&str, Option<&str>)> {
+    let network_settings = inspect_info.network_settings.as_ref()?;
+    if let Some(ip) = network_settings.ip_address.as_deref().filter(|ip| !ip.is_empty()) {
+        return Some((ip, None));
+    }
+    let mut networks: Vec<_> = network_settings.networks.iter().flatten().collect();
+    networks.sort_by(|a, b| a.0.cmp(b.0));
+    networks.into_iter().find_map(|(network_name, endpoint)| {
+        endpoint.ip_address.as_deref()
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| (ip, Some(network_name.as_str())))
+    })
+}
+
+/// Parses a `rust-network-mgr.forward` label value: comma-separated
+/// `[zone:]protocol:external_port->container_port` entries. Invalid entries are
+/// logged and skipped.
+fn parse_forward_label(container_id: &str, value: &str) -> Vec<ContainerForward> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .filter_map(|spec| {
+            let forward = parse_forward(spec);
+            if forward.is_none() {
+                warn!("Ignoring invalid {} entry '{}' on container {}", FORWARD_LABEL, spec, container_id);
+            }
+            forward
+        })
+        .collect()
+}
+
+fn parse_forward(spec: &str) -> Option<ContainerForward> {
+    let (head, container_port) = spec.split_once("->")?;
+    let mut parts: Vec<&str> = head.split(':').collect();
+    let external_port = parts.pop()?;
+    let protocol = match parts.pop()? {
+        "tcp" => ForwardProtocol::Tcp,
+        "udp" => ForwardProtocol::Udp,
+        _ => return None,
+    };
+    let from = match parts.as_slice() {
+        [] => None,
+        [zone] if !zone.is_empty() => Some(zone.to_string()),
+        _ => return None,
+    };
+    let external_port: u16 = external_port.parse().ok().filter(|p| *p != 0)?;
+    let container_port: u16 = container_port.parse().ok().filter(|p| *p != 0)?;
+    Some(ContainerForward { from, protocol, external_port, container_port })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_forward_label() {
+        let forwards = parse_forward_label("c1", "tcp:8080->80, wan:udp:5353->53,bogus,tcp:0->80");
+        assert_eq!(forwards, vec![
+            ContainerForward { from: None, protocol: ForwardProtocol::Tcp, external_port: 8080, container_port: 80 },
+            ContainerForward {
+                from: Some("wan".to_string()),
+                protocol: ForwardProtocol::Udp,
+                external_port: 5353,
+                container_port: 53,
+            },
+        ]);
+        assert!(parse_forward("sctp:1->1").is_none());
+        assert!(parse_forward("a:b:tcp:1->1").is_none());
+    }
+
+    #[test]
+    fn test_container_details_from_inspect() {
+        let inspect_info: ContainerInspectResponse = serde_json::from_value(serde_json::json!({
+            "Config": { "Labels": { "role": "db" } },
+            "NetworkSettings": {
+                "Networks": {
+                    "backend": { "IPAddress": "172.20.0.5", "GlobalIPv6Address": "fd00:20::5" },
+                    "frontend": { "IPAddress": "172.21.0.5", "GlobalIPv6Address": "" }
+                }
+            }
+        })).unwrap();
+        let details = container_details(&inspect_info);
+        assert_eq!(details.labels.get("role").map(String::as_str), Some("db"));
+        assert_eq!(details.networks["backend"], vec![
+            "172.20.0.5".parse::<IpAddr>().unwrap(),
+            "fd00:20::5".parse::<IpAddr>().unwrap(),
+        ]);
+        assert_eq!(details.networks["frontend"], vec!["172.21.0.5".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_primary_address_prefers_default_then_network_name() {
+        let mut inspect_info: ContainerInspectResponse = serde_json::from_value(serde_json::json!({
+            "NetworkSettings": {
+                "IPAddress": "",
+                "Networks": {
+                    "zeta": { "IPAddress": "172.30.0.5" },
+                    "beta": { "IPAddress": "172.21.0.5" },
+                    "alpha": { "IPAddress": "" },
+                    "gamma": { "IPAddress": "172.22.0.5" }
+                }
+            }
+        })).unwrap();
+        assert_eq!(primary_address(&inspect_info), Some(("172.21.0.5", Some("beta"))));
+
+        inspect_info.network_settings.as_mut().unwrap().ip_address = Some("172.17.0.2".to_string());
+        assert_eq!(primary_address(&inspect_info), Some(("172.17.0.2", None)));
+
+        inspect_info.network_settings = None;
+        assert_eq!(primary_address(&inspect_info), None);
+    }
+}
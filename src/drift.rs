@@ -0,0 +1,102 @@
+//! Watches the nftables ruleset for changes made by other tools.
+//!
+//! Subscribes to the nfnetlink `NFNLGRP_NFTABLES` multicast group, which reports
+//! every committed ruleset change (`nft flush ruleset`, `systemctl restart nftables`, ...),
+//! and additionally requests a periodic read-back in case notifications are lost
+//! or the socket cannot be opened. Comparing and repairing is left to the
+//! `NftablesManager`; this task only emits `RulesetEvent`s.
+
+use crate::types::{AppError, EventSender, Result, RulesetEvent, SystemEvent};
+use log::{debug, info, warn};
+use netlink_sys::{protocols::NETLINK_NETFILTER, AsyncSocket, AsyncSocketExt, SocketAddr, TokioSocket};
+use std::time::Duration;
+use tokio::time::{interval_at, Instant, Interval};
+
+/// nfnetlink multicast group carrying nf_tables change notifications.
+const NFNLGRP_NFTABLES: u32 = 7;
+
+/// One transaction produces a burst of notifications; wait for this much quiet before reporting it.
+const CHANGE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Changes reported this soon after a transaction of the daemon are taken as
+/// its own and not checked; the periodic read-back still covers them.
+pub const OWN_CHANGE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Emits `RulesetEvent`s when the nftables ruleset may have changed.
+pub struct DriftMonitor {
+    event_sender: EventSender,
+    check_interval: Option<Duration>,
+}
+
+impl DriftMonitor {
+    /// `check_interval_secs` of `0` disables the periodic read-back.
+    pub fn new(event_sender: EventSender, check_interval_secs: u64) -> Self {
+        DriftMonitor {
+            event_sender,
+            check_interval: (check_interval_secs > 0).then(|| Duration::from_secs(check_interval_secs)),
+        }
+    }
+
+    /// Starts the monitoring loop. Runs until the event channel is closed.
+    pub async fn start(self) -> Result<()> {
+        info!("Starting DriftMonitor task");
+
+        let mut socket = match open_nftables_socket() {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                warn!("Cannot subscribe to nftables change notifications ({}), relying on periodic checks.", e);
+                None
+            }
+        };
+        if socket.is_none() && self.check_interval.is_none() {
+            warn!("Ruleset drift detection disabled: no notifications and no periodic check.");
+            return Ok(());
+        }
+        let mut interval = self.check_interval.map(|period| interval_at(Instant::now() + period, period));
+
+        loop {
+            let event = tokio::select! {
+                _ = next_change(&mut socket) => RulesetEvent::Changed,
+                _ = next_tick(&mut interval) => RulesetEvent::PeriodicCheck,
+            };
+            debug!("Ruleset event: {:?}", event);
+            self.event_sender.send(SystemEvent::Ruleset(event)).await
+                .map_err(|e| AppError::MpscSendError(format!("Failed to send RulesetEvent: {}", e)))?;
+        }
+    }
+}
+
+fn open_nftables_socket() -> std::io::Result<TokioSocket> {
+    let mut socket = TokioSocket::new(NETLINK_NETFILTER)?;
+    socket.socket_mut().bind(&SocketAddr::new(0, 0))?;
+    socket.socket_mut().add_membership(NFNLGRP_NFTABLES)?;
+    Ok(socket)
+}
+
+/// Waits for a burst of change notifications to end. Never returns without a socket.
+async fn next_change(socket: &mut Option<TokioSocket>) {
+    let Some(socket) = socket else {
+        return std::future::pending().await;
+    };
+    if let Err(e) = socket.recv_from_full().await {
+        // Typically ENOBUFS: notifications were dropped, so assume something changed
+        warn!("Error receiving nftables notifications: {}", e);
+        tokio::time::sleep(CHANGE_DEBOUNCE).await;
+    }
+    while let Ok(result) = tokio::time::timeout(CHANGE_DEBOUNCE, socket.recv_from_full()).await {
+        if let Err(e) = result {
+            warn!("Error receiving nftables notifications: {}", e);
+            break;
+        }
+    }
+}
+
+/// Waits for the next periodic check. Never returns without an interval.
+pub(crate) async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
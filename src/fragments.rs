@@ -0,0 +1,191 @@
+//! Hand-written rule fragments loaded from `nftables_rules_path`.
+//!
+//! `*.nft` files hold nft syntax and `*.json` files the nftables JSON schema.
+//! They are read in file name order, and `${...}` placeholders are replaced
+//! first, so a fragment can name the managed table and sets without repeating
+//! the naming templates of the `nftables:` section.
+
+use crate::types::AppError;
+use log::warn;
+use nftables::schema::Nftables;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Contents of one fragment, placeholders already replaced.
+#[derive(Debug, Clone)]
+pub enum FragmentBody {
+    /// nft syntax, as accepted by `nft -f`.
+    Script(String),
+    /// Objects and commands in the nftables JSON schema, already checked to
+    /// deserialize; see `FragmentBody::ruleset`.
+    Json(serde_json::Value),
+}
+
+impl FragmentBody {
+    /// The objects of a JSON fragment, borrowing from it.
+    pub fn ruleset(&self) -> Option<Nftables<'_>> {
+        match self {
+            FragmentBody::Json(value) => Nftables::deserialize(value).ok(),
+            FragmentBody::Script(_) => None,
+        }
+    }
+
+    /// The chains a script fragment touches; see `script_chains`.
+    pub fn chains(&self) -> BTreeSet<String> {
+        match self {
+            FragmentBody::Script(script) => script_chains(script),
+            FragmentBody::Json(_) => BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleFragment {
+    pub path: PathBuf,
+    pub body: FragmentBody,
+}
+
+/// Reads the `*.nft` and `*.json` files of `dir`, sorted by file name.
+///
+/// A file that cannot be read, has an unknown placeholder or is not valid JSON
+/// is skipped with a warning; only an unreadable directory is an error.
+pub fn load_fragments(dir: &Path, placeholders: &BTreeMap<String, String>) -> Result<Vec<RuleFragment>, AppError> {
+    let entries = std::fs::read_dir(dir).map_err(|e|
+        AppError::ConfigIo(format!("Failed to read rule fragments from '{}': {}", dir.display(), e)))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && matches!(path.extension().and_then(|e| e.to_str()), Some("nft" | "json")))
+        .collect();
+    paths.sort();
+
+    let mut fragments = Vec::new();
+    for path in paths {
+        match load_fragment(&path, placeholders) {
+            Ok(body) => fragments.push(RuleFragment { path, body }),
+            Err(e) => warn!("Skipping rule fragment '{}': {}", path.display(), e),
+        }
+    }
+    Ok(fragments)
+}
+
+fn load_fragment(path: &Path, placeholders: &BTreeMap<String, String>) -> Result<FragmentBody, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let content = resolve_placeholders(&content, placeholders)?;
+    if path.extension().and_then(|e| e.to_str()) == Some("json") {
+        let value: serde_json::Value = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+        Nftables::deserialize(&value).map_err(|e| e.to_string())?;
+        Ok(FragmentBody::Json(value))
+    } else {
+        Ok(FragmentBody::Script(content))
+    }
+}
+
+/// Replaces every `${name}` in `content` with `placeholders[name]`.
+pub fn resolve_placeholders(content: &str, placeholders: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut resolved = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find("${") {
+        resolved.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err("unterminated placeholder".to_string());
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let value = placeholders.get(name).ok_or_else(|| format!("unknown placeholder ${{{}}}", name))?;
+        resolved.push_str(value);
+        rest = &rest[start + 3 + len..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+/// Names of the chains an nft syntax script declares (`chain <name> { ... }`)
+/// or names in a rule or chain command (`add rule [<family>] <table> <chain> ...`).
+///
+/// The script is only scanned, not evaluated, so chains named through
+/// variables or `include`d files are missed, and the table is not checked.
+pub fn script_chains(script: &str) -> BTreeSet<String> {
+    const COMMANDS: [&str; 6] = ["add", "create", "insert", "replace", "flush", "delete"];
+    const FAMILIES: [&str; 6] = ["ip", "ip6", "inet", "arp", "bridge", "netdev"];
+    let tokens: Vec<&str> = script.lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || matches!(c, '{' | '}' | ';')))
+        .filter(|token| !token.is_empty())
+        .collect();
+    let mut chains = BTreeSet::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let command = i > 0 && COMMANDS.contains(&tokens[i - 1]);
+        let chain = match *token {
+            "rule" | "chain" if command => {
+                let rest = &tokens[i + 1..];
+                let rest = if rest.first().is_some_and(|t| FAMILIES.contains(t)) { &rest[1..] } else { rest };
+                rest.get(1)
+            }
+            "chain" => tokens.get(i + 1),
+            _ => None,
+        };
+        chains.extend(chain.map(|chain| chain.to_string()));
+    }
+    chains
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn placeholders() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("table".to_string(), "filter".to_string()),
+            ("wan".to_string(), "wan_ips".to_string()),
+            ("wan.ipv6".to_string(), "wan_ipv6".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_resolve_placeholders() {
+        assert_eq!(
+            resolve_placeholders("ip saddr @${wan} ip6 saddr @${wan.ipv6} table ${table}", &placeholders()).unwrap(),
+            "ip saddr @wan_ips ip6 saddr @wan_ipv6 table filter"
+        );
+        assert_eq!(resolve_placeholders("no placeholders $HOME", &placeholders()).unwrap(), "no placeholders $HOME");
+        assert!(resolve_placeholders("@${lan}", &placeholders()).unwrap_err().contains("${lan}"));
+        assert!(resolve_placeholders("@${wan", &placeholders()).is_err());
+    }
+
+    #[test]
+    fn test_script_chains() {
+        let script = "add rule inet filter input tcp dport 22 accept\n\
+            insert rule filter forward accept # comment naming chain hidden\n\
+            flush chain inet filter custom\n\
+            table inet filter {\n\
+                chain output { type filter hook output priority 0; accept; }\n\
+            }\n";
+        let chains: Vec<String> = script_chains(script).into_iter().collect();
+        assert_eq!(chains, vec!["custom", "forward", "input", "output"]);
+        assert!(script_chains("add set inet filter blocked { type ipv4_addr; }").is_empty());
+    }
+
+    #[test]
+    fn test_load_fragments_sorted_and_skips_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &str| {
+            std::fs::File::create(dir.path().join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+        };
+        write("20-ssh.nft", "add rule inet ${table} input ip saddr @${wan} tcp dport 22 accept\n");
+        write("10-base.json", r#"{"nftables": [{"add": {"chain": {"family": "inet", "table": "${table}", "name": "custom"}}}]}"#);
+        write("30-broken.json", "{ not json");
+        write("40-unknown.nft", "add rule inet filter input ip saddr @${lan} accept\n");
+        write("README.md", "ignored");
+
+        let fragments = load_fragments(dir.path(), &placeholders()).unwrap();
+        let names: Vec<_> = fragments.iter().map(|f| f.path.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, vec!["10-base.json", "20-ssh.nft"]);
+        assert_eq!(fragments[0].body.ruleset().unwrap().objects.len(), 1);
+        match &fragments[1].body {
+            FragmentBody::Script(script) => assert!(script.contains("@wan_ips") && script.contains("inet filter")),
+            other => panic!("Expected a script, got {:?}", other),
+        }
+        assert!(load_fragments(&dir.path().join("missing"), &placeholders()).is_err());
+    }
+}
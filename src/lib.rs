@@ -1,10 +1,15 @@
 // Declare the modules that form the library's structure
 pub mod api;
+pub mod backend;
+pub mod blocklist;
 pub mod cli;
 pub mod config;
 pub mod docker;
+pub mod drift;
+pub mod fragments;
 pub mod network;
 pub mod nftables;
+pub mod ruleset;
 pub mod socket;
 pub mod types;
 
@@ -19,8 +24,10 @@ pub use config::load_config;
 // Monitoring Modules
 pub use network::NetworkMonitor;
 pub use nftables::NftablesManager;
+pub use backend::{FirewallBackend, MemoryBackend, NftBackend};
 pub use socket::SocketHandler;
 pub use docker::DockerMonitor;
+pub use drift::DriftMonitor;
 
 // Core Types (Consolidated)
 pub use types::{
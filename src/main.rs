@@ -1,28 +1,39 @@
 // Use the library crate
+use clap::Parser;
 use rust_network_mgr::api::{ApiState, spawn_http_server};
+use rust_network_mgr::backend;
+use rust_network_mgr::cli::{resolve_socket_path, send_socket_command, Cli, Commands};
 use rust_network_mgr::config::load_config;
+use rust_network_mgr::drift::{DriftMonitor, OWN_CHANGE_WINDOW};
 use rust_network_mgr::network::NetworkMonitor;
 use rust_network_mgr::nftables::NftablesManager;
+use rust_network_mgr::ruleset::empty_ruleset;
 use rust_network_mgr::socket::SocketHandler;
-use rust_network_mgr::types::{AppConfig, ControlCommand, NetworkEvent, Result, InterfaceConfig, NetworkState, SystemEvent, EventSender};
+use rust_network_mgr::types::{AppConfig, AppError, ControlCommand, NetworkEvent, Result, NetworkState, RulesetEvent, ShutdownAction, StateSnapshot, SystemEvent, EventSender};
 use tokio::sync::mpsc::{channel, Receiver};
 
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{Mutex};
 use tokio::signal::unix::{signal, SignalKind};
-use log::{info, error}; // Removed warn
-use tracing; // Assuming tracing is used
+use log::{debug, info, error};
 
 // Channel buffer sizes
 const EVENT_CHANNEL_SIZE: usize = 100;
+// How often to check for ended grace periods and set elements due for a refresh
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
 
 // Define AppState here since it's not in the types module
 struct AppState {
     config: AppConfig,
     network_state: NetworkState,
     container_ips: HashMap<String, IpAddr>,
+    // Copies served by the HTTP API, refreshed by `publish` after every change
+    api_network_state: Arc<Mutex<NetworkState>>,
+    api_container_ips: Arc<Mutex<HashMap<String, IpAddr>>>,
 }
 
 impl AppState {
@@ -31,31 +42,120 @@ impl AppState {
             config,
             network_state: NetworkState::default(),
             container_ips: HashMap::new(),
+            api_network_state: Arc::new(Mutex::new(NetworkState::default())),
+            api_container_ips: Arc::new(Mutex::new(HashMap::new())),
         }
     }
+
+    /// Makes the current state visible to the HTTP API.
+    async fn publish(&self) {
+        *self.api_network_state.lock().await = self.network_state.clone();
+        *self.api_container_ips.lock().await = self.container_ips.clone();
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Basic logging setup (consider a more robust solution like tracing-subscriber)
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let cli = Cli::parse();
+    let command = match cli.command.unwrap_or_default() {
+        Commands::Daemon => return run_daemon(cli.config).await,
+        Commands::Plan { state, offline, json } => {
+            return run_plan(cli.config.as_deref(), state.as_deref(), offline, json).await;
+        }
+        Commands::Cleanup { flush } => {
+            let action = if flush { ShutdownAction::Flush } else { ShutdownAction::Delete };
+            return run_cleanup(cli.config.as_deref(), action).await;
+        }
+        Commands::Reload => "reload".to_string(),
+        Commands::Status => "status".to_string(),
+        Commands::Ping => "ping".to_string(),
+        Commands::Shutdown => "shutdown".to_string(),
+        Commands::Block { target, ttl, reason } => {
+            let mut command = format!("block {}", target);
+            if let Some(ttl) = ttl {
+                command.push_str(&format!(" ttl={}", ttl));
+            }
+            if let Some(reason) = reason {
+                command.push_str(&format!(" {}", reason));
+            }
+            command
+        }
+        Commands::Unblock { target } => format!("unblock {}", target),
+        Commands::Blocklist => "blocklist".to_string(),
+    };
+
+    // Client mode: forward the command to the running daemon
+    let socket_config = load_initial_config(cli.config.as_deref()).ok().and_then(|c| c.socket_path);
+    let socket_path = resolve_socket_path(cli.socket.as_deref(), socket_config.as_deref());
+    let response = send_socket_command(&socket_path, &command).await?;
+    print!("{}", response);
+    Ok(())
+}
+
+/// Prints the nftables changes a reload with the given configuration would make.
+async fn run_plan(config_path: Option<&Path>, state_path: Option<&Path>, offline: bool, json: bool) -> Result<()> {
+    let config = load_initial_config(config_path)?;
+    let backend = backend::from_config(config.firewall_backend);
+    let nftables_manager = NftablesManager::with_backend(Arc::new(Mutex::new(config)), backend).await?;
+
+    let snapshot = match state_path {
+        Some(path) => {
+            let content = std::fs::read_to_string(path).map_err(|e|
+                AppError::ConfigIo(format!("Failed to read state snapshot '{}': {}", path.display(), e)))?;
+            Some(serde_json::from_str::<StateSnapshot>(&content)?.into_state())
+        }
+        None => None,
+    };
+    if let Some((network_state, _)) = &snapshot {
+        nftables_manager.set_links(&network_state.links).await;
+    }
+    let live = if offline {
+        empty_ruleset()
+    } else {
+        nftables_manager.live_ruleset()?
+    };
+
+    let plan = nftables_manager
+        .plan(snapshot.as_ref().map(|(network_state, containers)| (network_state, containers)), &live)
+        .await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&plan.transaction)?);
+    } else {
+        print!("{}", plan.render());
+    }
+    Ok(())
+}
+
+/// Removes (or empties) the nftables objects owned by the daemon.
+async fn run_cleanup(config_path: Option<&Path>, action: ShutdownAction) -> Result<()> {
+    let config = load_initial_config(config_path)?;
+    let backend = backend::from_config(config.firewall_backend);
+    let nftables_manager = NftablesManager::with_backend(Arc::new(Mutex::new(config)), backend).await?;
+    nftables_manager.cleanup(action).await
+}
+
+async fn run_daemon(config_path: Option<PathBuf>) -> Result<()> {
     info!("Starting rust-network-mgr...");
 
     // Set up signal handling
     let mut sigterm = signal(SignalKind::terminate()).expect("Failed to create SIGTERM signal stream");
     let mut sigint = signal(SignalKind::interrupt()).expect("Failed to create SIGINT signal stream");
 
-    let initial_config = load_initial_config()?;
+    let initial_config = load_initial_config(config_path.as_deref())?;
 
     // -- Create Communication Channels --
     // Channel for system events
     let (event_tx, mut event_rx): (EventSender, Receiver<SystemEvent>) = channel(EVENT_CHANNEL_SIZE);
     
     // Create and initialize components
-    let network_monitor = NetworkMonitor::new(event_tx.clone());
+    let network_monitor = NetworkMonitor::new(event_tx.clone(), initial_config.netlink_resync_interval());
 
-    let interface_config_arc = Arc::new(Mutex::new(initial_config.interfaces.clone()));
-    let nftables_manager = Arc::new(NftablesManager::new(interface_config_arc.clone()).await?);
+    let app_config_arc = Arc::new(Mutex::new(initial_config.clone()));
+    let backend = backend::from_config(initial_config.firewall_backend);
+    let nftables_manager = Arc::new(NftablesManager::with_backend(app_config_arc.clone(), backend).await?);
     let socket_handler = SocketHandler::new(initial_config.socket_path.as_deref(), event_tx.clone()).await?;
     let initial_state = AppState::new(initial_config.clone()); 
     let app_state = Arc::new(Mutex::new(initial_state));
@@ -83,10 +183,12 @@ async fn main() -> Result<()> {
     {
         let state_guard = app_state.lock().await;
         let api_state = ApiState {
-            network_state: Arc::new(Mutex::new(state_guard.network_state.clone())),
-            container_ips: Arc::new(Mutex::new(state_guard.container_ips.clone())),
+            network_state: state_guard.api_network_state.clone(),
+            container_ips: state_guard.api_container_ips.clone(),
             event_tx: event_tx.clone(),
             version: env!("CARGO_PKG_VERSION"),
+            nftables: nftables_manager.clone(),
+            config_path: config_path.clone(),
         };
         drop(state_guard);
         let _http_handle = spawn_http_server(api_state, &http_bind);
@@ -112,6 +214,30 @@ async fn main() -> Result<()> {
     // -- Start Background Tasks --
     info!("Starting background tasks...");
 
+    // Start Ruleset Drift Monitor
+    info!("Starting ruleset drift monitor...");
+    let drift_monitor = DriftMonitor::new(event_tx.clone(), initial_config.nftables.drift_check_interval);
+    let drift_handle = tokio::spawn(async move {
+        if let Err(e) = drift_monitor.start().await {
+            error!("Ruleset drift monitor failed: {}", e);
+        }
+    });
+
+    // Start Zone Counter Polling
+    let counter_interval = initial_config.nftables.counter_interval;
+    let counters_handle = (counter_interval > 0).then(|| {
+        let nft_manager_clone = nftables_manager.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(counter_interval));
+            loop {
+                ticker.tick().await;
+                if let Err(e) = nft_manager_clone.refresh_counters().await {
+                    debug!("Failed to read zone counters: {}", e);
+                }
+            }
+        })
+    });
+
     // Start Network Monitor
     info!("Starting network monitor...");
     let monitor_handle = tokio::spawn(async move {
@@ -130,6 +256,7 @@ async fn main() -> Result<()> {
 
     // -- Main Event Loop --
     info!("Starting main event loop...");
+    let mut maintenance = tokio::time::interval(MAINTENANCE_INTERVAL);
     loop {
         tokio::select! {
             // Main event channel handles all events now
@@ -140,7 +267,7 @@ async fn main() -> Result<()> {
                         // Spawn a task to handle the network event asynchronously
                         let state_clone = app_state.clone();
                         let nft_manager_clone = nftables_manager.clone(); // Clone Arc
-                        let config_clone = interface_config_arc.clone(); // Clone Arc
+                        let config_clone = app_config_arc.clone(); // Clone Arc
                         tokio::spawn(async move {
                             handle_network_event(network_event, nft_manager_clone, state_clone, config_clone).await;
                         });
@@ -153,18 +280,27 @@ async fn main() -> Result<()> {
                             let (network_state_snap, container_ips_snap) = {
                                 let mut state = state_clone.lock().await;
                                 match docker_event {
-                                    rust_network_mgr::types::DockerEvent::ContainerStarted(id, Some(ip)) => {
+                                    rust_network_mgr::types::DockerEvent::ContainerStarted(id, Some(ip), forwards, details) => {
                                         info!("Container started: {} (IP: {})", id, ip);
+                                        nft_manager_clone.set_container_forwards(&id, forwards).await;
+                                        nft_manager_clone.set_container_details(&id, details).await;
                                         state.container_ips.insert(id, ip);
                                     }
-                                    rust_network_mgr::types::DockerEvent::ContainerStarted(id, None) => {
+                                    rust_network_mgr::types::DockerEvent::ContainerStarted(id, None, forwards, details) => {
                                         info!("Container started: {} (no IP)", id);
+                                        nft_manager_clone.set_container_forwards(&id, forwards).await;
+                                        nft_manager_clone.set_container_details(&id, details).await;
+                                        // Disconnected from its last network
+                                        state.container_ips.remove(&id);
                                     }
                                     rust_network_mgr::types::DockerEvent::ContainerStopped(id) => {
                                         info!("Container stopped: {}", id);
+                                        nft_manager_clone.set_container_forwards(&id, Vec::new()).await;
+                                        nft_manager_clone.set_container_details(&id, Default::default()).await;
                                         state.container_ips.remove(&id);
                                     }
                                 }
+                                state.publish().await;
                                 (state.network_state.clone(), state.container_ips.clone())
                             };
                             if let Err(e) = nft_manager_clone
@@ -175,28 +311,55 @@ async fn main() -> Result<()> {
                             }
                         });
                     },
+                    SystemEvent::Ruleset(ruleset_event) => {
+                        debug!("Received ruleset event: {:?}", ruleset_event);
+                        if ruleset_event == RulesetEvent::Changed && nftables_manager.committed_within(OWN_CHANGE_WINDOW) {
+                            debug!("Ignoring ruleset change following our own transaction");
+                            continue;
+                        }
+                        let nft_manager_clone = nftables_manager.clone();
+                        let state_clone = app_state.clone();
+                        tokio::spawn(async move {
+                            let (network_state_snap, container_ips_snap) = {
+                                let state = state_clone.lock().await;
+                                (state.network_state.clone(), state.container_ips.clone())
+                            };
+                            if let Err(e) = nft_manager_clone
+                                .repair_drift(&network_state_snap, &container_ips_snap)
+                                .await
+                            {
+                                error!("Failed to check or repair the nftables ruleset: {}", e);
+                            }
+                        });
+                    },
                     SystemEvent::Control(command) => {
                         info!("Received control command: {:?}", command);
                         match command {
                             ControlCommand::Reload => {
                                 info!("Reload command received. Reloading configuration and applying rules...");
-                                let config_result = load_initial_config(); // Reload config
+                                let config_result = load_initial_config(config_path.as_deref()); // Reload config
                                 let nft_manager = nftables_manager.clone(); // Clone Arc for async block
                                 let state_clone = app_state.clone(); // Clone Arc for async block
+                                let config_clone = app_config_arc.clone(); // Clone Arc for async block
 
                                 tokio::spawn(async move {
                                     match config_result {
                                         Ok(new_config) => {
                                             let mut state = state_clone.lock().await;
+                                            // The NftablesManager reads zones and policy from the shared config
+                                            *config_clone.lock().await = new_config.clone();
                                             state.config = new_config;
-                                            // Optionally update interface_config_arc if NftablesManager needs it dynamically
-                                            // let mut if_config = interface_config_arc.lock().await;
-                                            // *if_config = state.config.interfaces.clone();
-                                            // drop(if_config);
-                                            
-                                            // Re-apply rules based on current state
-                                            // The NftablesManager uses its internally stored config reference
-                                            if let Err(e) = nft_manager.apply_rules(&state.network_state, &state.container_ips).await {
+                                            // Clone the relevant state *before* dropping the lock, so
+                                            // events are not held up for the whole reload
+                                            let network_state = state.network_state.clone();
+                                            let container_ips = state.container_ips.clone();
+                                            drop(state);
+
+                                            // Re-create sets and policy chains, then re-apply set elements
+                                            if let Err(e) = nft_manager.load_rules().await {
+                                                error!("Error loading rules after reload: {}", e);
+                                            }
+                                            if let Err(e) = nft_manager.apply_rules(&network_state, &container_ips).await {
                                                 error!("Error applying rules after reload: {}", e);
                                             }
                                             info!("Configuration reloaded and rules re-applied.");
@@ -217,25 +380,95 @@ async fn main() -> Result<()> {
                                 let container_status = state.container_ips.iter()
                                     .map(|(id, ip)| format!("  {}: {}", id, ip))
                                     .collect::<Vec<String>>().join("\n");
-                                
+                                let gateway_status = [("ipv4", false), ("ipv6", true)].iter()
+                                    .filter_map(|(family, ipv6)| {
+                                        state.network_state.default_route(*ipv6).map(|route| format!("  {}: {}", family, route))
+                                    })
+                                    .collect::<Vec<String>>().join("\n");
+                                let route_status = state.network_state.routes.iter()
+                                    .map(|route| format!("  {}", route))
+                                    .collect::<Vec<String>>().join("\n");
+
+                                let apply_status = match nftables_manager.last_apply().await {
+                                    None => "  (None)".to_string(),
+                                    Some(result) if result.success => format!("  ok at {}", result.timestamp),
+                                    Some(result) => format!(
+                                        "  failed at {} ({} in a row{}): {}",
+                                        result.timestamp,
+                                        result.consecutive_failures,
+                                        if result.rolled_back { ", rolled back" } else { "" },
+                                        result.error.unwrap_or_default()
+                                    ),
+                                };
+
                                 let status_report = format!(
-                                    "Current Status:\nInterfaces:\n{}\nTracked Containers:\n{}",
+                                    "Current Status:\nInterfaces:\n{}\nTracked Containers:\n{}\nDefault Gateways:\n{}\nRoutes:\n{}\nLast Apply:\n{}",
                                     if interface_status.is_empty() { "  (None)" } else { &interface_status },
-                                    if container_status.is_empty() { "  (None)" } else { &container_status }
+                                    if container_status.is_empty() { "  (None)" } else { &container_status },
+                                    if gateway_status.is_empty() { "  (None)" } else { &gateway_status },
+                                    if route_status.is_empty() { "  (None)" } else { &route_status },
+                                    apply_status
                                 );
                                 
                                 // Send the report back to the socket handler
-                                if let Err(_) = response_tx.send(status_report) {
+                                if response_tx.send(status_report).is_err() {
                                     error!("Failed to send status response back to socket handler.");
                                 }
                             }
                             ControlCommand::Ping { response_tx } => {
                                 info!("Ping command received.");
                                 // Send pong back
-                                if let Err(_) = response_tx.send("PONG".to_string()) {
+                                if response_tx.send("PONG".to_string()).is_err() {
                                     error!("Failed to send pong response back to socket handler.");
                                 }
                             }
+                            ControlCommand::Block { net, ttl, reason, response_tx } => {
+                                let nft_manager = nftables_manager.clone();
+                                tokio::spawn(async move {
+                                    let response = match nft_manager.block(net, ttl, reason).await {
+                                        Ok(entry) => format!(
+                                            "OK: Blocked {}{}",
+                                            entry.net,
+                                            ttl.map(|ttl| format!(" for {}s", ttl)).unwrap_or_default()
+                                        ),
+                                        Err(e) => format!("ERROR: {}", e),
+                                    };
+                                    if response_tx.send(response).is_err() {
+                                        error!("Failed to send block response back to socket handler.");
+                                    }
+                                });
+                            }
+                            ControlCommand::Unblock { net, response_tx } => {
+                                let nft_manager = nftables_manager.clone();
+                                tokio::spawn(async move {
+                                    let response = match nft_manager.unblock(net).await {
+                                        Ok(Some(entry)) => format!("OK: Unblocked {}", entry.net),
+                                        Ok(None) => format!("ERROR: {} is not blocked", net),
+                                        Err(e) => format!("ERROR: {}", e),
+                                    };
+                                    if response_tx.send(response).is_err() {
+                                        error!("Failed to send unblock response back to socket handler.");
+                                    }
+                                });
+                            }
+                            ControlCommand::Blocklist { response_tx } => {
+                                let entries = nftables_manager.blocklist().await;
+                                let now = std::time::SystemTime::now()
+                                    .duration_since(std::time::UNIX_EPOCH)
+                                    .map_or(0, |d| d.as_secs());
+                                let lines = entries.iter()
+                                    .map(|entry| format!(
+                                        "  {}: {}{}",
+                                        entry.net,
+                                        entry.remaining(now).map_or("permanent".to_string(), |left| format!("{}s left", left)),
+                                        entry.reason.as_ref().map(|reason| format!(" ({})", reason)).unwrap_or_default()
+                                    ))
+                                    .collect::<Vec<String>>().join("\n");
+                                let report = format!("Blocklist:\n{}", if lines.is_empty() { "  (None)" } else { &lines });
+                                if response_tx.send(report).is_err() {
+                                    error!("Failed to send blocklist response back to socket handler.");
+                                }
+                            }
                             ControlCommand::Shutdown => { // Added for completeness, might be handled by signals mainly
                                 info!("Shutdown command received via socket. Initiating graceful shutdown...");
                                 break; // Exit the main loop
@@ -259,6 +492,27 @@ async fn main() -> Result<()> {
                 break;
             }
 
+            // --- Grace Periods and Element Timeouts ---
+            _ = maintenance.tick() => {
+                let nft_manager_clone = nftables_manager.clone();
+                let state_clone = app_state.clone();
+                tokio::spawn(async move {
+                    if !nft_manager_clone.maintenance_due().await {
+                        return;
+                    }
+                    let (network_state_snap, container_ips_snap) = {
+                        let state = state_clone.lock().await;
+                        (state.network_state.clone(), state.container_ips.clone())
+                    };
+                    if let Err(e) = nft_manager_clone
+                        .apply_rules(&network_state_snap, &container_ips_snap)
+                        .await
+                    {
+                        error!("Failed to apply nftables rules for set maintenance: {}", e);
+                    }
+                });
+            }
+
             else => {
                 info!("All channels closed, shutting down.");
                 break;
@@ -270,18 +524,27 @@ async fn main() -> Result<()> {
     info!("Shutting down background tasks...");
     monitor_handle.abort();
     socket_handle.abort();
+    drift_handle.abort();
+    if let Some(handle) = counters_handle {
+        handle.abort();
+    }
     if let Some(handle) = docker_handle {
         handle.abort(); // Abort Docker monitor task
         let _ = handle.await; // Optionally wait, ignoring cancellation error
         info!("Docker monitor task shut down.");
     }
 
+    let on_shutdown = initial_config.nftables.on_shutdown;
+    if let Err(e) = nftables_manager.cleanup(on_shutdown).await {
+        error!("Failed to clean up nftables objects on shutdown ({:?}): {}", on_shutdown, e);
+    }
+
     info!("Shutdown complete.");
     Ok(())
 }
 
-fn load_initial_config() -> Result<AppConfig> {
-    let config = load_config(None).map_err(|e| 
+fn load_initial_config(config_path: Option<&Path>) -> Result<AppConfig> {
+    let config = load_config(config_path.and_then(|p| p.to_str())).map_err(|e| 
         rust_network_mgr::types::AppError::ConfigIo(format!("Failed to load configuration: {}", e)))?;
     
     // Validation will happen inside load_config now
@@ -293,19 +556,39 @@ async fn handle_network_event(
     event: NetworkEvent,
     nft_manager: Arc<NftablesManager>,
     shared_state: Arc<Mutex<AppState>>,
-    _config: Arc<Mutex<Vec<InterfaceConfig>>> // Prefix unused parameter
+    _config: Arc<Mutex<AppConfig>> // Prefix unused parameter
 ) {
     tracing::debug!("Handling network event: {:?}", event);
     let mut state_guard = shared_state.lock().await;
+    let mut reapply = true;
+    let mut reload = false;
     let if_name_for_removal: Option<String> = match event {
-        NetworkEvent::IpUpdate { interface, ips } => {
+        NetworkEvent::IpUpdate { interface, addresses } => {
+            // Refreshed lifetimes are recorded, but leave the sets as they are
+            reapply = !state_guard.network_state.interface_addresses.get(&interface).is_some_and(|current| {
+                current.len() == addresses.len()
+                    && addresses.iter().all(|address| current.iter().any(|known| known.same_state(address)))
+            });
             // Update interface IPs directly
-            state_guard.network_state.interface_ips.insert(interface.clone(), ips.clone());
-            tracing::debug!("State updated for interface {} with IPs {:?}", interface, ips);
+            state_guard.network_state.set_interface_addresses(&interface, addresses);
+            tracing::debug!("State updated for interface {} with IPs {:?}", interface, state_guard.network_state.interface_ips[&interface]);
             None // No interface to remove
         }
-        NetworkEvent::LinkChanged { name, is_up } => {
+        NetworkEvent::LinkChanged { name, is_up, details } => {
             tracing::debug!("Interface {} state changed, is_up: {}", name, is_up);
+            let links_changed = match details {
+                Some(details) => state_guard.network_state.links.insert(name.clone(), details.clone()) != Some(details),
+                None => state_guard.network_state.links.remove(&name).is_some(),
+            };
+            // Interfaces selected by `match:` blocks come and go with the links;
+            // the rules are reloaded once the state lock is released
+            reload = links_changed && nft_manager.set_links(&state_guard.network_state.links).await;
+            // Flowtable devices follow the links that are up
+            if is_up {
+                state_guard.network_state.up_links.insert(name.clone());
+            } else {
+                state_guard.network_state.up_links.remove(&name);
+            }
             if !is_up {
                 // If interface went down, consider removing its IPs
                 Some(name)
@@ -313,19 +596,59 @@ async fn handle_network_event(
                 None
             }
         }
+        NetworkEvent::PeerChanged { peer, present } => {
+            let peers = state_guard.network_state.peers.entry(peer.interface.clone()).or_default();
+            let previous_mac = peers.get(&peer.ip).and_then(|previous| previous.live_mac());
+            let mac = if present { peer.live_mac() } else { None };
+            // Reachability changes of a live peer leave the sets as they are
+            reapply = previous_mac != mac;
+            if present {
+                peers.insert(peer.ip, peer);
+            } else {
+                peers.remove(&peer.ip);
+                if peers.is_empty() {
+                    state_guard.network_state.peers.remove(&peer.interface);
+                }
+            }
+            None
+        }
+        NetworkEvent::RouteChanged { route, present } => {
+            if present {
+                state_guard.network_state.routes.insert(route);
+            } else {
+                state_guard.network_state.routes.remove(&route);
+            }
+            None
+        }
     };
 
     // Remove the interface entry outside the main borrow if necessary
     if let Some(if_name_to_remove) = if_name_for_removal {
         state_guard.network_state.interface_ips.remove(&if_name_to_remove);
+        state_guard.network_state.interface_prefixes.remove(&if_name_to_remove);
+        state_guard.network_state.interface_addresses.remove(&if_name_to_remove);
+        state_guard.network_state.peers.remove(&if_name_to_remove);
+        // The kernel drops a down link's IPv4 routes without announcing it
+        state_guard.network_state.routes.retain(|route| route.interface.as_ref() != Some(&if_name_to_remove));
         tracing::debug!("Removed interface {} from state as it went down.", if_name_to_remove);
     }
 
+    state_guard.publish().await;
+    if !reapply {
+        return;
+    }
+
     // Clone the relevant state *before* dropping the lock
     let current_network_state = state_guard.network_state.clone();
     let current_container_ips = state_guard.container_ips.clone();
     drop(state_guard); // Drop the lock before await
 
+    if reload {
+        if let Err(e) = nft_manager.load_rules().await {
+            error!("Failed to reload nftables rules for the matched interfaces: {}", e);
+        }
+    }
+
     // Apply nftables rules based on the updated state
     tracing::debug!("Applying NFT rules for state: {:?}", current_network_state);
     if let Err(e) = nft_manager.apply_rules(&current_network_state, &current_container_ips).await {
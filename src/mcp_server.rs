@@ -110,6 +110,15 @@ fn tools_list() -> Value {
                     "required": []
                 }
             },
+            {
+                "name": "get_routes",
+                "description": "Show the main routing table and the default gateway of each address family.",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {},
+                    "required": []
+                }
+            },
             {
                 "name": "reload_config",
                 "description": "Tell the running daemon to reload its YAML configuration file and re-apply all nftables rules.",
@@ -156,6 +165,12 @@ fn resources_list() -> Value {
                 "name": "Docker Containers",
                 "description": "Tracked Docker container IP addresses",
                 "mimeType": "application/json"
+            },
+            {
+                "uri": "network://routes",
+                "name": "Routes",
+                "description": "Main routing table and default gateways",
+                "mimeType": "application/json"
             }
         ]
     })
@@ -205,6 +220,10 @@ fn call_tool(name: &str, api_url: &str) -> Value {
             Ok(v) => json!({ "content": [{ "type": "text", "text": v.to_string() }] }),
             Err(e) => json!({ "content": [{ "type": "text", "text": format!("Error: {}", e) }], "isError": true }),
         },
+        "get_routes" => match http_get(api_url, "/routes") {
+            Ok(v) => json!({ "content": [{ "type": "text", "text": v.to_string() }] }),
+            Err(e) => json!({ "content": [{ "type": "text", "text": format!("Error: {}", e) }], "isError": true }),
+        },
         "reload_config" => match http_post(api_url, "/reload") {
             Ok(v) => json!({ "content": [{ "type": "text", "text": format!("Reload triggered: {}", v) }] }),
             Err(e) => json!({ "content": [{ "type": "text", "text": format!("Error: {}", e) }], "isError": true }),
@@ -229,6 +248,7 @@ fn read_resource(uri: &str, api_url: &str) -> Value {
         "network://status" => "/status",
         "network://interfaces" => "/interfaces",
         "network://containers" => "/containers",
+        "network://routes" => "/routes",
         _ => {
             return json!({ "error": format!("Unknown resource URI: {}", uri) });
         }
@@ -1,114 +1,213 @@
-use crate::types::{AppError, NetworkEvent, Result, EventSender, SystemEvent};
+use crate::drift::next_tick;
+use crate::types::{
+    AddressFlag, AddressScope, AppError, EventSender, InterfaceAddress, LinkDetails, MacAddr, NetworkEvent, Peer, PeerState,
+    Result, Route, SystemEvent,
+};
+use futures::channel::mpsc::UnboundedReceiver;
 use futures::stream::{StreamExt, TryStreamExt};
 // Import the netlink_packet_core crate directly for the message types
 use netlink_packet_core::{
-    NetlinkMessage, NetlinkPayload,
+    NetlinkMessage, NetlinkPayload, NLM_F_REPLACE,
 };
 // Import the netlink_packet_route crate directly for the route-specific types
 use netlink_packet_route::{
-    address::AddressMessage,
-    link::{LinkMessage, LinkFlags},
-    RouteNetlinkMessage,
+    address::{AddressAttribute, AddressFlags, AddressMessage, AddressScope as NetlinkScope},
+    link::{LinkAttribute, LinkFlags, LinkInfo, LinkMessage, Prop},
+    neighbour::{NeighbourAddress, NeighbourAttribute, NeighbourMessage, NeighbourState},
+    route::{RouteAddress, RouteAttribute, RouteHeader, RouteMessage, RouteType},
+    AddressFamily, RouteNetlinkMessage,
+};
+use ipnet::IpNet;
+use netlink_sys::{AsyncSocket, SocketAddr};
+use rtnetlink::constants::{
+    RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_IFADDR, RTMGRP_IPV6_ROUTE, RTMGRP_LINK, RTMGRP_NEIGH,
 };
-use std::collections::HashMap;
-use std::net::IpAddr;
+use rtnetlink::{Handle, RouteMessageBuilder};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{interval_at, Instant};
 use log::{info, debug, warn, error}; // Import log macros
 
+/// Unsolicited (multicast) messages of an rtnetlink connection.
+type NetlinkMessages = UnboundedReceiver<(NetlinkMessage<RouteNetlinkMessage>, SocketAddr)>;
+
+/// Pause before reconnecting after the netlink message stream ended; doubled
+/// after every failed attempt, up to `MAX_RECONNECT_DELAY`.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
 /// Monitors network interface and address changes using rtnetlink.
+///
+/// The kernel state is dumped on start, after a socket overrun (events were
+/// lost), after reconnecting and every `resync_interval`; each dump is diffed
+/// against the cached state so that only real changes are reported.
 pub struct NetworkMonitor {
     event_sender: EventSender, // Use the SystemEvent sender
+    resync_interval: Option<Duration>,
     // Store interface index to name mapping for easier lookup
     if_index_to_name: HashMap<u32, String>,
-    // Store current IPs per interface index
-    current_ips: HashMap<u32, Vec<IpAddr>>,
+    // Interface indexes whose link is up
+    up_links: HashSet<u32>,
+    // MAC address, kind and altnames per interface index
+    link_details: HashMap<u32, LinkDetails>,
+    // Store current addresses (with prefix length, scope and flags) per interface index
+    current_ips: HashMap<u32, Vec<InterfaceAddress>>,
+    // Neighbor table entries per (interface index, address)
+    peers: HashMap<(u32, IpAddr), Peer>,
+    // Main-table routes
+    routes: BTreeSet<Route>,
+}
+
+/// Kernel state read by one full dump.
+#[derive(Debug, Default)]
+struct KernelSnapshot {
+    /// Interface index -> name, whether the link is up, and its details.
+    links: HashMap<u32, (String, bool, LinkDetails)>,
+    addresses: HashMap<u32, Vec<InterfaceAddress>>,
+    peers: HashMap<(u32, IpAddr), Peer>,
+    routes: BTreeSet<Route>,
 }
 
 impl NetworkMonitor {
-    pub fn new(event_sender: EventSender) -> Self {
+    /// `resync_interval_secs` of `0` disables the periodic resync.
+    pub fn new(event_sender: EventSender, resync_interval_secs: u64) -> Self {
         NetworkMonitor {
             event_sender,
+            resync_interval: (resync_interval_secs > 0).then(|| Duration::from_secs(resync_interval_secs)),
             if_index_to_name: HashMap::new(),
+            up_links: HashSet::new(),
+            link_details: HashMap::new(),
             current_ips: HashMap::new(),
+            peers: HashMap::new(),
+            routes: BTreeSet::new(),
         }
     }
 
     /// Starts the monitoring loop.
-    /// This function will run indefinitely until an error occurs or the stream ends.
+    /// This function runs until the event channel is closed; a lost netlink
+    /// connection is re-established, retrying with backoff. Only failing to
+    /// connect at startup is an error.
     pub async fn start(mut self) -> Result<()> { // Correct Result type
         info!("Starting NetworkMonitor task");
+        let mut interval = self.resync_interval.map(|period| interval_at(Instant::now() + period, period));
+        let mut connection = Some(connect()?);
 
-        let (connection, handle, mut messages) =
-            rtnetlink::new_connection().map_err(|e| {
-                AppError::Netlink(format!("Failed to create netlink connection: {}", e))
-            })?;
-        tokio::spawn(connection); // Spawn the connection task
-
-        debug!("Gathering initial network state...");
-
-        // 1. Get Interfaces to map index to name
-        let mut links = handle.link().get().execute();
-        let mut initial_if_index_to_name = HashMap::new();
-        while let Some(link) = links.try_next().await.map_err(AppError::RtNetlink)? {
-            if let Some(name) = link.attributes.iter().find_map(|nla| {
-                if let netlink_packet_route::link::LinkAttribute::IfName(name) = nla {
-                    Some(name.clone())
-                } else {
-                    None
-                }
-            }) {
-                debug!("Found interface: index={}, name={}", link.header.index, name);
-                initial_if_index_to_name.insert(link.header.index, name);
-            }
-        }
-        self.if_index_to_name = initial_if_index_to_name.clone(); // Store initial map
-        debug!("Interface map populated: {:?}", self.if_index_to_name);
-
-        // 2. Get Addresses for initial state
-        let mut initial_ips: HashMap<u32, Vec<IpAddr>> = HashMap::new();
-        let mut addresses = handle.address().get().execute();
-        while let Some(msg) = addresses.try_next().await.map_err(AppError::RtNetlink)? {
-            let if_index = msg.header.index;
-             if let Some(ip_addr) = msg.attributes.iter().find_map(|attr| {
-                 if let netlink_packet_route::address::AddressAttribute::Address(ip) = attr {
-                     Some(*ip)
-                 } else {
-                     None
-                 }
-             }) {
-                 if let Some(if_name) = self.if_index_to_name.get(&if_index) {
-                    info!("Initial state: Found IP {} for interface {} ({})", ip_addr, if_name, if_index);
-                    let ips = initial_ips.entry(if_index).or_default();
-                    if !ips.contains(&ip_addr) {
-                        ips.push(ip_addr);
+        loop {
+            let (handle, mut messages) = match connection.take() {
+                Some(connection) => connection,
+                None => reconnect().await,
+            };
+            debug!("Gathering network state...");
+            if let Err(e) = self.resync(&handle).await {
+                error!("Failed to read the network state: {}", e);
+            }
+            info!("Listening for netlink address, link, neighbor and route events...");
+
+            // --- Listen for Events ---
+            loop {
+                tokio::select! {
+                    message = messages.next() => match message {
+                        Some((message, _addr)) if matches!(message.payload, NetlinkPayload::Overrun(_)) => {
+                            warn!("Netlink socket overrun, events were lost; resynchronizing.");
+                            if let Err(e) = self.resync(&handle).await {
+                                error!("Netlink resync failed: {}", e);
+                            }
+                        }
+                        Some((message, _addr)) => {
+                            if let Err(e) = self.handle_netlink_message(message).await {
+                                if matches!(e, AppError::MpscSendError(_)) {
+                                    return Err(e);
+                                }
+                                error!("Error handling netlink message: {}", e);
+                            }
+                        }
+                        None => {
+                            warn!("Netlink message stream ended unexpectedly, reconnecting in {:?}.", RECONNECT_DELAY);
+                            break;
+                        }
+                    },
+                    _ = next_tick(&mut interval) => {
+                        debug!("Periodic netlink resync");
+                        if let Err(e) = self.resync(&handle).await {
+                            error!("Netlink resync failed: {}", e);
+                        }
                     }
-                 } else {
-                     warn!("Found IP for unknown interface index {} during init", if_index);
-                 }
-             }
+                }
+            }
         }
-        self.current_ips = initial_ips;
-        debug!("Initial IP state populated: {:?}", self.current_ips);
+    }
 
-        info!("Listening for netlink address and link events...");
+    /// Dumps links, addresses, the neighbor table and the routes, and reports
+    /// whatever differs from the cached state.
+    async fn resync(&mut self, handle: &Handle) -> Result<()> {
+        let snapshot = dump_kernel_state(handle).await?;
+        debug!(
+            "Network state: {} links, {} peers, {} routes",
+            snapshot.links.len(), snapshot.peers.len(), snapshot.routes.len()
+        );
+        self.apply_snapshot(snapshot).await
+    }
 
-        // --- Listen for Events ---
-        loop {
-             match messages.next().await {
-                 Some((message, _addr)) => {
-                     if let Err(e) = self.handle_netlink_message(message).await {
-                         error!("Error handling netlink message: {}", e);
-                     }
-                 }
-                 None => {
-                      warn!("Netlink message stream ended unexpectedly.");
-                      break;
-                 }
-             }
-         }
-         Ok(())
+    async fn apply_snapshot(&mut self, mut snapshot: KernelSnapshot) -> Result<()> {
+        // 1. Links: removed ones first, so a reused name is not dropped again
+        let removed: Vec<u32> = self.if_index_to_name.keys()
+            .filter(|index| !snapshot.links.contains_key(index))
+            .copied()
+            .collect();
+        for if_index in removed {
+            self.remove_link(if_index).await?;
+        }
+        for (if_index, (name, is_up, details)) in snapshot.links {
+            self.update_link(if_index, name, is_up, details).await?;
+        }
+
+        // 2. Addresses
+        let indexes: BTreeSet<u32> = self.current_ips.keys().chain(snapshot.addresses.keys()).copied().collect();
+        for if_index in indexes {
+            let mut ips = snapshot.addresses.remove(&if_index).unwrap_or_default();
+            let mut current = self.current_ips.get(&if_index).cloned().unwrap_or_default();
+            ips.sort_by_key(|address| address.address);
+            current.sort_by_key(|address| address.address);
+            if ips.len() == current.len() && ips.iter().zip(&current).all(|(new, old)| new.same_state(old) && new.same_lifetimes(old)) {
+                // Nothing changed, or the lifetimes only counted down
+                if !ips.is_empty() {
+                    self.current_ips.insert(if_index, ips);
+                }
+                continue;
+            }
+            let Some(if_name) = self.if_index_to_name.get(&if_index).cloned() else {
+                self.current_ips.remove(&if_index);
+                continue;
+            };
+            info!("Resync: addresses of {} are now {:?}", if_name, ips.iter().map(|address| address.address).collect::<Vec<_>>());
+            self.current_ips.insert(if_index, ips.clone());
+            self.send_event(ip_update(if_name, &ips)).await?;
+        }
+
+        // 3. Neighbor table
+        let gone: Vec<(u32, IpAddr)> = self.peers.keys().filter(|key| !snapshot.peers.contains_key(key)).copied().collect();
+        for key in gone {
+            self.update_peer(key, None).await?;
+        }
+        for (key, peer) in snapshot.peers {
+            self.update_peer(key, Some(peer)).await?;
+        }
+
+        // 4. Routes
+        let gone: Vec<Route> = self.routes.difference(&snapshot.routes).cloned().collect();
+        for route in gone {
+            self.update_route(route, false).await?;
+        }
+        for route in snapshot.routes {
+            self.update_route(route, true).await?;
+        }
+        Ok(())
     }
 
     async fn handle_netlink_message(&mut self, message: NetlinkMessage<RouteNetlinkMessage>) -> Result<()> {
+         // `ip route replace` announces only the new route
+         let replace = message.header.flags & NLM_F_REPLACE != 0;
          match message.payload {
             NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewAddress(msg)) => {
                 self.handle_address_change(msg, true).await?;
@@ -117,10 +216,33 @@ impl NetworkMonitor {
                 self.handle_address_change(msg, false).await?;
             }
              NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewLink(msg)) => {
-                self.handle_link_change(msg, true).await?;
+                if let Some((name, is_up, details)) = link_entry(&msg) {
+                    self.update_link(msg.header.index, name, is_up, details).await?;
+                }
             }
             NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelLink(msg)) => {
-                self.handle_link_change(msg, false).await?;
+                self.remove_link(msg.header.index).await?;
+            }
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewNeighbour(msg)) => {
+                self.handle_neighbour_change(msg, true).await?;
+            }
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelNeighbour(msg)) => {
+                self.handle_neighbour_change(msg, false).await?;
+            }
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewRoute(msg)) => {
+                let routes = route_entries(&msg, &self.if_index_to_name);
+                if replace {
+                    self.replace_routes(routes).await?;
+                } else {
+                    for route in routes {
+                        self.update_route(route, true).await?;
+                    }
+                }
+            }
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelRoute(msg)) => {
+                for route in route_entries(&msg, &self.if_index_to_name) {
+                    self.update_route(route, false).await?;
+                }
             }
             NetlinkPayload::Error(err) => {
                 error!("Received netlink error message: {:?}", err);
@@ -135,37 +257,39 @@ impl NetworkMonitor {
 
         if let Some(if_name) = self.if_index_to_name.get(&if_index).cloned() {
             let mut changed = false;
-            if let Some(ip_addr) = msg.attributes.iter().find_map(|attr| {
-                 if let netlink_packet_route::address::AddressAttribute::Address(ip) = attr {
-                     Some(*ip)
-                 } else {
-                     None
-                 }
-             }) {
-                if is_add {
-                    let ips = self.current_ips.entry(if_index).or_default();
-                    if !ips.contains(&ip_addr) {
-                         info!("Detected IP Added: {} on {}", ip_addr, if_name);
-                         ips.push(ip_addr);
-                         changed = true;
+            if let Some(address) = interface_address(&msg) {
+                let ips = self.current_ips.entry(if_index).or_default();
+                let existing = ips.iter().position(|x| x.address == address.address);
+                match (is_add, existing) {
+                    (true, None) => {
+                        info!("Detected IP Added: {}/{} on {}", address.address, address.prefix_len, if_name);
+                        ips.push(address);
+                        changed = true;
                     }
-                } else {
-                    if let Some(ips) = self.current_ips.get_mut(&if_index) {
-                         if let Some(pos) = ips.iter().position(|&x| x == ip_addr) {
-                            info!("Detected IP Removed: {} from {}", ip_addr, if_name);
-                            ips.remove(pos);
+                    (true, Some(pos)) => {
+                        // e.g. duplicate address detection finished and `tentative` was cleared
+                        if !ips[pos].same_state(&address) {
+                            info!("Detected IP Updated: {} on {} ({:?})", address.address, if_name, address.flags);
                             changed = true;
-                         }
+                        } else if !ips[pos].same_lifetimes(&address) {
+                            // Reported for `GET /addresses`; the sets stay as they are
+                            debug!("Lifetimes of {} on {} refreshed", address.address, if_name);
+                            changed = true;
+                        }
+                        ips[pos] = address;
+                    }
+                    (false, Some(pos)) => {
+                        info!("Detected IP Removed: {} from {}", address.address, if_name);
+                        ips.remove(pos);
+                        changed = true;
                     }
+                    (false, None) => {}
                 }
             }
 
             if changed {
                 let current_ips_for_if = self.current_ips.get(&if_index).cloned().unwrap_or_default();
-                self.send_event(NetworkEvent::IpUpdate {
-                    interface: if_name.clone(),
-                    ips: current_ips_for_if,
-                }).await?;
+                self.send_event(ip_update(if_name.clone(), &current_ips_for_if)).await?;
             }
         } else {
             warn!("Received address event for unknown interface index: {}", if_index);
@@ -173,40 +297,113 @@ impl NetworkMonitor {
         Ok(())
     }
 
-     async fn handle_link_change(&mut self, msg: LinkMessage, is_add: bool) -> Result<()> {
-        let if_index = msg.header.index;
-        if is_add {
-             if let Some(name) = msg.attributes.iter().find_map(|nla| {
-                 if let netlink_packet_route::link::LinkAttribute::IfName(name) = nla {
-                     Some(name.clone())
-                 } else {
-                     None
-                 }
-             }) {
-                 info!("Detected Interface Added/Updated: index={}, name={}", if_index, name);
-                 let old_name = self.if_index_to_name.insert(if_index, name.clone());
-                 if old_name.is_none() || old_name.as_ref() != Some(&name) {
-                    // Fix LinkFlags case
-                    let is_up = msg.header.flags.contains(LinkFlags::Up);
-                     self.send_event(NetworkEvent::LinkChanged { name, is_up }).await?;
-                 }
-             }
-        } else {
-            if let Some(removed_name) = self.if_index_to_name.remove(&if_index) {
-                 info!("Detected Interface Removed: index={}, name={}", if_index, removed_name);
-                 if self.current_ips.remove(&if_index).is_some() {
-                     self.send_event(NetworkEvent::IpUpdate{
-                         interface: removed_name.clone(), // Use correct field name
-                         ips: vec![],
-                     }).await?;
-                 }
-                 self.send_event(NetworkEvent::LinkChanged { name: removed_name, is_up: false }).await?;
-            } else {
-                debug!("Ignoring DelLink for unknown index: {}", if_index);
+    /// Records a link that appeared, was renamed, went up or down, or changed
+    /// its MAC address, kind or altnames.
+    async fn update_link(&mut self, if_index: u32, name: String, is_up: bool, details: LinkDetails) -> Result<()> {
+        let old_name = self.if_index_to_name.insert(if_index, name.clone());
+        let was_up = if is_up { !self.up_links.insert(if_index) } else { self.up_links.remove(&if_index) };
+        let old_details = self.link_details.insert(if_index, details.clone());
+        if old_name.as_ref() == Some(&name) && was_up == is_up && old_details.as_ref() == Some(&details) {
+            return Ok(());
+        }
+        info!("Detected Interface Added/Updated: index={}, name={}, up={}", if_index, name, is_up);
+        if !is_up {
+            // The kernel drops a down link's IPv4 routes without announcing it
+            self.routes.retain(|route| route.interface.as_ref() != Some(&name));
+        }
+        if let Some(old_name) = old_name.as_ref().filter(|old_name| **old_name != name) {
+            // A renamed link is gone under its old name, e.g. for `match:` blocks selecting it
+            self.send_event(NetworkEvent::LinkChanged { name: old_name.clone(), is_up: false, details: None }).await?;
+        }
+        self.send_event(NetworkEvent::LinkChanged { name: name.clone(), is_up, details: Some(details) }).await?;
+        // The daemon forgets the addresses of a link that goes down, so they are reported again
+        if is_up && (!was_up || old_name.as_ref() != Some(&name)) {
+            if let Some(ips) = self.current_ips.get(&if_index).filter(|ips| !ips.is_empty()) {
+                self.send_event(ip_update(name, ips)).await?;
             }
         }
-         Ok(())
-     }
+        Ok(())
+    }
+
+    async fn remove_link(&mut self, if_index: u32) -> Result<()> {
+        let Some(removed_name) = self.if_index_to_name.remove(&if_index) else {
+            debug!("Ignoring DelLink for unknown index: {}", if_index);
+            return Ok(());
+        };
+        info!("Detected Interface Removed: index={}, name={}", if_index, removed_name);
+        self.up_links.remove(&if_index);
+        self.link_details.remove(&if_index);
+        if self.current_ips.remove(&if_index).is_some() {
+            self.send_event(ip_update(removed_name.clone(), &[])).await?;
+        }
+        // The daemon forgets the peers and routes of a link that went away
+        self.peers.retain(|(index, _), _| *index != if_index);
+        self.routes.retain(|route| route.interface.as_ref() != Some(&removed_name));
+        self.send_event(NetworkEvent::LinkChanged { name: removed_name, is_up: false, details: None }).await
+    }
+
+    async fn handle_neighbour_change(&mut self, msg: NeighbourMessage, is_add: bool) -> Result<()> {
+        let if_index = msg.header.ifindex;
+        let Some(if_name) = self.if_index_to_name.get(&if_index).cloned() else {
+            debug!("Ignoring neighbor on unknown interface index {}", if_index);
+            return Ok(());
+        };
+        let Some(peer) = peer_entry(&msg, if_name) else {
+            return Ok(());
+        };
+        self.update_peer((if_index, peer.ip), is_add.then_some(peer)).await
+    }
+
+    /// Tracks a neighbor entry; a peer is reported when it appears, changes its
+    /// MAC address or reachability state, or is deleted.
+    async fn update_peer(&mut self, key: (u32, IpAddr), peer: Option<Peer>) -> Result<()> {
+        match peer {
+            Some(peer) => {
+                if self.peers.get(&key) == Some(&peer) {
+                    return Ok(());
+                }
+                debug!("Peer {} on {} is {:?} at {:?}", peer.ip, peer.interface, peer.state, peer.mac);
+                self.peers.insert(key, peer.clone());
+                self.send_event(NetworkEvent::PeerChanged { peer, present: true }).await
+            }
+            None => match self.peers.remove(&key) {
+                Some(peer) => {
+                    debug!("Peer {} on {} removed", peer.ip, peer.interface);
+                    self.send_event(NetworkEvent::PeerChanged { peer, present: false }).await
+                }
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Tracks a main-table route; only routes that were not known, or are
+    /// removed while known, are reported.
+    async fn update_route(&mut self, route: Route, present: bool) -> Result<()> {
+        let changed = if present { self.routes.insert(route.clone()) } else { self.routes.remove(&route) };
+        if changed {
+            debug!("Route {} {}", if present { "added:" } else { "removed:" }, route);
+            self.send_event(NetworkEvent::RouteChanged { route, present }).await?;
+        }
+        Ok(())
+    }
+
+    /// Tracks the routes of a replacing route message: known routes with the
+    /// same destination and metric (the kernel's key within the main table)
+    /// that the message does not list again are reported removed first.
+    async fn replace_routes(&mut self, routes: Vec<Route>) -> Result<()> {
+        let replaced: Vec<Route> = self.routes.iter()
+            .filter(|known| !routes.contains(known))
+            .filter(|known| routes.iter().any(|route| route.destination == known.destination && route.metric == known.metric))
+            .cloned()
+            .collect();
+        for route in replaced {
+            self.update_route(route, false).await?;
+        }
+        for route in routes {
+            self.update_route(route, true).await?;
+        }
+        Ok(())
+    }
 
     async fn send_event(&self, event: NetworkEvent) -> Result<()> {
         // Send the specific NetworkEvent wrapped in SystemEvent::Network
@@ -216,4 +413,404 @@ impl NetworkMonitor {
     }
 }
 
-// Testing rtnetlink still requires specific setup (like network namespaces) or root privileges.
+/// Opens an rtnetlink connection subscribed to link, address, neighbor and route changes.
+fn connect() -> Result<(Handle, NetlinkMessages)> {
+    let (mut connection, handle, messages) =
+        rtnetlink::new_connection().map_err(|e| {
+            AppError::Netlink(format!("Failed to create netlink connection: {}", e))
+        })?;
+    // Join the multicast groups, otherwise the kernel reports no changes
+    let groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR | RTMGRP_NEIGH
+        | RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_ROUTE;
+    connection.socket_mut().socket_mut().bind(&SocketAddr::new(0, groups)).map_err(|e| {
+        AppError::Netlink(format!("Failed to subscribe to netlink groups: {}", e))
+    })?;
+    tokio::spawn(connection); // Spawn the connection task
+    Ok((handle, messages))
+}
+
+/// Re-opens the netlink connection after `RECONNECT_DELAY`, retrying with a
+/// doubling delay until it succeeds.
+async fn reconnect() -> (Handle, NetlinkMessages) {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        tokio::time::sleep(delay).await;
+        match connect() {
+            Ok(connection) => return connection,
+            Err(e) => {
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                error!("Netlink reconnect failed, retrying in {:?}: {}", delay, e);
+            }
+        }
+    }
+}
+
+/// Reads links, addresses, the neighbor table and the main routing table of both families.
+async fn dump_kernel_state(handle: &Handle) -> Result<KernelSnapshot> {
+    let mut snapshot = KernelSnapshot::default();
+
+    // 1. Get Interfaces to map index to name
+    let mut links = handle.link().get().execute();
+    while let Some(link) = links.try_next().await.map_err(AppError::RtNetlink)? {
+        if let Some(entry) = link_entry(&link) {
+            snapshot.links.insert(link.header.index, entry);
+        }
+    }
+    let if_index_to_name: HashMap<u32, String> = snapshot.links.iter()
+        .map(|(index, (name, _, _))| (*index, name.clone()))
+        .collect();
+
+    // 2. Get Addresses
+    let mut addresses = handle.address().get().execute();
+    while let Some(msg) = addresses.try_next().await.map_err(AppError::RtNetlink)? {
+        let if_index = msg.header.index;
+        if let Some(address) = interface_address(&msg) {
+            if !if_index_to_name.contains_key(&if_index) {
+                warn!("Found IP for unknown interface index {} during resync", if_index);
+                continue;
+            }
+            let ips = snapshot.addresses.entry(if_index).or_default();
+            if !ips.iter().any(|x| x.address == address.address) {
+                ips.push(address);
+            }
+        }
+    }
+
+    // 3. Get the neighbor table (LAN peers)
+    let mut neighbours = handle.neighbours().get().execute();
+    while let Some(msg) = neighbours.try_next().await.map_err(AppError::RtNetlink)? {
+        let Some(if_name) = if_index_to_name.get(&msg.header.ifindex) else { continue };
+        if let Some(peer) = peer_entry(&msg, if_name.clone()) {
+            snapshot.peers.insert((msg.header.ifindex, peer.ip), peer);
+        }
+    }
+
+    // 4. Get the main routing table of both families
+    let dumps = [
+        RouteMessageBuilder::<Ipv4Addr>::new().build(),
+        RouteMessageBuilder::<Ipv6Addr>::new().build(),
+    ];
+    for request in dumps {
+        let mut routes = handle.route().get(request).execute();
+        while let Some(msg) = routes.try_next().await.map_err(AppError::RtNetlink)? {
+            snapshot.routes.extend(route_entries(&msg, &if_index_to_name));
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Name, up state and details of a link message; links without a name are skipped.
+fn link_entry(msg: &LinkMessage) -> Option<(String, bool, LinkDetails)> {
+    let mut name = None;
+    let mut details = LinkDetails::default();
+    for attr in &msg.attributes {
+        match attr {
+            LinkAttribute::IfName(if_name) => name = Some(if_name.clone()),
+            LinkAttribute::Address(bytes) => details.mac = <[u8; 6]>::try_from(bytes.as_slice()).ok().map(MacAddr),
+            LinkAttribute::LinkInfo(infos) => {
+                details.kind = infos.iter().find_map(|info| match info {
+                    LinkInfo::Kind(kind) => Some(kind.to_string()),
+                    _ => None,
+                });
+            }
+            LinkAttribute::PropList(props) => {
+                details.altnames = props.iter()
+                    .filter_map(|prop| if let Prop::AltIfName(altname) = prop { Some(altname.clone()) } else { None })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    Some((name?, msg.header.flags.contains(LinkFlags::Up), details))
+}
+
+/// Address of an address message, with its prefix length, scope, flags and expiry times.
+fn interface_address(msg: &AddressMessage) -> Option<InterfaceAddress> {
+    let mut address = None;
+    // The header carries only the lower 8 flag bits; IFA_FLAGS has all of them
+    let mut flags = AddressFlags::from_bits_retain(u32::from(msg.header.flags.bits()));
+    let mut lifetimes = (None, None);
+    for attr in &msg.attributes {
+        match attr {
+            AddressAttribute::Address(ip) => address = Some(*ip),
+            AddressAttribute::Flags(all_flags) => flags = *all_flags,
+            AddressAttribute::CacheInfo(info) => {
+                // Kept as expiry times, which stay right as the lifetimes count down
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                let finite = |seconds: u32| (seconds != u32::MAX).then(|| now + u64::from(seconds));
+                lifetimes = (finite(info.ifa_preferred), finite(info.ifa_valid));
+            }
+            _ => {}
+        }
+    }
+    let address = address?;
+    let scope = match msg.header.scope {
+        NetlinkScope::Site => AddressScope::Site,
+        NetlinkScope::Link => AddressScope::Link,
+        NetlinkScope::Host => AddressScope::Host,
+        NetlinkScope::Nowhere => AddressScope::Nowhere,
+        // Universe, and scopes `ip` has no name for
+        _ => AddressScope::Global,
+    };
+    let named_flags = [
+        (AddressFlags::Secondary, if address.is_ipv6() { AddressFlag::Temporary } else { AddressFlag::Secondary }),
+        (AddressFlags::Nodad, AddressFlag::Nodad),
+        (AddressFlags::Optimistic, AddressFlag::Optimistic),
+        (AddressFlags::Dadfailed, AddressFlag::Dadfailed),
+        (AddressFlags::Homeaddress, AddressFlag::Home),
+        (AddressFlags::Deprecated, AddressFlag::Deprecated),
+        (AddressFlags::Tentative, AddressFlag::Tentative),
+        (AddressFlags::Permanent, AddressFlag::Permanent),
+        (AddressFlags::Managetempaddr, AddressFlag::Mngtmpaddr),
+        (AddressFlags::Noprefixroute, AddressFlag::Noprefixroute),
+        (AddressFlags::Mcautojoin, AddressFlag::Autojoin),
+        (AddressFlags::StablePrivacy, AddressFlag::StablePrivacy),
+    ];
+    Some(InterfaceAddress {
+        address,
+        prefix_len: msg.header.prefix_len,
+        scope,
+        flags: named_flags.into_iter().filter(|(bit, _)| flags.contains(*bit)).map(|(_, flag)| flag).collect(),
+        preferred_until: lifetimes.0,
+        valid_until: lifetimes.1,
+    })
+}
+
+/// The peer a neighbor message describes; entries without an address or
+/// without address resolution (`NOARP`, e.g. multicast addresses) are skipped.
+fn peer_entry(msg: &NeighbourMessage, interface: String) -> Option<Peer> {
+    let state = match msg.header.state {
+        NeighbourState::Incomplete => PeerState::Incomplete,
+        NeighbourState::Reachable => PeerState::Reachable,
+        NeighbourState::Stale => PeerState::Stale,
+        NeighbourState::Delay => PeerState::Delay,
+        NeighbourState::Probe => PeerState::Probe,
+        NeighbourState::Failed => PeerState::Failed,
+        NeighbourState::Permanent => PeerState::Permanent,
+        _ => return None,
+    };
+    let mut ip = None;
+    let mut mac = None;
+    for attr in &msg.attributes {
+        match attr {
+            NeighbourAttribute::Destination(NeighbourAddress::Inet(addr)) => ip = Some(IpAddr::V4(*addr)),
+            NeighbourAttribute::Destination(NeighbourAddress::Inet6(addr)) => ip = Some(IpAddr::V6(*addr)),
+            NeighbourAttribute::LinkLocalAddress(bytes) => mac = <[u8; 6]>::try_from(bytes.as_slice()).ok().map(MacAddr),
+            _ => {}
+        }
+    }
+    Some(Peer { ip: ip?, mac, interface, state })
+}
+
+/// Main-table unicast routes described by a route message, with their
+/// outgoing interfaces resolved to names.
+fn route_entries(msg: &RouteMessage, if_index_to_name: &HashMap<u32, String>) -> Vec<Route> {
+    let table = msg.attributes.iter()
+        .find_map(|attr| if let RouteAttribute::Table(table) = attr { Some(*table) } else { None })
+        .unwrap_or(u32::from(msg.header.table));
+    if table != u32::from(RouteHeader::RT_TABLE_MAIN) || msg.header.kind != RouteType::Unicast {
+        return Vec::new();
+    }
+    let unspecified = match msg.header.address_family {
+        AddressFamily::Inet => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        AddressFamily::Inet6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => return Vec::new(),
+    };
+    let mut destination = unspecified;
+    let mut metric = None;
+    let mut hops = Vec::new();
+    let mut gateway = None;
+    let mut oif = None;
+    for attr in &msg.attributes {
+        match attr {
+            RouteAttribute::Destination(addr) => destination = route_address(addr).unwrap_or(unspecified),
+            RouteAttribute::Priority(priority) => metric = Some(*priority),
+            RouteAttribute::Gateway(addr) => gateway = route_address(addr),
+            RouteAttribute::Oif(index) => oif = Some(*index),
+            RouteAttribute::MultiPath(next_hops) => {
+                for hop in next_hops {
+                    let hop_gateway = hop.attributes.iter().find_map(|attr| match attr {
+                        RouteAttribute::Gateway(addr) => route_address(addr),
+                        _ => None,
+                    });
+                    hops.push((hop_gateway, Some(hop.interface_index)));
+                }
+            }
+            _ => {}
+        }
+    }
+    if hops.is_empty() {
+        hops.push((gateway, oif));
+    }
+    let Ok(destination) = IpNet::new(destination, msg.header.destination_prefix_length) else {
+        return Vec::new();
+    };
+    hops.into_iter()
+        .map(|(gateway, oif)| Route {
+            destination,
+            gateway,
+            interface: oif.and_then(|index| if_index_to_name.get(&index).cloned()),
+            metric,
+        })
+        .collect()
+}
+
+fn route_address(addr: &RouteAddress) -> Option<IpAddr> {
+    match addr {
+        RouteAddress::Inet(addr) => Some(IpAddr::V4(*addr)),
+        RouteAddress::Inet6(addr) => Some(IpAddr::V6(*addr)),
+        _ => None,
+    }
+}
+
+/// Builds the `IpUpdate` for an interface.
+fn ip_update(interface: String, addresses: &[InterfaceAddress]) -> NetworkEvent {
+    NetworkEvent::IpUpdate { interface, addresses: addresses.to_vec() }
+}
+
+// Testing rtnetlink still requires specific setup (like network namespaces) or root privileges;
+// the resync diff works on snapshots and is tested without a socket.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn drain(rx: &mut mpsc::Receiver<SystemEvent>) -> Vec<String> {
+        let mut events = Vec::new();
+        while let Ok(SystemEvent::Network(event)) = rx.try_recv() {
+            events.push(match event {
+                NetworkEvent::IpUpdate { interface, addresses } => {
+                    let ips: Vec<String> = addresses.iter().map(|address| format!("{}/{}", address.address, address.prefix_len)).collect();
+                    format!("ips {} [{}]", interface, ips.join(", "))
+                }
+                NetworkEvent::LinkChanged { name, is_up, details } => match details.and_then(|details| details.kind) {
+                    Some(kind) => format!("link {} up={} kind={}", name, is_up, kind),
+                    None => format!("link {} up={}", name, is_up),
+                },
+                NetworkEvent::PeerChanged { peer, present } => format!("peer {} {:?} present={}", peer.ip, peer.state, present),
+                NetworkEvent::RouteChanged { route, present } => format!("route {} present={}", route, present),
+            });
+        }
+        events
+    }
+
+    fn snapshot(addresses: &[&str], peer_state: PeerState, routes: &[&str]) -> KernelSnapshot {
+        let peer_ip: IpAddr = "192.168.1.20".parse().unwrap();
+        let peer = Peer { ip: peer_ip, mac: Some(MacAddr([2, 0, 0, 0, 0, 20])), interface: "eth1".to_string(), state: peer_state };
+        KernelSnapshot {
+            links: HashMap::from([
+                (1, ("lo".to_string(), true, LinkDetails::default())),
+                (2, ("eth1".to_string(), true, LinkDetails { mac: Some(MacAddr([2, 0, 0, 0, 0, 1])), ..Default::default() })),
+            ]),
+            addresses: HashMap::from([(2, addresses.iter().map(|net| {
+                let net: IpNet = net.parse().unwrap();
+                InterfaceAddress::new(net.addr(), net.prefix_len())
+            }).collect())]),
+            peers: HashMap::from([((2, peer_ip), peer)]),
+            routes: routes.iter().map(|destination| Route {
+                destination: destination.parse().unwrap(),
+                gateway: None,
+                interface: Some("eth1".to_string()),
+                metric: None,
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_resync_reports_only_changes() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = mpsc::channel(64);
+            let mut monitor = NetworkMonitor::new(tx, 0);
+
+            // The first resync reports everything
+            monitor.apply_snapshot(snapshot(&["192.168.1.1/24"], PeerState::Reachable, &["192.168.1.0/24"])).await.unwrap();
+            let mut events = drain(&mut rx);
+            events.sort();
+            assert_eq!(events, vec![
+                "ips eth1 [192.168.1.1/24]",
+                "link eth1 up=true",
+                "link lo up=true",
+                "peer 192.168.1.20 Reachable present=true",
+                "route 192.168.1.0/24 dev eth1 present=true",
+            ]);
+
+            // An unchanged kernel state reports nothing
+            monitor.apply_snapshot(snapshot(&["192.168.1.1/24"], PeerState::Reachable, &["192.168.1.0/24"])).await.unwrap();
+            assert!(drain(&mut rx).is_empty());
+
+            // Missed events: a new address, a stale peer, a replaced route
+            monitor.apply_snapshot(snapshot(&["192.168.1.1/24", "fd00::1/64"], PeerState::Stale, &["10.0.0.0/8"])).await.unwrap();
+            assert_eq!(drain(&mut rx), vec![
+                "ips eth1 [192.168.1.1/24, fd00::1/64]",
+                "peer 192.168.1.20 Stale present=true",
+                "route 192.168.1.0/24 dev eth1 present=false",
+                "route 10.0.0.0/8 dev eth1 present=true",
+            ]);
+
+            // Lifetimes counting down are no change; refreshed lifetimes and a cleared `tentative` flag are
+            let expiring = |valid_until: u64| {
+                let mut aged = snapshot(&["192.168.1.1/24", "fd00::1/64"], PeerState::Stale, &["10.0.0.0/8"]);
+                aged.addresses.get_mut(&2).unwrap()[1].valid_until = Some(valid_until);
+                aged
+            };
+            monitor.apply_snapshot(expiring(10_000)).await.unwrap();
+            assert_eq!(drain(&mut rx), vec!["ips eth1 [192.168.1.1/24, fd00::1/64]"]);
+            monitor.apply_snapshot(expiring(10_001)).await.unwrap();
+            assert!(drain(&mut rx).is_empty());
+            monitor.apply_snapshot(expiring(13_600)).await.unwrap();
+            assert_eq!(drain(&mut rx), vec!["ips eth1 [192.168.1.1/24, fd00::1/64]"]);
+            assert_eq!(monitor.current_ips[&2][1].valid_until, Some(13_600));
+            monitor.apply_snapshot(snapshot(&["192.168.1.1/24", "fd00::1/64"], PeerState::Stale, &["10.0.0.0/8"])).await.unwrap();
+            drain(&mut rx);
+            monitor.current_ips.get_mut(&2).unwrap()[1].flags.insert(AddressFlag::Tentative);
+            monitor.apply_snapshot(snapshot(&["192.168.1.1/24", "fd00::1/64"], PeerState::Stale, &["10.0.0.0/8"])).await.unwrap();
+            assert_eq!(drain(&mut rx), vec!["ips eth1 [192.168.1.1/24, fd00::1/64]"]);
+
+            // An altname added with `ip link property add` changes the link, not its addresses
+            let mut altname = snapshot(&["192.168.1.1/24", "fd00::1/64"], PeerState::Stale, &["10.0.0.0/8"]);
+            altname.links.get_mut(&2).unwrap().2.altnames.push("lan0".to_string());
+            monitor.apply_snapshot(altname).await.unwrap();
+            assert_eq!(drain(&mut rx), vec!["link eth1 up=true"]);
+            assert_eq!(monitor.link_details[&2].altnames, vec!["lan0"]);
+
+            // A link that vanished is removed with its addresses
+            let mut gone = snapshot(&[], PeerState::Stale, &[]);
+            gone.links.remove(&2);
+            gone.peers.clear();
+            monitor.apply_snapshot(gone).await.unwrap();
+            assert_eq!(drain(&mut rx), vec!["ips eth1 []", "link eth1 up=false"]);
+            assert!(monitor.peers.is_empty() && monitor.routes.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_replaced_route_removes_the_old_one() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (tx, mut rx) = mpsc::channel(64);
+            let mut monitor = NetworkMonitor::new(tx, 0);
+            let route = |gateway: &str, metric: Option<u32>| Route {
+                destination: "10.8.0.0/24".parse().unwrap(),
+                gateway: Some(gateway.parse().unwrap()),
+                interface: Some("wg0".to_string()),
+                metric,
+            };
+            monitor.update_route(route("10.0.0.1", Some(50)), true).await.unwrap();
+            monitor.update_route(route("10.0.0.1", Some(100)), true).await.unwrap();
+            drain(&mut rx);
+
+            // `ip route replace 10.8.0.0/24 via 10.0.0.2 dev wg0 metric 50` keeps the metric 100 route
+            monitor.replace_routes(vec![route("10.0.0.2", Some(50))]).await.unwrap();
+            assert_eq!(drain(&mut rx), vec![
+                "route 10.8.0.0/24 via 10.0.0.1 dev wg0 metric 50 present=false",
+                "route 10.8.0.0/24 via 10.0.0.2 dev wg0 metric 50 present=true",
+            ]);
+            assert_eq!(monitor.routes, BTreeSet::from([route("10.0.0.1", Some(100)), route("10.0.0.2", Some(50))]));
+
+            // Replacing a route with itself reports nothing
+            monitor.replace_routes(vec![route("10.0.0.2", Some(50))]).await.unwrap();
+            assert!(drain(&mut rx).is_empty());
+        });
+    }
+}
@@ -1,294 +1,2789 @@
 //! NFTables management module using the nftables-rs crate (JSON API)
 
-use crate::types::{AppError, InterfaceConfig, NetworkState};
-use log::{debug, info};
-use std::collections::{HashMap, HashSet};
-use std::net::IpAddr;
+use crate::backend::{FirewallBackend, NftBackend};
+use crate::blocklist::{Blocklist, BlocklistEntry};
+use crate::fragments::{load_fragments, FragmentBody, RuleFragment};
+use crate::ruleset::{RulesetModel, RulesetPlan};
+use crate::types::{
+    AppConfig, AppError, ChainPolicy, ContainerDetails, ContainerForward, DockerSetsConfig, InterfaceConfig, LinkDetails, MacAddr, PortForward, NetworkState, NftablesConfig, NftablesFamily,
+    PolicyAction, PolicyConfig, PolicyProtocol, PolicyRule, ShutdownAction, ZoneConfig, ANY_ZONE, LOCAL_ZONE,
+};
+use ipnet::IpNet;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::Mutex as AsyncMutex;
 use std::borrow::Cow;
 
-// Corrected nftables-rs imports based on common structure and errors
-use nftables::{
-    batch::Batch,
-    helper, // NftablesError is now here
-    // Import base types from nftables crate directly
-    expr::Expression, // Need this for elements
-    schema::{NfCmd, NfListObject, NfObject, Table, Set, Element, FlushObject, Nftables},
-    types::NfFamily, // Keep NfFamily here
-};
+// Corrected nftables-rs imports based on common structure and errors
+use nftables::{
+    batch::Batch,
+    // Import base types from nftables crate directly
+    expr::{BinaryOperation, Elem, Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField, Prefix, SetItem, CT},
+    schema::{NfCmd, NfListObject, NfObject, Nftables, Table, Set, SetFlag, SetType, SetTypeValue, Element, FlushObject, Chain, Rule, Counter, FlowTable},
+    stmt::{
+        Accept, CTCount, Counter as CounterRef, Drop, Flow, Limit, Mangle, Match, Meter, NATFamily, Operator, Reject, Set as SetStatement,
+        SetOp, Statement, NAT,
+    },
+    types::{NfChainPolicy, NfChainType, NfFamily, NfHook}, // Keep NfFamily here
+};
+
+/// Comment marking the sets (and the first rule of the generated chains) owned by the daemon.
+pub const OWNER_COMMENT: &str = "managed by rust-network-mgr";
+
+/// Elements committed to the kernel by the last successful reconcile, keyed by set name.
+type AppliedSets = HashMap<String, HashSet<IpAddr>>;
+
+/// Contents of the `<zone>_nets` / `<zone>_nets6` interval sets, keyed by set name.
+type NetSets = BTreeMap<String, (SetType, BTreeSet<IpNet>)>;
+
+/// Contents of the `<zone>_macs` and IP-to-MAC pin sets, keyed by set name.
+type MacSets = BTreeMap<String, (SetTypeValue<'static>, BTreeSet<MacElement>)>;
+
+/// Element of a MAC set: an address, or an IP address pinned to one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MacElement {
+    Mac(MacAddr),
+    Pin(IpAddr, MacAddr),
+}
+
+/// Traffic totals of one zone and direction, read from its named counter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ZoneCounter {
+    pub zone: String,
+    /// `in`: traffic arriving from the zone, `out`: traffic sent to it.
+    pub direction: &'static str,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Outcome of the most recent `load_rules` or `apply_rules` transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ApplyResult {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub success: bool,
+    pub error: Option<String>,
+    /// Whether the touched sets, chains and flowtable were restored from the
+    /// pre-apply snapshot (taken only for backends without atomic transactions).
+    pub rolled_back: bool,
+    /// Failed applies in a row; a successful one resets it.
+    pub consecutive_failures: u32,
+}
+
+/// What the manager knows about the kernel sets between reconciles.
+#[derive(Default)]
+struct AppliedState {
+    sets: AppliedSets,
+    /// Networks committed to the interval sets.
+    nets: HashMap<String, BTreeSet<IpNet>>,
+    /// Elements committed to the MAC sets.
+    macs: HashMap<String, BTreeSet<MacElement>>,
+    /// The kernel contents are unknown (after `load_rules` or a failed apply);
+    /// the next reconcile flushes and refills every set.
+    stale: bool,
+    /// When each set with an element timeout was last flushed and refilled.
+    filled_at: HashMap<String, Instant>,
+    /// Addresses kept in a set during their zone's grace period, with the time they leave it.
+    lingering: HashMap<String, HashMap<IpAddr, Instant>>,
+    /// Container port forwards committed to the `prerouting` chain.
+    container_forwards: Vec<PortForward>,
+    /// Devices of the `offload` flowtable; empty when it does not exist.
+    flow_devices: Vec<String>,
+    /// Rule fragments applied by the last `load_rules`.
+    fragments: Vec<RuleFragment>,
+    /// Zones whose counters `load_rules` created, until `remove_orphans` sees
+    /// the zone gone.
+    counted_zones: BTreeSet<String>,
+    /// Managed chain names taken by chains without the owner comment, as found
+    /// by the last `load_rules`; they are neither created, flushed nor filled.
+    foreign_chains: BTreeSet<String>,
+}
+
+impl AppliedState {
+    /// Sets whose contents can be diffed against; the others get flushed and refilled.
+    ///
+    /// Sets with an element timeout are refilled every `timeout / 2` so that
+    /// maintained elements never expire.
+    fn tracked_sets(&self, zone_sets: &HashMap<String, ZoneConfig>, now: Instant) -> AppliedSets {
+        if self.stale {
+            return AppliedSets::new();
+        }
+        self.sets.iter()
+            .filter(|(name, _)| !self.refill_due(name, zone_sets, now))
+            .map(|(name, ips)| (name.clone(), ips.clone()))
+            .collect()
+    }
+
+    fn refill_due(&self, set_name: &str, zone_sets: &HashMap<String, ZoneConfig>, now: Instant) -> bool {
+        let Some(timeout) = zone_sets.get(set_name).and_then(|zone| zone.timeout) else {
+            return false;
+        };
+        match self.filled_at.get(set_name) {
+            Some(filled_at) => now >= *filled_at + Duration::from_secs(u64::from(timeout) / 2),
+            None => true,
+        }
+    }
+
+    /// Adds addresses that left a zone with a grace period back to its desired
+    /// sets until the period ends.
+    fn hold_grace_period(
+        &mut self,
+        desired: &mut BTreeMap<String, (SetType, HashSet<IpAddr>)>,
+        zone_sets: &HashMap<String, ZoneConfig>,
+        now: Instant,
+    ) {
+        for (set_name, (_, ips)) in desired.iter_mut() {
+            let Some(grace_period) = zone_sets.get(set_name).and_then(|zone| zone.grace_period) else {
+                self.lingering.remove(set_name);
+                continue;
+            };
+            let lingering = self.lingering.entry(set_name.clone()).or_default();
+            // Addresses that came back are regular members again
+            lingering.retain(|ip, _| !ips.contains(ip));
+            if let Some(previous) = self.sets.get(set_name) {
+                for ip in previous.difference(ips) {
+                    lingering.entry(*ip).or_insert(now + Duration::from_secs(grace_period));
+                }
+            }
+            lingering.retain(|_, until| *until > now);
+            ips.extend(lingering.keys().copied());
+        }
+    }
+}
+
+/// Manages nftables rules using the nftables-rs crate
+pub struct NftablesManager {
+    config: Arc<AsyncMutex<AppConfig>>,
+    /// Table, family and set naming, fixed for the lifetime of the manager.
+    settings: NftablesConfig,
+    family: NfFamily,
+    applied: AsyncMutex<AppliedState>,
+    /// Serializes drift checks so one change is never repaired twice.
+    repair_lock: AsyncMutex<()>,
+    repairs: AtomicU64,
+    /// When the daemon last sent a transaction, to tell its own changes from others'.
+    last_commit: std::sync::Mutex<Option<Instant>>,
+    backend: Arc<dyn FirewallBackend>,
+    /// Port forwards requested by container labels, keyed by container ID.
+    container_forwards: AsyncMutex<BTreeMap<String, Vec<ContainerForward>>>,
+    /// Networks and labels of the running containers, keyed by container ID.
+    container_details: AsyncMutex<BTreeMap<String, ContainerDetails>>,
+    /// Known links, keyed by name, that interface `match:` blocks are resolved against.
+    links: AsyncMutex<BTreeMap<String, LinkDetails>>,
+    /// Zone counters as of the last `refresh_counters`.
+    zone_counters: AsyncMutex<Vec<ZoneCounter>>,
+    last_apply: AsyncMutex<Option<ApplyResult>>,
+    /// Runtime blocklist, as saved at `blocklist_path`.
+    blocklist: AsyncMutex<Blocklist>,
+}
+
+impl NftablesManager {
+    /// Create a new NftablesManager instance applying rules through `nft`
+    pub async fn new(config: Arc<AsyncMutex<AppConfig>>) -> Result<Self, AppError> {
+        Self::with_backend(config, Arc::new(NftBackend::default())).await
+    }
+
+    /// Create a manager sending its transactions to `backend` instead of `nft`
+    pub async fn with_backend(
+        config: Arc<AsyncMutex<AppConfig>>,
+        backend: Arc<dyn FirewallBackend>,
+    ) -> Result<Self, AppError> {
+        let settings = config.lock().await.nftables.clone();
+        if !backend.supports_chains() {
+            let config_lock = config.lock().await;
+            if config_lock.policy.is_some()
+                || !config_lock.port_forwards.is_empty()
+                || config_lock.zones.values().any(|zone| zone.masquerade || zone.has_limits() || zone.has_macs())
+                || config_lock.flow_offload.is_some()
+            {
+                warn!("[NFTABLES-RS] The firewall backend only maintains address sets; policy, masquerade, port forwards, connection limits, MAC sets and flow offload are ignored.");
+            }
+        }
+        let family = match settings.family {
+            NftablesFamily::Inet => NfFamily::INet,
+            NftablesFamily::Ip => NfFamily::IP,
+            NftablesFamily::Ip6 => NfFamily::IP6,
+        };
+        let blocklist = load_blocklist(&config.lock().await.blocklist_path());
+        let manager = Self {
+            config,
+            settings,
+            family,
+            applied: AsyncMutex::new(AppliedState::default()),
+            repair_lock: AsyncMutex::new(()),
+            repairs: AtomicU64::new(0),
+            last_commit: std::sync::Mutex::new(None),
+            backend,
+            container_forwards: AsyncMutex::new(BTreeMap::new()),
+            container_details: AsyncMutex::new(BTreeMap::new()),
+            links: AsyncMutex::new(BTreeMap::new()),
+            zone_counters: AsyncMutex::new(Vec::new()),
+            last_apply: AsyncMutex::new(None),
+            blocklist: AsyncMutex::new(blocklist),
+        };
+        Ok(manager)
+    }
+
+    /// A manager for `config` as a reload would load it: the table settings,
+    /// backend, links, container forwards and details stay those of this manager.
+    /// Used to plan a reload without touching the running configuration.
+    pub async fn with_config(&self, config: AppConfig) -> NftablesManager {
+        let blocklist = if config.blocklist_path() == self.config.lock().await.blocklist_path() {
+            self.blocklist.lock().await.clone()
+        } else {
+            load_blocklist(&config.blocklist_path())
+        };
+        let (container_forwards, foreign_chains) = {
+            let applied = self.applied.lock().await;
+            (applied.container_forwards.clone(), applied.foreign_chains.clone())
+        };
+        Self {
+            config: Arc::new(AsyncMutex::new(config)),
+            settings: self.settings.clone(),
+            family: self.family,
+            applied: AsyncMutex::new(AppliedState { container_forwards, foreign_chains, ..Default::default() }),
+            repair_lock: AsyncMutex::new(()),
+            repairs: AtomicU64::new(0),
+            last_commit: std::sync::Mutex::new(None),
+            backend: self.backend.clone(),
+            container_forwards: AsyncMutex::new(self.container_forwards.lock().await.clone()),
+            container_details: AsyncMutex::new(self.container_details.lock().await.clone()),
+            links: AsyncMutex::new(self.links.lock().await.clone()),
+            zone_counters: AsyncMutex::new(Vec::new()),
+            last_apply: AsyncMutex::new(None),
+            blocklist: AsyncMutex::new(blocklist),
+        }
+    }
+
+    /// Ensures the base nftables structure exists (inet table, zone sets and,
+    /// if a `policy:` section is configured, the generated filter chains)
+    ///
+    /// Rule fragments from `nftables_rules_path` are re-read every time: JSON
+    /// fragments join this transaction, and nft syntax fragments follow it in a
+    /// second one, since `nft` cannot mix both in one input and cannot turn a
+    /// script into JSON without running it. Fragments failing `nft --check` are
+    /// skipped. The load is therefore not atomic with nft syntax fragments: if
+    /// the second transaction fails, the first stays applied without them, and
+    /// `load_rules` fails and records the error.
+    ///
+    /// The blocklist is re-read from `blocklist_path` and its sets refilled.
+    ///
+    /// A managed chain name already taken by a chain the daemon did not create
+    /// (one without a rule carrying `OWNER_COMMENT`) is left alone and logged.
+    /// Owned sets whose type, flags or timeouts changed are deleted and created
+    /// anew in the same transaction, after flushing the owned chains that may
+    /// refer to them.
+    pub async fn load_rules(&self) -> Result<(), AppError> {
+        info!("[NFTABLES-RS] Ensuring base nftables structure");
+        let blocklist_path = self.config.lock().await.blocklist_path();
+        *self.blocklist.lock().await = load_blocklist(&blocklist_path);
+        let live = match self.read_live_ruleset().await {
+            Ok(live) => Some(live),
+            Err(e) => {
+                warn!("[NFTABLES-RS] Could not read the managed table before loading rules: {}", e);
+                None
+            }
+        };
+        if let Some(live) = &live {
+            self.find_foreign_chains(live).await;
+        }
+        let fragments = self.rule_fragments().await;
+        let mut ruleset = self.plan_load_rules().await;
+        if let Some(live) = &live {
+            let recreate = self.recreate_changed_sets(&ruleset, live);
+            ruleset.objects.to_mut().splice(0..0, recreate.objects.iter().cloned());
+        }
+        let mut loaded = Vec::new();
+        let mut scripts = Vec::new();
+        for fragment in &fragments {
+            if let FragmentBody::Script(script) = &fragment.body {
+                scripts.push((fragment, script.as_str()));
+                continue;
+            }
+            let Some(json) = fragment.body.ruleset() else {
+                continue;
+            };
+            // Checked together with everything before it, as the fragment may
+            // refer to the managed sets or earlier fragments
+            let mut candidate = ruleset.clone();
+            candidate.objects.to_mut().extend(json.objects.iter().cloned());
+            match self.backend.check(&candidate) {
+                Ok(()) => {
+                    ruleset = candidate;
+                    loaded.push(fragment.clone());
+                }
+                Err(e) => error!("[NFTABLES-RS] Skipping rule fragment '{}': {}", fragment.path.display(), e),
+            }
+        }
+        debug!("[NFTABLES-RS] Load ruleset generated: {:?}", ruleset);
+
+        if let Err(e) = self.commit(&ruleset) {
+            self.record_apply(Some(&e), false).await;
+            return Err(e);
+        }
+        let result = self.apply_scripts(scripts).map(|scripts| loaded.extend(scripts));
+        self.record_apply(result.as_ref().err(), false).await;
+
+        // Existing sets keep whatever elements they had (e.g. from a previous run),
+        // so the next reconcile must flush and repopulate them.
+        let counted_zones = if self.settings.counter_interval > 0 { self.counter_zones().await } else { BTreeSet::new() };
+        let mut applied = self.applied.lock().await;
+        applied.stale = true;
+        applied.fragments = loaded;
+        applied.counted_zones.extend(counted_zones);
+        drop(applied);
+        result?;
+
+        info!("[NFTABLES-RS] Base table '{}' and required sets ensured.", self.settings.table);
+        if let Err(e) = self.remove_orphans().await {
+            warn!("[NFTABLES-RS] Could not check for orphaned sets and chains: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Records which managed chain names the `live` table already uses for
+    /// chains the daemon did not create.
+    async fn find_foreign_chains(&self, live: &Nftables<'_>) {
+        if !self.backend.supports_chains() {
+            return;
+        }
+        let live = RulesetModel::from_ruleset(live);
+        let foreign: BTreeSet<String> = live.foreign_chains(OWNER_COMMENT).into_iter()
+            .filter(|name| MANAGED_CHAINS.contains(&name.as_str()))
+            .collect();
+        for chain_name in &foreign {
+            error!(
+                "[NFTABLES-RS] Not touching chain '{}' in table '{}': it exists and was not created by rust-network-mgr",
+                chain_name, self.settings.table
+            );
+        }
+        self.applied.lock().await.foreign_chains = foreign;
+    }
+
+    /// Commands to run before `plan` so that the owned sets it adds with other
+    /// parameters than their `live` counterparts can be created: the owned
+    /// chains are flushed, as their rules may refer to the sets, and the sets
+    /// deleted. The chains are rebuilt and the sets refilled afterwards.
+    fn recreate_changed_sets<'a>(&'a self, plan: &Nftables, live: &Nftables<'static>) -> Nftables<'a> {
+        let live_sets: HashMap<&str, &Set> = live.objects.iter()
+            .filter_map(|object| match object {
+                NfObject::ListObject(NfListObject::Set(set)) if set.comment.as_deref() == Some(OWNER_COMMENT) => {
+                    Some((set.name.as_ref(), set.as_ref()))
+                }
+                _ => None,
+            })
+            .collect();
+        let mut changed = Vec::new();
+        for object in plan.objects.iter() {
+            let (NfObject::ListObject(NfListObject::Set(set)) | NfObject::CmdObject(NfCmd::Add(NfListObject::Set(set)))) = object else {
+                continue;
+            };
+            if let Some(live_set) = live_sets.get(set.name.as_ref()) {
+                if set_parameters_changed(set, live_set) {
+                    info!("[NFTABLES-RS] Recreating set '{}', as its type, flags or timeouts changed", set.name);
+                    changed.push(set.name.to_string());
+                }
+            }
+        }
+        let mut batch = Batch::new();
+        if changed.is_empty() {
+            return batch.to_nftables();
+        }
+        for chain_name in RulesetModel::from_ruleset(live).owned_chains(OWNER_COMMENT) {
+            batch.add_cmd(NfCmd::Flush(FlushObject::Chain(self.chain_ref(&chain_name))));
+        }
+        for set_name in changed {
+            let set_type = live_sets[set_name.as_str()].set_type.clone();
+            batch.delete(NfListObject::Set(Box::new(Set { set_type, ..self.set_ref(&set_name, SetType::Ipv4Addr) })));
+        }
+        batch.to_nftables()
+    }
+
+    /// Checks each nft syntax fragment against the ruleset just loaded and
+    /// applies the valid ones together.
+    ///
+    /// Returns the fragments applied, or the error of their transaction.
+    fn apply_scripts(&self, scripts: Vec<(&RuleFragment, &str)>) -> Result<Vec<RuleFragment>, AppError> {
+        let valid: Vec<(&RuleFragment, &str)> = scripts.into_iter()
+            .filter(|(fragment, script)| match self.backend.check_script(script) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("[NFTABLES-RS] Skipping rule fragment '{}': {}", fragment.path.display(), e);
+                    false
+                }
+            })
+            .collect();
+        if valid.is_empty() {
+            return Ok(Vec::new());
+        }
+        let script: Vec<&str> = valid.iter().map(|(_, script)| *script).collect();
+        if let Err(e) = self.commit_script(&script.join("\n")) {
+            error!("[NFTABLES-RS] Failed to apply the nft rule fragments: {}", e);
+            return Err(e);
+        }
+        info!("[NFTABLES-RS] Applied {} nft rule fragment(s)", valid.len());
+        Ok(valid.into_iter().map(|(fragment, _)| fragment.clone()).collect())
+    }
+
+    /// Rule fragments from `nftables_rules_path`, read from disk on every call.
+    async fn rule_fragments(&self) -> Vec<RuleFragment> {
+        let Some(dir) = self.config.lock().await.nftables_rules_path.clone() else {
+            return Vec::new();
+        };
+        if !self.backend.supports_chains() {
+            warn!("[NFTABLES-RS] The firewall backend only maintains sets; rule fragments in '{}' are ignored.", dir);
+            return Vec::new();
+        }
+        match load_fragments(Path::new(&dir), &self.fragment_placeholders().await) {
+            Ok(fragments) => fragments,
+            Err(e) => {
+                warn!("[NFTABLES-RS] {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Values of the `${...}` placeholders in rule fragments: `${table}`,
+    /// `${family}`, and for every zone `${<zone>}` (its address set, the IPv6
+    /// one on `ip6` tables), `${<zone>.ipv6}`, `${<zone>.nets}`, `${<zone>.nets6}`,
+    /// `${<zone>.peers}` and `${<zone>.peers6}`.
+    async fn fragment_placeholders(&self) -> BTreeMap<String, String> {
+        let (interface_zones, docker_zones): (BTreeSet<String>, Vec<String>) = {
+            let config_lock = self.config.lock().await;
+            let interface_zones = config_lock.interfaces.iter().filter_map(|iface| iface.nftables_zone.clone()).collect();
+            (interface_zones, config_lock.docker_sets.zones())
+        };
+        let family = match self.settings.family {
+            NftablesFamily::Inet => "inet",
+            NftablesFamily::Ip => "ip",
+            NftablesFamily::Ip6 => "ip6",
+        };
+        let mut placeholders = BTreeMap::from([
+            ("table".to_string(), self.settings.table.clone()),
+            ("family".to_string(), family.to_string()),
+        ]);
+        let zones = interface_zones.iter().map(String::as_str)
+            .chain(std::iter::once("docker"))
+            .chain(docker_zones.iter().map(String::as_str));
+        for zone in zones {
+            if let Some((set_name, _)) = zone_set_names(&self.settings, zone).into_iter().next() {
+                placeholders.insert(zone.to_string(), set_name);
+            }
+            if self.settings.family.has_ipv6() {
+                placeholders.insert(format!("{}.ipv6", zone), self.settings.ipv6_set_name(zone));
+            }
+            if interface_zones.contains(zone) {
+                if self.settings.family.has_ipv4() {
+                    placeholders.insert(format!("{}.nets", zone), self.settings.ipv4_net_set_name(zone));
+                }
+                if self.settings.family.has_ipv6() {
+                    placeholders.insert(format!("{}.nets6", zone), self.settings.ipv6_net_set_name(zone));
+                }
+                for (set_name, set_type) in zone_peer_set_names(&self.settings, zone) {
+                    let key = if set_type == SetType::Ipv4Addr { "peers" } else { "peers6" };
+                    placeholders.insert(format!("{}.{}", zone, key), set_name);
+                }
+            }
+        }
+        placeholders
+    }
+
+    /// Deletes owned chains and sets the current configuration no longer
+    /// generates, e.g. after a zone or the `policy:` section was removed.
+    ///
+    /// Each object is deleted in its own transaction, so one that is still
+    /// referenced by a hand-written rule does not block the others.
+    async fn remove_orphans(&self) -> Result<(), AppError> {
+        let expected = RulesetModel::from_ruleset(&self.plan_load_rules().await);
+        let live = RulesetModel::from_ruleset(&self.read_live_ruleset().await?);
+        let mut expected_chains: HashSet<String> = expected.owned_chains(OWNER_COMMENT).into_iter().collect();
+        // The offload chain exists while the flowtable has devices
+        if !self.applied.lock().await.flow_devices.is_empty() {
+            expected_chains.insert(OFFLOAD_CHAIN.to_string());
+        }
+        let expected_sets: HashSet<String> = expected.owned_sets(OWNER_COMMENT).into_iter().map(|(name, _)| name).collect();
+
+        let mut orphans = Vec::new();
+        for chain_name in live.owned_chains(OWNER_COMMENT) {
+            if !expected_chains.contains(&chain_name) {
+                let mut batch = Batch::new();
+                batch.delete(NfListObject::Chain(self.chain_ref(&chain_name)));
+                orphans.push((format!("chain {}", chain_name), batch));
+            }
+        }
+        for (set_name, set_type) in live.owned_sets(OWNER_COMMENT) {
+            if !expected_sets.contains(&set_name) {
+                let mut batch = Batch::new();
+                batch.delete(NfListObject::Set(Box::new(self.set_ref(&set_name, parse_set_type(&set_type)))));
+                orphans.push((format!("set {}", set_name), batch));
+            }
+        }
+        // Counters carry no comment; the zone counters are recognised by the names
+        // the current and previously counted zones give them, so those of a removed
+        // zone are found after its accounting rules are gone
+        let expected_counters: HashSet<String> = expected.counter_names().into_iter().collect();
+        let current_zones = self.counter_zones().await;
+        let possible_counters = self.possible_zone_counters().await;
+        for counter_name in live.counter_names().into_iter().filter(|name| possible_counters.contains(name)) {
+            if !expected_counters.contains(&counter_name) {
+                let mut batch = Batch::new();
+                batch.delete(NfListObject::Counter(self.counter_ref(&counter_name)));
+                orphans.push((format!("counter {}", counter_name), batch));
+            }
+        }
+        // The meter sets carry no comment either; those of a removed limit are
+        // recognised by the names the zones' meters would get
+        let expected_meters = self.limit_meters().await;
+        let possible_meters = self.possible_limit_meters().await;
+        for (set_name, set_type) in live.uncommented_sets().into_iter().filter(|(name, _)| possible_meters.contains(name)) {
+            if !expected_meters.contains(&set_name) {
+                let mut batch = Batch::new();
+                batch.delete(NfListObject::Set(Box::new(self.set_ref(&set_name, parse_set_type(&set_type)))));
+                orphans.push((format!("meter {}", set_name), batch));
+            }
+        }
+
+        let mut applied = self.applied.lock().await;
+        for (description, batch) in orphans {
+            match self.commit(&batch.to_nftables()) {
+                Ok(()) => info!("[NFTABLES-RS] Deleted orphaned {}", description),
+                Err(e) => warn!("[NFTABLES-RS] Could not delete orphaned {} (still referenced?): {}", description, e),
+            }
+        }
+        applied.sets.retain(|name, _| expected_sets.contains(name));
+        applied.nets.retain(|name, _| expected_sets.contains(name));
+        applied.macs.retain(|name, _| expected_sets.contains(name));
+        applied.lingering.retain(|name, _| expected_sets.contains(name));
+        applied.filled_at.retain(|name, _| expected_sets.contains(name));
+        applied.counted_zones.retain(|zone| current_zones.contains(zone));
+        Ok(())
+    }
+
+    /// Flushes or deletes the objects owned by the daemon, as found in the live table.
+    ///
+    /// `Delete` also removes the table when nothing else is left in it, so
+    /// hand-written objects sharing the table are never touched.
+    pub async fn cleanup(&self, action: ShutdownAction) -> Result<(), AppError> {
+        if action == ShutdownAction::Keep {
+            return Ok(());
+        }
+        let live = RulesetModel::from_ruleset(&self.read_live_ruleset().await?);
+        let mut batch = Batch::new();
+        if action == ShutdownAction::Delete {
+            for chain_name in live.owned_chains(OWNER_COMMENT) {
+                batch.delete(NfListObject::Chain(self.chain_ref(&chain_name)));
+            }
+            let possible_counters = self.possible_zone_counters().await;
+            for counter_name in live.counter_names().into_iter().filter(|name| possible_counters.contains(name)) {
+                batch.delete(NfListObject::Counter(self.counter_ref(&counter_name)));
+            }
+            // The sets created by the `limits` chain's meters carry no comment
+            for (set_name, set_type) in live.find_sets(&self.limit_meters().await) {
+                batch.delete(NfListObject::Set(Box::new(self.set_ref(&set_name, parse_set_type(&set_type)))));
+            }
+        }
+        for (set_name, set_type) in live.owned_sets(OWNER_COMMENT) {
+            let set = Box::new(self.set_ref(&set_name, parse_set_type(&set_type)));
+            match action {
+                ShutdownAction::Delete => batch.delete(NfListObject::Set(set)),
+                _ => batch.add_cmd(NfCmd::Flush(FlushObject::Set(set))),
+            }
+        }
+        let ruleset = batch.to_nftables();
+        if !ruleset.objects.is_empty() {
+            self.commit(&ruleset)?;
+        }
+        *self.applied.lock().await = AppliedState { stale: true, ..Default::default() };
+
+        if action == ShutdownAction::Delete {
+            let remaining = self.read_live_ruleset().await?;
+            let only_table = remaining.objects.iter().any(|o| matches!(o, NfObject::ListObject(NfListObject::Table(_))))
+                && remaining.objects.iter().all(|o| matches!(
+                    o,
+                    NfObject::ListObject(NfListObject::Table(_)) | NfObject::ListObject(NfListObject::MetainfoObject(_))
+                ));
+            if only_table {
+                let mut batch = Batch::new();
+                batch.delete(NfListObject::Table(Table {
+                    family: self.family,
+                    name: Cow::Borrowed(&self.settings.table),
+                    handle: None,
+                }));
+                self.commit(&batch.to_nftables())?;
+                info!("[NFTABLES-RS] Deleted empty table '{}'.", self.settings.table);
+            }
+        }
+        info!("[NFTABLES-RS] Owned nftables objects cleaned up ({:?}).", action);
+        Ok(())
+    }
+
+    /// Builds the transaction sent by `load_rules`, without applying it.
+    pub async fn plan_load_rules(&self) -> Nftables<'_> {
+        let mut batch = Batch::new();
+
+        // 1. Ensure Table Exists
+        batch.add(NfListObject::Table(Table {
+            family: self.family,
+            name: Cow::Borrowed(&self.settings.table),
+            handle: None, // Explicitly set handle if necessary, often optional
+        }));
+
+        // 2. Calculate unique zones from config
+        let config_lock = self.config.lock().await;
+        let unique_zones: HashSet<String> = config_lock.interfaces.iter()
+            .filter_map(|iface| iface.nftables_zone.clone())
+            .collect();
+        let interfaces = resolve_interfaces(&config_lock.interfaces, &*self.links.lock().await);
+        let policy = config_lock.policy.clone();
+        let zones = config_lock.zones.clone();
+        let port_forwards = config_lock.port_forwards.clone();
+        let docker_zones = config_lock.docker_sets.zones();
+        // Drop the lock explicitly after use
+        drop(config_lock);
+        // Rebuilding `prerouting` keeps the container port forwards in it
+        let (container_forwards, foreign_chains) = {
+            let applied = self.applied.lock().await;
+            (applied.container_forwards.clone(), applied.foreign_chains.clone())
+        };
+
+        // 3. Ensure Sets Exist for each unique zone, plus the built-in "docker" zone
+        // --- Subnet (interval) sets, only for zones bound to interfaces ---
+        for zone_name in &unique_zones {
+            for (set_name, set_type) in zone_net_set_names(&self.settings, zone_name) {
+                batch.add(NfListObject::Set(Box::new(Set {
+                    flags: Some(HashSet::from([SetFlag::Interval])),
+                    comment: Some(Cow::Borrowed(OWNER_COMMENT)),
+                    ..self.set_ref(&set_name, set_type)
+                })));
+            }
+            // --- Live LAN peers on the zone's interfaces ---
+            for (set_name, set_type) in zone_peer_set_names(&self.settings, zone_name) {
+                batch.add(NfListObject::Set(Box::new(Set {
+                    comment: Some(Cow::Borrowed(OWNER_COMMENT)),
+                    ..self.set_ref(&set_name, set_type)
+                })));
+            }
+        }
+        let mut all_zones = unique_zones;
+        all_zones.insert("docker".to_string());
+        let counted_zones: BTreeSet<String> = all_zones.iter().cloned().collect();
+        // Per-network and per-label container sets share the docker zone's settings
+        let docker_zone = zones.get("docker").cloned().unwrap_or_default();
+        let mut set_zones: Vec<(String, ZoneConfig)> = all_zones.into_iter()
+            .map(|zone_name| {
+                let zone = zones.get(&zone_name).cloned().unwrap_or_default();
+                (zone_name, zone)
+            })
+            .collect();
+        set_zones.extend(docker_zones.into_iter().map(|zone_name| (zone_name, docker_zone.clone())));
+        for (zone_name, zone) in set_zones {
+            let mut flags = HashSet::from([SetFlag::Dynamic]);
+            if zone.timeout.is_some() {
+                flags.insert(SetFlag::Timeout);
+            }
+            // --- IPv4 / IPv6 Set Definitions (only the families the table carries) ---
+            for (set_name, set_type) in zone_set_names(&self.settings, &zone_name) {
+                batch.add(NfListObject::Set(Box::new(Set {
+                    family: self.family,
+                    table: Cow::Borrowed(&self.settings.table),
+                    name: Cow::Owned(set_name),
+                    handle: None,
+                    set_type: SetTypeValue::Single(set_type),
+                    policy: None,
+                    flags: Some(flags.clone()),
+                    comment: Some(Cow::Borrowed(OWNER_COMMENT)),
+                    elem: None,
+                    gc_interval: zone.gc_interval,
+                    size: None,
+                    timeout: zone.timeout,
+                })));
+            }
+        }
+
+        // Backends without chains (ipset) only get the sets
+        if !self.backend.supports_chains() {
+            return batch.to_nftables();
+        }
+
+        // 4. Runtime blocklist
+        let blocked = self.blocklist.lock().await.active(unix_now());
+        self.add_blocklist(&mut batch, &blocked);
+
+        // 5. MAC sets of zones listing or learning MAC addresses
+        let mac_zones: BTreeMap<&String, &ZoneConfig> = zones.iter().filter(|(_, zone)| zone.has_macs()).collect();
+        for (zone_name, zone) in mac_zones {
+            for (set_name, set_type) in zone_mac_set_names(&self.settings, zone_name, zone) {
+                batch.add(NfListObject::Set(Box::new(Set {
+                    set_type,
+                    comment: Some(Cow::Borrowed(OWNER_COMMENT)),
+                    ..self.set_ref(&set_name, SetType::EtherAddr)
+                })));
+            }
+        }
+
+        // 6. Compile the zone policy into managed base chains
+        if let Some(policy) = &policy {
+            self.add_policy_chains(&mut batch, policy, &interfaces);
+        }
+
+        // 7. Masquerading and port forwards
+        let masquerade: BTreeSet<&String> = zones.iter()
+            .filter(|(_, zone)| zone.masquerade)
+            .map(|(name, _)| name)
+            .collect();
+        self.add_nat_chains(&mut batch, &masquerade, &port_forwards, &container_forwards, &interfaces);
+
+        // 8. Per-source connection limits and ban sets
+        let limited: BTreeMap<&String, &ZoneConfig> = zones.iter().filter(|(_, zone)| zone.has_limits()).collect();
+        if !limited.is_empty() {
+            self.add_limits_chain(&mut batch, &limited, &interfaces);
+        }
+
+        // 9. Per-zone traffic counters
+        if self.settings.counter_interval > 0 {
+            self.add_zone_counters(&mut batch, &counted_zones, &interfaces);
+        }
+
+        without_chains(batch.to_nftables(), &foreign_chains)
+    }
+
+    /// Apply rules based on the current network state and tracked container IPs.
+    ///
+    /// Container IPs are collected into a dedicated `docker_ips` / `docker_ipv6` set.
+    /// Passing an empty map disables docker set population without error.
+    ///
+    /// All changes are sent as a single nftables transaction. Only elements that
+    /// differ from the last successfully applied state are added or deleted; a set
+    /// that has not been applied yet (e.g. right after `load_rules`) is flushed and
+    /// repopulated within that same transaction, so no set is ever observed empty.
+    ///
+    /// With a backend that may carry out part of a failed transaction, the
+    /// managed table is read back before applying, and if the apply fails the
+    /// touched sets, chains and flowtable are restored from that snapshot. The
+    /// outcome is kept for `last_apply`.
+    ///
+    /// Zones with a `grace_period` keep removed addresses until the period ends,
+    /// and sets with an element `timeout` are periodically refilled; see
+    /// `maintenance_due` for when to call this without a state change.
+    pub async fn apply_rules(
+        &self,
+        network_state: &NetworkState,
+        container_ips: &HashMap<String, IpAddr>,
+    ) -> Result<(), AppError> {
+         info!("[NFTABLES-RS] Applying nftables rules (diff against last applied state)");
+
+         // Calculate the desired set contents based on current network state and config
+         let mut desired = self.desired_sets(network_state, container_ips).await;
+         let desired_nets = self.desired_nets(network_state).await;
+         let desired_macs = self.desired_macs(network_state).await;
+         let zone_sets = self.zone_set_settings().await;
+         let forwards = self.desired_container_forwards(container_ips).await;
+         let flow_devices = self.desired_flow_devices(network_state).await;
+         let (static_forwards, interfaces) = {
+             let config_lock = self.config.lock().await;
+             (config_lock.port_forwards.clone(), resolve_interfaces(&config_lock.interfaces, &*self.links.lock().await))
+         };
+
+         // Hold the applied-state lock for the whole reconcile so concurrent
+         // event handlers cannot interleave their diffs.
+         let mut applied = self.applied.lock().await;
+         let now = Instant::now();
+         applied.hold_grace_period(&mut desired, &zone_sets, now);
+         let tracked = applied.tracked_sets(&zone_sets, now);
+         let tracked_nets = if applied.stale { HashMap::new() } else { applied.nets.clone() };
+         let tracked_macs = if applied.stale { HashMap::new() } else { applied.macs.clone() };
+         let mut batch = self.build_set_diff(&desired, &tracked);
+         self.add_net_changes(&mut batch, &desired_nets, &tracked_nets);
+         self.add_mac_changes(&mut batch, &desired_macs, &tracked_macs);
+         // When stale, the kernel may still hold chains from before, so they
+         // are rebuilt even if nothing is wanted in them any more
+         let chains = self.backend.supports_chains();
+         let forwards_changed = chains && (applied.stale || forwards != applied.container_forwards);
+         if forwards_changed {
+             self.add_prerouting_rebuild(&mut batch, &static_forwards, &forwards, &interfaces);
+         }
+         let flow_devices_changed = chains && (applied.stale || flow_devices != applied.flow_devices);
+         let mut removed_flow_devices = Vec::new();
+         if flow_devices_changed && !applied.stale && !applied.flow_devices.is_empty() && !flow_devices.is_empty() {
+             // Updated in place, so the flows offloaded through the other devices survive
+             let added: Vec<String> = flow_devices.iter().filter(|device| !applied.flow_devices.contains(device)).cloned().collect();
+             if !added.is_empty() {
+                 batch.add(NfListObject::FlowTable(self.flowtable_ref(Some(&added))));
+             }
+             // The kernel drops the devices of deleted links by itself
+             removed_flow_devices = applied.flow_devices.iter()
+                 .filter(|device| !flow_devices.contains(device) && network_state.links.contains_key(*device))
+                 .cloned()
+                 .collect();
+         } else if flow_devices_changed {
+             self.add_offload_rebuild(&mut batch, &flow_devices);
+         }
+         let ruleset = without_chains(batch.to_nftables(), &applied.foreign_chains);
+         if ruleset.objects.is_empty() && removed_flow_devices.is_empty() {
+             if flow_devices_changed {
+                 applied.flow_devices = flow_devices;
+             }
+             info!("[NFTABLES-RS] Sets already up to date, nothing to apply.");
+             return Ok(());
+         }
+
+         if !ruleset.objects.is_empty() {
+             debug!("[NFTABLES-RS] Set diff ruleset generated: {:?}", ruleset);
+             // An atomic backend leaves nothing to roll back
+             let snapshot = if self.backend.is_atomic() {
+                 None
+             } else {
+                 match self.read_live_ruleset().await {
+                     Ok(snapshot) => Some(snapshot),
+                     Err(e) => {
+                         warn!("[NFTABLES-RS] Could not snapshot the managed table, a failed apply cannot be rolled back: {}", e);
+                         None
+                     }
+                 }
+             };
+             if let Err(e) = self.commit(&ruleset) {
+                 // The kernel state is unknown now, so the next reconcile
+                 // flushes and repopulates every set.
+                 applied.stale = true;
+                 let rolled_back = snapshot.is_some_and(|snapshot| self.roll_back(&ruleset, &snapshot));
+                 self.record_apply(Some(&e), rolled_back).await;
+                 return Err(e);
+             }
+             self.record_apply(None, false).await;
+
+             applied.stale = false;
+             for (set_name, (_, ips)) in desired {
+                 if !tracked.contains_key(&set_name) {
+                     applied.filled_at.insert(set_name.clone(), now);
+                 }
+                 applied.sets.insert(set_name, ips);
+             }
+             for (set_name, (_, nets)) in desired_nets {
+                 applied.nets.insert(set_name, nets);
+             }
+             for (set_name, (_, macs)) in desired_macs {
+                 applied.macs.insert(set_name, macs);
+             }
+             applied.container_forwards = forwards;
+             info!("[NFTABLES-RS] Successfully applied set changes in one transaction");
+         }
+
+         // Devices that could not be removed stay applied, so the next reconcile retries them
+         let mut kept = self.remove_flow_devices(&removed_flow_devices);
+         kept.extend(flow_devices);
+         kept.sort();
+         applied.flow_devices = kept;
+         Ok(())
+    }
+
+    /// Takes `devices` out of the `offload` flowtable. nftables JSON deletes
+    /// the whole flowtable, so this is done in nft syntax.
+    ///
+    /// Returns the devices that could not be removed.
+    fn remove_flow_devices(&self, devices: &[String]) -> Vec<String> {
+        if devices.is_empty() {
+            return Vec::new();
+        }
+        let family = match self.settings.family {
+            NftablesFamily::Inet => "inet",
+            NftablesFamily::Ip => "ip",
+            NftablesFamily::Ip6 => "ip6",
+        };
+        let script = format!(
+            "delete flowtable {} {} {} {{ devices = {{ {} }}; }}",
+            family, self.settings.table, FLOWTABLE_NAME, devices.join(", ")
+        );
+        match self.commit_script(&script) {
+            Ok(()) => {
+                info!("[NFTABLES-RS] Removed {} from the flowtable", devices.join(", "));
+                Vec::new()
+            }
+            Err(e) => {
+                warn!("[NFTABLES-RS] Could not remove {} from the flowtable: {}", devices.join(", "), e);
+                devices.to_vec()
+            }
+        }
+    }
+
+    /// Restores the sets, chains and flowtable a failed transaction touched to
+    /// their snapshot contents.
+    ///
+    /// Returns whether the restore was applied.
+    fn roll_back(&self, failed: &Nftables, snapshot: &Nftables) -> bool {
+        let rollback = rollback_ruleset(failed, snapshot);
+        if rollback.objects.is_empty() {
+            return true;
+        }
+        warn!("[NFTABLES-RS] Apply failed, restoring the touched objects from the snapshot");
+        match self.commit(&rollback) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("[NFTABLES-RS] Rollback failed, sets are refilled on the next reconcile: {}", e);
+                false
+            }
+        }
+    }
+
+    async fn record_apply(&self, error: Option<&AppError>, rolled_back: bool) {
+        let mut last_apply = self.last_apply.lock().await;
+        let failures = last_apply.as_ref().map_or(0, |result| result.consecutive_failures);
+        let consecutive_failures = if error.is_some() { failures + 1 } else { 0 };
+        if error.is_none() && failures >= self.settings.degraded_after && self.settings.degraded_after > 0 {
+            info!("[NFTABLES-RS] Apply succeeded again after {} failures, no longer degraded", failures);
+        }
+        *last_apply = Some(ApplyResult {
+            timestamp: unix_now(),
+            success: error.is_none(),
+            error: error.map(|e| e.to_string()),
+            rolled_back,
+            consecutive_failures,
+        });
+    }
+
+    /// Outcome of the most recent `load_rules` or `apply_rules` that sent a transaction.
+    pub async fn last_apply(&self) -> Option<ApplyResult> {
+        self.last_apply.lock().await.clone()
+    }
+
+    /// The last error if `nftables.degraded_after` applies in a row have failed.
+    pub async fn degraded(&self) -> Option<String> {
+        let last_apply = self.last_apply.lock().await;
+        let result = last_apply.as_ref()?;
+        if self.settings.degraded_after == 0 || result.consecutive_failures < self.settings.degraded_after {
+            return None;
+        }
+        result.error.clone()
+    }
+
+    /// Builds the transaction `apply_rules` sends right after `load_rules`, i.e.
+    /// with every set flushed and refilled, without applying it.
+    pub async fn plan_apply_rules(
+        &self,
+        network_state: &NetworkState,
+        container_ips: &HashMap<String, IpAddr>,
+    ) -> Nftables<'_> {
+        let desired = self.desired_sets(network_state, container_ips).await;
+        let desired_nets = self.desired_nets(network_state).await;
+        let desired_macs = self.desired_macs(network_state).await;
+        let mut batch = self.build_set_diff(&desired, &HashMap::new());
+        self.add_net_changes(&mut batch, &desired_nets, &HashMap::new());
+        self.add_mac_changes(&mut batch, &desired_macs, &HashMap::new());
+        batch.to_nftables()
+    }
+
+    /// Renders what a reload would change in the live ruleset: the `load_rules`
+    /// transaction, followed by the set contents for `state` when given.
+    ///
+    /// `live` is the current listing of the managed table (see `live_ruleset`).
+    pub async fn plan(
+        &self,
+        state: Option<(&NetworkState, &HashMap<String, IpAddr>)>,
+        live: &Nftables<'_>,
+    ) -> Result<RulesetPlan, AppError> {
+        let mut transaction = self.plan_load_rules().await;
+        let fragments = self.rule_fragments().await;
+        for json in fragments.iter().filter_map(|fragment| fragment.body.ruleset()) {
+            transaction.objects.to_mut().extend(json.objects.iter().cloned());
+        }
+        if let Some((network_state, container_ips)) = state {
+            let sets = self.plan_apply_rules(network_state, container_ips).await;
+            transaction.objects.to_mut().extend(sets.objects.iter().cloned());
+        }
+
+        let before = RulesetModel::from_ruleset(live);
+        let mut after = before.clone();
+        after.apply(&transaction);
+        Ok(RulesetPlan {
+            changes: before.diff(&after),
+            transaction: serde_json::to_value(&transaction)?,
+        })
+    }
+
+    /// Lists the managed table as currently loaded in the kernel.
+    ///
+    /// A table that does not exist yet yields an empty ruleset.
+    pub fn live_ruleset(&self) -> Result<Nftables<'static>, AppError> {
+        self.backend.list_table(self.family, &self.settings.table)
+    }
+
+    /// `live_ruleset` on the blocking thread pool, as listing the table runs `nft`.
+    async fn read_live_ruleset(&self) -> Result<Nftables<'static>, AppError> {
+        let backend = self.backend.clone();
+        let (family, table) = (self.family, self.settings.table.clone());
+        tokio::task::spawn_blocking(move || backend.list_table(family, &table))
+            .await
+            .map_err(|e| AppError::Io(e.into()))?
+    }
+
+    /// Compares the live managed table with the structure built by `load_rules`
+    /// (including the JSON rule fragments it applied) and the set elements of
+    /// the last successful `apply_rules`.
+    ///
+    /// nft syntax fragments are not modelled, so the rules of the chains they
+    /// touch are not compared.
+    ///
+    /// Returns one description per difference; empty means the ruleset is intact.
+    pub async fn detect_drift(&self) -> Result<Vec<String>, AppError> {
+        let mut expected = RulesetModel::from_ruleset(&self.plan_load_rules().await);
+
+        // Hold the applied state until the read-back is done, so a concurrent
+        // apply cannot land in between and show up as drift.
+        let applied = self.applied.lock().await;
+        let mut script_chains = Vec::new();
+        for fragment in &applied.fragments {
+            match fragment.body.ruleset() {
+                Some(json) => expected.apply(&json),
+                None => script_chains.extend(fragment.body.chains()),
+            }
+        }
+        let mut batch = Batch::new();
+        for (set_name, ips) in applied.sets.iter().filter(|(_, ips)| !ips.is_empty()) {
+            batch.add(NfListObject::Element(self.element(set_name, &sorted_ips(ips.iter()))));
+        }
+        for (set_name, nets) in applied.nets.iter().filter(|(_, nets)| !nets.is_empty()) {
+            batch.add(NfListObject::Element(self.net_element(set_name, nets)));
+        }
+        for (set_name, macs) in applied.macs.iter().filter(|(_, macs)| !macs.is_empty()) {
+            batch.add(NfListObject::Element(self.mac_element(set_name, macs)));
+        }
+        if !applied.flow_devices.is_empty() {
+            self.add_offload_rebuild(&mut batch, &applied.flow_devices);
+        }
+        expected.apply(&batch.to_nftables());
+
+        let mut live = RulesetModel::from_ruleset(&self.read_live_ruleset().await?);
+        // Ban sets are filled by the `limits` chain, and blocklist elements
+        // expire in the kernel on their own schedule
+        let mut unmanaged = self.ban_sets().await;
+        unmanaged.extend(blocklist_set_names(&self.settings).into_iter().map(|(set_name, _)| set_name));
+        expected.forget_elements(&unmanaged);
+        live.forget_elements(&unmanaged);
+        expected.forget_rules(&script_chains);
+        live.forget_rules(&script_chains);
+        Ok(expected.drift(&live))
+    }
+
+    /// Re-creates the managed table, sets and chains if the live ruleset drifted,
+    /// e.g. after `nft flush ruleset` or a reload of `/etc/nftables.conf`.
+    ///
+    /// Returns whether a repair was made.
+    pub async fn repair_drift(
+        &self,
+        network_state: &NetworkState,
+        container_ips: &HashMap<String, IpAddr>,
+    ) -> Result<bool, AppError> {
+        let _guard = self.repair_lock.lock().await;
+        let drift = self.detect_drift().await?;
+        if drift.is_empty() {
+            debug!("[NFTABLES-RS] Live ruleset matches the managed state.");
+            return Ok(false);
+        }
+
+        warn!("[NFTABLES-RS] Live ruleset drifted from the managed state: {}", drift.join("; "));
+        self.load_rules().await?;
+        self.apply_rules(network_state, container_ips).await?;
+        let repairs = self.repairs.fetch_add(1, Ordering::Relaxed) + 1;
+        info!("[NFTABLES-RS] Managed ruleset repaired ({} repair(s) since start).", repairs);
+        Ok(true)
+    }
+
+    /// Number of drift repairs made since the manager was created.
+    pub fn repair_count(&self) -> u64 {
+        self.repairs.load(Ordering::Relaxed)
+    }
+
+    /// Whether the daemon sent a transaction in the last `window`, so that a
+    /// ruleset change notification is most likely its own.
+    pub fn committed_within(&self, window: Duration) -> bool {
+        self.last_commit.lock().unwrap().is_some_and(|at| at.elapsed() < window)
+    }
+
+    /// Sends `ruleset` to the backend, remembering when (even if it fails,
+    /// as the kernel may still have reported a change).
+    fn commit(&self, ruleset: &Nftables) -> Result<(), AppError> {
+        let result = self.backend.apply(ruleset);
+        *self.last_commit.lock().unwrap() = Some(Instant::now());
+        result
+    }
+
+    /// Like `commit`, for an nft script.
+    fn commit_script(&self, script: &str) -> Result<(), AppError> {
+        let result = self.backend.apply_script(script);
+        *self.last_commit.lock().unwrap() = Some(Instant::now());
+        result
+    }
+
+    /// Desired contents of every subnet set, keyed by set name.
+    async fn desired_nets(&self, network_state: &NetworkState) -> NetSets {
+        let config_lock = self.config.lock().await;
+        let zone_to_nets = compute_zone_nets(&set_interfaces(&config_lock.interfaces, &*self.links.lock().await), &config_lock.zones, network_state);
+        drop(config_lock);
+        zone_net_elements(&zone_to_nets, &self.settings)
+    }
+
+    /// Desired contents of every MAC set, keyed by set name; none on backends
+    /// without chains, which only keep address sets.
+    async fn desired_macs(&self, network_state: &NetworkState) -> MacSets {
+        if !self.backend.supports_chains() {
+            return MacSets::new();
+        }
+        let config_lock = self.config.lock().await;
+        compute_zone_macs(&resolve_interfaces(&config_lock.interfaces, &*self.links.lock().await), &config_lock.zones, network_state, &self.settings)
+    }
+
+    /// Whether a grace period ended or a set with an element timeout needs
+    /// refilling, i.e. `apply_rules` has work to do even without a state change.
+    pub async fn maintenance_due(&self) -> bool {
+        let zone_sets = self.zone_set_settings().await;
+        let applied = self.applied.lock().await;
+        let now = Instant::now();
+        applied.lingering.values().flat_map(|ips| ips.values()).any(|until| *until <= now)
+            || applied.sets.keys().any(|set_name| applied.refill_due(set_name, &zone_sets, now))
+    }
+
+    /// Records the port forwards requested by a container's labels; an empty list
+    /// forgets the container. Takes effect on the next `apply_rules`.
+    pub async fn set_container_forwards(&self, container_id: &str, forwards: Vec<ContainerForward>) {
+        let mut container_forwards = self.container_forwards.lock().await;
+        if forwards.is_empty() {
+            container_forwards.remove(container_id);
+        } else {
+            container_forwards.insert(container_id.to_string(), forwards);
+        }
+    }
+
+    /// Records the links interface `match:` blocks select from, and returns whether
+    /// that changed the matched interfaces; if so, `load_rules` has to rebuild the
+    /// chains naming them. Sets follow on the next `apply_rules` either way.
+    pub async fn set_links(&self, links: &BTreeMap<String, LinkDetails>) -> bool {
+        let config_lock = self.config.lock().await;
+        let mut known = self.links.lock().await;
+        let before = std::mem::replace(&mut *known, links.clone());
+        if !config_lock.interfaces.iter().any(|iface| iface.matcher.is_some()) {
+            return false;
+        }
+        let matched = |links: &BTreeMap<String, LinkDetails>| -> Vec<(String, Option<String>)> {
+            resolve_interfaces(&config_lock.interfaces, links).into_iter()
+                .map(|iface| (iface.name, iface.nftables_zone))
+                .collect()
+        };
+        let after = matched(links);
+        if matched(&before) == after {
+            return false;
+        }
+        let names: Vec<String> = after.iter().map(|(name, zone)| format!("{} ({})", name, zone.as_deref().unwrap_or("-"))).collect();
+        info!("[NFTABLES-RS] Matched interfaces changed: {}", names.join(", "));
+        true
+    }
+
+    /// Records the networks and labels of a container for the `docker_sets:` sets;
+    /// empty details forget the container. Takes effect on the next `apply_rules`.
+    pub async fn set_container_details(&self, container_id: &str, details: ContainerDetails) {
+        let mut container_details = self.container_details.lock().await;
+        if details == ContainerDetails::default() {
+            container_details.remove(container_id);
+        } else {
+            container_details.insert(container_id.to_string(), details);
+        }
+    }
+
+    /// Container port forwards resolved against the containers' current addresses.
+    ///
+    /// Forwards of containers without an address, from zones without interfaces,
+    /// or to an address family the table does not carry are left out, as are all
+    /// of them on backends without chains. A forward listening where a static
+    /// forward or a container earlier in ID order already does is skipped with a
+    /// warning, since its DNAT rule would never match.
+    async fn desired_container_forwards(&self, container_ips: &HashMap<String, IpAddr>) -> Vec<PortForward> {
+        if !self.backend.supports_chains() {
+            return Vec::new();
+        }
+        let config_lock = self.config.lock().await;
+        let interfaces = resolve_interfaces(&config_lock.interfaces, &*self.links.lock().await);
+        let container_forwards = self.container_forwards.lock().await;
+        let mut listeners: HashSet<_> = config_lock.port_forwards.iter().map(PortForward::listener).collect();
+        let mut forwards = Vec::new();
+        for (container_id, requested) in container_forwards.iter() {
+            let Some(ip) = container_ips.get(container_id) else {
+                continue;
+            };
+            let supported = if ip.is_ipv4() { self.settings.family.has_ipv4() } else { self.settings.family.has_ipv6() };
+            for forward in requested {
+                let from = forward.from.as_deref().unwrap_or(config_lock.docker_forward_zone());
+                if !supported || !matches!(zone_match(from, &interfaces), ZoneMatch::Interfaces(_)) {
+                    debug!("[NFTABLES-RS] Skipping port forward {:?} of container {}", forward, container_id);
+                    continue;
+                }
+                let resolved = PortForward {
+                    from: from.to_string(),
+                    protocol: forward.protocol,
+                    external_port: forward.external_port,
+                    target: SocketAddr::new(*ip, forward.container_port),
+                };
+                if !listeners.insert(resolved.listener()) {
+                    warn!(
+                        "[NFTABLES-RS] Skipping port forward {} {} from '{}' of container {}: the port is already forwarded",
+                        resolved.protocol.as_str(), resolved.external_port, resolved.from, container_id
+                    );
+                    continue;
+                }
+                forwards.push(resolved);
+            }
+        }
+        forwards
+    }
+
+    /// Reads the zone counters from the kernel and keeps them for `zone_counters`.
+    pub async fn refresh_counters(&self) -> Result<(), AppError> {
+        let values = self.backend.list_counters(self.family, &self.settings.table)?;
+        let zones = self.counter_zones().await;
+        let mut counters = Vec::new();
+        for zone in zones {
+            for direction in [COUNTER_IN, COUNTER_OUT] {
+                if let Some((packets, bytes)) = values.get(&zone_counter_name(&zone, direction)) {
+                    counters.push(ZoneCounter { zone: zone.clone(), direction, packets: *packets, bytes: *bytes });
+                }
+            }
+        }
+        *self.zone_counters.lock().await = counters;
+        Ok(())
+    }
+
+    /// Zone counters as of the last `refresh_counters`, sorted by zone.
+    pub async fn zone_counters(&self) -> Vec<ZoneCounter> {
+        self.zone_counters.lock().await.clone()
+    }
+
+    /// Zone settings of every managed set that has any, keyed by set name.
+    async fn zone_set_settings(&self) -> HashMap<String, ZoneConfig> {
+        let config_lock = self.config.lock().await;
+        let mut sets = HashMap::new();
+        for (zone_name, zone) in &config_lock.zones {
+            for (set_name, _) in zone_set_names(&self.settings, zone_name) {
+                sets.insert(set_name, zone.clone());
+            }
+        }
+        if let Some(docker_zone) = config_lock.zones.get("docker") {
+            for zone_name in config_lock.docker_sets.zones() {
+                for (set_name, _) in zone_set_names(&self.settings, &zone_name) {
+                    sets.insert(set_name, docker_zone.clone());
+                }
+            }
+        }
+        sets
+    }
+
+    /// Desired contents of every managed set, keyed by set name.
+    async fn desired_sets(
+        &self,
+        network_state: &NetworkState,
+        container_ips: &HashMap<String, IpAddr>,
+    ) -> BTreeMap<String, (SetType, HashSet<IpAddr>)> {
+        let config_lock = self.config.lock().await;
+        let interfaces = set_interfaces(&config_lock.interfaces, &*self.links.lock().await);
+        let mut zone_to_ips = compute_zone_ips(&interfaces, &config_lock.zones, network_state, container_ips);
+        let container_details = self.container_details.lock().await;
+        zone_to_ips.extend(compute_docker_set_ips(&config_lock.docker_sets, &container_details));
+        drop(container_details);
+        let mut sets = zone_set_elements(&zone_to_ips, &self.settings);
+        sets.extend(zone_peer_elements(&interfaces, network_state, &self.settings));
+        sets
+    }
+
+    /// Builds the add/delete commands needed to move the kernel sets from `applied` to `desired`.
+    fn build_set_diff<'a>(
+        &'a self,
+        desired: &BTreeMap<String, (SetType, HashSet<IpAddr>)>,
+        applied: &AppliedSets,
+    ) -> Batch<'a> {
+        let mut batch = Batch::new();
+        for (set_name, (set_type, ips)) in desired {
+            let (to_delete, to_add): (Vec<IpAddr>, Vec<IpAddr>) = match applied.get(set_name) {
+                Some(previous) => (
+                    sorted_ips(previous.difference(ips)),
+                    sorted_ips(ips.difference(previous)),
+                ),
+                None => {
+                    // Unknown contents: flush and refill inside the same transaction.
+                    batch.add_cmd(NfCmd::Flush(FlushObject::Set(Box::new(self.set_ref(set_name, *set_type)))));
+                    (Vec::new(), sorted_ips(ips.iter()))
+                }
+            };
+
+            if !to_delete.is_empty() {
+                batch.delete(NfListObject::Element(self.element(set_name, &to_delete)));
+            }
+            if !to_add.is_empty() {
+                batch.add(NfListObject::Element(self.element(set_name, &to_add)));
+            }
+        }
+        batch
+    }
+
+    /// Adds the commands that bring the subnet sets from `applied` to `desired`.
+    ///
+    /// Interval set elements must be deleted exactly as they were added, so a
+    /// changed set is flushed and refilled as a whole; they rarely change.
+    fn add_net_changes<'a>(
+        &'a self,
+        batch: &mut Batch<'a>,
+        desired: &NetSets,
+        applied: &HashMap<String, BTreeSet<IpNet>>,
+    ) {
+        for (set_name, (set_type, nets)) in desired {
+            if applied.get(set_name) == Some(nets) {
+                continue;
+            }
+            batch.add_cmd(NfCmd::Flush(FlushObject::Set(Box::new(self.set_ref(set_name, *set_type)))));
+            if !nets.is_empty() {
+                batch.add(NfListObject::Element(self.net_element(set_name, nets)));
+            }
+        }
+    }
+
+    /// Adds the commands that bring the MAC sets from `applied` to `desired`.
+    ///
+    /// Like the subnet sets, a changed set is flushed and refilled as a whole
+    /// inside the transaction.
+    fn add_mac_changes<'a>(
+        &'a self,
+        batch: &mut Batch<'a>,
+        desired: &MacSets,
+        applied: &HashMap<String, BTreeSet<MacElement>>,
+    ) {
+        for (set_name, (set_type, macs)) in desired {
+            if applied.get(set_name) == Some(macs) {
+                continue;
+            }
+            batch.add_cmd(NfCmd::Flush(FlushObject::Set(Box::new(Set {
+                set_type: set_type.clone(),
+                ..self.set_ref(set_name, SetType::EtherAddr)
+            }))));
+            if !macs.is_empty() {
+                batch.add(NfListObject::Element(self.mac_element(set_name, macs)));
+            }
+        }
+    }
+
+    /// Adds the `policy_input`/`policy_forward`/`policy_output` base chains and the rules compiled from `policy`.
+    ///
+    /// Each chain is flushed before its rules are added, so reloading never
+    /// duplicates rules. Every chain starts by accepting established/related
+    /// traffic, and `policy_input` additionally accepts loopback traffic. `policy_forward` also
+    /// accepts the connections DNATed by the managed port forwards, whether static
+    /// or requested by a container label (which can appear at any time): their
+    /// rules set `PORT_FORWARD_MARK`, so DNAT done by other tools is not accepted.
+    fn add_policy_chains<'a>(
+        &'a self,
+        batch: &mut Batch<'a>,
+        policy: &PolicyConfig,
+        interfaces: &[InterfaceConfig],
+    ) {
+        let chains = [
+            (POLICY_INPUT_CHAIN, NfHook::Input, policy.input),
+            (POLICY_FORWARD_CHAIN, NfHook::Forward, policy.forward),
+            (POLICY_OUTPUT_CHAIN, NfHook::Output, policy.output),
+        ];
+        for (chain_name, hook, chain_policy) in chains {
+            batch.add(NfListObject::Chain(Chain {
+                family: self.family,
+                table: Cow::Borrowed(&self.settings.table),
+                name: Cow::Borrowed(chain_name),
+                _type: Some(NfChainType::Filter),
+                hook: Some(hook),
+                prio: Some(0),
+                policy: Some(match chain_policy {
+                    ChainPolicy::Accept => NfChainPolicy::Accept,
+                    ChainPolicy::Drop => NfChainPolicy::Drop,
+                }),
+                ..Default::default()
+            }));
+            batch.add_cmd(NfCmd::Flush(FlushObject::Chain(Chain {
+                family: self.family,
+                table: Cow::Borrowed(&self.settings.table),
+                name: Cow::Borrowed(chain_name),
+                ..Default::default()
+            })));
+            batch.add(NfListObject::Rule(self.rule(chain_name, vec![
+                match_expr(
+                    Expression::Named(NamedExpression::CT(CT { key: "state".into(), family: None, dir: None })),
+                    Operator::IN,
+                    Expression::List(vec![Expression::String("established".into()), Expression::String("related".into())]),
+                ),
+                Statement::Accept(Some(Accept {})),
+            ], Some(OWNER_COMMENT.to_string()))));
+        }
+        batch.add(NfListObject::Rule(self.rule(POLICY_INPUT_CHAIN, vec![
+            match_expr(meta(MetaKey::Iifname), Operator::EQ, Expression::String("lo".into())),
+            Statement::Accept(Some(Accept {})),
+        ], None)));
+        batch.add(NfListObject::Rule(self.rule(POLICY_FORWARD_CHAIN, vec![
+            match_expr(
+                Expression::Named(NamedExpression::CT(CT { key: "status".into(), family: None, dir: None })),
+                Operator::IN,
+                Expression::String("dnat".into()),
+            ),
+            match_expr(
+                Expression::BinaryOperation(Box::new(BinaryOperation::AND(ct_mark(), Expression::Number(PORT_FORWARD_MARK)))),
+                Operator::EQ,
+                Expression::Number(PORT_FORWARD_MARK),
+            ),
+            Statement::Accept(Some(Accept {})),
+        ], None)));
+
+        for rule in &policy.rules {
+            let chain_name = policy_rule_chain(rule);
+            let comment = format!("{} -> {}", rule.from, rule.to);
+            for expr in compile_policy_rule(rule, interfaces, &self.settings) {
+                batch.add(NfListObject::Rule(self.rule(chain_name, expr, Some(comment.clone()))));
+            }
+        }
+    }
+
+    /// Adds the `prerouting` (static and container port forwards) and
+    /// `postrouting` (masquerade) nat chains. A chain is only created when it has
+    /// rules, and is flushed before they are added; every rule carries the owner comment.
+    fn add_nat_chains<'a>(
+        &'a self,
+        batch: &mut Batch<'a>,
+        masquerade: &BTreeSet<&String>,
+        port_forwards: &[PortForward],
+        container_forwards: &[PortForward],
+        interfaces: &[InterfaceConfig],
+    ) {
+        let prerouting: Vec<_> = port_forwards.iter()
+            .chain(container_forwards)
+            .filter_map(|forward| compile_port_forward(forward, interfaces, &self.settings))
+            .collect();
+        let postrouting: Vec<_> = masquerade.iter()
+            .filter_map(|zone| match zone_match(zone, interfaces) {
+                ZoneMatch::Interfaces(names) => Some(vec![
+                    match_expr(
+                        meta(MetaKey::Oifname),
+                        Operator::EQ,
+                        anonymous_set(names.into_iter().map(|n| Expression::String(n.into())).collect()),
+                    ),
+                    Statement::Masquerade(None),
+                ]),
+                _ => None,
+            })
+            .collect();
+
+        if !prerouting.is_empty() {
+            self.add_nat_chain(batch, NAT_PREROUTING_CHAIN, prerouting);
+        }
+        if !postrouting.is_empty() {
+            self.add_nat_chain(batch, NAT_POSTROUTING_CHAIN, postrouting);
+        }
+    }
+
+    /// Rebuilds the `prerouting` chain with the static and container port forwards,
+    /// or deletes it once neither is left.
+    fn add_prerouting_rebuild<'a>(
+        &'a self,
+        batch: &mut Batch<'a>,
+        static_forwards: &[PortForward],
+        container_forwards: &[PortForward],
+        interfaces: &[InterfaceConfig],
+    ) {
+        let rules: Vec<_> = static_forwards.iter()
+            .chain(container_forwards)
+            .filter_map(|forward| compile_port_forward(forward, interfaces, &self.settings))
+            .collect();
+        if rules.is_empty() {
+            // Added first, so the delete also works when the chain does not exist
+            self.add_nat_chain(batch, NAT_PREROUTING_CHAIN, Vec::new());
+            batch.delete(NfListObject::Chain(self.chain_ref(NAT_PREROUTING_CHAIN)));
+        } else {
+            self.add_nat_chain(batch, NAT_PREROUTING_CHAIN, rules);
+        }
+    }
+
+    /// Interfaces of the `flow_offload` zones that are currently present: their
+    /// link was reported up, or they have addresses. Sorted, for comparison.
+    async fn desired_flow_devices(&self, network_state: &NetworkState) -> Vec<String> {
+        if !self.backend.supports_chains() {
+            return Vec::new();
+        }
+        let config_lock = self.config.lock().await;
+        let Some(offload) = &config_lock.flow_offload else {
+            return Vec::new();
+        };
+        let devices: BTreeSet<String> = resolve_interfaces(&config_lock.interfaces, &*self.links.lock().await).into_iter()
+            .filter(|iface| iface.nftables_zone.as_ref().is_some_and(|zone| offload.zones.contains(zone)))
+            .filter(|iface| {
+                network_state.up_links.contains(&iface.name) || network_state.interface_ips.contains_key(&iface.name)
+            })
+            .map(|iface| iface.name)
+            .collect();
+        devices.into_iter().collect()
+    }
+
+    /// Recreates the `offload` flowtable with `devices` and the `offload` chain
+    /// feeding it, or deletes both once no device is left. Used when the
+    /// flowtable is created or its kernel state is unknown; `apply_rules`
+    /// changes the devices of an existing one in place.
+    ///
+    /// nftables JSON cannot take devices out of a flowtable, so it is deleted
+    /// and added again. Both objects are added first, so the deletes also work
+    /// when they did not exist.
+    fn add_offload_rebuild<'a>(&'a self, batch: &mut Batch<'a>, devices: &[String]) {
+        batch.add(NfListObject::FlowTable(self.flowtable_ref(Some(devices))));
+        batch.add(NfListObject::Chain(Chain {
+            _type: Some(NfChainType::Filter),
+            hook: Some(NfHook::Forward),
+            prio: Some(OFFLOAD_PRIORITY),
+            policy: Some(NfChainPolicy::Accept),
+            ..self.chain_ref(OFFLOAD_CHAIN)
+        }));
+        if devices.is_empty() {
+            batch.delete(NfListObject::Chain(self.chain_ref(OFFLOAD_CHAIN)));
+            batch.delete(NfListObject::FlowTable(self.flowtable_ref(None)));
+            return;
+        }
+        batch.add_cmd(NfCmd::Flush(FlushObject::Chain(self.chain_ref(OFFLOAD_CHAIN))));
+        batch.delete(NfListObject::FlowTable(self.flowtable_ref(None)));
+        batch.add(NfListObject::FlowTable(self.flowtable_ref(Some(devices))));
+        let expr = vec![
+            Statement::Match(Match {
+                left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::L4proto })),
+                right: Expression::Named(NamedExpression::Set(vec![
+                    SetItem::Element(Expression::String(Cow::Borrowed("tcp"))),
+                    SetItem::Element(Expression::String(Cow::Borrowed("udp"))),
+                ])),
+                op: Operator::IN,
+            }),
+            Statement::Flow(Flow { op: SetOp::Add, flowtable: Cow::Owned(format!("@{}", FLOWTABLE_NAME)) }),
+        ];
+        batch.add(NfListObject::Rule(self.rule(OFFLOAD_CHAIN, expr, Some(OWNER_COMMENT.to_string()))));
+    }
+
+    /// The `offload` flowtable; with `devices`, as it is added (ingress hook).
+    /// An empty device list adds it without devices.
+    fn flowtable_ref(&self, devices: Option<&[String]>) -> FlowTable<'_> {
+        FlowTable {
+            family: self.family,
+            table: Cow::Borrowed(&self.settings.table),
+            name: Cow::Borrowed(FLOWTABLE_NAME),
+            handle: None,
+            hook: devices.map(|_| NfHook::Ingress),
+            prio: devices.map(|_| FLOWTABLE_PRIORITY),
+            dev: devices
+                .filter(|devices| !devices.is_empty())
+                .map(|devices| Cow::Owned(devices.iter().map(|d| Cow::Owned(d.clone())).collect())),
+        }
+    }
+
+    /// Adds (or flushes) one of the nat base chains and fills it with `rules`.
+    fn add_nat_chain<'a>(&'a self, batch: &mut Batch<'a>, chain_name: &'static str, rules: Vec<Vec<Statement<'static>>>) {
+        let (hook, prio) = match chain_name {
+            NAT_PREROUTING_CHAIN => (NfHook::Prerouting, NAT_PREROUTING_PRIORITY),
+            _ => (NfHook::Postrouting, NAT_POSTROUTING_PRIORITY),
+        };
+        batch.add(NfListObject::Chain(Chain {
+            _type: Some(NfChainType::NAT),
+            hook: Some(hook),
+            prio: Some(prio),
+            policy: Some(NfChainPolicy::Accept),
+            ..self.chain_ref(chain_name)
+        }));
+        batch.add_cmd(NfCmd::Flush(FlushObject::Chain(self.chain_ref(chain_name))));
+        for expr in rules {
+            batch.add(NfListObject::Rule(self.rule(chain_name, expr, Some(OWNER_COMMENT.to_string()))));
+        }
+    }
+
+    /// Adds the `limits` chain (prerouting hook, after conntrack) enforcing the
+    /// zones' per-source connection limits through meters, plus the ban sets of
+    /// zones with a `ban_timeout`. The chain is flushed before its rules are added.
+    fn add_limits_chain<'a>(
+        &'a self,
+        batch: &mut Batch<'a>,
+        zones: &BTreeMap<&String, &ZoneConfig>,
+        interfaces: &[InterfaceConfig],
+    ) {
+        for (zone_name, zone) in zones {
+            let Some(ban_timeout) = zone.ban_timeout else {
+                continue;
+            };
+            for (set_name, set_type) in ban_set_names(&self.settings, zone_name) {
+                batch.add(NfListObject::Set(Box::new(Set {
+                    flags: Some(HashSet::from([SetFlag::Dynamic, SetFlag::Timeout])),
+                    comment: Some(Cow::Borrowed(OWNER_COMMENT)),
+                    timeout: Some(ban_timeout),
+                    ..self.set_ref(&set_name, set_type)
+                })));
+            }
+        }
+        batch.add(NfListObject::Chain(Chain {
+            _type: Some(NfChainType::Filter),
+            hook: Some(NfHook::Prerouting),
+            prio: Some(LIMITS_PRIORITY),
+            policy: Some(NfChainPolicy::Accept),
+            ..self.chain_ref(LIMITS_CHAIN)
+        }));
+        batch.add_cmd(NfCmd::Flush(FlushObject::Chain(self.chain_ref(LIMITS_CHAIN))));
+        for (zone_name, zone) in zones {
+            for expr in compile_zone_limits(zone_name, zone, interfaces, &self.settings) {
+                batch.add(NfListObject::Rule(self.rule(LIMITS_CHAIN, expr, Some(OWNER_COMMENT.to_string()))));
+            }
+        }
+    }
+
+    /// Names of the ban sets of the zones with a `ban_timeout`.
+    async fn ban_sets(&self) -> Vec<String> {
+        let config_lock = self.config.lock().await;
+        config_lock.zones.iter()
+            .filter(|(_, zone)| zone.has_limits() && zone.ban_timeout.is_some())
+            .flat_map(|(zone_name, _)| ban_set_names(&self.settings, zone_name))
+            .map(|(set_name, _)| set_name)
+            .collect()
+    }
+
+    /// Names of the meters in the `limits` chain, which nft keeps as sets.
+    async fn limit_meters(&self) -> Vec<String> {
+        let config_lock = self.config.lock().await;
+        config_lock.zones.iter()
+            .flat_map(|(zone_name, zone)| zone_limit_meters(zone_name, zone, &self.settings))
+            .map(|(meter_name, _, _)| meter_name)
+            .collect()
+    }
+
+    /// Zones that get traffic counters: the interface zones and `docker`.
+    async fn counter_zones(&self) -> BTreeSet<String> {
+        let config_lock = self.config.lock().await;
+        config_lock.interfaces.iter()
+            .filter_map(|iface| iface.nftables_zone.clone())
+            .chain(std::iter::once("docker".to_string()))
+            .collect()
+    }
+
+    /// Names the counters of the current zones and of the zones counted since
+    /// they were last seen would get, so hand-written counters are left alone.
+    async fn possible_zone_counters(&self) -> HashSet<String> {
+        let mut zones = self.counter_zones().await;
+        zones.extend(self.applied.lock().await.counted_zones.iter().cloned());
+        zones.iter()
+            .flat_map(|zone| [COUNTER_IN, COUNTER_OUT].map(|direction| zone_counter_name(zone, direction)))
+            .collect()
+    }
+
+    /// Names the meters of every interface zone would get, with or without limits.
+    async fn possible_limit_meters(&self) -> HashSet<String> {
+        let config_lock = self.config.lock().await;
+        config_lock.interfaces.iter()
+            .filter_map(|iface| iface.nftables_zone.as_deref())
+            .flat_map(|zone| self.settings.limit_meter_names(zone))
+            .collect()
+    }
+
+    /// Adds the blocklist interval sets (`blocklist_set_name`) holding `entries`, each
+    /// element timing out with its entry, and the `blocklist` chain (prerouting
+    /// hook, raw priority) dropping traffic from them. The sets are flushed
+    /// first, so entries removed from the file while the daemon was down go away.
+    fn add_blocklist<'a>(&'a self, batch: &mut Batch<'a>, entries: &[BlocklistEntry]) {
+        let now = unix_now();
+        for (set_name, set_type) in blocklist_set_names(&self.settings) {
+            batch.add(NfListObject::Set(Box::new(Set {
+                flags: Some(HashSet::from([SetFlag::Interval, SetFlag::Timeout])),
+                comment: Some(Cow::Borrowed(OWNER_COMMENT)),
+                ..self.set_ref(&set_name, set_type)
+            })));
+            batch.add_cmd(NfCmd::Flush(FlushObject::Set(Box::new(self.set_ref(&set_name, set_type)))));
+            let family_entries: Vec<&BlocklistEntry> = entries.iter()
+                .filter(|entry| (set_type == SetType::Ipv6Addr) == matches!(entry.net, IpNet::V6(_)))
+                .collect();
+            if !family_entries.is_empty() {
+                batch.add(NfListObject::Element(self.blocklist_element(&set_name, &family_entries, now)));
+            }
+        }
+        batch.add(NfListObject::Chain(Chain {
+            _type: Some(NfChainType::Filter),
+            hook: Some(NfHook::Prerouting),
+            prio: Some(BLOCKLIST_PRIORITY),
+            policy: Some(NfChainPolicy::Accept),
+            ..self.chain_ref(BLOCKLIST_CHAIN)
+        }));
+        batch.add_cmd(NfCmd::Flush(FlushObject::Chain(self.chain_ref(BLOCKLIST_CHAIN))));
+        for (set_name, set_type) in blocklist_set_names(&self.settings) {
+            let protocol = if set_type == SetType::Ipv6Addr { "ip6" } else { "ip" };
+            batch.add(NfListObject::Rule(self.rule(BLOCKLIST_CHAIN, vec![
+                match_expr(payload(protocol, "saddr"), Operator::EQ, Expression::String(format!("@{}", set_name).into())),
+                Statement::Drop(Some(Drop {})),
+            ], Some(OWNER_COMMENT.to_string()))));
+        }
+    }
+
+    /// Blocks `net` until `ttl` seconds from now (or until unblocked), replacing
+    /// any entry for the same network. The entry takes effect immediately and is
+    /// saved to `blocklist_path`.
+    pub async fn block(&self, net: IpNet, ttl: Option<u64>, reason: Option<String>) -> Result<BlocklistEntry, AppError> {
+        if !self.backend.supports_chains() {
+            return Err(AppError::ConfigValidation("The firewall backend cannot enforce a blocklist".to_string()));
+        }
+        if ttl.is_some_and(|ttl| ttl == 0 || ttl > u64::from(u32::MAX)) {
+            return Err(AppError::ConfigValidation(format!("ttl must be between 1 and {} seconds", u32::MAX)));
+        }
+        let net = net.trunc();
+        let set_name = self.blocklist_set_for(&net)?;
+        let now = unix_now();
+        let entry = BlocklistEntry { net, added: now, expires: ttl.map(|ttl| now + ttl), reason };
+
+        let mut blocklist = self.blocklist.lock().await;
+        // Interval sets reject overlapping elements
+        if let Some(overlapping) = blocklist.active(now).into_iter()
+            .find(|existing| existing.net != net && (existing.net.contains(&net) || net.contains(&existing.net)))
+        {
+            return Err(AppError::ConfigValidation(format!(
+                "{} overlaps the blocked {}; unblock it first",
+                net, overlapping.net
+            )));
+        }
+        // Added, deleted and added again, so an existing element gets the new timeout
+        let mut batch = Batch::new();
+        batch.add(NfListObject::Element(self.net_element(&set_name, [&net])));
+        batch.delete(NfListObject::Element(self.net_element(&set_name, [&net])));
+        batch.add(NfListObject::Element(self.blocklist_element(&set_name, &[&entry], now)));
+        self.commit(&batch.to_nftables())?;
+        blocklist.insert(entry.clone());
+        blocklist.save(now)?;
+        info!(
+            "[NFTABLES-RS] Blocked {}{} ({})",
+            net,
+            ttl.map(|ttl| format!(" for {}s", ttl)).unwrap_or_default(),
+            entry.reason.as_deref().unwrap_or("no reason given")
+        );
+        Ok(entry)
+    }
+
+    /// Removes the entry for `net`, returning it; `None` if it was not blocked.
+    pub async fn unblock(&self, net: IpNet) -> Result<Option<BlocklistEntry>, AppError> {
+        let net = net.trunc();
+        let now = unix_now();
+        let mut blocklist = self.blocklist.lock().await;
+        let Some(entry) = blocklist.active(now).into_iter().find(|entry| entry.net == net) else {
+            return Ok(None);
+        };
+        let set_name = self.blocklist_set_for(&net)?;
+        // Added first, so the delete also works if the element just expired
+        let mut batch = Batch::new();
+        batch.add(NfListObject::Element(self.net_element(&set_name, [&net])));
+        batch.delete(NfListObject::Element(self.net_element(&set_name, [&net])));
+        self.commit(&batch.to_nftables())?;
+        blocklist.remove(&net);
+        blocklist.save(now)?;
+        info!("[NFTABLES-RS] Unblocked {}", net);
+        Ok(Some(entry))
+    }
+
+    /// Unexpired blocklist entries, sorted by network.
+    pub async fn blocklist(&self) -> Vec<BlocklistEntry> {
+        self.blocklist.lock().await.active(unix_now())
+    }
+
+    /// The blocklist set holding `net`, if the table carries its family.
+    fn blocklist_set_for(&self, net: &IpNet) -> Result<String, AppError> {
+        let wanted = if matches!(net, IpNet::V6(_)) { SetType::Ipv6Addr } else { SetType::Ipv4Addr };
+        blocklist_set_names(&self.settings).into_iter()
+            .find(|(_, set_type)| *set_type == wanted)
+            .map(|(set_name, _)| set_name)
+            .ok_or_else(|| AppError::ConfigValidation(format!(
+                "The nftables table family cannot hold {}", net
+            )))
+    }
+
+    /// Elements of a blocklist set, timing out with their entries.
+    fn blocklist_element(&self, set_name: &str, entries: &[&BlocklistEntry], now: u64) -> Element<'_> {
+        let mut element = self.net_element(set_name, entries.iter().map(|entry| &entry.net));
+        let elem = element.elem.to_mut();
+        for (expr, entry) in elem.iter_mut().zip(entries) {
+            if let Some(remaining) = entry.remaining(now) {
+                *expr = Expression::Named(NamedExpression::Elem(Elem {
+                    val: Box::new(expr.clone()),
+                    timeout: Some(u32::try_from(remaining).unwrap_or(u32::MAX)),
+                    expires: None,
+                    comment: None,
+                    counter: None,
+                }));
+            }
+        }
+        element
+    }
+
+    /// Adds a named counter per zone and direction, and the `accounting_in` /
+    /// `accounting_out` chains (prerouting / postrouting hooks, policy accept)
+    /// that count the zone's traffic into them. Interface zones match on the
+    /// interface names, the others on their address sets.
+    fn add_zone_counters<'a>(&'a self, batch: &mut Batch<'a>, zones: &BTreeSet<String>, interfaces: &[InterfaceConfig]) {
+        let chains = [
+            (ACCOUNTING_IN_CHAIN, NfHook::Prerouting, ACCOUNTING_IN_PRIORITY, COUNTER_IN, MetaKey::Iifname, "saddr"),
+            (ACCOUNTING_OUT_CHAIN, NfHook::Postrouting, ACCOUNTING_OUT_PRIORITY, COUNTER_OUT, MetaKey::Oifname, "daddr"),
+        ];
+        for zone in zones {
+            for (_, _, _, direction, _, _) in &chains {
+                batch.add(NfListObject::Counter(self.counter_ref(&zone_counter_name(zone, direction))));
+            }
+        }
+        for (chain_name, hook, prio, direction, key, field) in chains {
+            batch.add(NfListObject::Chain(Chain {
+                _type: Some(NfChainType::Filter),
+                hook: Some(hook),
+                prio: Some(prio),
+                policy: Some(NfChainPolicy::Accept),
+                ..self.chain_ref(chain_name)
+            }));
+            batch.add_cmd(NfCmd::Flush(FlushObject::Chain(self.chain_ref(chain_name))));
+            for zone in zones {
+                let counter = Statement::Counter(CounterRef::Named(Cow::Owned(zone_counter_name(zone, direction))));
+                let matches = match zone_match(zone, interfaces) {
+                    ZoneMatch::Interfaces(names) => vec![match_expr(
+                        meta(key),
+                        Operator::EQ,
+                        anonymous_set(names.into_iter().map(|n| Expression::String(n.into())).collect()),
+                    )],
+                    _ => zone_set_names(&self.settings, zone).into_iter()
+                        .map(|(set_name, set_type)| {
+                            let protocol = if set_type == SetType::Ipv6Addr { "ip6" } else { "ip" };
+                            match_expr(payload(protocol, field), Operator::EQ, Expression::String(format!("@{}", set_name).into()))
+                        })
+                        .collect(),
+                };
+                for matched in matches {
+                    batch.add(NfListObject::Rule(self.rule(
+                        chain_name,
+                        vec![matched, counter.clone()],
+                        Some(OWNER_COMMENT.to_string()),
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Named counter in the managed table (values are kept when it already exists).
+    fn counter_ref(&self, counter_name: &str) -> Counter<'_> {
+        Counter {
+            family: self.family,
+            table: Cow::Borrowed(&self.settings.table),
+            name: Cow::Owned(counter_name.to_string()),
+            handle: None,
+            packets: Some(0),
+            bytes: Some(0),
+        }
+    }
+
+    /// Rule in one of the managed chains.
+    fn rule(&self, chain_name: &'static str, expr: Vec<Statement<'static>>, comment: Option<String>) -> Rule<'_> {
+        Rule {
+            family: self.family,
+            table: Cow::Borrowed(&self.settings.table),
+            chain: Cow::Borrowed(chain_name),
+            expr: Cow::Owned(expr),
+            comment: comment.map(Cow::Owned),
+            ..Default::default()
+        }
+    }
+
+    /// Chain in the managed table, identified by name only (for flush and delete commands).
+    fn chain_ref(&self, chain_name: &str) -> Chain<'_> {
+        Chain {
+            family: self.family,
+            table: Cow::Borrowed(&self.settings.table),
+            name: Cow::Owned(chain_name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Minimal set definition identifying a managed set (used for flush commands).
+    fn set_ref(&self, set_name: &str, set_type: SetType) -> Set<'_> {
+        Set {
+            family: self.family,
+            table: Cow::Borrowed(&self.settings.table),
+            name: Cow::Owned(set_name.to_string()),
+            handle: None,
+            set_type: SetTypeValue::Single(set_type),
+            policy: None,
+            flags: None,
+            comment: None,
+            elem: None,
+            gc_interval: None,
+            size: None,
+            timeout: None,
+        }
+    }
+
+    /// Element list for a subnet set; host routes are added as plain addresses.
+    fn net_element<'n>(&self, set_name: &str, nets: impl IntoIterator<Item = &'n IpNet>) -> Element<'_> {
+        let elem = nets.into_iter().map(|net| {
+            let addr = Expression::String(net.addr().to_string().into());
+            if net.prefix_len() == net.max_prefix_len() {
+                addr
+            } else {
+                Expression::Named(NamedExpression::Prefix(Prefix {
+                    addr: Box::new(addr),
+                    len: u32::from(net.prefix_len()),
+                }))
+            }
+        }).collect();
+        Element {
+            family: self.family,
+            table: Cow::Borrowed(&self.settings.table),
+            name: Cow::Owned(set_name.to_string()),
+            elem: Cow::Owned(elem),
+        }
+    }
+
+    /// Element list for a MAC set; pins become `ip . mac` concatenations.
+    fn mac_element<'m>(&self, set_name: &str, macs: impl IntoIterator<Item = &'m MacElement>) -> Element<'_> {
+        let elem = macs.into_iter().map(|element| match element {
+            MacElement::Mac(mac) => Expression::String(mac.to_string().into()),
+            MacElement::Pin(ip, mac) => Expression::Named(NamedExpression::Concat(vec![
+                Expression::String(ip.to_string().into()),
+                Expression::String(mac.to_string().into()),
+            ])),
+        }).collect();
+        Element {
+            family: self.family,
+            table: Cow::Borrowed(&self.settings.table),
+            name: Cow::Owned(set_name.to_string()),
+            elem: Cow::Owned(elem),
+        }
+    }
+
+    /// Element list for a managed set.
+    fn element(&self, set_name: &str, ips: &[IpAddr]) -> Element<'_> {
+        Element {
+            family: self.family,
+            table: Cow::Borrowed(&self.settings.table),
+            name: Cow::Owned(set_name.to_string()),
+            elem: Cow::Owned(ips.iter().map(|ip| Expression::String(ip.to_string().into())).collect()),
+        }
+    }
+}
+
+/// Expands the `match:` entries of `interfaces` into one entry per selected link,
+/// named after it. A link named by an exact entry, or selected by an earlier
+/// `match:` entry, is not selected again.
+pub fn resolve_interfaces(interfaces: &[InterfaceConfig], links: &BTreeMap<String, LinkDetails>) -> Vec<InterfaceConfig> {
+    let mut taken: HashSet<&str> = interfaces.iter()
+        .filter(|iface| iface.matcher.is_none())
+        .map(|iface| iface.name.as_str())
+        .collect();
+    let mut resolved = Vec::new();
+    for iface in interfaces {
+        let Some(matcher) = &iface.matcher else {
+            resolved.push(iface.clone());
+            continue;
+        };
+        for (name, details) in links {
+            if matcher.matches(name, details) && taken.insert(name) {
+                resolved.push(InterfaceConfig { name: name.clone(), matcher: None, ..iface.clone() });
+            }
+        }
+    }
+    resolved
+}
+
+/// The interfaces the zone sets are computed from: `resolve_interfaces` plus the
+/// `match:` entries themselves. Those have no name, so no addresses, but keep
+/// the sets of a zone whose links all went away managed, and emptied.
+fn set_interfaces(interfaces: &[InterfaceConfig], links: &BTreeMap<String, LinkDetails>) -> Vec<InterfaceConfig> {
+    let mut resolved = resolve_interfaces(interfaces, links);
+    resolved.extend(interfaces.iter().filter(|iface| iface.matcher.is_some()).cloned());
+    resolved
+}
+
+/// Maps every interface zone to its networks: the on-link prefixes reported by
+/// the network monitor plus the networks of configured static addresses, and
+/// for `routed_nets` zones the destinations routed through their interfaces.
+fn compute_zone_nets(
+    interfaces: &[InterfaceConfig],
+    zones: &HashMap<String, ZoneConfig>,
+    network_state: &NetworkState,
+) -> HashMap<String, Vec<IpNet>> {
+    let mut zone_to_nets: HashMap<String, Vec<IpNet>> = HashMap::new();
+    for interface_config in interfaces {
+        if let Some(zone) = &interface_config.nftables_zone {
+            let zone_nets = zone_to_nets.entry(zone.clone()).or_default();
+            if let Some(prefixes) = network_state.interface_prefixes.get(&interface_config.name) {
+                zone_nets.extend(prefixes.iter().map(|net| net.trunc()));
+            }
+            zone_nets.extend(interface_config.static_prefix());
+            if zones.get(zone).is_some_and(|zone_config| zone_config.routed_nets) {
+                // A default route would put the whole address space into the zone
+                zone_nets.extend(network_state.routes.iter()
+                    .filter(|route| !route.is_default() && route.interface.as_ref() == Some(&interface_config.name))
+                    .map(|route| route.destination));
+            }
+        }
+    }
+    zone_to_nets
+}
+
+/// Splits zone networks into the per-family `<zone>_nets` / `<zone>_nets6` sets.
+///
+/// Overlapping and adjacent networks are merged, since an interval set rejects
+/// overlapping elements.
+fn zone_net_elements(zone_to_nets: &HashMap<String, Vec<IpNet>>, settings: &NftablesConfig) -> NetSets {
+    let mut sets = BTreeMap::new();
+    for (zone_name, nets) in zone_to_nets {
+        for (set_name, set_type) in zone_net_set_names(settings, zone_name) {
+            let family_nets: Vec<IpNet> = nets.iter()
+                .filter(|net| matches!(net, IpNet::V4(_)) == (set_type == SetType::Ipv4Addr))
+                .copied()
+                .collect();
+            sets.insert(set_name, (set_type, IpNet::aggregate(&family_nets).into_iter().collect()));
+        }
+    }
+    sets
+}
+
+/// Subnet sets of a zone for the families the managed table carries.
+fn zone_net_set_names(settings: &NftablesConfig, zone_name: &str) -> Vec<(String, SetType)> {
+    let mut sets = Vec::new();
+    if settings.family.has_ipv4() {
+        sets.push((settings.ipv4_net_set_name(zone_name), SetType::Ipv4Addr));
+    }
+    if settings.family.has_ipv6() {
+        sets.push((settings.ipv6_net_set_name(zone_name), SetType::Ipv6Addr));
+    }
+    sets
+}
+
+/// Maps every configured zone (plus the reserved "docker" zone) to its current IPs.
+///
+/// Zones without any address are still present with an empty set, so that
+/// addresses disappearing from a zone are removed from its sets. Interface
+/// addresses failing the zone's address filters are left out; addresses the
+/// network monitor reported no details for pass them.
+fn compute_zone_ips(
+    interfaces: &[InterfaceConfig],
+    zones: &HashMap<String, ZoneConfig>,
+    network_state: &NetworkState,
+    container_ips: &HashMap<String, IpAddr>,
+) -> HashMap<String, HashSet<IpAddr>> {
+    let mut zone_to_ips: HashMap<String, HashSet<IpAddr>> = HashMap::new();
+    for interface_config in interfaces {
+        if let Some(zone) = &interface_config.nftables_zone {
+            let zone_config = zones.get(zone).filter(|zone_config| zone_config.filters_addresses());
+            let details = network_state.interface_addresses.get(&interface_config.name);
+            let admitted = |ip: &IpAddr| match (zone_config, details) {
+                (Some(zone_config), Some(details)) => details.iter()
+                    .find(|address| address.address == *ip)
+                    .is_none_or(|address| zone_config.admits(address)),
+                _ => true,
+            };
+            let zone_ips = zone_to_ips.entry(zone.clone()).or_default();
+            if let Some(ips) = network_state.interface_ips.get(&interface_config.name) {
+                zone_ips.extend(ips.iter().copied().filter(admitted));
+            }
+        }
+    }
 
-/// Manages nftables rules using the nftables-rs crate
-pub struct NftablesManager {
-    #[allow(dead_code)] // Allow config field to be unused for now
-    config: Arc<AsyncMutex<Vec<InterfaceConfig>>>,
-    table_name: String,
+    // Merge container IPs into the reserved "docker" zone
+    zone_to_ips
+        .entry("docker".to_string())
+        .or_default()
+        .extend(container_ips.values().copied());
+    zone_to_ips
 }
 
-impl NftablesManager {
-    /// Create a new NftablesManager instance
-    pub async fn new(config: Arc<AsyncMutex<Vec<InterfaceConfig>>>) -> Result<Self, AppError> {
-        let manager = Self {
-            config,
-            table_name: "filter".to_string(),
-        };
-        Ok(manager)
+/// Maps the zone of every `docker_sets:` network and label selector to the
+/// addresses of its containers: those on the network, or all addresses of the
+/// containers matching the selector.
+fn compute_docker_set_ips(
+    docker_sets: &DockerSetsConfig,
+    container_details: &BTreeMap<String, ContainerDetails>,
+) -> HashMap<String, HashSet<IpAddr>> {
+    let mut zone_to_ips: HashMap<String, HashSet<IpAddr>> = HashMap::new();
+    for network in &docker_sets.networks {
+        let ips = zone_to_ips.entry(DockerSetsConfig::zone_name(network)).or_default();
+        for details in container_details.values() {
+            ips.extend(details.networks.get(network).into_iter().flatten().copied());
+        }
+    }
+    for (name, selector) in &docker_sets.labels {
+        let ips = zone_to_ips.entry(DockerSetsConfig::zone_name(name)).or_default();
+        for details in container_details.values().filter(|details| selector.matches(&details.labels)) {
+            ips.extend(details.networks.values().flatten().copied());
+        }
     }
+    zone_to_ips
+}
 
-    /// Ensures the base nftables structure exists (inet table)
-    pub async fn load_rules(&self) -> Result<(), AppError> {
-        info!("[NFTABLES-RS] Ensuring base nftables structure");
-        let mut batch = Batch::new();
+/// Splits zone IPs into the per-family `<zone>_ips` / `<zone>_ipv6` sets.
+///
+/// Sets for a family the managed table does not carry are left out.
+fn zone_set_elements(
+    zone_to_ips: &HashMap<String, HashSet<IpAddr>>,
+    settings: &NftablesConfig,
+) -> BTreeMap<String, (SetType, HashSet<IpAddr>)> {
+    let mut sets = BTreeMap::new();
+    for (zone_name, ips) in zone_to_ips {
+        for (set_name, set_type) in zone_set_names(settings, zone_name) {
+            let family_ips = ips.iter()
+                .filter(|ip| ip.is_ipv4() == (set_type == SetType::Ipv4Addr))
+                .copied()
+                .collect();
+            sets.insert(set_name, (set_type, family_ips));
+        }
+    }
+    sets
+}
 
-        // 1. Ensure Table Exists
-        batch.add(NfListObject::Table(Table {
-            family: NfFamily::INet,
-            name: Cow::Borrowed(&self.table_name),
-            handle: None, // Explicitly set handle if necessary, often optional
-        }));
+/// Address sets of a zone for the families the managed table carries.
+fn zone_set_names(settings: &NftablesConfig, zone_name: &str) -> Vec<(String, SetType)> {
+    let mut sets = Vec::new();
+    if settings.family.has_ipv4() {
+        sets.push((settings.ipv4_set_name(zone_name), SetType::Ipv4Addr));
+    }
+    if settings.family.has_ipv6() {
+        sets.push((settings.ipv6_set_name(zone_name), SetType::Ipv6Addr));
+    }
+    sets
+}
 
-        // 2. Calculate unique zones from config
-        let config_lock = self.config.lock().await;
-        let unique_zones: HashSet<String> = config_lock.iter()
-            .filter_map(|iface| iface.nftables_zone.clone())
-            .collect();
-        // Drop the lock explicitly after use
-        drop(config_lock);
+/// Fills the `<zone>_peers` / `<zone>_peers6` sets of every interface zone
+/// with the live peers (usable neighbor table entries) on its interfaces.
+fn zone_peer_elements(
+    interfaces: &[InterfaceConfig],
+    network_state: &NetworkState,
+    settings: &NftablesConfig,
+) -> BTreeMap<String, (SetType, HashSet<IpAddr>)> {
+    let mut sets: BTreeMap<String, (SetType, HashSet<IpAddr>)> = BTreeMap::new();
+    for interface_config in interfaces {
+        let Some(zone) = &interface_config.nftables_zone else { continue };
+        let peers = network_state.peers.get(&interface_config.name);
+        for (set_name, set_type) in zone_peer_set_names(settings, zone) {
+            let live = peers.into_iter().flat_map(|peers| peers.values())
+                .filter(|peer| peer.live_mac().is_some() && peer.ip.is_ipv4() == (set_type == SetType::Ipv4Addr))
+                .map(|peer| peer.ip);
+            sets.entry(set_name).or_insert_with(|| (set_type, HashSet::new())).1.extend(live);
+        }
+    }
+    sets
+}
 
-        // 3. Ensure Sets Exist for each unique zone, plus the built-in "docker" zone
-        let mut all_zones = unique_zones;
-        all_zones.insert("docker".to_string());
-        for zone_name in all_zones {
-            // --- IPv4 Set Definition ---
-            let ipv4_set_name = format!("{}_ips", zone_name);
-            batch.add(NfListObject::Set(Box::new(Set {
-                family: NfFamily::INet,
-                table: Cow::Borrowed(&self.table_name),
-                name: Cow::Owned(ipv4_set_name),
-                handle: None,
-                set_type: nftables::schema::SetTypeValue::Single(nftables::schema::SetType::Ipv4Addr),
-                policy: None,
-                flags: Some(HashSet::from([nftables::schema::SetFlag::Dynamic])),
-                comment: None,
-                elem: None,
-                gc_interval: None,
-                size: None,
-                timeout: None,
-            })));
+/// Peer sets of a zone for the families the managed table carries.
+fn zone_peer_set_names(settings: &NftablesConfig, zone_name: &str) -> Vec<(String, SetType)> {
+    let mut sets = Vec::new();
+    if settings.family.has_ipv4() {
+        sets.push((settings.ipv4_peer_set_name(zone_name), SetType::Ipv4Addr));
+    }
+    if settings.family.has_ipv6() {
+        sets.push((settings.ipv6_peer_set_name(zone_name), SetType::Ipv6Addr));
+    }
+    sets
+}
 
-            // --- IPv6 Set Definition ---
-            let ipv6_set_name = format!("{}_ipv6", zone_name);
-            batch.add(NfListObject::Set(Box::new(Set {
-                family: NfFamily::INet,
-                table: Cow::Borrowed(&self.table_name),
-                name: Cow::Owned(ipv6_set_name),
-                handle: None,
-                set_type: nftables::schema::SetTypeValue::Single(nftables::schema::SetType::Ipv6Addr),
-                policy: None,
-                flags: Some(HashSet::from([nftables::schema::SetFlag::Dynamic])),
-                comment: None,
-                elem: None,
-                gc_interval: None,
-                size: None,
-                timeout: None,
-            })));
+/// Fills the MAC sets of every zone listing or learning MAC addresses.
+///
+/// `<zone>_macs` holds the configured `macs`, the MACs of `mac_pins` and, with
+/// `learn_macs`, those of the live peers on the zone's interfaces. The pin
+/// sets hold the learned IP-to-MAC pairs, overridden by the static `mac_pins`.
+fn compute_zone_macs(
+    interfaces: &[InterfaceConfig],
+    zones: &HashMap<String, ZoneConfig>,
+    network_state: &NetworkState,
+    settings: &NftablesConfig,
+) -> MacSets {
+    let mut sets = MacSets::new();
+    for (zone_name, zone) in zones {
+        let mut pins: BTreeMap<IpAddr, MacAddr> = BTreeMap::new();
+        if zone.learn_macs {
+            for interface_config in interfaces {
+                if interface_config.nftables_zone.as_deref() != Some(zone_name.as_str()) {
+                    continue;
+                }
+                if let Some(peers) = network_state.peers.get(&interface_config.name) {
+                    pins.extend(peers.values().filter_map(|peer| Some((peer.ip, peer.live_mac()?))));
+                }
+            }
         }
+        pins.extend(zone.mac_pins.iter().map(|(ip, mac)| (*ip, *mac)));
+        for (set_name, set_type) in zone_mac_set_names(settings, zone_name, zone) {
+            let elements = match &set_type {
+                SetTypeValue::Single(_) => zone.macs.iter().chain(pins.values())
+                    .map(|mac| MacElement::Mac(*mac))
+                    .collect(),
+                SetTypeValue::Concatenated(types) => pins.iter()
+                    .filter(|(ip, _)| ip.is_ipv4() == (types[0] == SetType::Ipv4Addr))
+                    .map(|(ip, mac)| MacElement::Pin(*ip, *mac))
+                    .collect(),
+            };
+            sets.insert(set_name, (set_type, elements));
+        }
+    }
+    sets
+}
 
-        let ruleset = batch.to_nftables();
-        debug!("[NFTABLES-RS] Load ruleset generated: {:?}", ruleset);
+/// MAC sets of a zone: `<zone>_macs` (`ether_addr`) and, if it pins addresses,
+/// `<zone>_ip_macs` / `<zone>_ip6_macs` (`ipv4_addr . ether_addr` and
+/// `ipv6_addr . ether_addr`) for the families the managed table carries,
+/// named by the MAC set templates.
+fn zone_mac_set_names(settings: &NftablesConfig, zone_name: &str, zone: &ZoneConfig) -> Vec<(String, SetTypeValue<'static>)> {
+    let mut sets = Vec::new();
+    if !zone.has_macs() {
+        return sets;
+    }
+    sets.push((settings.mac_set_name(zone_name), SetTypeValue::Single(SetType::EtherAddr)));
+    if zone.pins_macs() {
+        if settings.family.has_ipv4() {
+            let set_type = Cow::Borrowed(&[SetType::Ipv4Addr, SetType::EtherAddr][..]);
+            sets.push((settings.ipv4_mac_pin_set_name(zone_name), SetTypeValue::Concatenated(set_type)));
+        }
+        if settings.family.has_ipv6() {
+            let set_type = Cow::Borrowed(&[SetType::Ipv6Addr, SetType::EtherAddr][..]);
+            sets.push((settings.ipv6_mac_pin_set_name(zone_name), SetTypeValue::Concatenated(set_type)));
+        }
+    }
+    sets
+}
 
-        // Use the helper error type from nftables::helper
-        // apply_ruleset takes only one argument
-        helper::apply_ruleset(&ruleset).map_err(AppError::NftablesError)?;
+/// Ban sets of a zone (`<zone>_ban` / `<zone>_ban6` by default) for the families the managed table carries.
+fn ban_set_names(settings: &NftablesConfig, zone_name: &str) -> Vec<(String, SetType)> {
+    let mut sets = Vec::new();
+    if settings.family.has_ipv4() {
+        sets.push((settings.ipv4_ban_set_name(zone_name), SetType::Ipv4Addr));
+    }
+    if settings.family.has_ipv6() {
+        sets.push((settings.ipv6_ban_set_name(zone_name), SetType::Ipv6Addr));
+    }
+    sets
+}
 
-        info!("[NFTABLES-RS] Base table '{}' and required sets ensured.", self.table_name);
-        Ok(())
+/// The blocklist sets (`blocklist` / `blocklist6` by default) for the families the managed table carries.
+fn blocklist_set_names(settings: &NftablesConfig) -> Vec<(String, SetType)> {
+    let mut sets = Vec::new();
+    if settings.family.has_ipv4() {
+        sets.push((settings.ipv4_blocklist_set_name(), SetType::Ipv4Addr));
     }
+    if settings.family.has_ipv6() {
+        sets.push((settings.ipv6_blocklist_set_name(), SetType::Ipv6Addr));
+    }
+    sets
+}
 
-    /// Apply rules based on the current network state and tracked container IPs.
-    ///
-    /// Container IPs are collected into a dedicated `docker_ips` / `docker_ipv6` set.
-    /// Passing an empty map disables docker set population without error.
-    pub async fn apply_rules(
-        &self,
-        network_state: &NetworkState,
-        container_ips: &HashMap<String, IpAddr>,
-    ) -> Result<(), AppError> {
-         info!("[NFTABLES-RS] Applying nftables rules (flush and add elements)");
-
-         // Calculate zone_to_ips based on current network state and config
-         let config_lock = self.config.lock().await;
-         let mut zone_to_ips: HashMap<String, HashSet<IpAddr>> = HashMap::new();
-         for interface_config in config_lock.iter() {
-             if let Some(zone) = &interface_config.nftables_zone {
-                 if let Some(ips) = network_state.interface_ips.get(&interface_config.name) {
-                     let zone_ips = zone_to_ips.entry(zone.clone()).or_default();
-                     for ip in ips {
-                         zone_ips.insert(*ip);
-                     }
-                 }
-             }
-         }
-         drop(config_lock);
+/// Reads the blocklist file; an unreadable one is replaced by an empty blocklist.
+fn load_blocklist(path: &Path) -> Blocklist {
+    match Blocklist::load(path) {
+        Ok(blocklist) => blocklist,
+        Err(e) => {
+            error!("[NFTABLES-RS] Ignoring blocklist '{}': {}", path.display(), e);
+            // The file is still in place if it could not be read or moved aside;
+            // when its existence cannot even be checked, assume it is
+            if !matches!(path.try_exists(), Ok(false)) {
+                Blocklist::unsaved(path)
+            } else {
+                Blocklist::empty(path)
+            }
+        }
+    }
+}
 
-         // Merge container IPs into the reserved "docker" zone
-         if !container_ips.is_empty() {
-             let docker_zone = zone_to_ips.entry("docker".to_string()).or_default();
-             for ip in container_ips.values() {
-                 docker_zone.insert(*ip);
-             }
-         }
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
 
+/// Names of the base chains generated from the `policy:` section.
+/// Prefixed, so they do not meet the `input` / `forward` / `output` chains of a
+/// distribution's own `inet filter` table.
+const POLICY_INPUT_CHAIN: &str = "policy_input";
+const POLICY_FORWARD_CHAIN: &str = "policy_forward";
+const POLICY_OUTPUT_CHAIN: &str = "policy_output";
+const ACCOUNTING_IN_CHAIN: &str = "accounting_in";
+const ACCOUNTING_OUT_CHAIN: &str = "accounting_out";
+/// Before conntrack and NAT on the way in, after NAT on the way out.
+const ACCOUNTING_IN_PRIORITY: i32 = -250;
+const ACCOUNTING_OUT_PRIORITY: i32 = 250;
+const COUNTER_IN: &str = "in";
+const COUNTER_OUT: &str = "out";
+const NAT_PREROUTING_CHAIN: &str = "prerouting";
+const NAT_POSTROUTING_CHAIN: &str = "postrouting";
+/// Standard `dstnat` / `srcnat` priorities.
+const NAT_PREROUTING_PRIORITY: i32 = -100;
+const NAT_POSTROUTING_PRIORITY: i32 = 100;
+const FLOWTABLE_NAME: &str = "offload";
+const FLOWTABLE_PRIORITY: u32 = 0;
+const OFFLOAD_CHAIN: &str = "offload";
+/// After the policy `forward` chain, so only accepted connections are offloaded.
+const OFFLOAD_PRIORITY: i32 = 10;
+const BLOCKLIST_CHAIN: &str = "blocklist";
+/// Standard `raw` priority: blocked traffic never reaches conntrack.
+const BLOCKLIST_PRIORITY: i32 = -300;
+const LIMITS_CHAIN: &str = "limits";
+/// Conntrack mark bit set by the managed port forwards, so the policy `forward`
+/// chain accepts the connections they DNAT and no others.
+const PORT_FORWARD_MARK: u32 = 0x0040_0000;
+/// Standard `mangle` priority: after conntrack, before DNAT.
+const LIMITS_PRIORITY: i32 = -150;
+/// Every chain the daemon creates; one of these names held by a chain without
+/// the owner comment is left alone.
+const MANAGED_CHAINS: [&str; 10] = [
+    POLICY_INPUT_CHAIN,
+    POLICY_FORWARD_CHAIN,
+    POLICY_OUTPUT_CHAIN,
+    ACCOUNTING_IN_CHAIN,
+    ACCOUNTING_OUT_CHAIN,
+    NAT_PREROUTING_CHAIN,
+    NAT_POSTROUTING_CHAIN,
+    OFFLOAD_CHAIN,
+    BLOCKLIST_CHAIN,
+    LIMITS_CHAIN,
+];
 
-         // --- Flushing Phase --- Execute Flush commands directly
-         let mut flush_commands: Vec<NfObject> = Vec::new();
-         for zone_name in zone_to_ips.keys() {
-              // --- IPv4: Flush Set --- //
-              let ipv4_set_name = format!("{}_ips", zone_name);
-              // Use NfObject::CmdObject with NfCmd::Flush(FlushObject::Set(...))
-              // FlushObject::Set is a tuple variant expecting Box<Set>
-              let set_to_flush_v4 = Box::new(Set {
-                   family: NfFamily::INet,
-                   table: Cow::Borrowed(&self.table_name),
-                   name: Cow::Owned(ipv4_set_name),
-                   // Only include fields needed for identification
-                   handle: None,
-                   set_type: nftables::schema::SetTypeValue::Single(nftables::schema::SetType::Ipv4Addr),
-                   policy: None,
-                   flags: None,
-                   comment: None,
-                   elem: None,
-                   gc_interval: None,
-                   size: None,
-                   timeout: None,
-              });
-              flush_commands.push(NfObject::CmdObject(NfCmd::Flush(FlushObject::Set(set_to_flush_v4))));
-
-              // --- IPv6: Flush Set --- //
-              let ipv6_set_name = format!("{}_ipv6", zone_name);
-              let set_to_flush_v6 = Box::new(Set {
-                   family: NfFamily::INet,
-                   table: Cow::Borrowed(&self.table_name),
-                   name: Cow::Owned(ipv6_set_name),
-                   handle: None,
-                   set_type: nftables::schema::SetTypeValue::Single(nftables::schema::SetType::Ipv6Addr),
-                   policy: None,
-                   flags: None,
-                   comment: None,
-                   elem: None,
-                   gc_interval: None,
-                   size: None,
-                   timeout: None,
-              });
-              flush_commands.push(NfObject::CmdObject(NfCmd::Flush(FlushObject::Set(set_to_flush_v6))));
-         }
+/// How traffic belonging to a zone is recognised in a rule.
+enum ZoneMatch {
+    /// No restriction (`any`, or `local` which is expressed by the chain choice).
+    Any,
+    /// Zones bound to interfaces match on the interface name.
+    Interfaces(Vec<String>),
+    /// Zones without interfaces (e.g. `docker`) match on their address sets.
+    Addresses(String),
+}
 
-         // Apply flush commands if any
-         if !flush_commands.is_empty() {
-            let flush_nftables = Nftables { objects: Cow::Owned(flush_commands) };
-            debug!("[NFTABLES-RS] Flush commands generated: {:?}", flush_nftables);
-            // apply_ruleset takes only one argument
-            helper::apply_ruleset(&flush_nftables).map_err(AppError::NftablesError)?;
-            info!("[NFTABLES-RS] Successfully flushed nftables sets");
-         } else {
-            info!("[NFTABLES-RS] No sets to flush.");
-         }
+fn zone_match(zone: &str, interfaces: &[InterfaceConfig]) -> ZoneMatch {
+    if zone == ANY_ZONE || zone == LOCAL_ZONE {
+        return ZoneMatch::Any;
+    }
+    let names: Vec<String> = interfaces.iter()
+        .filter(|iface| iface.nftables_zone.as_deref() == Some(zone))
+        .map(|iface| iface.name.clone())
+        .collect();
+    if names.is_empty() {
+        ZoneMatch::Addresses(zone.to_string())
+    } else {
+        ZoneMatch::Interfaces(names)
+    }
+}
 
+/// Traffic to `local` is filtered in `policy_input`, traffic from `local` in
+/// `policy_output`, everything else in `policy_forward`.
+fn policy_rule_chain(rule: &PolicyRule) -> &'static str {
+    if rule.to == LOCAL_ZONE {
+        POLICY_INPUT_CHAIN
+    } else if rule.from == LOCAL_ZONE {
+        POLICY_OUTPUT_CHAIN
+    } else {
+        POLICY_FORWARD_CHAIN
+    }
+}
 
-         // --- Adding Elements Phase ---
-         let mut add_batch = Batch::new();
-         for (zone_name, ips) in zone_to_ips {
-             // --- IPv4: Add Elements --- //
-             let ipv4_set_name = format!("{}_ips", zone_name);
-             let ipv4_elements: Vec<Element> = ips.iter()
-                 .filter_map(|ip| match ip {
-                     IpAddr::V4(v4) => Some(Element {
-                         family: NfFamily::INet,
-                         table: Cow::Borrowed(&self.table_name),
-                         name: Cow::Owned(ipv4_set_name.clone()),
-                         elem: Cow::Owned(vec![Expression::String(v4.to_string().into())]),
-                     }),
-                     _ => None,
-                 })
-                 .collect();
+/// Compiles one policy rule into the statement lists of the nftables rules implementing it.
+///
+/// Address-based zones need one rule per IP family, so a single policy rule can
+/// expand into two nftables rules.
+fn compile_policy_rule(
+    rule: &PolicyRule,
+    interfaces: &[InterfaceConfig],
+    settings: &NftablesConfig,
+) -> Vec<Vec<Statement<'static>>> {
+    let from = zone_match(&rule.from, interfaces);
+    let to = zone_match(&rule.to, interfaces);
+    let per_family = matches!(from, ZoneMatch::Addresses(_)) || matches!(to, ZoneMatch::Addresses(_));
+    let families: Vec<Option<bool>> = if per_family {
+        [(settings.family.has_ipv4(), false), (settings.family.has_ipv6(), true)]
+            .into_iter()
+            .filter(|(present, _)| *present)
+            .map(|(_, ipv6)| Some(ipv6))
+            .collect()
+    } else {
+        vec![None]
+    };
 
-             if !ipv4_elements.is_empty() {
-                 add_batch.add(NfListObject::Element(Element {
-                    family: NfFamily::INet,
-                    table: Cow::Borrowed(&self.table_name),
-                    name: Cow::Owned(ipv4_set_name),
-                    elem: Cow::Owned(ipv4_elements.into_iter().flat_map(|e| e.elem.into_owned()).collect()),
-                }));
-             }
+    let mut rules = Vec::new();
+    for ipv6 in families {
+        // ICMP and ICMPv6 rules only make sense for their own family
+        match (rule.protocol, ipv6) {
+            (Some(PolicyProtocol::Icmp), Some(true)) | (Some(PolicyProtocol::Icmpv6), Some(false)) => continue,
+            _ => {}
+        }
 
+        let mut expr = Vec::new();
+        for (zone, key, field) in [(&from, MetaKey::Iifname, "saddr"), (&to, MetaKey::Oifname, "daddr")] {
+            match zone {
+                ZoneMatch::Any => {}
+                ZoneMatch::Interfaces(names) => expr.push(match_expr(
+                    meta(key),
+                    Operator::EQ,
+                    anonymous_set(names.iter().map(|n| Expression::String(n.clone().into())).collect()),
+                )),
+                ZoneMatch::Addresses(zone_name) => {
+                    let (protocol, set_name) = if ipv6 == Some(true) {
+                        ("ip6", settings.ipv6_set_name(zone_name))
+                    } else {
+                        ("ip", settings.ipv4_set_name(zone_name))
+                    };
+                    expr.push(match_expr(
+                        payload(protocol, field),
+                        Operator::EQ,
+                        Expression::String(format!("@{}", set_name).into()),
+                    ));
+                }
+            }
+        }
 
-             // --- IPv6: Add Elements --- //
-             let ipv6_set_name = format!("{}_ipv6", zone_name);
-             let ipv6_elements: Vec<Element> = ips.iter()
-                 .filter_map(|ip| match ip {
-                     IpAddr::V6(v6) => Some(Element {
-                         family: NfFamily::INet,
-                         table: Cow::Borrowed(&self.table_name),
-                         name: Cow::Owned(ipv6_set_name.clone()),
-                         elem: Cow::Owned(vec![Expression::String(v6.to_string().into())]),
-                     }),
-                     _ => None,
-                 })
-                 .collect();
+        if let Some(protocol) = rule.protocol {
+            let name = match protocol {
+                PolicyProtocol::Tcp => "tcp",
+                PolicyProtocol::Udp => "udp",
+                PolicyProtocol::Icmp => "icmp",
+                PolicyProtocol::Icmpv6 => "ipv6-icmp",
+            };
+            expr.push(match_expr(meta(MetaKey::L4proto), Operator::EQ, Expression::String(name.into())));
+            if !rule.ports.is_empty() {
+                expr.push(match_expr(
+                    payload(name, "dport"),
+                    Operator::EQ,
+                    anonymous_set(rule.ports.iter().map(|p| Expression::Number(u32::from(*p))).collect()),
+                ));
+            }
+        }
 
-             if !ipv6_elements.is_empty() {
-                 add_batch.add(NfListObject::Element(Element {
-                    family: NfFamily::INet,
-                    table: Cow::Borrowed(&self.table_name),
-                    name: Cow::Owned(ipv6_set_name),
-                    elem: Cow::Owned(ipv6_elements.into_iter().flat_map(|e| e.elem.into_owned()).collect()),
-                 }));
-             }
-         }
+        expr.push(match rule.action {
+            PolicyAction::Allow => Statement::Accept(Some(Accept {})),
+            PolicyAction::Deny => Statement::Drop(Some(Drop {})),
+            PolicyAction::Reject => Statement::Reject(Some(Reject::new(None, None))),
+        });
+        rules.push(expr);
+    }
+    rules
+}
 
-         // Check internal vector for emptiness using to_nftables() and checking the result
-         let add_ruleset = add_batch.to_nftables();
-         if !add_ruleset.objects.is_empty() {
-            debug!("[NFTABLES-RS] Add elements ruleset generated: {:?}", add_ruleset);
-            // apply_ruleset takes only one argument
-            helper::apply_ruleset(&add_ruleset).map_err(AppError::NftablesError)?;
-            info!("[NFTABLES-RS] Successfully added elements to nftables sets");
-         } else {
-             info!("[NFTABLES-RS] No elements to add.");
-         }
+/// Name of a zone's traffic counter, e.g. `zone_wan_in`.
+fn zone_counter_name(zone: &str, direction: &str) -> String {
+    format!("zone_{}_{}", zone, direction)
+}
 
-         Ok(())
+
+/// Meters of a zone's connection limits as `(name, key type, statement)`:
+/// `<zone>_newconn` (rate of new connections) and `<zone>_conns` (concurrent
+/// connections) by default, with a `6` suffix for the IPv6 ones.
+fn zone_limit_meters(zone_name: &str, zone: &ZoneConfig, settings: &NftablesConfig) -> Vec<(String, SetType, Statement<'static>)> {
+    let families = [
+        (
+            settings.family.has_ipv4(),
+            SetType::Ipv4Addr,
+            settings.ipv4_rate_meter_name(zone_name),
+            settings.ipv4_conn_meter_name(zone_name),
+        ),
+        (
+            settings.family.has_ipv6(),
+            SetType::Ipv6Addr,
+            settings.ipv6_rate_meter_name(zone_name),
+            settings.ipv6_conn_meter_name(zone_name),
+        ),
+    ];
+    let mut meters = Vec::new();
+    for (_, set_type, rate_meter, conn_meter) in families.into_iter().filter(|(carried, _, _, _)| *carried) {
+        if let Some(rate) = zone.max_new_conns_per_source {
+            meters.push((rate_meter, set_type, Statement::Limit(Limit {
+                rate: rate.count,
+                rate_unit: None,
+                per: Some(rate.per.as_str().into()),
+                burst: None,
+                burst_unit: None,
+                inv: Some(true),
+            })));
+        }
+        if let Some(max) = zone.max_conns_per_source {
+            meters.push((conn_meter, set_type, Statement::CTCount(CTCount {
+                val: Expression::Number(max),
+                inv: Some(true),
+            })));
+        }
+    }
+    meters
+}
+
+/// Compiles a zone's connection limits into rules of the `limits` chain, per IP family:
+///
+/// - `iifname { <zone interfaces> } ip saddr @<zone>_ban drop` (with `ban_timeout`)
+/// - `iifname { ... } ct state new meter <zone>_newconn { ip saddr limit rate over 50/second } [add @<zone>_ban { ip saddr }] drop`
+/// - `iifname { ... } ct state new meter <zone>_conns { ip saddr ct count over 200 } [add @<zone>_ban { ip saddr }] drop`
+///
+/// The IPv6 meters and ban set carry a `6` suffix. Zones without interfaces get no rules.
+fn compile_zone_limits(
+    zone_name: &str,
+    zone: &ZoneConfig,
+    interfaces: &[InterfaceConfig],
+    settings: &NftablesConfig,
+) -> Vec<Vec<Statement<'static>>> {
+    let ZoneMatch::Interfaces(names) = zone_match(zone_name, interfaces) else {
+        return Vec::new();
+    };
+    let iifname = match_expr(
+        meta(MetaKey::Iifname),
+        Operator::EQ,
+        anonymous_set(names.into_iter().map(|n| Expression::String(n.into())).collect()),
+    );
+    let ct_new = match_expr(
+        Expression::Named(NamedExpression::CT(CT { key: "state".into(), family: None, dir: None })),
+        Operator::IN,
+        Expression::String("new".into()),
+    );
+    let mut rules = Vec::new();
+    let meters = zone_limit_meters(zone_name, zone, settings);
+    for (ban_set, set_type) in ban_set_names(settings, zone_name) {
+        let protocol = if set_type == SetType::Ipv6Addr { "ip6" } else { "ip" };
+        let ban = zone.ban_timeout.map(|_| Statement::Set(SetStatement {
+            op: SetOp::Add,
+            elem: payload(protocol, "saddr"),
+            set: format!("@{}", ban_set).into(),
+        }));
+        if ban.is_some() {
+            rules.push(vec![
+                iifname.clone(),
+                match_expr(payload(protocol, "saddr"), Operator::EQ, Expression::String(format!("@{}", ban_set).into())),
+                Statement::Drop(Some(Drop {})),
+            ]);
+        }
+        for (meter_name, _, limit) in meters.iter().filter(|(_, meter_type, _)| *meter_type == set_type) {
+            let mut expr = vec![
+                iifname.clone(),
+                ct_new.clone(),
+                Statement::Meter(Meter {
+                    name: meter_name.clone().into(),
+                    key: payload(protocol, "saddr"),
+                    stmt: Box::new(limit.clone()),
+                }),
+            ];
+            expr.extend(ban.clone());
+            expr.push(Statement::Drop(Some(Drop {})));
+            rules.push(expr);
+        }
+    }
+    rules
+}
+
+/// Compiles a port forward into a DNAT rule:
+/// `iifname { <zone interfaces> } ip daddr @<zone>_ips meta l4proto tcp tcp dport 8080 ct mark set ct mark | 0x00400000 dnat ip to 192.168.1.10:80`.
+///
+/// Matching the zone's address set makes the rule follow address changes on the
+/// zone's interfaces. Returns `None` if the zone has no interfaces.
+fn compile_port_forward(
+    forward: &PortForward,
+    interfaces: &[InterfaceConfig],
+    settings: &NftablesConfig,
+) -> Option<Vec<Statement<'static>>> {
+    let ZoneMatch::Interfaces(names) = zone_match(&forward.from, interfaces) else {
+        return None;
+    };
+    let (protocol, set_name, family) = if forward.target.is_ipv4() {
+        ("ip", settings.ipv4_set_name(&forward.from), NATFamily::IP)
+    } else {
+        ("ip6", settings.ipv6_set_name(&forward.from), NATFamily::IP6)
+    };
+    let l4proto = forward.protocol.as_str();
+    Some(vec![
+        match_expr(
+            meta(MetaKey::Iifname),
+            Operator::EQ,
+            anonymous_set(names.into_iter().map(|n| Expression::String(n.into())).collect()),
+        ),
+        match_expr(payload(protocol, "daddr"), Operator::EQ, Expression::String(format!("@{}", set_name).into())),
+        match_expr(meta(MetaKey::L4proto), Operator::EQ, Expression::String(l4proto.into())),
+        match_expr(payload(l4proto, "dport"), Operator::EQ, Expression::Number(u32::from(forward.external_port))),
+        Statement::Mangle(Mangle {
+            key: ct_mark(),
+            value: Expression::BinaryOperation(Box::new(BinaryOperation::OR(ct_mark(), Expression::Number(PORT_FORWARD_MARK)))),
+        }),
+        Statement::DNAT(Some(NAT {
+            addr: Some(Expression::String(forward.target.ip().to_string().into())),
+            family: Some(family),
+            port: Some(Expression::Number(u32::from(forward.target.port()))),
+            flags: None,
+        })),
+    ])
+}
+
+fn match_expr<'a>(left: Expression<'a>, op: Operator, right: Expression<'a>) -> Statement<'a> {
+    Statement::Match(Match { left, right, op })
+}
+
+fn ct_mark() -> Expression<'static> {
+    Expression::Named(NamedExpression::CT(CT { key: "mark".into(), family: None, dir: None }))
+}
+
+fn meta(key: MetaKey) -> Expression<'static> {
+    Expression::Named(NamedExpression::Meta(Meta { key }))
+}
+
+fn payload(protocol: &'static str, field: &'static str) -> Expression<'static> {
+    Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+        protocol: protocol.into(),
+        field: field.into(),
+    })))
+}
+
+/// A single value is matched directly, several values through an anonymous set.
+fn anonymous_set(mut items: Vec<Expression<'static>>) -> Expression<'static> {
+    if items.len() == 1 {
+        items.remove(0)
+    } else {
+        Expression::Named(NamedExpression::Set(items.into_iter().map(SetItem::Element).collect()))
+    }
+}
+
+/// Set type from its nft name (e.g. `ipv4_addr`), as listed by the model.
+fn parse_set_type(set_type: &str) -> SetType {
+    serde_json::from_value(serde_json::Value::String(set_type.to_string())).unwrap_or(SetType::Ipv4Addr)
+}
+
+/// Sorted copy of an IP iterator, keeping generated transactions deterministic.
+fn sorted_ips<'a>(ips: impl Iterator<Item = &'a IpAddr>) -> Vec<IpAddr> {
+    let mut ips: Vec<IpAddr> = ips.copied().collect();
+    ips.sort();
+    ips
+}
+
+/// Whether adding `planned` over the existing `live` set would be rejected: the
+/// type, the interval and timeout flags, or a configured default timeout or gc
+/// interval differ.
+fn set_parameters_changed(planned: &Set, live: &Set) -> bool {
+    let has_flag = |set: &Set, flag: SetFlag| set.flags.as_ref().is_some_and(|flags| flags.contains(&flag));
+    let timed = |set: &Set| has_flag(set, SetFlag::Timeout) || set.timeout.is_some();
+    planned.set_type != live.set_type
+        || has_flag(planned, SetFlag::Interval) != has_flag(live, SetFlag::Interval)
+        || timed(planned) != timed(live)
+        || (planned.timeout.is_some() && planned.timeout != live.timeout)
+        || (planned.gc_interval.is_some() && planned.gc_interval != live.gc_interval)
+}
+
+/// `ruleset` without the objects creating, flushing or filling the chains in
+/// `foreign`.
+fn without_chains<'a>(mut ruleset: Nftables<'a>, foreign: &BTreeSet<String>) -> Nftables<'a> {
+    if foreign.is_empty() {
+        return ruleset;
     }
+    ruleset.objects.to_mut().retain(|object| {
+        let chain_name = match object {
+            NfObject::CmdObject(NfCmd::Flush(FlushObject::Chain(chain))) => chain.name.as_ref(),
+            NfObject::CmdObject(NfCmd::Add(object)) | NfObject::ListObject(object) => match object {
+                NfListObject::Chain(chain) => chain.name.as_ref(),
+                NfListObject::Rule(rule) => rule.chain.as_ref(),
+                _ => return true,
+            },
+            _ => return true,
+        };
+        !foreign.contains(chain_name)
+    });
+    ruleset
 }
 
-// Note: ip_to_expr helper is no longer needed as conversion happens inline.
+/// Transaction putting every set, chain and flowtable touched by `failed` back
+/// to its contents in `snapshot`. Objects missing from the snapshot are left alone;
+/// flowtable devices the failed transaction added stay until the next reconcile.
+fn rollback_ruleset<'a>(failed: &Nftables, snapshot: &Nftables<'a>) -> Nftables<'a> {
+    let mut touched_sets: HashSet<&str> = HashSet::new();
+    let mut touched_chains: HashSet<&str> = HashSet::new();
+    let mut touched_flowtables: HashSet<&str> = HashSet::new();
+    for object in failed.objects.iter() {
+        let object = match object {
+            NfObject::CmdObject(NfCmd::Flush(FlushObject::Set(set))) => {
+                touched_sets.insert(set.name.as_ref());
+                continue;
+            }
+            NfObject::CmdObject(NfCmd::Flush(FlushObject::Chain(chain))) => {
+                touched_chains.insert(chain.name.as_ref());
+                continue;
+            }
+            NfObject::CmdObject(NfCmd::Add(object) | NfCmd::Delete(object)) | NfObject::ListObject(object) => object,
+            _ => continue,
+        };
+        match object {
+            NfListObject::Element(element) => touched_sets.insert(element.name.as_ref()),
+            NfListObject::Chain(chain) => touched_chains.insert(chain.name.as_ref()),
+            NfListObject::Rule(rule) => touched_chains.insert(rule.chain.as_ref()),
+            NfListObject::FlowTable(flowtable) => touched_flowtables.insert(flowtable.name.as_ref()),
+            _ => false,
+        };
+    }
+    let mut batch = Batch::new();
+    // Flowtables first, since restored rules may refer to them
+    for object in snapshot.objects.iter() {
+        if let NfObject::ListObject(NfListObject::FlowTable(flowtable)) = object {
+            if touched_flowtables.contains(flowtable.name.as_ref()) {
+                batch.add(NfListObject::FlowTable(FlowTable { handle: None, ..flowtable.clone() }));
+            }
+        }
+    }
+    for object in snapshot.objects.iter() {
+        match object {
+            NfObject::ListObject(NfListObject::Set(set)) if touched_sets.contains(set.name.as_ref()) => {
+                // Listed elements carry their remaining lifetime; re-add the bare values
+                let elements: Vec<Expression> = set.elem.iter().flat_map(|elem| elem.iter())
+                    .map(|elem| match elem {
+                        Expression::Named(NamedExpression::Elem(elem)) => (*elem.val).clone(),
+                        other => other.clone(),
+                    })
+                    .collect();
+                batch.add_cmd(NfCmd::Flush(FlushObject::Set(Box::new(Set { elem: None, ..(**set).clone() }))));
+                if !elements.is_empty() {
+                    batch.add(NfListObject::Element(Element {
+                        family: set.family,
+                        table: set.table.clone(),
+                        name: set.name.clone(),
+                        elem: Cow::Owned(elements),
+                    }));
+                }
+            }
+            NfObject::ListObject(NfListObject::Chain(chain)) if touched_chains.contains(chain.name.as_ref()) => {
+                let chain = Chain { handle: None, ..chain.clone() };
+                batch.add(NfListObject::Chain(chain.clone()));
+                batch.add_cmd(NfCmd::Flush(FlushObject::Chain(chain)));
+            }
+            // Listed after their chain
+            NfObject::ListObject(NfListObject::Rule(rule)) if touched_chains.contains(rule.chain.as_ref()) => {
+                batch.add(NfListObject::Rule(Rule { handle: None, index: None, ..rule.clone() }));
+            }
+            _ => {}
+        }
+    }
+    batch.to_nftables()
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{InterfaceConfig, AppStateShared}; // Need AppStateShared for NetworkState access
+    use crate::ruleset::empty_ruleset;
+    use crate::types::{
+        AddressFlag, AddressScope, ConnRate, InterfaceAddress, InterfaceConfig, Ipv6Privacy, MacAddr, Peer, PeerState, RateUnit,
+        Route,
+    };
+    use nftables::schema::NfObject;
     use std::net::{Ipv4Addr, IpAddr};
     use std::sync::Arc;
     use tokio::runtime::Runtime;
     use tokio::sync::Mutex as AsyncMutex;
     use std::collections::HashMap; // Need HashMap for test state
 
-    // Mock config now uses Vec<InterfaceConfig>
-    fn create_mock_config() -> Arc<AsyncMutex<Vec<InterfaceConfig>>> {
-        Arc::new(AsyncMutex::new(vec![
+    // Mock config now wraps the full AppConfig
+    fn create_mock_config() -> Arc<AsyncMutex<AppConfig>> {
+        Arc::new(AsyncMutex::new(AppConfig {
+            interfaces: vec![
                 InterfaceConfig {
                     name: "eth0".to_string(),
                     dhcp: Some(true),
                     address: None,
                     nftables_zone: Some("wan".to_string()),
+                    matcher: None,
                 },
                 InterfaceConfig {
                     name: "eth1".to_string(),
                     dhcp: None,
                     address: Some("192.168.1.1/24".to_string()),
                     nftables_zone: Some("lan".to_string()),
+                    matcher: None,
                 },
-        ]))
+            ],
+            ..Default::default()
+        }))
     }
 
     fn create_test_network_state() -> NetworkState {
@@ -345,4 +2840,459 @@ mod tests {
             assert!(apply_result.is_ok(), "apply_rules should succeed: {:?}", apply_result.err());
         });
     }
+
+    #[test]
+    fn test_first_diff_flushes_and_fills_in_one_batch() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let zone_to_ips = compute_zone_ips(&config.lock().await.interfaces, &HashMap::new(), &create_test_network_state(), &HashMap::new());
+            let desired = zone_set_elements(&zone_to_ips, &manager.settings);
+
+            let ruleset = manager.build_set_diff(&desired, &HashMap::new()).to_nftables();
+            let flushes = ruleset.objects.iter()
+                .filter(|o| matches!(o, NfObject::CmdObject(NfCmd::Flush(_))))
+                .count();
+            let adds = ruleset.objects.iter()
+                .filter(|o| matches!(o, NfObject::CmdObject(NfCmd::Add(NfListObject::Element(_)))))
+                .count();
+            // wan, lan and docker, each with an IPv4 and IPv6 set
+            assert_eq!(flushes, 6);
+            // Only wan_ips and lan_ips have elements
+            assert_eq!(adds, 2);
+        });
+    }
+
+    #[test]
+    fn test_diff_only_touches_changed_elements() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let state = create_test_network_state();
+            let desired = zone_set_elements(&compute_zone_ips(&config.lock().await.interfaces, &HashMap::new(), &state, &HashMap::new()), &manager.settings);
+            let applied: AppliedSets = desired.iter().map(|(k, (_, ips))| (k.clone(), ips.clone())).collect();
+
+            // Unchanged state produces an empty transaction
+            assert!(manager.build_set_diff(&desired, &applied).to_nftables().objects.is_empty());
+
+            // One WAN address replaced, one container started
+            let mut new_state = state.clone();
+            new_state.interface_ips.insert("eth0".to_string(), vec![
+                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                IpAddr::V4(Ipv4Addr::new(9, 9, 9, 9)),
+            ]);
+            let containers = HashMap::from([("c1".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)))]);
+            let desired = zone_set_elements(&compute_zone_ips(&config.lock().await.interfaces, &HashMap::new(), &new_state, &containers), &manager.settings);
+            let ruleset = manager.build_set_diff(&desired, &applied).to_nftables();
+
+            let summary: Vec<(&str, String, Vec<String>)> = ruleset.objects.iter().map(|o| match o {
+                NfObject::CmdObject(NfCmd::Add(NfListObject::Element(e))) => ("add", e.name.to_string(), elem_strings(e)),
+                NfObject::CmdObject(NfCmd::Delete(NfListObject::Element(e))) => ("delete", e.name.to_string(), elem_strings(e)),
+                other => panic!("unexpected command in diff: {:?}", other),
+            }).collect();
+            assert_eq!(summary, vec![
+                ("add", "docker_ips".to_string(), vec!["172.17.0.2".to_string()]),
+                ("delete", "wan_ips".to_string(), vec!["5.6.7.8".to_string()]),
+                ("add", "wan_ips".to_string(), vec!["9.9.9.9".to_string()]),
+            ]);
+        });
+    }
+
+    fn elem_strings(element: &Element) -> Vec<String> {
+        element.elem.iter().map(|e| match e {
+            Expression::String(s) => s.to_string(),
+            other => panic!("unexpected element expression: {:?}", other),
+        }).collect()
+    }
+
+    #[test]
+    fn test_policy_compiles_into_chains() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            let policy: PolicyConfig = serde_yaml::from_str(r#"
+input: drop
+forward: drop
+rules:
+  - from: lan
+    to: local
+    action: allow
+    protocol: tcp
+    ports: [22, 53]
+  - from: lan
+    to: wan
+    action: allow
+  - from: docker
+    to: wan
+    action: reject
+"#).unwrap();
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let interfaces = config.lock().await.interfaces.clone();
+            let mut batch = Batch::new();
+            manager.add_policy_chains(&mut batch, &policy, &interfaces);
+            let json = serde_json::to_value(batch.to_nftables()).unwrap();
+            let commands = json["nftables"].as_array().unwrap();
+
+            let input_chain = commands.iter()
+                .find(|c| c["add"]["chain"]["name"] == "policy_input")
+                .expect("input chain should be created");
+            assert_eq!(input_chain["add"]["chain"]["hook"], "input");
+            assert_eq!(input_chain["add"]["chain"]["policy"], "drop");
+            assert_eq!(commands.iter().filter(|c| c.get("flush").is_some()).count(), 3);
+
+            let rules: Vec<&serde_json::Value> = commands.iter()
+                .filter_map(|c| c["add"].get("rule"))
+                .filter(|r| r.get("comment").is_some_and(|c| c != OWNER_COMMENT))
+                .collect();
+            // lan -> local, lan -> wan, and docker -> wan split into IPv4 and IPv6
+            assert_eq!(rules.len(), 4);
+            assert_eq!(rules[0]["chain"], "policy_input");
+            assert_eq!(rules[0]["expr"][0]["match"]["right"], "eth1");
+            assert_eq!(rules[0]["expr"][2]["match"]["right"]["set"], serde_json::json!([22, 53]));
+            assert_eq!(rules[1]["chain"], "policy_forward");
+            assert_eq!(rules[1]["expr"][1]["match"]["left"]["meta"]["key"], "oifname");
+            assert_eq!(rules[2]["expr"][0]["match"]["right"], "@docker_ips");
+            assert_eq!(rules[3]["expr"][0]["match"]["right"], "@docker_ipv6");
+            assert!(rules[3]["expr"][2].get("reject").is_some());
+        });
+    }
+
+    #[test]
+    fn test_nat_chains_from_masquerade_and_port_forwards() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            {
+                let mut config = config.lock().await;
+                config.zones.insert("wan".to_string(), ZoneConfig { masquerade: true, ..Default::default() });
+                config.port_forwards.push(PortForward {
+                    from: "wan".to_string(),
+                    protocol: crate::types::ForwardProtocol::Tcp,
+                    external_port: 8080,
+                    target: "192.168.1.10:80".parse().unwrap(),
+                });
+                config.policy = Some(PolicyConfig::default());
+            }
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let changes = manager.plan(None, &empty_ruleset()).await.unwrap().changes;
+            let has = |line: &str| changes.iter().any(|c| c == line);
+
+            assert!(has("+ chain inet filter prerouting { hook prerouting priority -100 policy accept }"), "{:#?}", changes);
+            assert!(has("+ chain inet filter postrouting { hook postrouting priority 100 policy accept }"), "{:#?}", changes);
+            assert!(has(&format!(
+                "+ rule inet filter prerouting meta iifname eth0 ip daddr @wan_ips meta l4proto tcp tcp dport 8080 \
+                 ct mark set ct mark | 4194304 dnat ip to 192.168.1.10:80 comment \"{}\"", OWNER_COMMENT
+            )), "{:#?}", changes);
+            assert!(has(&format!("+ rule inet filter postrouting meta oifname eth0 masquerade comment \"{}\"", OWNER_COMMENT)));
+            assert!(has("+ rule inet filter policy_forward ct status dnat ct mark & 4194304 == 4194304 accept"), "{:#?}", changes);
+
+            // Without NAT settings no nat chains are generated
+            {
+                let mut config = config.lock().await;
+                config.zones.clear();
+                config.port_forwards.clear();
+            }
+            let changes = manager.plan(None, &empty_ruleset()).await.unwrap().changes;
+            assert!(!changes.iter().any(|c| c.contains("filter prerouting") || c.contains("filter postrouting")), "{:#?}", changes);
+        });
+    }
+
+    #[test]
+    fn test_zone_connection_limits_compile_into_meters() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            config.lock().await.zones.insert("wan".to_string(), ZoneConfig {
+                max_new_conns_per_source: Some(ConnRate { count: 50, per: RateUnit::Second }),
+                max_conns_per_source: Some(200),
+                ban_timeout: Some(600),
+                ..Default::default()
+            });
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let changes = manager.plan(None, &empty_ruleset()).await.unwrap().changes;
+            let has = |line: &str| changes.iter().any(|c| c == line);
+
+            assert!(has("+ chain inet filter limits { hook prerouting priority -150 policy accept }"), "{:#?}", changes);
+            assert!(has("+ set inet filter wan_ban { type ipv4_addr }"), "{:#?}", changes);
+            assert!(has("+ set inet filter wan_ban6 { type ipv6_addr }"), "{:#?}", changes);
+            assert!(has(&format!("+ rule inet filter limits meta iifname eth0 ip saddr @wan_ban drop comment \"{}\"", OWNER_COMMENT)));
+            assert!(has(&format!(
+                "+ rule inet filter limits meta iifname eth0 ct state new meter wan_newconn {{ ip saddr limit rate over 50/second }} \
+                 add @wan_ban {{ ip saddr }} drop comment \"{}\"", OWNER_COMMENT
+            )), "{:#?}", changes);
+            assert!(has(&format!(
+                "+ rule inet filter limits meta iifname eth0 ct state new meter wan_conns6 {{ ip6 saddr ct count over 200 }} \
+                 add @wan_ban6 {{ ip6 saddr }} drop comment \"{}\"", OWNER_COMMENT
+            )), "{:#?}", changes);
+            assert_eq!(changes.iter().filter(|c| c.starts_with("+ rule inet filter limits")).count(), 6);
+
+            // Without a ban timeout only the excess is dropped
+            config.lock().await.zones.get_mut("wan").unwrap().ban_timeout = None;
+            let changes = manager.plan(None, &empty_ruleset()).await.unwrap().changes;
+            assert!(!changes.iter().any(|c| c.contains("wan_ban")), "{:#?}", changes);
+            assert_eq!(changes.iter().filter(|c| c.starts_with("+ rule inet filter limits")).count(), 4);
+        });
+    }
+
+    #[test]
+    fn test_zone_mac_sets_from_config_and_neighbors() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            let mac = |s: &str| s.parse::<MacAddr>().unwrap();
+            config.lock().await.zones.insert("lan".to_string(), ZoneConfig {
+                macs: vec![mac("aa:bb:cc:00:00:01")],
+                learn_macs: true,
+                mac_pins: BTreeMap::from([("192.168.1.10".parse().unwrap(), mac("AA-BB-CC-00-00-02"))]),
+                ..Default::default()
+            });
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let mut state = create_test_network_state();
+            let peers = |interface: &str, entries: &[(&str, &str, PeerState)]| -> HashMap<IpAddr, Peer> {
+                entries.iter().map(|(ip, mac_addr, peer_state)| {
+                    let ip: IpAddr = ip.parse().unwrap();
+                    (ip, Peer { ip, mac: Some(mac(mac_addr)), interface: interface.to_string(), state: *peer_state })
+                }).collect()
+            };
+            state.peers.insert("eth1".to_string(), peers("eth1", &[
+                ("192.168.1.10", "aa:bb:cc:00:00:03", PeerState::Reachable), // overridden by the pin
+                ("192.168.1.20", "aa:bb:cc:00:00:04", PeerState::Stale),
+                ("192.168.1.30", "aa:bb:cc:00:00:07", PeerState::Failed), // not live
+                ("fe80::1", "aa:bb:cc:00:00:05", PeerState::Permanent),
+            ]));
+            state.peers.insert("eth0".to_string(), peers("eth0", &[
+                ("1.2.3.1", "aa:bb:cc:00:00:06", PeerState::Reachable),
+            ]));
+            let changes = manager.plan(Some((&state, &HashMap::new())), &empty_ruleset()).await.unwrap().changes;
+            let elements = |set: &str| -> Vec<&str> {
+                let prefix = format!("+ element inet filter {} ", set);
+                changes.iter().filter_map(|c| c.strip_prefix(prefix.as_str())).collect()
+            };
+
+            assert!(changes.iter().any(|c| c == "+ set inet filter lan_macs { type ether_addr }"), "{:#?}", changes);
+            assert!(changes.iter().any(|c| c == "+ set inet filter lan_ip_macs { type ipv4_addr . ether_addr }"), "{:#?}", changes);
+            assert!(changes.iter().any(|c| c == "+ set inet filter lan_ip6_macs { type ipv6_addr . ether_addr }"), "{:#?}", changes);
+            assert!(!changes.iter().any(|c| c.contains("wan_macs")), "{:#?}", changes);
+            assert_eq!(elements("lan_macs"), vec![
+                "aa:bb:cc:00:00:01", "aa:bb:cc:00:00:02", "aa:bb:cc:00:00:04", "aa:bb:cc:00:00:05",
+            ]);
+            assert_eq!(elements("lan_ip_macs"), vec![
+                "192.168.1.10 . aa:bb:cc:00:00:02", "192.168.1.20 . aa:bb:cc:00:00:04",
+            ]);
+            assert_eq!(elements("lan_ip6_macs"), vec!["fe80::1 . aa:bb:cc:00:00:05"]);
+
+            // Static MACs alone need no pin sets
+            config.lock().await.zones.insert("lan".to_string(), ZoneConfig {
+                macs: vec![mac("aa:bb:cc:00:00:01")],
+                ..Default::default()
+            });
+            let changes = manager.plan(Some((&state, &HashMap::new())), &empty_ruleset()).await.unwrap().changes;
+            assert!(!changes.iter().any(|c| c.contains("lan_ip_macs") || c.contains("lan_ip6_macs")), "{:#?}", changes);
+            assert!(changes.iter().any(|c| c == "+ element inet filter lan_macs aa:bb:cc:00:00:01"), "{:#?}", changes);
+        });
+    }
+
+    #[test]
+    fn test_zone_nets_from_prefixes_and_static_addresses() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let mut state = create_test_network_state();
+            state.interface_prefixes.insert("eth0".to_string(), vec![
+                "1.2.3.0/24".parse().unwrap(),
+                "1.2.3.128/25".parse().unwrap(),
+                "2001:db8::/64".parse().unwrap(),
+            ]);
+            state.interface_prefixes.insert("eth1".to_string(), vec!["192.168.1.0/24".parse().unwrap()]);
+
+            let nets = manager.desired_nets(&state).await;
+            let as_strings = |set: &str| nets[set].1.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+            // Overlapping prefixes are merged; the static address duplicates the live prefix
+            assert_eq!(as_strings("wan_nets"), vec!["1.2.3.0/24"]);
+            assert_eq!(as_strings("wan_nets6"), vec!["2001:db8::/64"]);
+            assert_eq!(as_strings("lan_nets"), vec!["192.168.1.0/24"]);
+            assert!(!nets.contains_key("docker_nets"));
+
+            // Unchanged networks produce no commands, changed ones are flushed and refilled
+            let applied: HashMap<String, BTreeSet<IpNet>> = nets.iter().map(|(k, (_, n))| (k.clone(), n.clone())).collect();
+            let mut batch = Batch::new();
+            manager.add_net_changes(&mut batch, &nets, &applied);
+            assert!(batch.to_nftables().objects.is_empty());
+
+            state.interface_prefixes.remove("eth0");
+            let nets = manager.desired_nets(&state).await;
+            let mut batch = Batch::new();
+            manager.add_net_changes(&mut batch, &nets, &applied);
+            let json = serde_json::to_value(batch.to_nftables()).unwrap();
+            assert_eq!(json["nftables"].as_array().unwrap().len(), 2);
+            assert_eq!(json["nftables"][0]["flush"]["set"]["name"], "wan_nets");
+            assert_eq!(json["nftables"][1]["flush"]["set"]["name"], "wan_nets6");
+        });
+    }
+
+    #[test]
+    fn test_zone_address_filters() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            config.lock().await.zones.insert("lan".to_string(), ZoneConfig {
+                exclude_scopes: vec![AddressScope::Link],
+                exclude_flags: vec![AddressFlag::Tentative],
+                ipv6_privacy: Ipv6Privacy::Exclude,
+                ..Default::default()
+            });
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let address = |ip: &str, scope: AddressScope, flags: &[AddressFlag]| InterfaceAddress {
+                scope,
+                flags: flags.iter().copied().collect(),
+                ..InterfaceAddress::new(ip.parse().unwrap(), 64)
+            };
+            let mut state = create_test_network_state();
+            state.set_interface_addresses("eth1", vec![
+                address("192.168.1.1", AddressScope::Global, &[AddressFlag::Permanent]),
+                address("fe80::1", AddressScope::Link, &[]),
+                address("fd00::1", AddressScope::Global, &[AddressFlag::Tentative]),
+                address("fd00::2", AddressScope::Global, &[AddressFlag::Temporary]),
+                address("fd00::3", AddressScope::Global, &[AddressFlag::Mngtmpaddr]),
+            ]);
+            let ips = |sets: &BTreeMap<String, (SetType, HashSet<IpAddr>)>, set: &str| sorted_ips(sets[set].1.iter());
+            let desired = manager.desired_sets(&state, &HashMap::new()).await;
+            assert_eq!(ips(&desired, "lan_ips"), vec!["192.168.1.1".parse::<IpAddr>().unwrap()]);
+            assert_eq!(ips(&desired, "lan_ipv6"), vec!["fd00::3".parse::<IpAddr>().unwrap()]);
+
+            // Only privacy addresses among the IPv6 ones; IPv4 is unaffected
+            config.lock().await.zones.get_mut("lan").unwrap().ipv6_privacy = Ipv6Privacy::Only;
+            let desired = manager.desired_sets(&state, &HashMap::new()).await;
+            assert_eq!(ips(&desired, "lan_ips"), vec!["192.168.1.1".parse::<IpAddr>().unwrap()]);
+            assert_eq!(ips(&desired, "lan_ipv6"), vec!["fd00::2".parse::<IpAddr>().unwrap()]);
+            // The subnet sets are not filtered
+            assert_eq!(manager.desired_nets(&state).await["lan_nets6"].1.len(), 2);
+        });
+    }
+
+    #[test]
+    fn test_zone_nets_from_routes() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            config.lock().await.zones.insert("lan".to_string(), ZoneConfig { routed_nets: true, ..Default::default() });
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let mut state = create_test_network_state();
+            let route = |destination: &str, gateway: Option<&str>, interface: &str, metric: u32| Route {
+                destination: destination.parse().unwrap(),
+                gateway: gateway.map(|gateway| gateway.parse().unwrap()),
+                interface: Some(interface.to_string()),
+                metric: Some(metric),
+            };
+            state.routes.extend([
+                route("0.0.0.0/0", Some("192.168.1.1"), "eth1", 200),
+                route("0.0.0.0/0", Some("1.2.3.1"), "eth0", 100),
+                route("10.8.0.0/24", Some("192.168.1.254"), "eth1", 0),
+                route("10.9.0.0/16", Some("1.2.3.254"), "eth0", 0),
+                route("fd00:8::/48", None, "eth1", 1024),
+            ]);
+            assert_eq!(state.default_route(false).unwrap().to_string(), "default via 1.2.3.1 dev eth0 metric 100");
+            assert!(state.default_route(true).is_none());
+
+            // Only the routed zone gains routes, and never its default route;
+            // 192.168.1.0/24 is eth1's static address
+            let nets = manager.desired_nets(&state).await;
+            let as_strings = |set: &str| nets[set].1.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+            assert_eq!(as_strings("lan_nets"), vec!["10.8.0.0/24", "192.168.1.0/24"]);
+            assert_eq!(as_strings("lan_nets6"), vec!["fd00:8::/48"]);
+            assert!(as_strings("wan_nets").is_empty());
+        });
+    }
+
+    #[test]
+    fn test_grace_period_and_timeout_refill() {
+        let wan = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let old = IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8));
+        let zone_sets = HashMap::from([
+            ("wan_ips".to_string(), ZoneConfig { grace_period: Some(30), ..Default::default() }),
+            ("docker_ips".to_string(), ZoneConfig { timeout: Some(60), ..Default::default() }),
+        ]);
+        let start = Instant::now();
+        let mut applied = AppliedState::default();
+        applied.sets.insert("wan_ips".to_string(), HashSet::from([wan, old]));
+        applied.filled_at.insert("docker_ips".to_string(), start);
+        applied.sets.insert("docker_ips".to_string(), HashSet::new());
+
+        // The removed address stays until the grace period ends
+        let desired_now = |ips: &[IpAddr]| {
+            BTreeMap::from([("wan_ips".to_string(), (SetType::Ipv4Addr, ips.iter().copied().collect::<HashSet<_>>()))])
+        };
+        let mut desired = desired_now(&[wan]);
+        applied.hold_grace_period(&mut desired, &zone_sets, start);
+        assert_eq!(desired["wan_ips"].1, HashSet::from([wan, old]));
+        applied.sets.insert("wan_ips".to_string(), desired["wan_ips"].1.clone());
+
+        let later = start + Duration::from_secs(31);
+        let mut desired = desired_now(&[wan]);
+        applied.hold_grace_period(&mut desired, &zone_sets, later);
+        assert_eq!(desired["wan_ips"].1, HashSet::from([wan]));
+
+        // Timed sets are refilled after half their timeout
+        assert!(applied.tracked_sets(&zone_sets, start).contains_key("docker_ips"));
+        assert!(!applied.tracked_sets(&zone_sets, later).contains_key("docker_ips"));
+        assert!(applied.tracked_sets(&zone_sets, later).contains_key("wan_ips"));
+    }
+
+    #[test]
+    fn test_plan_diffs_against_live_ruleset() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let manager = NftablesManager::new(create_mock_config()).await.unwrap();
+            let state = create_test_network_state();
+            let containers = HashMap::new();
+
+            let plan = manager.plan(Some((&state, &containers)), &empty_ruleset()).await.unwrap();
+            assert_eq!(plan.changes[0], "+ table inet filter");
+            assert!(plan.changes.contains(&"+ element inet filter wan_ips 5.6.7.8".to_string()));
+
+            // Once the plan is live, planning again yields no changes
+            let live: Nftables = serde_json::from_value(plan.transaction).unwrap();
+            let again = manager.plan(Some((&state, &containers)), &live).await.unwrap();
+            assert!(again.changes.is_empty(), "unexpected changes: {:?}", again.changes);
+
+            // An edited configuration is planned without touching the running one;
+            // the table settings stay those of the running manager
+            let mut edited = manager.config.lock().await.clone();
+            edited.interfaces[1].nftables_zone = Some("dmz".to_string());
+            edited.nftables.table = "other".to_string();
+            let planner = manager.with_config(edited).await;
+            let changes = planner.plan(Some((&state, &containers)), &live).await.unwrap().changes;
+            assert!(changes.contains(&"+ element inet filter dmz_ips 192.168.1.1".to_string()), "{:#?}", changes);
+            assert!(changes.iter().any(|c| c.starts_with("+ rule inet filter accounting_in meta iifname eth1 counter name \"zone_dmz_in\"")));
+            assert_eq!(manager.config.lock().await.interfaces[1].nftables_zone.as_deref(), Some("lan"));
+        });
+    }
+
+    #[test]
+    fn test_custom_table_family_and_set_names() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config = create_mock_config();
+            {
+                let mut cfg = config.lock().await;
+                cfg.nftables.table = "rust_network_mgr".to_string();
+                cfg.nftables.family = NftablesFamily::Ip;
+                cfg.nftables.ipv4_set_template = "rnm_{zone}".to_string();
+            }
+            let manager = NftablesManager::new(config.clone()).await.unwrap();
+            let zone_to_ips = compute_zone_ips(&config.lock().await.interfaces, &HashMap::new(), &create_test_network_state(), &HashMap::new());
+            let desired = zone_set_elements(&zone_to_ips, &manager.settings);
+            // IPv4-only table: no IPv6 sets
+            assert_eq!(desired.keys().cloned().collect::<Vec<_>>(), vec!["rnm_docker", "rnm_lan", "rnm_wan"]);
+
+            let json = serde_json::to_value(manager.build_set_diff(&desired, &HashMap::new()).to_nftables()).unwrap();
+            let flush = &json["nftables"][0]["flush"]["set"];
+            assert_eq!(flush["family"], "ip");
+            assert_eq!(flush["table"], "rust_network_mgr");
+            assert_eq!(flush["name"], "rnm_docker");
+        });
+    }
 }
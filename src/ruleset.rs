@@ -0,0 +1,696 @@
+//! Simplified model of an nftables ruleset, used to preview and compare transactions.
+//!
+//! The model tracks tables, sets with their elements, chains with their
+//! rules rendered in an nft-like text form, and named counters. It can be built from a listing
+//! (`nft -j list table ...`) and updated by replaying the commands of a
+//! transaction, which makes it possible to show what a transaction would change
+//! without sending it to the kernel.
+
+use nftables::{
+    expr::{BinaryOperation, Expression, NamedExpression, Payload, SetItem},
+    schema::{FlushObject, NfCmd, NfListObject, NfObject, Nftables, SetTypeValue},
+    stmt::{Counter, Match, NATFamily, Operator, Statement},
+};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Result of planning a transaction against the live ruleset.
+#[derive(Debug, Serialize)]
+pub struct RulesetPlan {
+    /// Human-readable changes, one per line (`+` added, `-` removed, `~` modified).
+    pub changes: Vec<String>,
+    /// The exact nftables JSON transaction that would be applied.
+    pub transaction: serde_json::Value,
+}
+
+impl RulesetPlan {
+    /// Renders the plan as text: the changes followed by a short summary.
+    pub fn render(&self) -> String {
+        if self.changes.is_empty() {
+            return "No changes: the live ruleset already matches the plan.\n".to_string();
+        }
+        let mut out = self.changes.join("\n");
+        out.push_str(&format!("\n\n{} change(s) planned.\n", self.changes.len()));
+        out
+    }
+}
+
+/// A ruleset without any object, e.g. a table that does not exist yet.
+pub fn empty_ruleset() -> Nftables<'static> {
+    Nftables { objects: Cow::Owned(Vec::new()) }
+}
+
+/// A set and its elements.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelSet {
+    pub set_type: String,
+    pub comment: Option<String>,
+    pub elements: BTreeSet<String>,
+}
+
+/// A chain: its base-chain definition (if any) and its rules in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelChain {
+    pub base: Option<String>,
+    pub rules: Vec<String>,
+    /// The rules in the form drift checks compare, see `normalize_rule`.
+    pub normalized: Vec<String>,
+    /// Named counters referenced by the rules.
+    pub counters: BTreeSet<String>,
+}
+
+/// Tables, sets, chains and counters keyed by `"<family> <table>"` / `"<family> <table> <name>"`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RulesetModel {
+    pub tables: BTreeSet<String>,
+    pub sets: BTreeMap<String, ModelSet>,
+    pub chains: BTreeMap<String, ModelChain>,
+    pub counters: BTreeSet<String>,
+}
+
+impl RulesetModel {
+    /// Builds a model from a ruleset listing. Command objects are replayed as well.
+    pub fn from_ruleset(ruleset: &Nftables) -> Self {
+        let mut model = Self::default();
+        model.apply(ruleset);
+        model
+    }
+
+    /// Replays the objects of a listing or transaction on the model.
+    pub fn apply(&mut self, ruleset: &Nftables) {
+        for object in ruleset.objects.iter() {
+            match object {
+                NfObject::ListObject(obj) => self.add(obj, false),
+                NfObject::CmdObject(cmd) => self.apply_cmd(cmd),
+            }
+        }
+    }
+
+    fn apply_cmd(&mut self, cmd: &NfCmd) {
+        match cmd {
+            NfCmd::Add(obj) | NfCmd::Create(obj) => self.add(obj, false),
+            NfCmd::Insert(obj) => self.add(obj, true),
+            NfCmd::Delete(obj) => self.delete(obj),
+            NfCmd::Flush(FlushObject::Set(set)) => {
+                if let Some(model_set) = self.sets.get_mut(&object_key(set.family, &set.table, &set.name)) {
+                    model_set.elements.clear();
+                }
+            }
+            NfCmd::Flush(FlushObject::Chain(chain)) => {
+                if let Some(model_chain) = self.chains.get_mut(&object_key(chain.family, &chain.table, &chain.name)) {
+                    model_chain.clear_rules();
+                }
+            }
+            NfCmd::Flush(FlushObject::Table(table)) => {
+                let prefix = format!("{} ", table_key(table.family, &table.name));
+                for (_, chain) in self.chains.iter_mut().filter(|(k, _)| k.starts_with(&prefix)) {
+                    chain.clear_rules();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add(&mut self, obj: &NfListObject, insert: bool) {
+        match obj {
+            NfListObject::Table(table) => {
+                self.tables.insert(table_key(table.family, &table.name));
+            }
+            NfListObject::Set(set) => {
+                let model_set = self.sets.entry(object_key(set.family, &set.table, &set.name)).or_default();
+                model_set.set_type = render_set_type(&set.set_type);
+                if let Some(comment) = &set.comment {
+                    model_set.comment = Some(comment.to_string());
+                }
+                if let Some(elem) = &set.elem {
+                    model_set.elements.extend(elem.iter().map(render_expr));
+                }
+            }
+            NfListObject::Element(element) => {
+                let model_set = self.sets.entry(object_key(element.family, &element.table, &element.name)).or_default();
+                model_set.elements.extend(element.elem.iter().map(render_expr));
+            }
+            NfListObject::Chain(chain) => {
+                let model_chain = self.chains.entry(object_key(chain.family, &chain.table, &chain.name)).or_default();
+                if let Some(hook) = &chain.hook {
+                    let mut base = format!("hook {} priority {}", json_string(hook), chain.prio.unwrap_or(0));
+                    if let Some(policy) = &chain.policy {
+                        base.push_str(&format!(" policy {}", json_string(policy)));
+                    }
+                    model_chain.base = Some(base);
+                }
+            }
+            NfListObject::Rule(rule) => {
+                let model_chain = self.chains.entry(object_key(rule.family, &rule.table, &rule.chain)).or_default();
+                let mut text = rule.expr.iter().map(render_statement).collect::<Vec<_>>().join(" ");
+                let mut normalized = normalize_rule(&rule.expr);
+                if let Some(comment) = &rule.comment {
+                    text.push_str(&format!(" comment \"{}\"", comment));
+                    normalized.push_str(&format!(" comment \"{}\"", comment));
+                }
+                if insert {
+                    model_chain.rules.insert(0, text);
+                    model_chain.normalized.insert(0, normalized);
+                } else {
+                    model_chain.rules.push(text);
+                    model_chain.normalized.push(normalized);
+                }
+                model_chain.counters.extend(rule.expr.iter().filter_map(|stmt| match stmt {
+                    Statement::Counter(Counter::Named(name)) => Some(name.to_string()),
+                    _ => None,
+                }));
+            }
+            NfListObject::Counter(counter) => {
+                self.counters.insert(object_key(counter.family, &counter.table, &counter.name));
+            }
+            _ => {}
+        }
+    }
+
+    fn delete(&mut self, obj: &NfListObject) {
+        match obj {
+            NfListObject::Table(table) => {
+                let key = table_key(table.family, &table.name);
+                let prefix = format!("{} ", key);
+                self.tables.remove(&key);
+                self.sets.retain(|k, _| !k.starts_with(&prefix));
+                self.chains.retain(|k, _| !k.starts_with(&prefix));
+                self.counters.retain(|k| !k.starts_with(&prefix));
+            }
+            NfListObject::Set(set) => {
+                self.sets.remove(&object_key(set.family, &set.table, &set.name));
+            }
+            NfListObject::Chain(chain) => {
+                self.chains.remove(&object_key(chain.family, &chain.table, &chain.name));
+            }
+            NfListObject::Counter(counter) => {
+                self.counters.remove(&object_key(counter.family, &counter.table, &counter.name));
+            }
+            NfListObject::Element(element) => {
+                if let Some(model_set) = self.sets.get_mut(&object_key(element.family, &element.table, &element.name)) {
+                    for elem in element.elem.iter() {
+                        model_set.elements.remove(&render_expr(elem));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets carrying the `owner` comment, as `(name, type)`.
+    pub fn owned_sets(&self, owner: &str) -> Vec<(String, String)> {
+        self.sets.iter()
+            .filter(|(_, set)| set.comment.as_deref() == Some(owner))
+            .map(|(key, set)| (object_name(key).to_string(), set.set_type.clone()))
+            .collect()
+    }
+
+    /// Sets carrying no comment, as `(name, type)`, e.g. those created by meters.
+    pub fn uncommented_sets(&self) -> Vec<(String, String)> {
+        self.sets.iter()
+            .filter(|(_, set)| set.comment.is_none())
+            .map(|(key, set)| (object_name(key).to_string(), set.set_type.clone()))
+            .collect()
+    }
+
+    /// Sets with one of `names`, as `(name, type)`.
+    pub fn find_sets(&self, names: &[String]) -> Vec<(String, String)> {
+        self.sets.iter()
+            .filter(|(key, _)| names.iter().any(|name| name == object_name(key)))
+            .map(|(key, set)| (object_name(key).to_string(), set.set_type.clone()))
+            .collect()
+    }
+
+    /// Drops the elements of the sets with one of `names`, e.g. sets filled by
+    /// the rules themselves whose contents are not compared.
+    pub fn forget_elements(&mut self, names: &[String]) {
+        for (key, set) in self.sets.iter_mut() {
+            if names.iter().any(|name| name == object_name(key)) {
+                set.elements.clear();
+            }
+        }
+    }
+
+    /// Drops the rules of the chains with one of `names`, e.g. chains that
+    /// scripts not modelled here add rules to.
+    pub fn forget_rules(&mut self, names: &[String]) {
+        for (key, chain) in self.chains.iter_mut() {
+            if names.iter().any(|name| name == object_name(key)) {
+                chain.clear_rules();
+            }
+        }
+    }
+
+    /// Chains containing a rule with the `owner` comment.
+    pub fn owned_chains(&self, owner: &str) -> Vec<String> {
+        let marker = format!(" comment \"{}\"", owner);
+        self.chains.iter()
+            .filter(|(_, chain)| chain.rules.iter().any(|rule| rule.ends_with(&marker)))
+            .map(|(key, _)| object_name(key).to_string())
+            .collect()
+    }
+
+    /// Chains without a rule carrying the `owner` comment, i.e. someone else's.
+    pub fn foreign_chains(&self, owner: &str) -> Vec<String> {
+        let owned = self.owned_chains(owner);
+        self.chains.keys()
+            .map(|key| object_name(key).to_string())
+            .filter(|name| !owned.contains(name))
+            .collect()
+    }
+
+    /// Names of the named counters.
+    pub fn counter_names(&self) -> Vec<String> {
+        self.counters.iter().map(|key| object_name(key).to_string()).collect()
+    }
+
+    /// Lists the differences between `self` (before) and `after`, one change per line.
+    pub fn diff(&self, after: &RulesetModel) -> Vec<String> {
+        let mut changes = Vec::new();
+
+        for table in self.tables.difference(&after.tables) {
+            changes.push(format!("- table {}", table));
+        }
+        for table in after.tables.difference(&self.tables) {
+            changes.push(format!("+ table {}", table));
+        }
+
+        let set_names: BTreeSet<&String> = self.sets.keys().chain(after.sets.keys()).collect();
+        let empty_set = ModelSet::default();
+        for name in set_names {
+            let (old, new) = (self.sets.get(name), after.sets.get(name));
+            match (old, new) {
+                (Some(_), None) => changes.push(format!("- set {}", name)),
+                (None, Some(set)) => changes.push(format!("+ set {} {{ type {} }}", name, set.set_type)),
+                _ => {}
+            }
+            let old_elements = &old.unwrap_or(&empty_set).elements;
+            let new_elements = &new.unwrap_or(&empty_set).elements;
+            for elem in old_elements.difference(new_elements) {
+                changes.push(format!("- element {} {}", name, elem));
+            }
+            for elem in new_elements.difference(old_elements) {
+                changes.push(format!("+ element {} {}", name, elem));
+            }
+        }
+
+        let chain_names: BTreeSet<&String> = self.chains.keys().chain(after.chains.keys()).collect();
+        let empty_chain = ModelChain::default();
+        for name in chain_names {
+            let (old, new) = (self.chains.get(name), after.chains.get(name));
+            match (old, new) {
+                (Some(_), None) => changes.push(format!("- chain {}", name)),
+                (None, Some(chain)) => changes.push(format!(
+                    "+ chain {}{}", name,
+                    chain.base.as_ref().map(|b| format!(" {{ {} }}", b)).unwrap_or_default()
+                )),
+                (Some(o), Some(n)) if o.base != n.base => changes.push(format!(
+                    "~ chain {} {{ {} }} -> {{ {} }}", name,
+                    o.base.as_deref().unwrap_or("regular"), n.base.as_deref().unwrap_or("regular")
+                )),
+                _ => {}
+            }
+            let old_rules = &old.unwrap_or(&empty_chain).rules;
+            let new_rules = &new.unwrap_or(&empty_chain).rules;
+            if old_rules != new_rules {
+                for rule in multiset_difference(old_rules, new_rules) {
+                    changes.push(format!("- rule {} {}", name, rule));
+                }
+                for rule in multiset_difference(new_rules, old_rules) {
+                    changes.push(format!("+ rule {} {}", name, rule));
+                }
+            }
+        }
+
+        for counter in self.counters.difference(&after.counters) {
+            changes.push(format!("- counter {}", counter));
+        }
+        for counter in after.counters.difference(&self.counters) {
+            changes.push(format!("+ counter {}", counter));
+        }
+        changes
+    }
+}
+
+impl RulesetModel {
+    /// Lists what `live` is missing compared to this (expected) model.
+    ///
+    /// Objects that only exist in `live` are ignored, so hand-written chains in
+    /// the managed table are not reported. The rules of a chain are compared in
+    /// order, in the normalized form that evens out how nft lists some
+    /// expressions differently from how they were added.
+    pub fn drift(&self, live: &RulesetModel) -> Vec<String> {
+        let mut drift = Vec::new();
+        for table in self.tables.difference(&live.tables) {
+            drift.push(format!("table {} is missing", table));
+        }
+        for (name, set) in &self.sets {
+            match live.sets.get(name) {
+                None => drift.push(format!("set {} is missing", name)),
+                Some(live_set) if live_set.elements != set.elements => drift.push(format!(
+                    "set {} has {} missing and {} unexpected element(s)", name,
+                    set.elements.difference(&live_set.elements).count(),
+                    live_set.elements.difference(&set.elements).count()
+                )),
+                _ => {}
+            }
+        }
+        for (name, chain) in &self.chains {
+            match live.chains.get(name) {
+                None => drift.push(format!("chain {} is missing", name)),
+                Some(live_chain) if live_chain.base != chain.base => drift.push(format!(
+                    "chain {} is {{ {} }}, expected {{ {} }}", name,
+                    live_chain.base.as_deref().unwrap_or("regular"), chain.base.as_deref().unwrap_or("regular")
+                )),
+                Some(live_chain) if live_chain.normalized != chain.normalized => {
+                    let missing = multiset_difference(&chain.normalized, &live_chain.normalized).len();
+                    let unexpected = multiset_difference(&live_chain.normalized, &chain.normalized).len();
+                    drift.push(if missing == 0 && unexpected == 0 {
+                        format!("chain {} has its rules in another order", name)
+                    } else {
+                        format!("chain {} has {} missing and {} unexpected rule(s)", name, missing, unexpected)
+                    });
+                }
+                _ => {}
+            }
+        }
+        for counter in self.counters.difference(&live.counters) {
+            drift.push(format!("counter {} is missing", counter));
+        }
+        drift
+    }
+}
+
+impl ModelChain {
+    fn clear_rules(&mut self) {
+        self.rules.clear();
+        self.normalized.clear();
+        self.counters.clear();
+    }
+}
+
+/// A rule's statements as drift checks compare them, with what nft changes when
+/// listing a rule evened out: protocol matches that a following payload match
+/// depends on are left out, and a set or list of values is sorted, a single
+/// value written without braces.
+fn normalize_rule(expr: &[Statement]) -> String {
+    expr.iter().enumerate()
+        .filter(|(index, stmt)| !is_protocol_dependency(stmt, expr.get(index + 1)))
+        .map(|(_, stmt)| match stmt {
+            Statement::Match(m) if matches!(m.op, Operator::EQ | Operator::IN) && !matches!(m.left, Expression::BinaryOperation(_)) => {
+                format!("{} {}", render_expr(&m.left), normalize_values(&m.right))
+            }
+            other => render_statement(other),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether `stmt` matches the transport protocol (`meta l4proto tcp`,
+/// `ip protocol tcp`, ...) that the payload match in `next` requires anyway.
+fn is_protocol_dependency(stmt: &Statement, next: Option<&Statement>) -> bool {
+    let Statement::Match(m) = stmt else {
+        return false;
+    };
+    let is_protocol_key = match &m.left {
+        Expression::Named(NamedExpression::Meta(meta)) => json_string(&meta.key) == "l4proto",
+        Expression::Named(NamedExpression::Payload(Payload::PayloadField(field))) => {
+            matches!((field.protocol.as_ref(), field.field.as_ref()), ("ip", "protocol") | ("ip6", "nexthdr"))
+        }
+        _ => false,
+    };
+    let (true, Operator::EQ, Expression::String(protocol)) = (is_protocol_key, m.op, &m.right) else {
+        return false;
+    };
+    matches!(
+        next,
+        Some(Statement::Match(Match { left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(field))), .. }))
+            if field.protocol == *protocol
+    )
+}
+
+/// The right-hand side of a match, with sets and lists sorted.
+fn normalize_values(expr: &Expression) -> String {
+    let mut values: Vec<String> = match expr {
+        Expression::List(items) => items.iter().map(render_expr).collect(),
+        Expression::Named(NamedExpression::Set(items)) => items.iter().map(|item| match item {
+            SetItem::Element(e) => render_expr(e),
+            other => render_expr(&Expression::Named(NamedExpression::Set(vec![other.clone()]))),
+        }).collect(),
+        other => return render_expr(other),
+    };
+    values.sort();
+    match values.as_slice() {
+        [value] => value.clone(),
+        _ => format!("{{ {} }}", values.join(", ")),
+    }
+}
+
+/// Items of `a` not matched one-for-one by items of `b`, in `a`'s order.
+fn multiset_difference<'a>(a: &'a [String], b: &[String]) -> Vec<&'a String> {
+    let mut remaining: Vec<&String> = b.iter().collect();
+    a.iter()
+        .filter(|item| match remaining.iter().position(|r| r == item) {
+            Some(pos) => {
+                remaining.remove(pos);
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+/// Object name part of a `"<family> <table> <name>"` key.
+fn object_name(key: &str) -> &str {
+    key.rsplit(' ').next().unwrap_or(key)
+}
+
+fn table_key(family: impl Serialize, table: &str) -> String {
+    format!("{} {}", json_string(&family), table)
+}
+
+fn object_key(family: impl Serialize, table: &str, name: &str) -> String {
+    format!("{} {} {}", json_string(&family), table, name)
+}
+
+/// Serialized form of a value, without quotes for plain strings (e.g. `NfFamily::INet` -> `inet`).
+fn json_string(value: &impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        Ok(other) => other.to_string(),
+        Err(_) => "?".to_string(),
+    }
+}
+
+/// Renders a set type as nft writes it, e.g. `ipv4_addr . ether_addr` for a concatenation.
+fn render_set_type(set_type: &SetTypeValue) -> String {
+    match set_type {
+        SetTypeValue::Single(set_type) => json_string(set_type),
+        SetTypeValue::Concatenated(types) => types.iter().map(json_string).collect::<Vec<_>>().join(" . "),
+    }
+}
+
+/// Renders a statement in nft-like syntax; unknown statements fall back to their JSON form.
+pub fn render_statement(stmt: &Statement) -> String {
+    match stmt {
+        Statement::Match(m) => match m.op {
+            // nft spells out the operator after a bitwise expression
+            Operator::EQ if matches!(m.left, Expression::BinaryOperation(_)) => {
+                format!("{} == {}", render_expr(&m.left), render_expr(&m.right))
+            }
+            Operator::EQ | Operator::IN => format!("{} {}", render_expr(&m.left), render_expr(&m.right)),
+            op => format!("{} {} {}", render_expr(&m.left), json_string(&op), render_expr(&m.right)),
+        },
+        Statement::Accept(_) => "accept".to_string(),
+        Statement::Drop(_) => "drop".to_string(),
+        Statement::Reject(_) => "reject".to_string(),
+        Statement::Jump(target) => format!("jump {}", target.target),
+        Statement::Goto(target) => format!("goto {}", target.target),
+        Statement::Masquerade(None) => "masquerade".to_string(),
+        Statement::Counter(Counter::Named(name)) => format!("counter name \"{}\"", name),
+        Statement::Counter(Counter::Anonymous(_)) => "counter".to_string(),
+        Statement::Mangle(mangle) => format!("{} set {}", render_expr(&mangle.key), render_expr(&mangle.value)),
+        Statement::Flow(flow) => format!("flow {} {}", json_string(&flow.op), flow.flowtable),
+        Statement::Set(set) => format!("{} {} {{ {} }}", json_string(&set.op), set.set, render_expr(&set.elem)),
+        Statement::Meter(meter) => format!(
+            "meter {} {{ {} {} }}", meter.name, render_expr(&meter.key), render_statement(&meter.stmt)
+        ),
+        Statement::Limit(limit) => format!(
+            "limit rate {}{}/{}",
+            if limit.inv == Some(true) { "over " } else { "" },
+            limit.rate,
+            limit.per.as_deref().unwrap_or("second")
+        ),
+        Statement::CTCount(count) => format!(
+            "ct count {}{}", if count.inv == Some(true) { "over " } else { "" }, render_expr(&count.val)
+        ),
+        Statement::DNAT(Some(nat)) | Statement::SNAT(Some(nat)) => {
+            let kind = if matches!(stmt, Statement::DNAT(_)) { "dnat" } else { "snat" };
+            let family = nat.family.map(|f| format!(" {}", json_string(&f))).unwrap_or_default();
+            let addr = nat.addr.as_ref().map(render_expr).unwrap_or_default();
+            let addr = if nat.family == Some(NATFamily::IP6) && nat.port.is_some() { format!("[{}]", addr) } else { addr };
+            let port = nat.port.as_ref().map(|p| format!(":{}", render_expr(p))).unwrap_or_default();
+            format!("{}{} to {}{}", kind, family, addr, port)
+        }
+        other => json_string(other),
+    }
+}
+
+/// Renders an expression in nft-like syntax; unknown expressions fall back to their JSON form.
+pub fn render_expr(expr: &Expression) -> String {
+    match expr {
+        Expression::String(s) => s.to_string(),
+        Expression::Number(n) => n.to_string(),
+        Expression::Boolean(b) => b.to_string(),
+        Expression::List(items) => items.iter().map(render_expr).collect::<Vec<_>>().join(","),
+        Expression::Named(NamedExpression::Set(items)) => format!(
+            "{{ {} }}",
+            items.iter().map(|item| match item {
+                SetItem::Element(e) => render_expr(e),
+                SetItem::Mapping(k, v) => format!("{} : {}", render_expr(k), render_expr(v)),
+                SetItem::MappingStatement(k, s) => format!("{} : {}", render_expr(k), render_statement(s)),
+            }).collect::<Vec<_>>().join(", ")
+        ),
+        Expression::Named(NamedExpression::Meta(meta)) => format!("meta {}", json_string(&meta.key)),
+        Expression::Named(NamedExpression::Payload(Payload::PayloadField(field))) => {
+            format!("{} {}", field.protocol, field.field)
+        }
+        Expression::Named(NamedExpression::CT(ct)) => format!("ct {}", ct.key),
+        Expression::Named(NamedExpression::Concat(items)) => items.iter().map(render_expr).collect::<Vec<_>>().join(" . "),
+        Expression::Named(NamedExpression::Prefix(prefix)) => format!("{}/{}", render_expr(&prefix.addr), prefix.len),
+        Expression::Named(NamedExpression::Elem(elem)) => render_expr(&elem.val),
+        Expression::BinaryOperation(operation) => match operation.as_ref() {
+            BinaryOperation::AND(left, right) => format!("{} & {}", render_expr(left), render_expr(right)),
+            BinaryOperation::OR(left, right) => format!("{} | {}", render_expr(left), render_expr(right)),
+            other => json_string(other),
+        },
+        Expression::Range(range) => format!("{}-{}", render_expr(&range.range[0]), render_expr(&range.range[1])),
+        other => json_string(other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_reports_set_element_and_rule_changes() {
+        let live: Nftables = serde_json::from_value(serde_json::json!({"nftables": [
+            {"table": {"family": "inet", "name": "filter"}},
+            {"set": {"family": "inet", "table": "filter", "name": "wan_ips", "type": "ipv4_addr", "elem": ["1.2.3.4", "5.6.7.8"]}},
+            {"chain": {"family": "inet", "table": "filter", "name": "input", "type": "filter", "hook": "input", "prio": 0, "policy": "accept"}},
+            {"rule": {"family": "inet", "table": "filter", "chain": "input", "expr": [{"accept": null}]}}
+        ]})).unwrap();
+        let transaction: Nftables = serde_json::from_value(serde_json::json!({"nftables": [
+            {"add": {"table": {"family": "inet", "name": "filter"}}},
+            {"add": {"set": {"family": "inet", "table": "filter", "name": "lan_ips", "type": "ipv4_addr"}}},
+            {"add": {"chain": {"family": "inet", "table": "filter", "name": "input", "type": "filter", "hook": "input", "prio": 0, "policy": "drop"}}},
+            {"flush": {"chain": {"family": "inet", "table": "filter", "name": "input"}}},
+            {"add": {"rule": {"family": "inet", "table": "filter", "chain": "input", "expr": [
+                {"match": {"op": "==", "left": {"meta": {"key": "iifname"}}, "right": "lo"}},
+                {"accept": null}
+            ]}}},
+            {"flush": {"set": {"family": "inet", "table": "filter", "name": "wan_ips", "type": "ipv4_addr"}}},
+            {"add": {"element": {"family": "inet", "table": "filter", "name": "wan_ips", "elem": ["1.2.3.4", "9.9.9.9"]}}}
+        ]})).unwrap();
+
+        let before = RulesetModel::from_ruleset(&live);
+        let mut after = before.clone();
+        after.apply(&transaction);
+
+        assert_eq!(before.diff(&after), vec![
+            "+ set inet filter lan_ips { type ipv4_addr }",
+            "- element inet filter wan_ips 5.6.7.8",
+            "+ element inet filter wan_ips 9.9.9.9",
+            "~ chain inet filter input { hook input priority 0 policy accept } -> { hook input priority 0 policy drop }",
+            "- rule inet filter input accept",
+            "+ rule inet filter input meta iifname lo accept",
+        ]);
+        assert!(after.diff(&after).is_empty());
+    }
+
+    #[test]
+    fn test_owned_objects_are_recognised_by_comment() {
+        let live: Nftables = serde_json::from_value(serde_json::json!({"nftables": [
+            {"table": {"family": "inet", "name": "filter"}},
+            {"set": {"family": "inet", "table": "filter", "name": "wan_ips", "type": "ipv4_addr", "comment": "owner"}},
+            {"set": {"family": "inet", "table": "filter", "name": "blocklist", "type": "ipv4_addr"}},
+            {"chain": {"family": "inet", "table": "filter", "name": "input", "type": "filter", "hook": "input", "prio": 0, "policy": "accept"}},
+            {"rule": {"family": "inet", "table": "filter", "chain": "input", "expr": [{"accept": null}], "comment": "owner"}},
+            {"chain": {"family": "inet", "table": "filter", "name": "custom"}},
+            {"rule": {"family": "inet", "table": "filter", "chain": "custom", "expr": [{"accept": null}], "comment": "mine"}}
+        ]})).unwrap();
+        let model = RulesetModel::from_ruleset(&live);
+        assert_eq!(model.owned_sets("owner"), vec![("wan_ips".to_string(), "ipv4_addr".to_string())]);
+        assert_eq!(model.owned_chains("owner"), vec!["input".to_string()]);
+        assert_eq!(model.foreign_chains("owner"), vec!["custom".to_string()]);
+    }
+
+    #[test]
+    fn test_drift_ignores_extra_objects_and_reports_missing_ones() {
+        let expected: Nftables = serde_json::from_value(serde_json::json!({"nftables": [
+            {"add": {"table": {"family": "inet", "name": "filter"}}},
+            {"add": {"set": {"family": "inet", "table": "filter", "name": "wan_ips", "type": "ipv4_addr"}}},
+            {"add": {"element": {"family": "inet", "table": "filter", "name": "wan_ips", "elem": ["1.2.3.4"]}}},
+            {"add": {"chain": {"family": "inet", "table": "filter", "name": "input", "type": "filter", "hook": "input", "prio": 0, "policy": "drop"}}},
+            {"add": {"rule": {"family": "inet", "table": "filter", "chain": "input", "expr": [{"accept": null}]}}}
+        ]})).unwrap();
+        let expected = RulesetModel::from_ruleset(&expected);
+
+        let mut live = expected.clone();
+        live.chains.insert("inet filter custom".to_string(), ModelChain::default());
+        assert!(expected.drift(&live).is_empty());
+
+        live.sets.get_mut("inet filter wan_ips").unwrap().elements.clear();
+        live.chains.remove("inet filter input");
+        assert_eq!(expected.drift(&live), vec![
+            "set inet filter wan_ips has 1 missing and 0 unexpected element(s)",
+            "chain inet filter input is missing",
+        ]);
+        assert_eq!(expected.drift(&RulesetModel::default())[0], "table inet filter is missing");
+    }
+
+    #[test]
+    fn test_drift_compares_normalized_rules() {
+        let expected: Nftables = serde_json::from_value(serde_json::json!({"nftables": [
+            {"add": {"table": {"family": "inet", "name": "filter"}}},
+            {"add": {"chain": {"family": "inet", "table": "filter", "name": "input", "type": "filter", "hook": "input", "prio": 0, "policy": "drop"}}},
+            {"add": {"rule": {"family": "inet", "table": "filter", "chain": "input", "expr": [
+                {"match": {"op": "==", "left": {"meta": {"key": "l4proto"}}, "right": "tcp"}},
+                {"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": 22}},
+                {"accept": null}
+            ]}}},
+            {"add": {"rule": {"family": "inet", "table": "filter", "chain": "input", "expr": [
+                {"match": {"op": "in", "left": {"ct": {"key": "state"}}, "right": ["related", "established"]}},
+                {"accept": null}
+            ]}}}
+        ]})).unwrap();
+        // How nft lists the same rules.
+        let listed: Nftables = serde_json::from_value(serde_json::json!({"nftables": [
+            {"table": {"family": "inet", "name": "filter"}},
+            {"chain": {"family": "inet", "table": "filter", "name": "input", "type": "filter", "hook": "input", "prio": 0, "policy": "drop"}},
+            {"rule": {"family": "inet", "table": "filter", "chain": "input", "expr": [
+                {"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": 22}},
+                {"accept": null}
+            ]}},
+            {"rule": {"family": "inet", "table": "filter", "chain": "input", "expr": [
+                {"match": {"op": "in", "left": {"ct": {"key": "state"}}, "right": {"set": ["established", "related"]}}},
+                {"accept": null}
+            ]}}
+        ]})).unwrap();
+        let expected = RulesetModel::from_ruleset(&expected);
+        let mut live = RulesetModel::from_ruleset(&listed);
+        assert!(expected.drift(&live).is_empty());
+
+        live.chains.get_mut("inet filter input").unwrap().normalized.reverse();
+        assert_eq!(expected.drift(&live), vec!["chain inet filter input has its rules in another order"]);
+
+        // A rule edited in place keeps the count but not the content.
+        live.chains.get_mut("inet filter input").unwrap().normalized = vec![
+            "tcp dport 2222 accept".to_string(),
+            "ct state { established, related } accept".to_string(),
+        ];
+        assert_eq!(expected.drift(&live), vec!["chain inet filter input has 1 missing and 1 unexpected rule(s)"]);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::blocklist::parse_target;
 use crate::types::{AppError, ControlCommand, Result, EventSender, SystemEvent};
 use directories::ProjectDirs; // Changed from BaseDirs to ProjectDirs for runtime path
 use std::path::{Path, PathBuf};
@@ -53,14 +54,14 @@ impl SocketHandler {
         if socket_path.exists() {
             warn!("Existing socket file found at {:?}. Removing.", socket_path);
             std::fs::remove_file(&socket_path)
-                .map_err(|e| AppError::Io(e))?;
+                .map_err(AppError::Io)?;
         }
 
         if let Some(parent) = socket_path.parent() {
              if !parent.exists() {
                 info!("Creating socket directory: {:?}", parent);
                 std::fs::create_dir_all(parent)
-                    .map_err(|e| AppError::Io(e))?;
+                    .map_err(AppError::Io)?;
              }
         }
 
@@ -69,7 +70,7 @@ impl SocketHandler {
 
     pub async fn start(self) -> Result<()> {
         let listener = UnixListener::bind(&self.socket_path)
-            .map_err(|e| AppError::Io(e))?;
+            .map_err(AppError::Io)?;
 
         info!("Control socket listening on {}", self.socket_path.display());
 
@@ -90,6 +91,17 @@ impl SocketHandler {
         }
     }
 
+    /// Sends a command answered through a oneshot channel and waits for the answer.
+    async fn request(
+        sender: &EventSender,
+        command: impl FnOnce(oneshot::Sender<String>) -> ControlCommand,
+    ) -> Result<String> {
+        let (tx, rx) = oneshot::channel();
+        sender.send(SystemEvent::Control(command(tx))).await
+            .map_err(|e| AppError::MpscSendError(format!("Failed to send command: {}", e)))?;
+        rx.await.map_err(|e| AppError::ChannelRecvError(format!("Failed to receive response: {}", e)))
+    }
+
     async fn handle_connection(mut stream: UnixStream, sender: EventSender) -> Result<()> {
         let mut reader = BufReader::new(&mut stream);
         let mut line = String::new();
@@ -97,15 +109,16 @@ impl SocketHandler {
         match reader.read_line(&mut line).await {
             Ok(0) => Ok(()),
             Ok(_) => {
-                let command = line.trim();
-                info!("Received command: {}", command);
+                let line = line.trim();
+                info!("Received command: {}", line);
+                let (command, args) = line.split_once(' ').unwrap_or((line, ""));
 
                 match command {
                     "reload" => {
                         sender.send(SystemEvent::Control(ControlCommand::Reload)).await
                             .map_err(|e| AppError::MpscSendError(format!("Failed to send Reload command: {}", e)))?;
                         stream.write_all(b"OK: Reload command sent\n").await
-                            .map_err(|e| AppError::Io(e))?;
+                            .map_err(AppError::Io)?;
                     }
                     "status" => {
                         let (tx, rx) = oneshot::channel();
@@ -114,14 +127,14 @@ impl SocketHandler {
                         match rx.await {
                             Ok(status_response) => {
                                 stream.write_all(status_response.as_bytes()).await
-                                     .map_err(|e| AppError::Io(e))?;
-                                stream.write_all(b"\n").await.map_err(|e| AppError::Io(e))?;
+                                     .map_err(AppError::Io)?;
+                                stream.write_all(b"\n").await.map_err(AppError::Io)?;
                             }
                             Err(e) => {
                                 let err_msg = format!("Failed to receive status response: {}", e);
                                 error!("{}", err_msg);
                                 stream.write_all(format!("ERROR: {}\n", err_msg).as_bytes()).await
-                                      .map_err(|e| AppError::Io(e))?;
+                                      .map_err(AppError::Io)?;
                                 return Err(AppError::ChannelRecvError(err_msg));
                             }
                         }
@@ -133,14 +146,14 @@ impl SocketHandler {
                         match rx.await {
                             Ok(ping_response) => {
                                 stream.write_all(ping_response.as_bytes()).await
-                                     .map_err(|e| AppError::Io(e))?;
-                                stream.write_all(b"\n").await.map_err(|e| AppError::Io(e))?;
+                                     .map_err(AppError::Io)?;
+                                stream.write_all(b"\n").await.map_err(AppError::Io)?;
                             }
                             Err(e) => {
                                 let err_msg = format!("Failed to receive ping response: {}", e);
                                 error!("{}", err_msg);
                                 stream.write_all(format!("ERROR: {}\n", err_msg).as_bytes()).await
-                                      .map_err(|e| AppError::Io(e))?;
+                                      .map_err(AppError::Io)?;
                                 return Err(AppError::ChannelRecvError(err_msg));
                             }
                         }
@@ -150,11 +163,31 @@ impl SocketHandler {
                          sender.send(SystemEvent::Control(ControlCommand::Shutdown)).await
                              .map_err(|e| AppError::MpscSendError(format!("Failed to send Shutdown command: {}", e)))?;
                          stream.write_all(b"OK: Shutdown command sent\n").await
-                             .map_err(|e| AppError::Io(e))?;
+                             .map_err(AppError::Io)?;
+                    }
+                    "block" => {
+                        let response = match parse_block_args(args) {
+                            Ok((net, ttl, reason)) => Self::request(&sender, |response_tx| {
+                                ControlCommand::Block { net, ttl, reason, response_tx }
+                            }).await?,
+                            Err(e) => format!("ERROR: {}", e),
+                        };
+                        stream.write_all(format!("{}\n", response).as_bytes()).await.map_err(AppError::Io)?;
+                    }
+                    "unblock" => {
+                        let response = match parse_target(args.trim()) {
+                            Ok(net) => Self::request(&sender, |response_tx| ControlCommand::Unblock { net, response_tx }).await?,
+                            Err(e) => format!("ERROR: {} (usage: unblock <ip|cidr>)", e),
+                        };
+                        stream.write_all(format!("{}\n", response).as_bytes()).await.map_err(AppError::Io)?;
+                    }
+                    "blocklist" => {
+                        let response = Self::request(&sender, |response_tx| ControlCommand::Blocklist { response_tx }).await?;
+                        stream.write_all(format!("{}\n", response).as_bytes()).await.map_err(AppError::Io)?;
                     }
                     _ => {
                         stream.write_all(b"ERROR: Unknown command\n").await
-                             .map_err(|e| AppError::Io(e))?;
+                             .map_err(AppError::Io)?;
                     }
                 }
                 Ok(())
@@ -164,5 +197,47 @@ impl SocketHandler {
     }
 }
 
+/// Parses `<ip|cidr> [ttl=<seconds>] [reason...]` of the `block` command; the
+/// `ttl=` key keeps a reason starting with a number from being read as the TTL.
+fn parse_block_args(args: &str) -> std::result::Result<(ipnet::IpNet, Option<u64>, Option<String>), String> {
+    let usage = "usage: block <ip|cidr> [ttl=<seconds>] [reason]";
+    let mut words = args.split_whitespace();
+    let net = parse_target(words.next().ok_or(usage)?).map_err(|e| format!("{} ({})", e, usage))?;
+    let mut rest: Vec<&str> = words.collect();
+    let ttl = match rest.first().and_then(|word| word.strip_prefix("ttl=")) {
+        Some(ttl) => {
+            let ttl = ttl.parse::<u64>().map_err(|_| format!("invalid ttl '{}' ({})", ttl, usage))?;
+            rest.remove(0);
+            Some(ttl)
+        }
+        None => None,
+    };
+    let reason = (!rest.is_empty()).then(|| rest.join(" "));
+    Ok((net, ttl, reason))
+}
+
 // Note: Testing socket interaction often requires integration tests or mocking frameworks.
 // Basic unit tests might focus on command parsing if extracted.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_block_args() {
+        let (net, ttl, reason) = parse_block_args("203.0.113.7 ttl=3600 ssh brute force").unwrap();
+        assert_eq!(net.to_string(), "203.0.113.7/32");
+        assert_eq!(ttl, Some(3600));
+        assert_eq!(reason.as_deref(), Some("ssh brute force"));
+
+        // Without `ttl=`, a leading number belongs to the reason
+        assert_eq!(parse_block_args("203.0.113.7 404 scanner").unwrap().1, None);
+        assert_eq!(parse_block_args("203.0.113.7 404 scanner").unwrap().2.as_deref(), Some("404 scanner"));
+        assert!(parse_block_args("203.0.113.7 ttl=soon").unwrap_err().contains("invalid ttl"));
+
+        assert_eq!(parse_block_args("198.51.100.0/24").unwrap(), ("198.51.100.0/24".parse().unwrap(), None, None));
+        assert_eq!(parse_block_args("2001:db8::/32 scanner").unwrap().2.as_deref(), Some("scanner"));
+        assert!(parse_block_args("").unwrap_err().contains("usage"));
+        assert!(parse_block_args("not-an-ip 60").is_err());
+    }
+}
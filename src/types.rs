@@ -1,14 +1,19 @@
 //! Core types for the application, including configuration, errors, and events.
 
-use serde::Deserialize;
-use std::net::IpAddr;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use thiserror::Error;
 use tokio::sync::mpsc; // For channels
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex as AsyncMutex;
 use tokio::sync::oneshot;
 use bollard;
+use crate::blocklist::DEFAULT_BLOCKLIST_PATH;
 
 /// Central application error type.
 #[derive(Error, Debug)]
@@ -37,6 +42,10 @@ pub enum AppError {
     ChannelRecvError(String),
     #[error("Oneshot channel send error: {0}")]
     OneshotSendError(String),
+    #[error("ipset error: {0}")]
+    Ipset(String),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
     #[error("Anyhow error: {0}")]
     Anyhow(#[from] anyhow::Error),
 }
@@ -48,20 +57,876 @@ pub type Result<T> = std::result::Result<T, AppError>;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct InterfaceConfig {
+    /// Exact interface name; left out when `match` selects the links instead.
+    #[serde(default)]
     pub name: String,
     pub dhcp: Option<bool>, // Use Option for flexibility
     pub address: Option<String>, // e.g., "192.168.1.1/24"
     pub nftables_zone: Option<String>,
+    /// Selects the links the entry applies to (`match:` block), resolved as links appear.
+    #[serde(default, rename = "match")]
+    pub matcher: Option<InterfaceMatch>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl InterfaceConfig {
+    /// Network of the static `address`, e.g. `192.168.1.0/24` for `192.168.1.1/24`.
+    pub fn static_prefix(&self) -> Option<IpNet> {
+        self.address.as_deref()?.parse::<IpNet>().ok().map(|net| net.trunc())
+    }
+}
+
+/// Criteria of an interface `match:` block; a link is selected when all given ones hold.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct InterfaceMatch {
+    /// Shell-style pattern (`*`, `?`) for the name or an altname, e.g. `veth*`.
+    #[serde(deserialize_with = "deserialize_glob")]
+    pub glob: Option<NamePattern>,
+    /// Regular expression matching the whole name or an altname, e.g. `wg[0-9]+`.
+    pub regex: Option<NamePattern>,
+    pub mac: Option<MacAddr>,
+    /// Link kind as shown by `ip -d link`, e.g. `bridge`, `vlan`, `wireguard`.
+    pub kind: Option<String>,
+    /// Alternative name (`ip link property add dev ... altname ...`) the link carries.
+    pub altname: Option<String>,
+}
+
+impl InterfaceMatch {
+    pub fn is_empty(&self) -> bool {
+        self.glob.is_none() && self.regex.is_none() && self.mac.is_none() && self.kind.is_none() && self.altname.is_none()
+    }
+
+    /// Whether the link `name` with `details` is selected.
+    pub fn matches(&self, name: &str, details: &LinkDetails) -> bool {
+        let any_name = |pattern: &NamePattern| {
+            std::iter::once(name).chain(details.altnames.iter().map(String::as_str)).any(|name| pattern.is_match(name))
+        };
+        self.glob.as_ref().is_none_or(any_name)
+            && self.regex.as_ref().is_none_or(any_name)
+            && self.mac.is_none_or(|mac| details.mac == Some(mac))
+            && self.kind.as_ref().is_none_or(|kind| details.kind.as_ref() == Some(kind))
+            && self.altname.as_ref().is_none_or(|altname| details.altnames.contains(altname))
+    }
+}
+
+/// An interface name pattern, compiled to a regular expression anchored at both ends.
+#[derive(Debug, Clone)]
+pub struct NamePattern {
+    source: String,
+    regex: regex::Regex,
+}
+
+impl NamePattern {
+    pub fn regex(pattern: &str) -> std::result::Result<Self, String> {
+        let regex = regex::Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| format!("invalid interface name regex '{}': {}", pattern, e))?;
+        Ok(NamePattern { source: pattern.to_string(), regex })
+    }
+
+    /// A glob where `*` stands for any run of characters and `?` for one.
+    pub fn glob(pattern: &str) -> std::result::Result<Self, String> {
+        let regex: String = pattern.chars()
+            .map(|c| match c {
+                '*' => ".*".to_string(),
+                '?' => ".".to_string(),
+                c => regex::escape(c.encode_utf8(&mut [0; 4])),
+            })
+            .collect();
+        Ok(NamePattern { source: pattern.to_string(), ..NamePattern::regex(&regex)? })
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.regex.is_match(name)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl<'de> Deserialize<'de> for NamePattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        NamePattern::regex(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+fn deserialize_glob<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<NamePattern>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| NamePattern::glob(&pattern).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Attributes of a link that `match:` blocks select by, besides its name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LinkDetails {
+    pub mac: Option<MacAddr>,
+    /// Link kind, e.g. `bridge`, `vlan`, `wireguard`; `None` for physical NICs.
+    pub kind: Option<String>,
+    pub altnames: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct AppConfig {
     pub interfaces: Vec<InterfaceConfig>,
     pub socket_path: Option<String>,
+    /// Directory of `*.nft` / `*.json` rule fragments applied with the managed
+    /// table on startup and every reload.
     pub nftables_rules_path: Option<String>,
     /// Bind address for the HTTP REST API, e.g. "127.0.0.1:9100".
     /// Set to null/omit to disable the HTTP API.
     pub http_bind_addr: Option<String>,
+    /// Zone-to-zone firewall policy. When present, the managed table gets
+    /// `policy_input`, `policy_forward` and `policy_output` chains generated from it.
+    pub policy: Option<PolicyConfig>,
+    /// Table and set naming used by the NftablesManager.
+    #[serde(default)]
+    pub nftables: NftablesConfig,
+    /// Per-zone set settings, keyed by zone name (including `docker`).
+    #[serde(default)]
+    pub zones: HashMap<String, ZoneConfig>,
+    /// Static port forwards (DNAT) from a zone's addresses to internal hosts.
+    #[serde(default)]
+    pub port_forwards: Vec<PortForward>,
+    /// Zone that container port forwards (`rust-network-mgr.forward` labels) come
+    /// from unless the label names one; defaults to `wan`.
+    pub docker_forward_zone: Option<String>,
+    /// Container sets per Docker network and label selector, besides the `docker` zone.
+    #[serde(default)]
+    pub docker_sets: DockerSetsConfig,
+    /// Firewall stack the sets are maintained in.
+    #[serde(default)]
+    pub firewall_backend: FirewallBackendKind,
+    /// Flowtable offload of forwarded traffic between zones.
+    pub flow_offload: Option<FlowOffloadConfig>,
+    /// File the runtime blocklist is saved to; defaults to
+    /// `/var/lib/rust-network-mgr/blocklist.json`.
+    pub blocklist_path: Option<String>,
+    /// Seconds between full re-reads of the kernel's links, addresses, neighbors
+    /// and routes, in case netlink events were missed; defaults to 300, `0` disables.
+    /// Read when the network monitor starts; a reload does not change it.
+    pub netlink_resync_interval: Option<u64>,
+}
+
+impl AppConfig {
+    pub fn docker_forward_zone(&self) -> &str {
+        self.docker_forward_zone.as_deref().unwrap_or(DEFAULT_DOCKER_FORWARD_ZONE)
+    }
+
+    pub fn blocklist_path(&self) -> PathBuf {
+        PathBuf::from(self.blocklist_path.as_deref().unwrap_or(DEFAULT_BLOCKLIST_PATH))
+    }
+
+    pub fn netlink_resync_interval(&self) -> u64 {
+        self.netlink_resync_interval.unwrap_or(DEFAULT_NETLINK_RESYNC_INTERVAL)
+    }
+}
+
+/// Seconds between periodic netlink resyncs unless `netlink_resync_interval` says otherwise.
+pub const DEFAULT_NETLINK_RESYNC_INTERVAL: u64 = 300;
+
+/// Source zone of container port forwards when neither the label nor `docker_forward_zone` names one.
+pub const DEFAULT_DOCKER_FORWARD_ZONE: &str = "wan";
+
+/// Settings of one zone's address sets (`zones.<name>` section).
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ZoneConfig {
+    /// Element lifetime in seconds. The sets are created with `flags timeout` and the
+    /// daemon refills them every `timeout / 2`, so only addresses it stops
+    /// maintaining (e.g. after a crash) expire.
+    pub timeout: Option<u32>,
+    /// Garbage collection interval in seconds for expired elements (kernel default if unset).
+    pub gc_interval: Option<u32>,
+    /// Seconds an address stays in the zone's sets after leaving its interface or
+    /// container, so in-flight connections survive renumbering and restarts.
+    pub grace_period: Option<u64>,
+    /// Masquerade traffic leaving through this zone's interfaces (source NAT to
+    /// whatever address the interface currently has).
+    pub masquerade: bool,
+    /// New connections a single source address may open through the zone's
+    /// interfaces, e.g. `50/second`; the excess is dropped.
+    pub max_new_conns_per_source: Option<ConnRate>,
+    /// Concurrent connections a single source address may hold; new ones beyond
+    /// it are dropped.
+    pub max_conns_per_source: Option<u32>,
+    /// Seconds a source exceeding one of the limits stays in the zone's ban set,
+    /// with all its traffic dropped. Unset means only the excess is dropped.
+    pub ban_timeout: Option<u32>,
+    /// MAC addresses put into the zone's `<zone>_macs` set.
+    pub macs: Vec<MacAddr>,
+    /// Also put the MAC addresses of neighbors (ARP / NDP entries) seen on the
+    /// zone's interfaces into `<zone>_macs`, and pin their IP-to-MAC pairs.
+    pub learn_macs: bool,
+    /// Static IP-to-MAC pairs for the `<zone>_ip_macs` / `<zone>_ip6_macs` sets;
+    /// they take precedence over learned pairs for the same address.
+    pub mac_pins: BTreeMap<IpAddr, MacAddr>,
+    /// Also put the destinations of routes through the zone's interfaces (other
+    /// than default routes) into `<zone>_nets` / `<zone>_nets6`, e.g. the remote
+    /// networks reached over a VPN interface.
+    pub routed_nets: bool,
+    /// Leave interface addresses of these scopes (e.g. `link` for `fe80::/10`)
+    /// out of the zone's address sets.
+    pub exclude_scopes: Vec<AddressScope>,
+    /// Leave interface addresses carrying any of these flags (e.g. `tentative`,
+    /// `deprecated`) out of the zone's address sets.
+    pub exclude_flags: Vec<AddressFlag>,
+    /// Whether IPv6 privacy (`temporary`) addresses go into the zone's address sets.
+    pub ipv6_privacy: Ipv6Privacy,
+}
+
+impl ZoneConfig {
+    /// Whether the zone has connection limits (and so rules in the `limits` chain).
+    pub fn has_limits(&self) -> bool {
+        self.max_new_conns_per_source.is_some() || self.max_conns_per_source.is_some()
+    }
+
+    /// Whether the zone has a `<zone>_macs` set.
+    pub fn has_macs(&self) -> bool {
+        !self.macs.is_empty() || self.learn_macs || !self.mac_pins.is_empty()
+    }
+
+    /// Whether the zone has IP-to-MAC pin sets.
+    pub fn pins_macs(&self) -> bool {
+        self.learn_macs || !self.mac_pins.is_empty()
+    }
+
+    /// Whether the zone filters interface addresses by scope, flags or privacy.
+    pub fn filters_addresses(&self) -> bool {
+        !self.exclude_scopes.is_empty() || !self.exclude_flags.is_empty() || self.ipv6_privacy != Ipv6Privacy::Include
+    }
+
+    /// Whether an interface address passes the zone's address filters.
+    pub fn admits(&self, address: &InterfaceAddress) -> bool {
+        let privacy_ok = match self.ipv6_privacy {
+            Ipv6Privacy::Include => true,
+            Ipv6Privacy::Exclude => !address.is_privacy(),
+            Ipv6Privacy::Only => address.address.is_ipv4() || address.is_privacy(),
+        };
+        privacy_ok
+            && !self.exclude_scopes.contains(&address.scope)
+            && !self.exclude_flags.iter().any(|flag| address.flags.contains(flag))
+    }
+}
+
+/// Treatment of IPv6 privacy (`temporary`, RFC 4941) addresses in a zone's address sets.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Ipv6Privacy {
+    #[default]
+    Include,
+    Exclude,
+    /// Only privacy addresses of the zone's IPv6 addresses; IPv4 addresses are kept.
+    Only,
+}
+
+/// Scope of an interface address, named as `ip address` shows it.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressScope {
+    Global,
+    Site,
+    Link,
+    Host,
+    Nowhere,
+}
+
+/// Flag of an interface address, named as `ip address` shows it.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressFlag {
+    /// Secondary IPv4 address (same kernel flag as `temporary`).
+    Secondary,
+    /// IPv6 privacy address (same kernel flag as `secondary`).
+    Temporary,
+    Nodad,
+    Optimistic,
+    Dadfailed,
+    Home,
+    Deprecated,
+    /// Duplicate address detection has not completed yet.
+    Tentative,
+    Permanent,
+    Mngtmpaddr,
+    Noprefixroute,
+    Autojoin,
+    StablePrivacy,
+}
+
+/// An interface address with the details netlink reports for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InterfaceAddress {
+    pub address: IpAddr,
+    pub prefix_len: u8,
+    pub scope: AddressScope,
+    pub flags: BTreeSet<AddressFlag>,
+    /// Unix time the address gets deprecated, from the lifetime last reported; `None` for never.
+    pub preferred_until: Option<u64>,
+    /// Unix time the address is removed, from the lifetime last reported; `None` for never.
+    pub valid_until: Option<u64>,
+}
+
+/// Seconds two computed expiry times may differ by and still be the same
+/// expiry; lifetimes are reported in whole seconds.
+const LIFETIME_SLACK_SECS: u64 = 2;
+
+impl InterfaceAddress {
+    /// A permanent global address without flags, e.g. a statically configured one.
+    pub fn new(address: IpAddr, prefix_len: u8) -> Self {
+        InterfaceAddress {
+            address,
+            prefix_len,
+            scope: AddressScope::Global,
+            flags: BTreeSet::new(),
+            preferred_until: None,
+            valid_until: None,
+        }
+    }
+
+    /// The network the address is on, e.g. `192.168.1.0/24` for `192.168.1.1/24`.
+    pub fn network(&self) -> IpNet {
+        IpNet::new(self.address, self.prefix_len).map(|net| net.trunc()).unwrap_or_else(|_| IpNet::from(self.address))
+    }
+
+    pub fn is_privacy(&self) -> bool {
+        self.address.is_ipv6() && self.flags.contains(&AddressFlag::Temporary)
+    }
+
+    /// Whether two reports of an address agree on everything but the lifetimes,
+    /// which count down between reports.
+    pub fn same_state(&self, other: &InterfaceAddress) -> bool {
+        (self.address, self.prefix_len, self.scope, &self.flags) == (other.address, other.prefix_len, other.scope, &other.flags)
+    }
+
+    /// Whether two reports of an address expire at the same times, e.g. not
+    /// after a router advertisement refreshed its lifetimes.
+    pub fn same_lifetimes(&self, other: &InterfaceAddress) -> bool {
+        let same = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => a.abs_diff(b) <= LIFETIME_SLACK_SECS,
+            (a, b) => a == b,
+        };
+        same(self.preferred_until, other.preferred_until) && same(self.valid_until, other.valid_until)
+    }
+}
+
+/// An Ethernet (link-layer) address, written `aa:bb:cc:dd:ee:ff` (`-` separators are accepted too).
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let octets: Vec<String> = self.0.iter().map(|byte| format!("{:02x}", byte)).collect();
+        f.write_str(&octets.join(":"))
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid MAC address '{}' (expected e.g. aa:bb:cc:dd:ee:ff)", value);
+        let mut bytes = [0u8; 6];
+        let mut octets = value.split([':', '-']);
+        for byte in bytes.iter_mut() {
+            let octet = octets.next().filter(|octet| octet.len() == 2).ok_or_else(invalid)?;
+            *byte = u8::from_str_radix(octet, 16).map_err(|_| invalid())?;
+        }
+        if octets.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(MacAddr(bytes))
+    }
+}
+
+impl TryFrom<String> for MacAddr {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<MacAddr> for String {
+    fn from(mac: MacAddr) -> Self {
+        mac.to_string()
+    }
+}
+
+/// A rate such as `50/second`, as written in `max_new_conns_per_source`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct ConnRate {
+    pub count: u32,
+    pub per: RateUnit,
+}
+
+/// Denominator of a `ConnRate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+}
+
+impl RateUnit {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RateUnit::Second => "second",
+            RateUnit::Minute => "minute",
+            RateUnit::Hour => "hour",
+            RateUnit::Day => "day",
+        }
+    }
+}
+
+impl TryFrom<String> for ConnRate {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let invalid = || format!("invalid rate '{}' (expected e.g. 50/second)", value);
+        let (count, per) = value.split_once('/').ok_or_else(invalid)?;
+        let count = count.trim().parse::<u32>().map_err(|_| invalid())?;
+        let per = match per.trim() {
+            "second" => RateUnit::Second,
+            "minute" => RateUnit::Minute,
+            "hour" => RateUnit::Hour,
+            "day" => RateUnit::Day,
+            _ => return Err(invalid()),
+        };
+        Ok(ConnRate { count, per })
+    }
+}
+
+/// Transport protocol of a port forward.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+impl ForwardProtocol {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ForwardProtocol::Tcp => "tcp",
+            ForwardProtocol::Udp => "udp",
+        }
+    }
+}
+
+/// A static port forward, e.g. `from: wan, protocol: tcp, external_port: 8080, target: 192.168.1.10:80`.
+///
+/// Matches traffic arriving on the `from` zone's interfaces for one of the zone's
+/// current addresses, so the rule follows address changes on the WAN side.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct PortForward {
+    /// Source zone; must be bound to interfaces.
+    pub from: String,
+    pub protocol: ForwardProtocol,
+    pub external_port: u16,
+    /// Internal address and port, e.g. `192.168.1.10:80` or `[fd00::10]:80`.
+    pub target: SocketAddr,
+}
+
+impl PortForward {
+    /// What the forward's DNAT rule matches: source zone, protocol, external
+    /// port and whether the target is IPv4. Of two forwards sharing it, the
+    /// second would never see a packet.
+    pub fn listener(&self) -> (String, ForwardProtocol, u16, bool) {
+        (self.from.clone(), self.protocol, self.external_port, self.target.is_ipv4())
+    }
+}
+
+/// The `flow_offload:` section: established connections forwarded between the
+/// interfaces of these zones take the nftables flowtable fast path (software
+/// offload, no special hardware needed).
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FlowOffloadConfig {
+    /// Interface zones whose interfaces join the flowtable.
+    pub zones: Vec<String>,
+}
+
+/// The `docker_sets:` section: sets holding only some of the containers. Each
+/// entry becomes a zone named `docker_<name>` with sets named like the other
+/// zones' (e.g. `docker_backend_ips`) and the settings of `zones.docker`.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct DockerSetsConfig {
+    /// Docker networks whose containers get a set of their addresses on that network.
+    pub networks: Vec<String>,
+    /// Label selectors keyed by set name, e.g. `db: role=db` for `docker_db_ips`;
+    /// matching containers get all of their addresses in the set.
+    pub labels: BTreeMap<String, LabelSelector>,
+}
+
+impl DockerSetsConfig {
+    /// Zone name of a network's or label selector's sets, e.g. `docker_my_net` for `my-net`.
+    pub fn zone_name(name: &str) -> String {
+        let name: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+            .collect();
+        format!("docker_{}", name)
+    }
+
+    /// Zone names of all per-network and per-label sets.
+    pub fn zones(&self) -> Vec<String> {
+        self.networks.iter().chain(self.labels.keys()).map(|name| Self::zone_name(name)).collect()
+    }
+}
+
+/// A container label selector: `key=value`, or `key` for any value.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct LabelSelector {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl LabelSelector {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        match (labels.get(&self.key), &self.value) {
+            (Some(value), Some(wanted)) => value == wanted,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+impl TryFrom<String> for LabelSelector {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let (key, wanted) = match value.split_once('=') {
+            Some((key, wanted)) => (key.trim(), Some(wanted.trim().to_string())),
+            None => (value.trim(), None),
+        };
+        if key.is_empty() {
+            return Err(format!("invalid label selector '{}' (expected key=value or key)", value));
+        }
+        Ok(LabelSelector { key: key.to_string(), value: wanted })
+    }
+}
+
+/// A port forward requested by a container label, e.g. `tcp:8080->80` or `wan:tcp:8080->80`.
+/// The target is the container's current address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerForward {
+    /// Source zone; `None` uses `docker_forward_zone`.
+    pub from: Option<String>,
+    pub protocol: ForwardProtocol,
+    pub external_port: u16,
+    pub container_port: u16,
+}
+
+/// Placeholder replaced by the zone name in set-name templates.
+pub const ZONE_PLACEHOLDER: &str = "{zone}";
+
+/// nftables address family of the managed table.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NftablesFamily {
+    /// Dual-stack table holding both IPv4 and IPv6 sets.
+    #[default]
+    Inet,
+    /// IPv4-only table; IPv6 sets are not created.
+    Ip,
+    /// IPv6-only table; IPv4 sets are not created.
+    Ip6,
+}
+
+impl NftablesFamily {
+    pub fn has_ipv4(self) -> bool {
+        self != NftablesFamily::Ip6
+    }
+
+    pub fn has_ipv6(self) -> bool {
+        self != NftablesFamily::Ip
+    }
+}
+
+/// The `nftables:` section: where the daemon keeps its sets and chains.
+///
+/// Changes take effect on restart; a reload keeps the table the daemon started with.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct NftablesConfig {
+    /// Name of the managed table, e.g. `rust_network_mgr` to stay out of distro rulesets.
+    pub table: String,
+    pub family: NftablesFamily,
+    /// Name of each zone's IPv4 set; `{zone}` is replaced by the zone name.
+    pub ipv4_set_template: String,
+    /// Name of each zone's IPv6 set; `{zone}` is replaced by the zone name.
+    pub ipv6_set_template: String,
+    /// Name of each zone's IPv4 subnet (interval) set.
+    pub ipv4_net_set_template: String,
+    /// Name of each zone's IPv6 subnet (interval) set.
+    pub ipv6_net_set_template: String,
+    /// Name of each zone's IPv4 set of banned sources (`ban_timeout`).
+    pub ipv4_ban_set_template: String,
+    /// Name of each zone's IPv6 set of banned sources.
+    pub ipv6_ban_set_template: String,
+    /// Name of each zone's IPv4 meter for `max_new_conns_per_source`.
+    pub ipv4_rate_meter_template: String,
+    /// Name of each zone's IPv6 meter for `max_new_conns_per_source`.
+    pub ipv6_rate_meter_template: String,
+    /// Name of each zone's IPv4 meter for `max_conns_per_source`.
+    pub ipv4_conn_meter_template: String,
+    /// Name of each zone's IPv6 meter for `max_conns_per_source`.
+    pub ipv6_conn_meter_template: String,
+    /// Name of each interface zone's set of live IPv4 peers.
+    pub ipv4_peer_set_template: String,
+    /// Name of each interface zone's set of live IPv6 peers.
+    pub ipv6_peer_set_template: String,
+    /// Name of each zone's MAC address set (`macs`, `learn_macs`).
+    pub mac_set_template: String,
+    /// Name of each zone's IPv4-to-MAC pin set (`mac_pins`).
+    pub ipv4_mac_pin_set_template: String,
+    /// Name of each zone's IPv6-to-MAC pin set.
+    pub ipv6_mac_pin_set_template: String,
+    /// Name of the IPv4 blocklist set; the IPv6 one gets a `6` suffix.
+    pub blocklist_set_name: String,
+    /// Seconds between read-backs of the live ruleset to catch changes made by other
+    /// tools; `0` disables the periodic check (nfnetlink events are still watched).
+    pub drift_check_interval: u64,
+    /// Seconds between reads of the per-zone traffic counters; `0` disables the
+    /// counters and their accounting chains.
+    pub counter_interval: u64,
+    /// What happens to the daemon's sets and chains when it stops.
+    pub on_shutdown: ShutdownAction,
+    /// Failed applies in a row after which the daemon reports itself degraded
+    /// on `/health`; `0` never does.
+    pub degraded_after: u32,
+}
+
+/// Firewall stack used by the daemon (`firewall_backend:`).
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallBackendKind {
+    /// nftables if the kernel supports it, otherwise ipset.
+    #[default]
+    Auto,
+    Nftables,
+    /// ipset `hash:ip` / `hash:net` sets for iptables hosts; sets only, no chains.
+    Ipset,
+}
+
+/// Fate of the objects owned by the daemon when it shuts down.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownAction {
+    /// Leave sets, elements and chains in place.
+    #[default]
+    Keep,
+    /// Empty the owned sets, keep the structure.
+    Flush,
+    /// Delete the owned chains and sets, and the table if nothing else is left in it.
+    Delete,
+}
+
+impl Default for NftablesConfig {
+    fn default() -> Self {
+        Self {
+            table: "filter".to_string(),
+            family: NftablesFamily::Inet,
+            ipv4_set_template: "{zone}_ips".to_string(),
+            ipv6_set_template: "{zone}_ipv6".to_string(),
+            ipv4_net_set_template: "{zone}_nets".to_string(),
+            ipv6_net_set_template: "{zone}_nets6".to_string(),
+            ipv4_ban_set_template: "{zone}_ban".to_string(),
+            ipv6_ban_set_template: "{zone}_ban6".to_string(),
+            ipv4_rate_meter_template: "{zone}_newconn".to_string(),
+            ipv6_rate_meter_template: "{zone}_newconn6".to_string(),
+            ipv4_conn_meter_template: "{zone}_conns".to_string(),
+            ipv6_conn_meter_template: "{zone}_conns6".to_string(),
+            ipv4_peer_set_template: "{zone}_peers".to_string(),
+            ipv6_peer_set_template: "{zone}_peers6".to_string(),
+            mac_set_template: "{zone}_macs".to_string(),
+            ipv4_mac_pin_set_template: "{zone}_ip_macs".to_string(),
+            ipv6_mac_pin_set_template: "{zone}_ip6_macs".to_string(),
+            blocklist_set_name: "blocklist".to_string(),
+            drift_check_interval: 60,
+            counter_interval: 15,
+            on_shutdown: ShutdownAction::Keep,
+            degraded_after: 3,
+        }
+    }
+}
+
+impl NftablesConfig {
+    pub fn ipv4_set_name(&self, zone: &str) -> String {
+        self.ipv4_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv6_set_name(&self, zone: &str) -> String {
+        self.ipv6_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv4_net_set_name(&self, zone: &str) -> String {
+        self.ipv4_net_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv6_net_set_name(&self, zone: &str) -> String {
+        self.ipv6_net_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv4_ban_set_name(&self, zone: &str) -> String {
+        self.ipv4_ban_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv6_ban_set_name(&self, zone: &str) -> String {
+        self.ipv6_ban_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv4_rate_meter_name(&self, zone: &str) -> String {
+        self.ipv4_rate_meter_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv6_rate_meter_name(&self, zone: &str) -> String {
+        self.ipv6_rate_meter_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv4_conn_meter_name(&self, zone: &str) -> String {
+        self.ipv4_conn_meter_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv6_conn_meter_name(&self, zone: &str) -> String {
+        self.ipv6_conn_meter_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv4_peer_set_name(&self, zone: &str) -> String {
+        self.ipv4_peer_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv6_peer_set_name(&self, zone: &str) -> String {
+        self.ipv6_peer_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn mac_set_name(&self, zone: &str) -> String {
+        self.mac_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv4_mac_pin_set_name(&self, zone: &str) -> String {
+        self.ipv4_mac_pin_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv6_mac_pin_set_name(&self, zone: &str) -> String {
+        self.ipv6_mac_pin_set_template.replace(ZONE_PLACEHOLDER, zone)
+    }
+
+    pub fn ipv4_blocklist_set_name(&self) -> String {
+        self.blocklist_set_name.clone()
+    }
+
+    pub fn ipv6_blocklist_set_name(&self) -> String {
+        format!("{}6", self.blocklist_set_name)
+    }
+
+    /// The set-name templates with their configuration keys.
+    pub fn set_templates(&self) -> Vec<(&'static str, &String)> {
+        vec![
+            ("ipv4_set_template", &self.ipv4_set_template),
+            ("ipv6_set_template", &self.ipv6_set_template),
+            ("ipv4_net_set_template", &self.ipv4_net_set_template),
+            ("ipv6_net_set_template", &self.ipv6_net_set_template),
+            ("ipv4_ban_set_template", &self.ipv4_ban_set_template),
+            ("ipv6_ban_set_template", &self.ipv6_ban_set_template),
+            ("ipv4_rate_meter_template", &self.ipv4_rate_meter_template),
+            ("ipv6_rate_meter_template", &self.ipv6_rate_meter_template),
+            ("ipv4_conn_meter_template", &self.ipv4_conn_meter_template),
+            ("ipv6_conn_meter_template", &self.ipv6_conn_meter_template),
+            ("ipv4_peer_set_template", &self.ipv4_peer_set_template),
+            ("ipv6_peer_set_template", &self.ipv6_peer_set_template),
+            ("mac_set_template", &self.mac_set_template),
+            ("ipv4_mac_pin_set_template", &self.ipv4_mac_pin_set_template),
+            ("ipv6_mac_pin_set_template", &self.ipv6_mac_pin_set_template),
+        ]
+    }
+
+    /// The set-name templates of the families the managed table carries.
+    pub fn carried_set_templates(&self) -> Vec<(&'static str, &String)> {
+        self.set_templates().into_iter()
+            .filter(|(key, _)| {
+                (self.family.has_ipv4() || !key.starts_with("ipv4")) && (self.family.has_ipv6() || !key.starts_with("ipv6"))
+            })
+            .collect()
+    }
+
+    /// Every set name the templates give `zone`, as `(template key, name)`.
+    pub fn zone_set_names(&self, zone: &str) -> Vec<(&'static str, String)> {
+        self.carried_set_templates().into_iter()
+            .map(|(key, template)| (key, template.replace(ZONE_PLACEHOLDER, zone)))
+            .collect()
+    }
+
+    /// Names of the meters a zone's connection limits may create.
+    pub fn limit_meter_names(&self, zone: &str) -> Vec<String> {
+        self.zone_set_names(zone).into_iter()
+            .filter(|(key, _)| key.ends_with("_meter_template"))
+            .map(|(_, name)| name)
+            .collect()
+    }
+}
+
+/// Zone name referring to the host itself in policy rules.
+pub const LOCAL_ZONE: &str = "local";
+/// Zone name matching any zone in policy rules.
+pub const ANY_ZONE: &str = "any";
+
+/// Verdict of a policy rule.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Allow,
+    Deny,
+    Reject,
+}
+
+/// Protocols a policy rule can be restricted to.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Icmpv6,
+}
+
+/// Default verdict of a generated base chain.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChainPolicy {
+    #[default]
+    Accept,
+    Drop,
+}
+
+/// A single zone-to-zone rule, e.g. `from: lan, to: local, action: allow, protocol: tcp, ports: [22]`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PolicyRule {
+    /// Source zone, `local` for traffic originating on this host, or `any`.
+    pub from: String,
+    /// Destination zone, `local` for traffic addressed to this host, or `any`.
+    pub to: String,
+    pub action: PolicyAction,
+    pub protocol: Option<PolicyProtocol>,
+    /// Destination ports; requires `protocol: tcp` or `protocol: udp`.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+/// The `policy:` section of the configuration.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PolicyConfig {
+    #[serde(default)]
+    pub input: ChainPolicy,
+    #[serde(default)]
+    pub forward: ChainPolicy,
+    #[serde(default)]
+    pub output: ChainPolicy,
+    /// Evaluated in order; the first matching rule wins.
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
 }
 
 // --- Network State ---
@@ -70,10 +935,141 @@ pub struct AppConfig {
 #[derive(Debug, Default, Clone)]
 pub struct NetworkState {
     pub interface_ips: HashMap<String, Vec<IpAddr>>, // Interface name -> IPs
+    pub interface_prefixes: HashMap<String, Vec<IpNet>>, // Interface name -> on-link prefixes
+    /// Interface name -> addresses with prefix length, scope, flags and lifetimes.
+    pub interface_addresses: HashMap<String, Vec<InterfaceAddress>>,
     pub if_index_to_name: HashMap<u32, String>,
+    /// Interfaces whose link was last reported up.
+    pub up_links: HashSet<String>,
+    /// Interface name -> MAC address, kind and altnames of every known link.
+    pub links: BTreeMap<String, LinkDetails>,
+    /// Interface name -> neighbor (ARP / NDP) table entries, keyed by address.
+    pub peers: HashMap<String, HashMap<IpAddr, Peer>>,
+    /// Unicast routes of the main routing table.
+    pub routes: BTreeSet<Route>,
     // Potentially add container IPs here later if needed directly for rules
 }
 
+impl NetworkState {
+    /// Records the current addresses of an interface, with the addresses and
+    /// (deduplicated) networks derived from them.
+    pub fn set_interface_addresses(&mut self, interface: &str, addresses: Vec<InterfaceAddress>) {
+        let mut prefixes: Vec<IpNet> = addresses.iter().map(InterfaceAddress::network).collect();
+        prefixes.sort();
+        prefixes.dedup();
+        self.interface_ips.insert(interface.to_string(), addresses.iter().map(|address| address.address).collect());
+        self.interface_prefixes.insert(interface.to_string(), prefixes);
+        self.interface_addresses.insert(interface.to_string(), addresses);
+    }
+
+    /// The default route of the family with the lowest metric, if there is one.
+    pub fn default_route(&self, ipv6: bool) -> Option<&Route> {
+        self.routes.iter()
+            .filter(|route| route.is_default() && route.destination.addr().is_ipv6() == ipv6)
+            .min_by_key(|route| route.metric.unwrap_or(0))
+    }
+}
+
+/// A LAN peer: an entry of the kernel's neighbor (ARP / NDP) table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Peer {
+    pub ip: IpAddr,
+    pub mac: Option<MacAddr>,
+    pub interface: String,
+    pub state: PeerState,
+}
+
+impl Peer {
+    /// The MAC address, if the peer is live (see `PeerState::is_live`).
+    pub fn live_mac(&self) -> Option<MacAddr> {
+        self.mac.filter(|_| self.state.is_live())
+    }
+}
+
+/// Reachability of a neighbor table entry, as reported by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerState {
+    /// Address resolution is in progress.
+    Incomplete,
+    /// Recently confirmed reachable.
+    Reachable,
+    /// Not confirmed lately; checked again on the next packet to it.
+    Stale,
+    Delay,
+    Probe,
+    /// Address resolution failed.
+    Failed,
+    /// Static entry.
+    Permanent,
+}
+
+impl PeerState {
+    /// Whether the entry holds a usable (resolved or static) link-layer address.
+    pub fn is_live(self) -> bool {
+        !matches!(self, PeerState::Incomplete | PeerState::Failed)
+    }
+}
+
+/// A route of the main routing table, e.g. `10.8.0.0/24 via 10.8.0.1 dev wg0 metric 50`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct Route {
+    pub destination: IpNet,
+    pub gateway: Option<IpAddr>,
+    /// Outgoing interface; `None` for routes without one (e.g. `blackhole`).
+    pub interface: Option<String>,
+    pub metric: Option<u32>,
+}
+
+impl Route {
+    pub fn is_default(&self) -> bool {
+        self.destination.prefix_len() == 0
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_default() {
+            f.write_str("default")?;
+        } else {
+            write!(f, "{}", self.destination)?;
+        }
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        if let Some(interface) = &self.interface {
+            write!(f, " dev {}", interface)?;
+        }
+        if let Some(metric) = self.metric {
+            write!(f, " metric {}", metric)?;
+        }
+        Ok(())
+    }
+}
+
+/// Interface and container addresses in the shape served by `GET /status`,
+/// read by the `plan` subcommand (`--state <file>`).
+#[derive(Debug, Default, Deserialize)]
+pub struct StateSnapshot {
+    #[serde(default)]
+    pub interfaces: HashMap<String, Vec<IpAddr>>,
+    #[serde(default)]
+    pub containers: HashMap<String, IpAddr>,
+}
+
+impl StateSnapshot {
+    /// Splits the snapshot into the network state and container map used by the nftables manager.
+    pub fn into_state(self) -> (NetworkState, HashMap<String, IpAddr>) {
+        // A snapshot carries no link details, so `match:` blocks only see the interface names
+        let network_state = NetworkState {
+            links: self.interfaces.keys().map(|name| (name.clone(), LinkDetails::default())).collect(),
+            interface_ips: self.interfaces,
+            ..Default::default()
+        };
+        (network_state, self.containers)
+    }
+}
+
 /// Represents the shared application state.
 #[derive(Debug, Default)] // Removed AppConfig/AppState structs from here as they are separate concerns
 pub struct AppStateShared {
@@ -86,8 +1082,25 @@ pub struct AppStateShared {
 
 #[derive(Debug, Clone)]
 pub enum NetworkEvent {
-    IpUpdate { interface: String, ips: Vec<IpAddr> },
-    LinkChanged { name: String, is_up: bool },
+    /// Current addresses of an interface.
+    IpUpdate { interface: String, addresses: Vec<InterfaceAddress> },
+    /// A link appeared, changed or went up or down; `details` is `None` once it is gone.
+    LinkChanged { name: String, is_up: bool, details: Option<LinkDetails> },
+    /// A neighbor table entry appeared or changed its MAC address or state
+    /// (`present: true`), or was deleted.
+    PeerChanged { peer: Peer, present: bool },
+    /// A main-table route was added or replaced (`present: true`) or deleted.
+    RouteChanged { route: Route, present: bool },
+}
+
+/// Reasons to compare the live ruleset with the managed one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RulesetEvent {
+    /// nfnetlink reported a ruleset change (by this daemon or another tool;
+    /// see `OWN_CHANGE_WINDOW` in `drift`).
+    Changed,
+    /// The periodic read-back is due.
+    PeriodicCheck,
 }
 
 #[derive(Debug)]
@@ -95,6 +1108,10 @@ pub enum ControlCommand {
     Reload,
     Status { response_tx: oneshot::Sender<String> },
     Ping { response_tx: oneshot::Sender<String> },
+    /// Block an address or network, optionally for `ttl` seconds.
+    Block { net: IpNet, ttl: Option<u64>, reason: Option<String>, response_tx: oneshot::Sender<String> },
+    Unblock { net: IpNet, response_tx: oneshot::Sender<String> },
+    Blocklist { response_tx: oneshot::Sender<String> },
     Shutdown, // Graceful shutdown command
 }
 
@@ -102,10 +1119,18 @@ pub enum ControlCommand {
 pub type EventSender = mpsc::Sender<SystemEvent>;
 pub type EventReceiver = mpsc::Receiver<SystemEvent>; // Keep receiver alias
 
+/// Docker networks and labels of a running container.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContainerDetails {
+    /// Network name -> the container's addresses on that network.
+    pub networks: BTreeMap<String, Vec<IpAddr>>,
+    pub labels: BTreeMap<String, String>,
+}
+
 /// Events related to Docker containers.
 #[derive(Debug, Clone)]
 pub enum DockerEvent {
-    ContainerStarted(String, Option<IpAddr>), // Container ID, Optional IP Address
+    ContainerStarted(String, Option<IpAddr>, Vec<ContainerForward>, ContainerDetails), // Container ID, Optional IP Address, label port forwards, networks and labels; resent on network connect/disconnect
     ContainerStopped(String),                // Container ID
 }
 
@@ -114,6 +1139,7 @@ pub enum DockerEvent {
 pub enum SystemEvent {
     Network(NetworkEvent),
     Docker(DockerEvent),
+    Ruleset(RulesetEvent),
     Control(ControlCommand),
     Signal(i32),
 }
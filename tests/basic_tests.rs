@@ -32,6 +32,8 @@ async fn test_config_loading_integration() {
     // If it's intended to be public, consider making it public or providing a public wrapper.
     // For demonstration, let's assume there's a public wrapper or an alternative validation method.
     // assert!(validate_config(&config).is_ok());
+    assert_eq!(config.interfaces.len(), 2);
+    assert_eq!(config.interfaces[1].nftables_zone.as_deref(), Some("wan"));
 }
 
 #[test]
@@ -42,17 +44,17 @@ fn test_component_instantiation() {
         let (_temp_file, config) = create_dummy_config_file();
 
         // Create channels
-        let (network_tx, _network_rx) = mpsc::channel::<NetworkEvent>(100);
-        let (control_tx, _control_rx) = mpsc::channel::<ControlCommand>(10);
-        let (docker_ev, _docker_ev) = mpsc::channel::<DockerEvent>(100);
+        let (_network_tx, _network_rx) = mpsc::channel::<NetworkEvent>(100);
+        let (_control_tx, _control_rx) = mpsc::channel::<ControlCommand>(10);
+        let (_docker_tx, _docker_rx) = mpsc::channel::<DockerEvent>(100);
         let (system_ev, _system_ev) = mpsc::channel::<SystemEvent>(100);
 
         // Test NetworkMonitor instantiation (Assuming new returns Self)
-        let _monitor = NetworkMonitor::new(system_ev.clone()); 
+        let _monitor = NetworkMonitor::new(system_ev.clone(), config.netlink_resync_interval());
 
         // Test NftablesManager instantiation
-        let interface_config_arc = Arc::new(Mutex::new(config.interfaces.clone()));
-        let nft_manager_result = NftablesManager::new(interface_config_arc).await;
+        let app_config_arc = Arc::new(Mutex::new(config.clone()));
+        let nft_manager_result = NftablesManager::new(app_config_arc).await;
         assert!(nft_manager_result.is_ok(), "NftablesManager creation failed: {:?}", nft_manager_result.err());
 
         // Test SocketHandler instantiation
@@ -1,29 +1,45 @@
 use rust_network_mgr::{
+    backend::{FirewallBackend, MemoryBackend},
     nftables::NftablesManager,
-    types::{InterfaceConfig, NetworkState}
+    ruleset::RulesetModel,
+    types::{
+        AppConfig, AppError, ContainerDetails, ContainerForward, FlowOffloadConfig, ForwardProtocol, InterfaceConfig, LinkDetails,
+        NetworkState, Peer, PeerState, PortForward, ZoneConfig,
+    }
 };
 
-use std::net::{IpAddr, Ipv4Addr};
+use nftables::batch::Batch;
+use nftables::expr::{Expression, NamedExpression, Payload, PayloadField};
+use nftables::schema::{Chain, Counter, Element, NfCmd, NfListObject, NfObject, FlushObject, Rule, Set, SetType, SetTypeValue};
+use nftables::stmt::{Match, Operator, Statement};
+use nftables::types::NfFamily;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 use tokio::sync::Mutex as AsyncMutex;
 use tokio::runtime::Runtime;
 
 /// Helper to create a mock interface configuration.
-fn create_mock_config() -> Arc<AsyncMutex<Vec<InterfaceConfig>>> {
-    Arc::new(AsyncMutex::new(vec![
-        InterfaceConfig {
-            name: "eth0".to_string(),
-            dhcp: Some(true),
-            address: None,
-            nftables_zone: Some("wan".to_string()),
-        },
-        InterfaceConfig {
-            name: "eth1".to_string(),
-            dhcp: None,
-            address: Some("192.168.1.1/24".to_string()),
-            nftables_zone: Some("lan".to_string()),
-        },
-    ]))
+fn create_mock_config() -> Arc<AsyncMutex<AppConfig>> {
+    Arc::new(AsyncMutex::new(AppConfig {
+        interfaces: vec![
+            InterfaceConfig {
+                name: "eth0".to_string(),
+                dhcp: Some(true),
+                address: None,
+                nftables_zone: Some("wan".to_string()),
+                matcher: None,
+            },
+            InterfaceConfig {
+                name: "eth1".to_string(),
+                dhcp: None,
+                address: Some("192.168.1.1/24".to_string()),
+                nftables_zone: Some("lan".to_string()),
+                matcher: None,
+            },
+        ],
+        ..Default::default()
+    }))
 }
 
 /// Helper to create a test network state with IPs.
@@ -95,9 +111,1041 @@ fn test_nftables_set_management() {
         assert!(result.is_ok(), "load_rules should succeed: {:?}", result.err());
 
         let network_state = create_test_network_state();
-        let apply_result = manager.apply_rules(&network_state).await;
+        let apply_result = manager.apply_rules(&network_state, &HashMap::new()).await;
         assert!(apply_result.is_ok(), "apply_rules should succeed: {:?}", apply_result.err());
     });
 }
 
-// Add more tests as needed for specific functionality 
\ No newline at end of file
+// The tests below run against the in-memory backend and need no privileges.
+
+async fn memory_manager(config: Arc<AsyncMutex<AppConfig>>) -> (NftablesManager, Arc<MemoryBackend>) {
+    let backend = Arc::new(MemoryBackend::new());
+    let manager = NftablesManager::with_backend(config, backend.clone())
+        .await
+        .expect("Failed to create NftablesManager");
+    (manager, backend)
+}
+
+fn elements(backend: &MemoryBackend, set: &str) -> Vec<String> {
+    backend.set_elements(NfFamily::INet, "filter", set)
+        .unwrap_or_else(|| panic!("set {} should exist", set))
+}
+
+fn peer(ip: IpAddr, mac: &str, state: PeerState) -> Peer {
+    Peer { ip, mac: Some(mac.parse().unwrap()), interface: "eth1".to_string(), state }
+}
+
+fn flushes(ruleset: &nftables::schema::Nftables) -> usize {
+    ruleset.objects.iter()
+        .filter(|o| matches!(o, NfObject::CmdObject(NfCmd::Flush(FlushObject::Set(_)))))
+        .count()
+}
+
+#[test]
+fn test_memory_backend_populates_zone_sets() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (manager, backend) = memory_manager(create_mock_config()).await;
+        manager.load_rules().await.unwrap();
+        manager.apply_rules(&create_test_network_state(), &HashMap::new()).await.unwrap();
+
+        assert_eq!(elements(&backend, "wan_ips"), vec!["192.0.2.100"]);
+        assert_eq!(elements(&backend, "lan_ips"), vec!["192.168.1.1"]);
+        assert_eq!(elements(&backend, "lan_nets"), vec!["192.168.1.0/24"]);
+        assert!(elements(&backend, "docker_ips").is_empty());
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_applies_only_changes() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (manager, backend) = memory_manager(create_mock_config()).await;
+        manager.load_rules().await.unwrap();
+        let mut state = create_test_network_state();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        // The first reconcile after loading flushes and refills every set
+        assert!(flushes(backend.transactions().last().unwrap()) > 0);
+
+        state.interface_ips.insert("eth0".to_string(), vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 200))]);
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(flushes(backend.transactions().last().unwrap()), 0);
+        assert_eq!(elements(&backend, "wan_ips"), vec!["192.0.2.200"]);
+        assert_eq!(elements(&backend, "lan_ips"), vec!["192.168.1.1"]);
+
+        // Nothing changed: no transaction at all
+        let count = backend.transactions().len();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(backend.transactions().len(), count);
+    });
+}
+
+#[test]
+fn test_memory_backend_merges_docker_containers() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (manager, backend) = memory_manager(create_mock_config()).await;
+        manager.load_rules().await.unwrap();
+        let mut containers = HashMap::new();
+        containers.insert("web".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)));
+        containers.insert("db".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 3)));
+        containers.insert("v6".to_string(), IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)));
+        manager.apply_rules(&create_test_network_state(), &containers).await.unwrap();
+        assert_eq!(elements(&backend, "docker_ips"), vec!["172.17.0.2", "172.17.0.3"]);
+        assert_eq!(elements(&backend, "docker_ipv6"), vec!["fd00::2"]);
+
+        containers.remove("web");
+        manager.apply_rules(&create_test_network_state(), &containers).await.unwrap();
+        assert_eq!(elements(&backend, "docker_ips"), vec!["172.17.0.3"]);
+    });
+}
+
+#[test]
+fn test_memory_backend_reload_removes_orphans() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        let (manager, backend) = memory_manager(config.clone()).await;
+        manager.load_rules().await.unwrap();
+        manager.apply_rules(&create_test_network_state(), &HashMap::new()).await.unwrap();
+
+        // Drop the "wan" zone and reload
+        config.lock().await.interfaces.retain(|i| i.name != "eth0");
+        manager.load_rules().await.unwrap();
+        assert!(backend.set_elements(NfFamily::INet, "filter", "wan_ips").is_none());
+        // Surviving sets keep their elements until the next reconcile refills them
+        assert_eq!(elements(&backend, "lan_ips"), vec!["192.168.1.1"]);
+
+        manager.apply_rules(&create_test_network_state(), &HashMap::new()).await.unwrap();
+        assert_eq!(elements(&backend, "lan_ips"), vec!["192.168.1.1"]);
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_foreign_chains_survive_load_rules() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.policy = Some(serde_yaml::from_str("input: drop").unwrap());
+        config.lock().await.port_forwards.push(PortForward {
+            from: "wan".to_string(),
+            protocol: ForwardProtocol::Tcp,
+            external_port: 8080,
+            target: "192.168.1.10:80".parse().unwrap(),
+        });
+        let (manager, backend) = memory_manager(config).await;
+
+        // The distribution's own `input` chain, and a hand-written one named like a managed chain
+        let mut batch = Batch::new();
+        batch.add(NfListObject::Table(nftables::schema::Table { family: NfFamily::INet, name: "filter".into(), handle: None }));
+        for name in ["input", "prerouting"] {
+            batch.add(NfListObject::Chain(Chain {
+                family: NfFamily::INet,
+                table: "filter".into(),
+                name: name.into(),
+                ..Default::default()
+            }));
+            batch.add(NfListObject::Rule(Rule {
+                family: NfFamily::INet,
+                table: "filter".into(),
+                chain: name.into(),
+                expr: vec![Statement::Accept(None)].into(),
+                ..Default::default()
+            }));
+        }
+        backend.apply(&batch.to_nftables()).unwrap();
+
+        manager.load_rules().await.unwrap();
+        manager.apply_rules(&create_test_network_state(), &HashMap::new()).await.unwrap();
+        for name in ["input", "prerouting"] {
+            let rules = backend.chain_rules(NfFamily::INet, "filter", name).unwrap();
+            assert_eq!(rules.len(), 1, "{}: {:#?}", name, rules);
+            assert!(rules[0].comment.is_none());
+        }
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "policy_input").unwrap().len(), 2);
+        assert_eq!(manager.detect_drift().await.unwrap(), Vec::<String>::new());
+    });
+}
+
+#[test]
+fn test_memory_backend_set_timeout_change_recreates_the_set() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.zones.insert("wan".to_string(), ZoneConfig { timeout: Some(3600), ..Default::default() });
+        // The port forward's rule refers to the wan sets
+        config.lock().await.port_forwards.push(PortForward {
+            from: "wan".to_string(),
+            protocol: ForwardProtocol::Tcp,
+            external_port: 8080,
+            target: "192.168.1.10:80".parse().unwrap(),
+        });
+        let (manager, backend) = memory_manager(config.clone()).await;
+        let state = create_test_network_state();
+        manager.load_rules().await.unwrap();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        let wan_ips = || manager.live_ruleset().unwrap().objects.iter()
+            .find_map(|object| match object {
+                NfObject::ListObject(NfListObject::Set(set)) if set.name == "wan_ips" => Some((**set).clone()),
+                _ => None,
+            })
+            .expect("wan_ips should exist");
+        assert_eq!(wan_ips().timeout, Some(3600));
+
+        // Another timeout, then no timeout at all
+        for timeout in [Some(600), None] {
+            config.lock().await.zones.get_mut("wan").unwrap().timeout = timeout;
+            manager.load_rules().await.unwrap();
+            assert_eq!(wan_ips().timeout, timeout);
+            assert_eq!(wan_ips().flags.unwrap_or_default().contains(&nftables::schema::SetFlag::Timeout), timeout.is_some());
+            manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+            assert!(backend.set_elements(NfFamily::INet, "filter", "wan_ips").is_some_and(|elements| !elements.is_empty()));
+            assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "prerouting").unwrap().len(), 1);
+            assert!(manager.detect_drift().await.unwrap().is_empty());
+        }
+    });
+}
+
+#[test]
+fn test_memory_backend_reload_keeps_referenced_orphans() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        let (manager, backend) = memory_manager(config.clone()).await;
+        manager.load_rules().await.unwrap();
+
+        // A hand-written rule matching on the "wan" zone's set
+        let mut batch = Batch::new();
+        batch.add(NfListObject::Chain(Chain {
+            family: NfFamily::INet,
+            table: "filter".into(),
+            name: "custom".into(),
+            ..Default::default()
+        }));
+        batch.add(NfListObject::Rule(Rule {
+            family: NfFamily::INet,
+            table: "filter".into(),
+            chain: "custom".into(),
+            expr: vec![
+                Statement::Match(Match {
+                    left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                        protocol: "ip".into(),
+                        field: "saddr".into(),
+                    }))),
+                    right: Expression::String("@wan_ips".into()),
+                    op: Operator::EQ,
+                }),
+                Statement::Drop(None),
+            ].into(),
+            ..Default::default()
+        }));
+        backend.apply(&batch.to_nftables()).unwrap();
+
+        config.lock().await.interfaces.retain(|i| i.name != "eth0");
+        manager.load_rules().await.unwrap();
+        assert!(backend.set_elements(NfFamily::INet, "filter", "wan_ips").is_some());
+        assert!(backend.set_elements(NfFamily::INet, "filter", "wan_ipv6").is_none());
+    });
+}
+
+#[test]
+fn test_memory_backend_reload_removes_meters_of_removed_limits() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.zones.insert("wan".to_string(), ZoneConfig {
+            max_conns_per_source: Some(200),
+            ..Default::default()
+        });
+        let (manager, backend) = memory_manager(config.clone()).await;
+        manager.load_rules().await.unwrap();
+
+        // The kernel creates the meter sets, without a comment
+        let mut batch = Batch::new();
+        for (name, set_type) in [("wan_conns", SetType::Ipv4Addr), ("wan_conns6", SetType::Ipv6Addr), ("custom", SetType::Ipv4Addr)] {
+            batch.add(NfListObject::Set(Box::new(Set {
+                family: NfFamily::INet,
+                table: "filter".into(),
+                name: name.into(),
+                set_type: SetTypeValue::Single(set_type),
+                ..Default::default()
+            })));
+        }
+        backend.apply(&batch.to_nftables()).unwrap();
+        manager.load_rules().await.unwrap();
+        assert!(backend.set_elements(NfFamily::INet, "filter", "wan_conns").is_some());
+
+        // Dropping the limit deletes its meters, but not hand-written sets
+        config.lock().await.zones.remove("wan");
+        manager.load_rules().await.unwrap();
+        assert!(backend.set_elements(NfFamily::INet, "filter", "wan_conns").is_none());
+        assert!(backend.set_elements(NfFamily::INet, "filter", "wan_conns6").is_none());
+        assert!(backend.set_elements(NfFamily::INet, "filter", "custom").is_some());
+    });
+}
+
+#[test]
+fn test_memory_backend_failed_apply_refills_sets() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (manager, backend) = memory_manager(create_mock_config()).await;
+        manager.load_rules().await.unwrap();
+        let mut state = create_test_network_state();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+
+        state.interface_ips.insert("eth1".to_string(), vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2))]);
+        backend.fail_next_apply("simulated failure");
+        assert!(manager.apply_rules(&state, &HashMap::new()).await.is_err());
+        assert_eq!(elements(&backend, "lan_ips"), vec!["192.168.1.1"]);
+        // An atomic backend is not snapshotted, so there is nothing to roll back
+        assert!(!manager.last_apply().await.unwrap().rolled_back);
+
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert!(flushes(backend.transactions().last().unwrap()) > 0);
+        assert_eq!(elements(&backend, "lan_ips"), vec!["192.168.1.2"]);
+    });
+}
+
+#[test]
+fn test_memory_backend_rolls_back_partial_apply() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let backend = Arc::new(MemoryBackend::non_atomic());
+        let manager = NftablesManager::with_backend(create_mock_config(), backend.clone()).await.unwrap();
+        manager.load_rules().await.unwrap();
+        let state = create_test_network_state();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert!(manager.last_apply().await.unwrap().success);
+        let sets = ["wan_ips", "lan_ips", "lan_nets", "docker_ips"];
+        let before: Vec<Vec<String>> = sets.iter().map(|set| elements(&backend, set)).collect();
+
+        // A reload makes the next apply flush and refill every set; stop it just
+        // before the first refill, leaving a flushed set behind
+        manager.load_rules().await.unwrap();
+        let refill = manager.plan_apply_rules(&state, &HashMap::new()).await;
+        let first_add = refill.objects.iter()
+            .position(|object| matches!(object, NfObject::CmdObject(NfCmd::Add(NfListObject::Element(_)))))
+            .unwrap();
+        for attempt in 1..=3 {
+            backend.fail_next_apply_after(first_add, "simulated failure");
+            assert!(manager.apply_rules(&state, &HashMap::new()).await.is_err());
+            let result = manager.last_apply().await.unwrap();
+            assert!(!result.success && result.rolled_back);
+            assert!(result.error.unwrap().contains("simulated failure"));
+            assert_eq!(result.consecutive_failures, attempt);
+            assert_eq!(sets.iter().map(|set| elements(&backend, set)).collect::<Vec<_>>(), before);
+        }
+        assert!(manager.degraded().await.unwrap().contains("simulated failure"));
+
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(manager.last_apply().await.unwrap().consecutive_failures, 0);
+        assert!(manager.degraded().await.is_none());
+
+        // Chains are restored too: a container forward rebuild stopped after the flush
+        manager.set_container_forwards("c1", vec![ContainerForward {
+            from: None,
+            protocol: ForwardProtocol::Tcp,
+            external_port: 8080,
+            container_port: 80,
+        }]).await;
+        let containers = HashMap::from([("c1".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)))]);
+        manager.apply_rules(&state, &containers).await.unwrap();
+        let rules = backend.chain_rules(NfFamily::INet, "filter", "prerouting").unwrap();
+        manager.set_container_forwards("c1", Vec::new()).await;
+        manager.set_container_forwards("c2", vec![ContainerForward {
+            from: None,
+            protocol: ForwardProtocol::Tcp,
+            external_port: 8081,
+            container_port: 80,
+        }]).await;
+        // Same address, so the transaction starts with the chain: add, flush, rules
+        let containers = HashMap::from([("c2".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)))]);
+        backend.fail_next_apply_after(2, "simulated failure");
+        assert!(manager.apply_rules(&state, &containers).await.is_err());
+        assert!(manager.last_apply().await.unwrap().rolled_back);
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "prerouting").unwrap(), rules);
+
+        // Failed reloads are recorded as well
+        backend.fail_next_apply("simulated reload failure");
+        assert!(manager.load_rules().await.is_err());
+        let result = manager.last_apply().await.unwrap();
+        assert!(!result.success && result.error.unwrap().contains("simulated reload failure"));
+        manager.load_rules().await.unwrap();
+        assert!(manager.last_apply().await.unwrap().success);
+    });
+}
+
+#[test]
+fn test_memory_backend_repairs_flushed_ruleset() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (manager, backend) = memory_manager(create_mock_config()).await;
+        manager.load_rules().await.unwrap();
+        let state = create_test_network_state();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+
+        backend.flush_ruleset();
+        assert!(!manager.detect_drift().await.unwrap().is_empty());
+        assert!(manager.repair_drift(&state, &HashMap::new()).await.unwrap());
+        assert_eq!(manager.repair_count(), 1);
+        assert_eq!(elements(&backend, "lan_ips"), vec!["192.168.1.1"]);
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+        assert!(!manager.repair_drift(&state, &HashMap::new()).await.unwrap());
+    });
+}
+
+#[test]
+fn test_memory_backend_rule_edited_in_place_is_drift() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (manager, backend) = memory_manager(create_mock_config()).await;
+        manager.load_rules().await.unwrap();
+        let forward = ContainerForward { from: None, protocol: ForwardProtocol::Tcp, external_port: 8080, container_port: 80 };
+        manager.set_container_forwards("web", vec![forward]).await;
+        let state = create_test_network_state();
+        let mut containers = HashMap::new();
+        containers.insert("web".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)));
+        manager.apply_rules(&state, &containers).await.unwrap();
+        assert!(manager.committed_within(std::time::Duration::from_secs(60)));
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+
+        // Another tool rewrites the DNAT rule to another port: same number of rules
+        let rules = backend.chain_rules(NfFamily::INet, "filter", "prerouting").unwrap();
+        let edited = serde_json::to_string(&rules).unwrap().replace("8080", "8081");
+        let edited: Vec<Rule<'static>> = serde_json::from_str(&edited).unwrap();
+        let mut batch = Batch::new();
+        batch.add_cmd(NfCmd::Flush(FlushObject::Chain(Chain {
+            family: NfFamily::INet,
+            table: "filter".into(),
+            name: "prerouting".into(),
+            ..Default::default()
+        })));
+        for rule in edited {
+            batch.add(NfListObject::Rule(rule));
+        }
+        backend.apply(&batch.to_nftables()).unwrap();
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "prerouting").unwrap().len(), rules.len());
+
+        assert_eq!(
+            manager.detect_drift().await.unwrap(),
+            vec!["chain inet filter prerouting has 1 missing and 1 unexpected rule(s)"]
+        );
+        assert!(manager.repair_drift(&state, &containers).await.unwrap());
+        let repaired = serde_json::to_string(&backend.chain_rules(NfFamily::INet, "filter", "prerouting").unwrap()).unwrap();
+        assert!(repaired.contains("8080") && !repaired.contains("8081"));
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_container_port_forwards() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (manager, backend) = memory_manager(create_mock_config()).await;
+        manager.load_rules().await.unwrap();
+        let state = create_test_network_state();
+        let prerouting = || backend.chain_rules(NfFamily::INet, "filter", "prerouting");
+        let dnat_target = |rules: Vec<nftables::schema::Rule>| {
+            let json = serde_json::to_value(&rules[0].expr).unwrap();
+            (json[5]["dnat"]["addr"].clone(), json[5]["dnat"]["port"].clone())
+        };
+
+        let forward = ContainerForward { from: None, protocol: ForwardProtocol::Tcp, external_port: 8080, container_port: 80 };
+        manager.set_container_forwards("web", vec![forward.clone()]).await;
+        let mut containers = HashMap::new();
+        containers.insert("web".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)));
+        manager.apply_rules(&state, &containers).await.unwrap();
+        let rules = prerouting().expect("prerouting chain should exist");
+        assert_eq!(rules.len(), 1);
+        assert_eq!(dnat_target(rules), (serde_json::json!("172.17.0.2"), serde_json::json!(80)));
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+
+        // Restart with a new address: the rule follows
+        containers.insert("web".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 5)));
+        manager.apply_rules(&state, &containers).await.unwrap();
+        assert_eq!(dnat_target(prerouting().unwrap()), (serde_json::json!("172.17.0.5"), serde_json::json!(80)));
+
+        // A reload keeps the forward, before the next reconcile rebuilds the chain
+        manager.load_rules().await.unwrap();
+        assert_eq!(dnat_target(prerouting().unwrap()), (serde_json::json!("172.17.0.5"), serde_json::json!(80)));
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+        manager.apply_rules(&state, &containers).await.unwrap();
+        assert_eq!(prerouting().unwrap().len(), 1);
+
+        // Stopped: the forward and, with no static forwards, the chain go away
+        manager.set_container_forwards("web", Vec::new()).await;
+        containers.remove("web");
+        manager.apply_rules(&state, &containers).await.unwrap();
+        assert!(prerouting().is_none());
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_colliding_container_forwards_are_skipped() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.port_forwards.push(PortForward {
+            from: "wan".to_string(),
+            protocol: ForwardProtocol::Tcp,
+            external_port: 8443,
+            target: "192.168.1.10:443".parse().unwrap(),
+        });
+        let (manager, backend) = memory_manager(config).await;
+        manager.load_rules().await.unwrap();
+        let forward = |external_port| ContainerForward { from: None, protocol: ForwardProtocol::Tcp, external_port, container_port: 80 };
+        // `b` collides with `a`, `c` with the static forward
+        manager.set_container_forwards("a", vec![forward(8080)]).await;
+        manager.set_container_forwards("b", vec![forward(8080)]).await;
+        manager.set_container_forwards("c", vec![forward(8443), forward(9000)]).await;
+        let mut containers = HashMap::new();
+        containers.insert("a".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)));
+        containers.insert("b".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 3)));
+        containers.insert("c".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 4)));
+        manager.apply_rules(&create_test_network_state(), &containers).await.unwrap();
+
+        let targets: Vec<(serde_json::Value, serde_json::Value)> = backend.chain_rules(NfFamily::INet, "filter", "prerouting").unwrap()
+            .iter()
+            .map(|rule| {
+                let json = serde_json::to_value(&rule.expr).unwrap();
+                (json[3]["match"]["right"].clone(), json[5]["dnat"]["addr"].clone())
+            })
+            .collect();
+        assert_eq!(targets, vec![
+            (serde_json::json!(8443), serde_json::json!("192.168.1.10")),
+            (serde_json::json!(8080), serde_json::json!("172.17.0.2")),
+            (serde_json::json!(9000), serde_json::json!("172.17.0.4")),
+        ]);
+    });
+}
+
+#[test]
+fn test_memory_backend_forward_removed_after_failed_apply() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (manager, backend) = memory_manager(create_mock_config()).await;
+        manager.load_rules().await.unwrap();
+        let mut state = create_test_network_state();
+        let forward = ContainerForward { from: None, protocol: ForwardProtocol::Tcp, external_port: 8080, container_port: 80 };
+        manager.set_container_forwards("web", vec![forward]).await;
+        let mut containers = HashMap::new();
+        containers.insert("web".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)));
+        manager.apply_rules(&state, &containers).await.unwrap();
+
+        state.interface_ips.insert("eth1".to_string(), vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2))]);
+        backend.fail_next_apply("simulated failure");
+        assert!(manager.apply_rules(&state, &containers).await.is_err());
+
+        // The forward goes away while the kernel state is unknown
+        manager.set_container_forwards("web", Vec::new()).await;
+        containers.remove("web");
+        manager.apply_rules(&state, &containers).await.unwrap();
+        assert!(backend.chain_rules(NfFamily::INet, "filter", "prerouting").is_none());
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_forward_policy_accepts_container_forwards() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.policy = Some(serde_yaml::from_str("forward: drop").unwrap());
+        let (manager, backend) = memory_manager(config).await;
+        // No static port forwards: the container forward arrives after the chains were built
+        manager.load_rules().await.unwrap();
+        let forward = ContainerForward { from: None, protocol: ForwardProtocol::Tcp, external_port: 8080, container_port: 80 };
+        manager.set_container_forwards("web", vec![forward]).await;
+        let containers = HashMap::from([("web".to_string(), IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)))]);
+        manager.apply_rules(&create_test_network_state(), &containers).await.unwrap();
+
+        // The DNAT rule marks its connections, and only marked ones are accepted
+        let prerouting = backend.chain_rules(NfFamily::INet, "filter", "prerouting").expect("prerouting chain should exist");
+        let sets_mark = serde_json::to_value(&prerouting[0].expr).unwrap()[4]["mangle"].clone();
+        assert_eq!(sets_mark["key"]["ct"]["key"], "mark", "{:#?}", prerouting);
+        let mark = sets_mark["value"]["|"][1].clone();
+        let forward_rules = backend.chain_rules(NfFamily::INet, "filter", "policy_forward").expect("forward chain should exist");
+        let accepts_dnat = forward_rules.iter().any(|rule| {
+            let json = serde_json::to_value(&rule.expr).unwrap();
+            json[0]["match"]["left"]["ct"]["key"] == "status" && json[0]["match"]["right"] == "dnat"
+                && json[1]["match"]["left"]["&"][1] == mark && json[1]["match"]["right"] == mark
+        });
+        assert!(accepts_dnat, "{:#?}", forward_rules);
+    });
+}
+
+#[test]
+fn test_memory_backend_zone_counters() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        let (manager, backend) = memory_manager(config.clone()).await;
+        manager.load_rules().await.unwrap();
+        manager.apply_rules(&create_test_network_state(), &HashMap::new()).await.unwrap();
+        assert!(backend.set_counter(NfFamily::INet, "filter", "zone_wan_in", 10, 1500));
+        assert!(backend.set_counter(NfFamily::INet, "filter", "zone_docker_out", 5_000_000_000, 6_000_000_000));
+
+        manager.refresh_counters().await.unwrap();
+        let counters = manager.zone_counters().await;
+        let find = |zone: &str, direction: &str| counters.iter()
+            .find(|c| c.zone == zone && c.direction == direction)
+            .map(|c| (c.packets, c.bytes));
+        assert_eq!(counters.len(), 6);
+        assert_eq!(find("wan", "in"), Some((10, 1500)));
+        assert_eq!(find("wan", "out"), Some((0, 0)));
+        assert_eq!(find("docker", "out"), Some((5_000_000_000, 6_000_000_000)));
+
+        // Reloading keeps the values
+        manager.load_rules().await.unwrap();
+        manager.refresh_counters().await.unwrap();
+        assert_eq!(manager.zone_counters().await, counters);
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+
+        // A removed zone's counters are deleted on reload, and all of them once counting is off
+        config.lock().await.interfaces.retain(|iface| iface.name != "eth0");
+        manager.load_rules().await.unwrap();
+        let counter_names = || RulesetModel::from_ruleset(&manager.live_ruleset().unwrap()).counter_names();
+        assert!(!counter_names().iter().any(|name| name.starts_with("zone_wan_")), "{:?}", counter_names());
+        assert!(counter_names().contains(&"zone_lan_in".to_string()));
+        // `counter_interval` is read at startup, so this takes a restart
+        config.lock().await.nftables.counter_interval = 0;
+        let restarted = NftablesManager::with_backend(config, backend.clone()).await.unwrap();
+        restarted.load_rules().await.unwrap();
+        assert!(counter_names().is_empty(), "{:?}", counter_names());
+
+        // Deleting the owned objects removes the counters and the empty table
+        manager.cleanup(rust_network_mgr::types::ShutdownAction::Delete).await.unwrap();
+        assert!(manager.live_ruleset().unwrap().objects.is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_hand_written_zone_counter_is_kept() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        let (manager, backend) = memory_manager(config.clone()).await;
+        manager.load_rules().await.unwrap();
+        // Named like a zone counter, but no configured zone gives it this name
+        let mut batch = Batch::new();
+        batch.add(NfListObject::Counter(Counter {
+            family: NfFamily::INet,
+            table: "filter".into(),
+            name: "zone_guest_in".into(),
+            ..Default::default()
+        }));
+        backend.apply(&batch.to_nftables()).unwrap();
+        let counter_names = || RulesetModel::from_ruleset(&manager.live_ruleset().unwrap()).counter_names();
+
+        config.lock().await.interfaces.retain(|iface| iface.name != "eth0");
+        manager.load_rules().await.unwrap();
+        assert!(!counter_names().iter().any(|name| name.starts_with("zone_wan_")), "{:?}", counter_names());
+        assert!(counter_names().contains(&"zone_guest_in".to_string()));
+
+        manager.cleanup(rust_network_mgr::types::ShutdownAction::Delete).await.unwrap();
+        assert_eq!(counter_names(), vec!["zone_guest_in".to_string()]);
+    });
+}
+
+#[test]
+fn test_memory_backend_rule_fragments() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &str| std::fs::write(dir.path().join(name), content).unwrap();
+        // Flushing its chain first keeps the fragment idempotent across reloads
+        write("10-custom.json", r#"{"nftables": [
+            {"add": {"chain": {"family": "${family}", "table": "${table}", "name": "custom"}}},
+            {"flush": {"chain": {"family": "${family}", "table": "${table}", "name": "custom"}}},
+            {"add": {"rule": {"family": "${family}", "table": "${table}", "chain": "custom", "expr": [
+                {"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": "@${lan.nets}"}},
+                {"accept": null}
+            ]}}}
+        ]}"#);
+        write("20-missing-chain.json", r#"{"nftables": [
+            {"add": {"rule": {"family": "inet", "table": "filter", "chain": "missing", "expr": [{"accept": null}]}}}
+        ]}"#);
+        write("30-ssh.nft", "add rule ${family} ${table} custom ip saddr @${wan} tcp dport 22 accept\n");
+
+        let config = create_mock_config();
+        config.lock().await.nftables_rules_path = Some(dir.path().to_str().unwrap().to_string());
+        let (manager, backend) = memory_manager(config).await;
+        manager.load_rules().await.unwrap();
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "custom").unwrap().len(), 1);
+        assert!(backend.chain_rules(NfFamily::INet, "filter", "missing").is_none());
+        assert_eq!(backend.scripts(), vec!["add rule inet filter custom ip saddr @wan_ips tcp dport 22 accept\n"]);
+        manager.apply_rules(&create_test_network_state(), &HashMap::new()).await.unwrap();
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+
+        // Fragments are re-read on reload
+        write("30-ssh.nft", "add rule ${family} ${table} custom ip saddr @${wan} tcp dport 2222 accept\n");
+        manager.load_rules().await.unwrap();
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "custom").unwrap().len(), 1);
+        assert!(backend.scripts().last().unwrap().contains("dport 2222"));
+
+        // A failed script transaction fails the load and is recorded
+        backend.fail_next_script("simulated failure");
+        let error = manager.load_rules().await.unwrap_err().to_string();
+        assert!(error.contains("simulated failure"), "{}", error);
+        let last_apply = manager.last_apply().await.unwrap();
+        assert!(!last_apply.success);
+        assert!(last_apply.error.unwrap().contains("simulated failure"));
+        assert_eq!(backend.scripts().len(), 2);
+        manager.load_rules().await.unwrap();
+        assert!(manager.last_apply().await.unwrap().success);
+    });
+}
+
+#[test]
+fn test_memory_backend_fragment_in_managed_chain_is_not_drift() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("10-ssh.json"), r#"{"nftables": [
+            {"add": {"rule": {"family": "${family}", "table": "${table}", "chain": "policy_input", "expr": [
+                {"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": 22}},
+                {"accept": null}
+            ]}}}
+        ]}"#).unwrap();
+        let config = create_mock_config();
+        config.lock().await.policy = Some(serde_yaml::from_str("input: drop").unwrap());
+        config.lock().await.nftables_rules_path = Some(dir.path().to_str().unwrap().to_string());
+        let (manager, backend) = memory_manager(config).await;
+        manager.load_rules().await.unwrap();
+        let state = create_test_network_state();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "policy_input").unwrap().len(), 3);
+
+        assert_eq!(manager.detect_drift().await.unwrap(), Vec::<String>::new());
+        assert!(!manager.repair_drift(&state, &HashMap::new()).await.unwrap());
+        assert_eq!(manager.repair_count(), 0);
+
+        // Losing the fragment's rule is drift, and the repair restores it
+        backend.flush_ruleset();
+        assert!(manager.repair_drift(&state, &HashMap::new()).await.unwrap());
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "policy_input").unwrap().len(), 3);
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_flow_offload_follows_links() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.flow_offload = Some(FlowOffloadConfig { zones: vec!["lan".to_string(), "wan".to_string()] });
+        let (manager, backend) = memory_manager(config).await;
+        let devices = || backend.flowtable_devices(NfFamily::INet, "filter", "offload");
+        let offload_rules = || backend.chain_rules(NfFamily::INet, "filter", "offload").map(|rules| rules.len());
+        manager.load_rules().await.unwrap();
+
+        // eth1 has an address, eth0 only a link
+        let mut state = NetworkState::default();
+        state.links.insert("eth0".to_string(), LinkDetails::default());
+        state.links.insert("eth1".to_string(), LinkDetails::default());
+        state.interface_ips.insert("eth1".to_string(), vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))]);
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(devices(), Some(vec!["eth1".to_string()]));
+        assert_eq!(offload_rules(), Some(1));
+
+        // A new device is added to the existing flowtable, keeping its flows
+        state.up_links.insert("eth0".to_string());
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(devices(), Some(vec!["eth0".to_string(), "eth1".to_string()]));
+        assert_eq!(offload_rules(), Some(1));
+        let last = backend.transactions().pop().unwrap();
+        assert!(!last.objects.iter().any(|object| matches!(object, NfObject::CmdObject(NfCmd::Delete(_)))));
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+
+        // A reload rebuilds the flowtable with the same devices
+        manager.load_rules().await.unwrap();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(devices(), Some(vec!["eth0".to_string(), "eth1".to_string()]));
+
+        // Devices leave the flowtable with their links, without recreating it
+        let transactions = backend.transactions().len();
+        state.up_links.remove("eth0");
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(backend.transactions().len(), transactions);
+        assert_eq!(
+            backend.scripts().last().map(String::as_str),
+            Some("delete flowtable inet filter offload { devices = { eth0 }; }")
+        );
+
+        state.interface_ips.clear();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(devices(), None);
+        assert_eq!(offload_rules(), None);
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_flow_offload_removed_after_failed_apply() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.flow_offload = Some(FlowOffloadConfig { zones: vec!["lan".to_string(), "wan".to_string()] });
+        let (manager, backend) = memory_manager(config).await;
+        manager.load_rules().await.unwrap();
+        let mut state = NetworkState::default();
+        state.interface_ips.insert("eth1".to_string(), vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))]);
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+
+        state.interface_ips.insert("eth1".to_string(), vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2))]);
+        backend.fail_next_apply("simulated failure");
+        assert!(manager.apply_rules(&state, &HashMap::new()).await.is_err());
+
+        // The last device goes away while the kernel state is unknown
+        state.interface_ips.clear();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(backend.flowtable_devices(NfFamily::INet, "filter", "offload"), None);
+        assert!(backend.chain_rules(NfFamily::INet, "filter", "offload").is_none());
+    });
+}
+
+#[test]
+fn test_memory_backend_ban_sets_survive_reload_and_drift_checks() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.zones.insert("wan".to_string(), ZoneConfig {
+            max_conns_per_source: Some(200),
+            ban_timeout: Some(600),
+            ..Default::default()
+        });
+        let (manager, backend) = memory_manager(config).await;
+        manager.load_rules().await.unwrap();
+        manager.apply_rules(&create_test_network_state(), &HashMap::new()).await.unwrap();
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "limits").map(|rules| rules.len()), Some(4));
+
+        // The limits chain bans a source
+        let mut batch = Batch::new();
+        batch.add(NfListObject::Element(Element {
+            family: NfFamily::INet,
+            table: "filter".into(),
+            name: "wan_ban".into(),
+            elem: vec![Expression::String("203.0.113.7".into())].into(),
+        }));
+        backend.apply(&batch.to_nftables()).unwrap();
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+
+        manager.load_rules().await.unwrap();
+        manager.apply_rules(&create_test_network_state(), &HashMap::new()).await.unwrap();
+        assert_eq!(elements(&backend, "wan_ban"), vec!["203.0.113.7"]);
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "limits").map(|rules| rules.len()), Some(4));
+    });
+}
+
+#[test]
+fn test_memory_backend_blocklist_persists_across_restarts() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let dir = tempfile::tempdir().unwrap();
+        let config = create_mock_config();
+        config.lock().await.blocklist_path = Some(dir.path().join("blocklist.json").to_str().unwrap().to_string());
+        let (manager, backend) = memory_manager(config.clone()).await;
+        manager.load_rules().await.unwrap();
+        assert!(elements(&backend, "blocklist").is_empty());
+        assert_eq!(backend.chain_rules(NfFamily::INet, "filter", "blocklist").map(|rules| rules.len()), Some(2));
+
+        let host = "203.0.113.7/32".parse().unwrap();
+        let entry = manager.block(host, Some(3600), Some("scanner".to_string())).await.unwrap();
+        assert_eq!(entry.expires, Some(entry.added + 3600));
+        manager.block("2001:db8::/32".parse().unwrap(), None, None).await.unwrap();
+        // Blocking again replaces the entry
+        manager.block(host, None, None).await.unwrap();
+        assert_eq!(elements(&backend, "blocklist"), vec!["203.0.113.7"]);
+        assert_eq!(elements(&backend, "blocklist6"), vec!["2001:db8::/32"]);
+        assert_eq!(manager.blocklist().await.len(), 2);
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+        assert!(manager.block("192.0.2.1/32".parse().unwrap(), Some(0), None).await.is_err());
+        // Networks overlapping a blocked entry in either direction are rejected
+        for overlapping in ["2001:db8::1/128", "203.0.113.0/24"] {
+            let result = manager.block(overlapping.parse().unwrap(), None, None).await;
+            assert!(matches!(result, Err(AppError::ConfigValidation(_))), "{} was accepted", overlapping);
+        }
+        assert_eq!(manager.blocklist().await.len(), 2);
+
+        // A restarted daemon refills the sets from the file
+        backend.flush_ruleset();
+        let (restarted, backend) = memory_manager(config).await;
+        restarted.load_rules().await.unwrap();
+        assert_eq!(elements(&backend, "blocklist"), vec!["203.0.113.7"]);
+        let blocked = restarted.blocklist().await;
+        assert_eq!(blocked[0].expires, None);
+        assert_eq!(blocked[0].reason, None);
+
+        assert!(restarted.unblock(host).await.unwrap().is_some());
+        assert!(restarted.unblock(host).await.unwrap().is_none());
+        assert!(elements(&backend, "blocklist").is_empty());
+        restarted.load_rules().await.unwrap();
+        assert_eq!(restarted.blocklist().await.len(), 1);
+    });
+}
+
+#[test]
+fn test_memory_backend_mac_sets_follow_neighbor_table() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        let pinned: IpAddr = "192.168.1.10".parse().unwrap();
+        let neighbor: IpAddr = "192.168.1.20".parse().unwrap();
+        config.lock().await.zones.insert("lan".to_string(), ZoneConfig {
+            learn_macs: true,
+            mac_pins: BTreeMap::from([(pinned, "02:00:00:00:00:0a".parse().unwrap())]),
+            ..Default::default()
+        });
+        let (manager, backend) = memory_manager(config).await;
+        manager.load_rules().await.unwrap();
+
+        let mut state = create_test_network_state();
+        state.peers.insert("eth1".to_string(), HashMap::from([(neighbor, peer(neighbor, "02:00:00:00:00:14", PeerState::Reachable))]));
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(elements(&backend, "lan_macs"), vec!["02:00:00:00:00:0a", "02:00:00:00:00:14"]);
+        assert_eq!(elements(&backend, "lan_ip_macs"), vec!["192.168.1.10 . 02:00:00:00:00:0a", "192.168.1.20 . 02:00:00:00:00:14"]);
+        assert!(elements(&backend, "lan_ip6_macs").is_empty());
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+
+        // The neighbor shows up with another MAC: only the MAC sets are refilled
+        state.peers.insert("eth1".to_string(), HashMap::from([(neighbor, peer(neighbor, "02:00:00:00:00:15", PeerState::Reachable))]));
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        let transaction = backend.transactions().pop().unwrap();
+        assert_eq!(flushes(&transaction), 2);
+        assert_eq!(elements(&backend, "lan_ip_macs"), vec!["192.168.1.10 . 02:00:00:00:00:0a", "192.168.1.20 . 02:00:00:00:00:15"]);
+
+        // A static pin outlives the neighbor entry
+        state.peers.clear();
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(elements(&backend, "lan_macs"), vec!["02:00:00:00:00:0a"]);
+        assert_eq!(elements(&backend, "lan_ip_macs"), vec!["192.168.1.10 . 02:00:00:00:00:0a"]);
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_peer_sets_follow_reachability() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let (manager, backend) = memory_manager(create_mock_config()).await;
+        manager.load_rules().await.unwrap();
+        assert!(elements(&backend, "lan_peers").is_empty());
+        assert!(elements(&backend, "wan_peers6").is_empty());
+
+        let v4: IpAddr = "192.168.1.20".parse().unwrap();
+        let v6: IpAddr = "fe80::20".parse().unwrap();
+        let mut state = create_test_network_state();
+        state.peers.insert("eth1".to_string(), HashMap::from([
+            (v4, peer(v4, "02:00:00:00:00:14", PeerState::Reachable)),
+            (v6, peer(v6, "02:00:00:00:00:14", PeerState::Stale)),
+        ]));
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(elements(&backend, "lan_peers"), vec!["192.168.1.20"]);
+        assert_eq!(elements(&backend, "lan_peers6"), vec!["fe80::20"]);
+        assert!(elements(&backend, "wan_peers").is_empty());
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+
+        // A peer whose address resolution failed leaves the set
+        state.peers.get_mut("eth1").unwrap().insert(v4, peer(v4, "02:00:00:00:00:14", PeerState::Failed));
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert!(elements(&backend, "lan_peers").is_empty());
+        assert_eq!(elements(&backend, "lan_peers6"), vec!["fe80::20"]);
+    });
+}
+
+#[test]
+fn test_memory_backend_matched_interfaces_follow_links() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.interfaces.extend(serde_yaml::from_str::<Vec<InterfaceConfig>>(r#"
+- match: { glob: "veth*" }
+  nftables_zone: containers
+- match: { regex: "wg[0-9]+", kind: wireguard }
+  nftables_zone: vpn
+- match: { glob: "eth*" }
+  nftables_zone: other
+"#).unwrap());
+        let (manager, backend) = memory_manager(config).await;
+        manager.load_rules().await.unwrap();
+        assert!(elements(&backend, "containers_ips").is_empty());
+
+        let wireguard = LinkDetails { kind: Some("wireguard".to_string()), ..Default::default() };
+        let mut links = BTreeMap::from([
+            ("eth1".to_string(), LinkDetails::default()),
+            ("veth0".to_string(), LinkDetails::default()),
+            ("wg0".to_string(), wireguard),
+            ("wg1".to_string(), LinkDetails::default()),
+        ]);
+        assert!(manager.set_links(&links).await);
+        assert!(!manager.set_links(&links).await);
+
+        let mut state = create_test_network_state();
+        for (interface, ip) in [("veth0", "10.1.0.1"), ("wg0", "10.8.0.1"), ("wg1", "10.9.0.1")] {
+            state.interface_ips.insert(interface.to_string(), vec![ip.parse().unwrap()]);
+        }
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert_eq!(elements(&backend, "containers_ips"), vec!["10.1.0.1"]);
+        // wg1 is no wireguard link, and eth1 stays in the zone it is named in
+        assert_eq!(elements(&backend, "vpn_ips"), vec!["10.8.0.1"]);
+        assert!(elements(&backend, "other_ips").is_empty());
+        assert_eq!(elements(&backend, "lan_ips"), vec!["192.168.1.1"]);
+
+        // A link that went away leaves its zone
+        links.remove("veth0");
+        assert!(manager.set_links(&links).await);
+        manager.apply_rules(&state, &HashMap::new()).await.unwrap();
+        assert!(elements(&backend, "containers_ips").is_empty());
+    });
+}
+
+#[test]
+fn test_memory_backend_docker_network_and_label_sets() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let config = create_mock_config();
+        config.lock().await.docker_sets = serde_yaml::from_str("
+networks: [backend]
+labels:
+  db: role=db
+").unwrap();
+        let (manager, backend) = memory_manager(config).await;
+        manager.load_rules().await.unwrap();
+        assert!(elements(&backend, "docker_backend_ips").is_empty());
+        assert!(elements(&backend, "docker_db_ipv6").is_empty());
+
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        manager.set_container_details("db1", ContainerDetails {
+            networks: BTreeMap::from([
+                ("backend".to_string(), vec![ip("172.20.0.5"), ip("fd00:20::5")]),
+                ("monitoring".to_string(), vec![ip("172.22.0.5")]),
+            ]),
+            labels: BTreeMap::from([("role".to_string(), "db".to_string())]),
+        }).await;
+        manager.set_container_details("web1", ContainerDetails {
+            networks: BTreeMap::from([("frontend".to_string(), vec![ip("172.21.0.6")])]),
+            labels: BTreeMap::from([("role".to_string(), "web".to_string())]),
+        }).await;
+        let containers = HashMap::from([
+            ("db1".to_string(), ip("172.20.0.5")),
+            ("web1".to_string(), ip("172.21.0.6")),
+        ]);
+        manager.apply_rules(&create_test_network_state(), &containers).await.unwrap();
+        assert_eq!(elements(&backend, "docker_ips"), vec!["172.20.0.5", "172.21.0.6"]);
+        assert_eq!(elements(&backend, "docker_backend_ips"), vec!["172.20.0.5"]);
+        assert_eq!(elements(&backend, "docker_backend_ipv6"), vec!["fd00:20::5"]);
+        assert_eq!(elements(&backend, "docker_db_ips"), vec!["172.20.0.5", "172.22.0.5"]);
+        assert!(manager.detect_drift().await.unwrap().is_empty());
+
+        // A stopped container leaves every set
+        manager.set_container_details("db1", ContainerDetails::default()).await;
+        let containers = HashMap::from([("web1".to_string(), ip("172.21.0.6"))]);
+        manager.apply_rules(&create_test_network_state(), &containers).await.unwrap();
+        assert_eq!(elements(&backend, "docker_ips"), vec!["172.21.0.6"]);
+        assert!(elements(&backend, "docker_backend_ips").is_empty());
+        assert!(elements(&backend, "docker_db_ips").is_empty());
+    });
+}